@@ -0,0 +1,71 @@
+//! Rebroadcasting a storm of events, e.g. at coldplug
+//!
+//! Run with `cargo bench --bench rebroadcast`, the allocations are counted along with the time.
+
+use std::{env, fs, path::PathBuf, process};
+
+use divan::{AllocProfiler, Bencher};
+use kobject_uevent::{ActionType, UEvent};
+use mdev::{seqpacket::Publisher, RebroadcastFormat, RebroadcastMessage, Rebroadcaster};
+
+#[global_allocator]
+static ALLOC: AllocProfiler = AllocProfiler::system();
+
+/// Events queued before the rebroadcaster runs
+const STORM: usize = 1024;
+
+fn main() {
+    divan::main();
+}
+
+fn event(seq: u64) -> UEvent {
+    let devpath =
+        format!("/devices/pci0000:00/0000:00:1f.2/ata1/host0/target0:0:0/0:0:0:{seq}/block/sda");
+    let seqnum = seq.to_string();
+    UEvent {
+        action: ActionType::Add,
+        devpath: PathBuf::from(&devpath),
+        subsystem: String::from("block"),
+        env: [
+            ("ACTION", "add"),
+            ("DEVPATH", &devpath),
+            ("SUBSYSTEM", "block"),
+            ("MAJOR", "8"),
+            ("MINOR", "0"),
+            ("DEVNAME", "sda"),
+            ("DEVTYPE", "disk"),
+            ("DISKSEQ", "9"),
+            ("TAGS", ":systemd:"),
+            ("SEQNUM", &seqnum),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect(),
+        seq,
+    }
+}
+
+#[divan::bench(args = [RebroadcastFormat::Kernel, RebroadcastFormat::Libudev])]
+fn storm(bencher: Bencher, format: RebroadcastFormat) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    // nobody subscribes, only the encoding and the queuing are measured
+    let path = env::temp_dir().join(format!("mdev-bench-{}", process::id()));
+    bencher
+        .with_inputs(|| {
+            let publisher = Publisher::bind(&path).unwrap();
+            let (rebroadcaster, sender) = Rebroadcaster::builder(STORM)
+                .seqpacket(publisher, format)
+                .build();
+            for seq in 0..STORM as u64 {
+                sender
+                    .try_send(RebroadcastMessage::Event(event(seq)))
+                    .unwrap();
+            }
+            // the rebroadcaster stops once the channel is drained
+            rebroadcaster
+        })
+        .bench_local_values(|rebroadcaster| runtime.block_on(rebroadcaster).unwrap());
+    fs::remove_file(path).unwrap();
+}
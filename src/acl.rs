@@ -0,0 +1,181 @@
+//! POSIX access ACLs, stored in the `system.posix_acl_access` extended attribute
+
+use std::{fmt, str::FromStr};
+
+/// Name of the extended attribute holding the access ACL
+pub const XATTR: &str = "system.posix_acl_access";
+
+const VERSION: u32 = 2;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+/// Who an [`Entry`] grants permissions to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Qualifier<U = String, G = String> {
+    User(U),
+    Group(G),
+}
+
+/// An ACL entry like `u:video-user:rw`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<U = String, G = String> {
+    pub qualifier: Qualifier<U, G>,
+    /// Permissions as `rwx` bits
+    pub perms: u16,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("invalid ACL entry {0:?}, expected u:NAME:PERMS or g:NAME:PERMS")]
+    Format(String),
+    #[error("invalid ACL permission {0:?}")]
+    Perm(char),
+}
+
+impl FromStr for Entry {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(kind), Some(name), Some(perms)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::Format(s.to_string()));
+        };
+        if name.is_empty() {
+            return Err(ParseError::Format(s.to_string()));
+        }
+
+        let qualifier = match kind {
+            "u" | "user" => Qualifier::User(name.to_string()),
+            "g" | "group" => Qualifier::Group(name.to_string()),
+            _ => return Err(ParseError::Format(s.to_string())),
+        };
+        let perms = perms.chars().try_fold(0, |perms, c| match c {
+            'r' => Ok(perms | 0o4),
+            'w' => Ok(perms | 0o2),
+            'x' => Ok(perms | 0o1),
+            '-' => Ok(perms),
+            c => Err(ParseError::Perm(c)),
+        })?;
+
+        Ok(Self { qualifier, perms })
+    }
+}
+
+impl<U: fmt::Display, G: fmt::Display> fmt::Display for Entry<U, G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.qualifier {
+            Qualifier::User(user) => write!(f, "u:{user}:")?,
+            Qualifier::Group(group) => write!(f, "g:{group}:")?,
+        }
+        for (bit, c) in [(0o4, 'r'), (0o2, 'w'), (0o1, 'x')] {
+            write!(f, "{}", if self.perms & bit != 0 { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the access ACL of a file with the given `mode` and named `entries`,
+/// in the format expected by the kernel
+pub fn encode(mode: u32, entries: &[Entry<u32, u32>]) -> Vec<u8> {
+    let mut named: Vec<(u16, u16, u32)> = entries
+        .iter()
+        .map(|entry| match entry.qualifier {
+            Qualifier::User(uid) => (USER, entry.perms, uid),
+            Qualifier::Group(gid) => (GROUP, entry.perms, gid),
+        })
+        .collect();
+    named.sort_by_key(|&(tag, _, id)| (tag, id));
+
+    let group_perms = ((mode >> 3) & 0o7) as u16;
+    let mask = named
+        .iter()
+        .fold(group_perms, |mask, &(_, perms, _)| mask | perms);
+
+    let mut all = vec![(USER_OBJ, ((mode >> 6) & 0o7) as u16, UNDEFINED_ID)];
+    all.extend(named.iter().filter(|&&(tag, _, _)| tag == USER));
+    all.push((GROUP_OBJ, group_perms, UNDEFINED_ID));
+    all.extend(named.iter().filter(|&&(tag, _, _)| tag == GROUP));
+    if !named.is_empty() {
+        all.push((MASK, mask, UNDEFINED_ID));
+    }
+    all.push((OTHER, (mode & 0o7) as u16, UNDEFINED_ID));
+
+    let mut buf = Vec::with_capacity(4 + all.len() * 8);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    for (tag, perms, id) in all {
+        buf.extend_from_slice(&tag.to_le_bytes());
+        buf.extend_from_slice(&perms.to_le_bytes());
+        buf.extend_from_slice(&id.to_le_bytes());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "u:video-user:rw".parse::<Entry>().unwrap(),
+            Entry {
+                qualifier: Qualifier::User(String::from("video-user")),
+                perms: 0o6,
+            }
+        );
+        assert_eq!(
+            "group:audio:r-x".parse::<Entry>().unwrap(),
+            Entry {
+                qualifier: Qualifier::Group(String::from("audio")),
+                perms: 0o5,
+            }
+        );
+        assert_eq!("u:foo:rq".parse::<Entry>(), Err(ParseError::Perm('q')));
+        assert!("o::rw".parse::<Entry>().is_err());
+        assert!("u:foo".parse::<Entry>().is_err());
+    }
+
+    #[test]
+    fn encode_entries() {
+        let entries = [
+            Entry {
+                qualifier: Qualifier::Group(44),
+                perms: 0o6,
+            },
+            Entry {
+                qualifier: Qualifier::User(1000),
+                perms: 0o4,
+            },
+        ];
+        let buf = encode(0o640, &entries);
+        let words: Vec<(u16, u16, u32)> = buf[4..]
+            .chunks(8)
+            .map(|c| {
+                (
+                    u16::from_le_bytes([c[0], c[1]]),
+                    u16::from_le_bytes([c[2], c[3]]),
+                    u32::from_le_bytes([c[4], c[5], c[6], c[7]]),
+                )
+            })
+            .collect();
+        assert_eq!(&buf[..4], &2u32.to_le_bytes());
+        assert_eq!(
+            words,
+            [
+                (USER_OBJ, 0o6, UNDEFINED_ID),
+                (USER, 0o4, 1000),
+                (GROUP_OBJ, 0o4, UNDEFINED_ID),
+                (GROUP, 0o6, 44),
+                (MASK, 0o6, UNDEFINED_ID),
+                (OTHER, 0o0, UNDEFINED_ID),
+            ]
+        );
+    }
+}
@@ -1,13 +1,12 @@
 use std::path::PathBuf;
 
 use clap::Parser;
-use mdev::setup_log;
+use mdev::{enumerate::Enumerator, setup_log, LogFormat};
 use tracing::{debug, error};
-use walkdir::WalkDir;
 
 #[derive(Parser)]
 struct Opt {
-    /// Verbose mode, logs to stderr
+    /// Verbose mode, -v logs the debug lines as well, -vv the trace ones
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
@@ -18,7 +17,7 @@ struct Opt {
 
 impl Opt {
     fn setup_log(&self) -> anyhow::Result<()> {
-        setup_log(self.verbose)
+        Ok(setup_log(self.verbose, LogFormat::Text)?)
     }
 }
 
@@ -27,22 +26,11 @@ fn main() -> anyhow::Result<()> {
 
     opt.setup_log()?;
 
-    let classdir = WalkDir::new(opt.sysfs_mount.join("class"))
-        .follow_links(true)
-        .max_depth(4)
-        .into_iter();
-    let busdir = WalkDir::new(opt.sysfs_mount.join("bus"))
-        .follow_links(true)
-        .max_depth(3)
-        .into_iter();
-
-    for entry in classdir
-        .chain(busdir)
-        .filter_map(|e| e.ok().filter(|e| e.file_name().eq("uevent")))
-    {
-        debug!("{entry:?}");
-        let p = entry.path();
-        std::fs::write(p, "add").unwrap_or_else(|e| error!("cannot write to {}: {e}", p.display()));
+    for device in Enumerator::new(&opt.sysfs_mount).scan() {
+        debug!("{:?}", device.syspath());
+        let p = device.syspath().join("uevent");
+        std::fs::write(&p, "add")
+            .unwrap_or_else(|e| error!("cannot write to {}: {e}", p.display()));
     }
 
     Ok(())
@@ -1,48 +1,147 @@
 use std::{
-    collections::HashMap,
-    ffi::OsStr,
-    path::{Path, PathBuf},
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
+    ffi::OsString,
+    io,
+    net::SocketAddr,
+    os::{fd::RawFd, unix::fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
-use clap::Parser;
+use anyhow::Context;
+use clap::{ArgGroup, Parser};
 use fork::{daemon, Fork};
-use futures_util::StreamExt;
+use futures_util::{
+    stream::{self, FuturesUnordered},
+    StreamExt,
+};
 use kobject_uevent::{ActionType, UEvent};
 use nix::{
-    sys::stat::{makedev, mknod, Mode, SFlag},
-    unistd::{chown, unlink},
+    sys::signal::{kill, Signal},
+    sys::stat::{makedev, Mode, SFlag},
+    unistd::Pid,
+};
+use tokio::{
+    fs, join,
+    net::{TcpListener, UnixListener, UnixStream},
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch, Notify},
+    time::sleep,
 };
-use tokio::{fs, join};
-use tracing::{debug, info, warn};
-use walkdir::WalkDir;
+use tokio_util::compat::TokioAsyncWriteCompatExt;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 
-use mdev::{rule, setup_log, RebroadcastMessage, Rebroadcaster};
-use mdev_parser::Conf;
+use mdev::{
+    action_name, bootstrap,
+    control::{self, Request, Response},
+    db,
+    enumerate::Enumerator,
+    filter::{self, DevpathPattern, PropertyMatch, Subsystems},
+    firmware, log_filter,
+    manager::{make_node, DeviceManager},
+    metrics,
+    modalias::{self, Blacklist, ModuleIndex, ModuleLoader},
+    notify::{Notifier, ReadyFd},
+    pidfile::{self, PidFile},
+    rule::{self, Rule},
+    seq, setup_log_with,
+    stream::Listener,
+    sysfs,
+    table::{self, Table},
+    watchdog::{self, Watchdog},
+    Backpressure, LogFormat, LogTarget, RebroadcastFormat, RebroadcastMessage, Rebroadcaster,
+};
 
 #[derive(Parser)]
 #[command(after_help = r#"It uses /etc/mdev.conf with lines
-[-][ENV=regex;]...DEVNAME UID:GID PERM [>|=PATH]|[!] [@|$|*PROG]
+[-][ENV=regex;]...DEVNAME UID:GID PERM [KEY=value]... [>PATH[,PATH]...|=PATH]|[!] [@|$|*PROG]
 
 where DEVNAME is device name regex, @major,minor[-minor2], or environment variable regex.
 
 A common use of the latter is to load modules for hotplugged devices:
 $MODALIAS=.* 0:0 660 @modprobe "$MODALIAS"
 
+Options can be added after PERM as KEY=value fields:
+xattr=NAME=VALUE sets an extended attribute on the node
+selabel=CONTEXT sets the SELinux label of the node
+acl=u:USER:PERMS,g:GROUP:PERMS,... grants access to additional users and groups
+attr=NAME=VALUE writes a sysfs attribute of the device on add and change events, e.g.
+SUBSYSTEM=usb;.* root:root 660 attr=power/control=auto
+sysctl=KEY=VALUE sets a kernel parameter on add events, $VAR and %k are expanded, e.g.
+SUBSYSTEM=net;.* root:root 660 sysctl=net.ipv6.conf.$INTERFACE.disable_ipv6=1
+
+Lines starting with % are directives, restricting the subsystems whose events are handled:
+%subsystem-only block,usb
+%subsystem-skip bdi
+
+USB devices are created in subdirectories as bus/usb/BBB/DDD, removed with them once empty:
+SUBSYSTEM=usb;DEVTYPE=usb_device;.* root:usb 664
+
+Network interfaces have no node, =NAME renames them on add instead:
+SUBSYSTEM=net;DEVPATH=.*/usb[0-9]+/.*;eth([0-9]+) root:root 660 =usb%1
+
+Bind, unbind, online and offline events only run the commands of rules matching ACTION explicitly:
+ACTION=bind;DRIVER=foo;.* root:root 660 @setup-foo
+ACTION=online;$SUBSYSTEM=cpu root:root 660 @/etc/mdev/cpu-online.sh
+
+The dev directory of an image can be described without being root, e.g. for gen_init_cpio:
+mdev --scan --static-nodes --emit cpio-list > dev.list
+
 If /dev/mdev.seq file exists, mdev will wait for its value to match $SEQNUM variable. This prevents plug/unplug races.
+The daemon writes there the sequence number following each handled event.
 
 To activate this feature, create empty /dev/mdev.seq at boot.
 
+mdev handles the event in its environment when started with no arguments, so it can be the
+kernel hotplug helper:
+echo /sbin/mdev > /proc/sys/kernel/hotplug
+When the daemon runs, the helper forwards the event to it through the control socket instead.
+
 If /dev/mdev.log file exists, debug log will be appended to it.
+
+The daemon accepts commands on its control socket, one per connection:
+reload                     reads /etc/mdev.conf again
+settle                     waits for the events emitted so far to be handled
+trigger DEVPATH [ACTION]   makes the kernel emit ACTION, change by default, for a device
+status                     reports the pid, the handled events and the number of rules
+event KEY=VALUE...         handles the event of a hotplug helper, \u{HEX} escaping whitespace
 "#)]
+#[command(group(ArgGroup::new("sinks").multiple(true)))]
+#[command(group(ArgGroup::new("packet_sinks").multiple(true)))]
 struct Opt {
-    /// Verbose mode, logs to stderr
+    /// Verbose mode, -v logs the debug lines as well, -vv the trace ones
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
     /// Log to syslog as well
     #[arg(short = 'S', long)]
     syslog: bool,
-    /// Scan /sys and populates /dev
+    /// Format of the log lines, text or json, one object per line with the seqnum, devpath,
+    /// action and rule fields of the events
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    log_format: LogFormat,
+    /// Signal each event handled by the daemon on the system bus, with its nodes and
+    /// properties, as org.mdev.Events.Event from /org/mdev/Events
+    #[cfg(feature = "dbus")]
+    #[arg(long, requires = "daemon")]
+    dbus: bool,
+    /// Lines to log, as directives of $RUST_LOG overriding it and -v, e.g.
+    /// mdev::rule=trace,info to debug the rules only
+    #[arg(long, value_name = "DIRECTIVES")]
+    log_filter: Option<String>,
+    /// Export the spans of the events to an OpenTelemetry collector through OTLP over HTTP,
+    /// e.g. http://localhost:4318/v1/traces
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+    /// Scan the sysfs and populates /dev
     #[arg(short, long)]
     scan: bool,
     /// Daemon mode, listen on netlink
@@ -54,250 +153,1450 @@ struct Opt {
     /// Path to the dev to populate (useful for debugging and testing)
     #[arg(long, default_value = "/dev")]
     devpath: PathBuf,
-    /// Rebroadcast events to 0x4 netlink group
-    #[arg(long, short)]
+    /// Path where the sysfs is mounted (useful for testing and chroots)
+    #[arg(long, default_value = "/sys")]
+    sysfs: PathBuf,
+    /// Rebroadcast the handled events on netlink, to the 0x4 group unless --rebroadcast-group
+    /// is given
+    #[arg(long, short, groups = ["sinks", "packet_sinks"])]
     rebroadcast: bool,
+    /// Format of the events rebroadcast on netlink and on the socket, kernel or libudev,
+    /// received as udev events by the applications linked against libudev
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "kernel",
+        requires = "packet_sinks"
+    )]
+    rebroadcast_format: RebroadcastFormat,
+    /// Rebroadcast the handled events on a Unix seqpacket socket at PATH, one event per
+    /// packet, for the unprivileged or containerized listeners
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = mdev::seqpacket::DEFAULT_PATH,
+        groups = ["sinks", "packet_sinks"]
+    )]
+    rebroadcast_socket: Option<PathBuf>,
+    /// Append the properties of the handled events to PATH, a KEY=VALUE line each, followed
+    /// by an empty line, whatever the --rebroadcast-format
+    #[arg(long, value_name = "PATH", group = "sinks")]
+    rebroadcast_file: Option<PathBuf>,
+    /// Netlink groups the events are rebroadcast to, as a bitmask [default: 4, 2 in the
+    /// libudev format]
+    #[arg(long, value_name = "GROUPS", requires = "rebroadcast")]
+    rebroadcast_group: Option<u32>,
+    /// Port the events are rebroadcast to as well, e.g. the pid of a listener
+    #[arg(
+        long,
+        value_name = "PORT",
+        default_value_t = 0,
+        requires = "rebroadcast"
+    )]
+    rebroadcast_pid: u32,
+    /// Only rebroadcast the events of these subsystems
+    #[arg(
+        long,
+        value_name = "SUBSYSTEMS",
+        value_delimiter = ',',
+        requires = "sinks"
+    )]
+    rebroadcast_subsystem: Vec<String>,
+    /// Only rebroadcast the events with these actions, e.g. add,remove
+    #[arg(
+        long,
+        value_name = "ACTIONS",
+        value_delimiter = ',',
+        requires = "sinks"
+    )]
+    rebroadcast_action: Vec<ActionType>,
+    /// Only rebroadcast the events whose property VAR matches REGEX, as the $VAR=regex fields
+    /// of the rules, e.g. DEVTYPE=disk, can be repeated
+    #[arg(long, value_name = "VAR=REGEX", requires = "sinks")]
+    rebroadcast_match: Vec<PropertyMatch>,
+    /// Add what the rules created to the rebroadcast events: DEVNAME becomes the path of the
+    /// node, DEVLINKS lists its links, MDEV_OWNER, MDEV_GROUP and MDEV_MODE are set as well
+    #[arg(long, requires = "sinks")]
+    rebroadcast_resolved: bool,
+    /// What happens to the events of a sink falling behind: block the handling of the events,
+    /// drop-newest or drop-oldest to never stall it, the dropped events being counted
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "block",
+        requires = "sinks"
+    )]
+    rebroadcast_backpressure: Backpressure,
+    /// Failed sends to a sink in a row before giving up on it, the other sinks are still
+    /// served and the failed sends are retried after a backoff
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = mdev::DEFAULT_MAX_FAILURES,
+        requires = "sinks"
+    )]
+    rebroadcast_max_failures: u32,
+    /// Do not create the node as root:root 660 when no rule matches
+    #[arg(long)]
+    no_default_node: bool,
+    /// Seconds after which cached user and group names are resolved again
+    #[arg(long, value_name = "SECONDS")]
+    id_cache_ttl: Option<u64>,
+    /// Directory where the nodes and links created for each device are recorded
+    #[arg(long, default_value = db::DEFAULT_DIR)]
+    db: PathBuf,
+    /// Directory where the firmware requested by the kernel is looked up, can be repeated
+    #[arg(long = "firmware-dir", value_name = "DIR", default_values = firmware::DEFAULT_DIRS)]
+    firmware_dirs: Vec<PathBuf>,
+    /// Load the modules matching $MODALIAS using modules.alias, without running modprobe
+    #[arg(long)]
+    modalias: bool,
+    /// Directory of the kernel modules [default: /lib/modules/$(uname -r)]
+    #[arg(long, value_name = "DIR")]
+    modules_dir: Option<PathBuf>,
+    /// Create the standard nodes such as null and console, the fd and stdio links and the pts
+    /// and shm directories, for a dev directory not populated by devtmpfs
+    #[arg(long)]
+    static_nodes: bool,
+    /// Link the block devices in /dev/disk/by-id, by-path, by-partuuid and by-partlabel, and in
+    /// by-uuid and by-label probing them for filesystems
+    #[arg(long)]
+    probe: bool,
+    /// Link the input devices in /dev/input/by-id and by-path
+    #[arg(long)]
+    input_links: bool,
+    /// Name the network interfaces not renamed by the rules after a template,
+    /// %mac% being the permanent hardware address and %path% the parent device, e.g. enx%mac%
+    #[arg(long, value_name = "TEMPLATE")]
+    net_name: Option<String>,
+    /// Ignore the events of the devices whose DEVPATH starts with PATTERN, or matches it when
+    /// it contains *, ? or [, e.g. /devices/virtual/bdi/*, can be repeated
+    #[arg(long = "ignore", value_name = "PATTERN")]
+    ignore: Vec<DevpathPattern>,
+    /// Only handle the events of these subsystems, on top of the %subsystem-only directives
+    #[arg(long, value_name = "SUBSYSTEMS", value_delimiter = ',')]
+    subsystem_only: Vec<String>,
+    /// Skip the events of these subsystems, on top of the %subsystem-skip directives
+    #[arg(long, value_name = "SUBSYSTEMS", value_delimiter = ',')]
+    subsystem_skip: Vec<String>,
+    /// Module never loaded for a $MODALIAS, on top of the modprobe.d blacklist, can be repeated
+    #[arg(long = "blacklist", value_name = "MODULE")]
+    blacklist: Vec<String>,
+    /// Load the modules matching $MODALIAS through libkmod, honoring the modprobe.d configuration
+    #[cfg(feature = "kmod")]
+    #[arg(long, conflicts_with_all = ["modalias", "modules_dir"])]
+    kmod: bool,
+    /// Print the nodes and links the rules would create, as a device-table or a cpio-list,
+    /// instead of creating them, e.g. to build the dev directory of an image without root
+    #[arg(long, value_name = "FORMAT", conflicts_with = "daemon")]
+    emit: Option<table::Format>,
+    /// When events have been lost, e.g. under load once the receive buffer overflows, rescan
+    /// the sysfs to remove the devices gone meanwhile and add the ones not created yet
+    #[arg(long, requires = "daemon")]
+    rescan_on_gap: bool,
+    /// Hold the events up to this long to handle them in SEQNUM order, e.g. a remove received
+    /// before the add of the same device
+    #[arg(long, value_name = "MILLISECONDS", requires = "daemon")]
+    reorder_window: Option<u64>,
+    /// Bytes of events the kernel queues while the daemon is busy, e.g. at coldplug, capped to
+    /// net.core.rmem_max unless the daemon has CAP_NET_ADMIN
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = mdev::stream::RECEIVE_BUFFER,
+        requires = "daemon"
+    )]
+    receive_buffer: usize,
+    /// Handle the change events of a device with the same properties at most once this often,
+    /// the last one received, e.g. for the storms of some thermal or power_supply drivers
+    #[arg(long, value_name = "MILLISECONDS", requires = "daemon")]
+    debounce: Option<u64>,
+    /// When the daemon falls behind, merge the waiting events of a device with the same
+    /// action and properties and drop the adds cancelled by a remove
+    #[arg(long, requires = "daemon")]
+    coalesce: bool,
+    /// Descriptor where the daemon writes a newline once ready, as the s6 readiness protocol
+    /// expects, e.g. for s6 or OpenRC to start the services needing the devices
+    #[arg(long, value_name = "FD", requires = "daemon")]
+    notify_fd: Option<RawFd>,
+    /// Keep the watchdog device alive from the event loop, so that the system is reset if the
+    /// daemon hangs [default: /dev/watchdog]
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = watchdog::DEFAULT_PATH,
+        requires = "daemon"
+    )]
+    watchdog: Option<PathBuf>,
+    /// Seconds between the keepalives of the watchdog device
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        requires = "watchdog"
+    )]
+    watchdog_interval: u64,
+    /// Serve the Prometheus metrics of the daemon at http://ADDR/metrics, e.g. 127.0.0.1:9117
+    #[arg(long, value_name = "ADDR", requires = "daemon")]
+    metrics_listen: Option<SocketAddr>,
+    /// Write the Prometheus metrics of the daemon to this file every 15 seconds, e.g. for the
+    /// textfile collector of the node exporter
+    #[arg(long, value_name = "PATH", requires = "daemon")]
+    metrics_file: Option<PathBuf>,
+    /// File where the daemon writes its pid
+    #[arg(long, value_name = "PATH", default_value = pidfile::DEFAULT_PATH)]
+    pidfile: PathBuf,
+    /// Stop the running daemon, found through the pid file, and wait for it to exit
+    #[arg(long, conflicts_with_all = ["daemon", "scan"])]
+    kill: bool,
+    /// Events the daemon handles at once, those of a device and its parents and children
+    /// still being handled in order
+    #[arg(long, value_name = "COUNT", default_value_t = 8, requires = "daemon")]
+    workers: usize,
+    /// Runtime of the daemon, multi-thread handles the events of several devices in parallel
+    /// while current-thread keeps the footprint of small systems down
+    #[arg(long, value_enum, default_value_t = Runtime::MultiThread, requires = "daemon")]
+    runtime: Runtime,
+    /// Threads of the multi-thread runtime [default: the number of CPUs]
+    #[arg(long, value_name = "COUNT", requires = "daemon")]
+    worker_threads: Option<usize>,
+    /// Socket where the daemon accepts the reload, settle, trigger and status commands
+    #[arg(long, value_name = "PATH", default_value = control::DEFAULT_PATH)]
+    control: PathBuf,
+    /// Handle the event described by the environment, as the kernel hotplug helper does when
+    /// started with the subsystem as its only argument
+    #[arg(long, conflicts_with_all = ["daemon", "scan", "kill", "emit"])]
+    hotplug: bool,
+    /// Subsystem of the event, the argument the kernel passes to the hotplug helper
+    #[arg(value_name = "SUBSYSTEM", conflicts_with_all = ["daemon", "scan", "kill", "emit"])]
+    subsystem: Option<String>,
 }
 
-async fn react_to_event(
-    path: &Path,
-    env: &HashMap<String, String>,
-    action: ActionType,
-    conf: &[Conf],
-    devpath: &Path,
-) -> anyhow::Result<()> {
-    let in_sys = Path::new("/sys").join(path.strip_prefix("/")?);
-    let dev = fs::read_to_string(&in_sys.join("dev")).await.ok();
-    let uevent = fs::read_to_string(&in_sys.join("uevent")).await.ok();
-
-    let devname = if let Some(devname) = env.get("DEVNAME") {
-        devname.as_str()
-    } else {
-        if let Some(ref uevent) = uevent {
-            uevent.lines().find_map(|line| {
-                if let Some((k, v)) = line.split_once('=') {
-                    if k == "DEVNAME" {
-                        Some(v)
-                    } else {
-                        None
-                    }
-                } else {
+/// Exports the last spans when dropped
+#[derive(Default)]
+struct LogGuard {
+    #[cfg(feature = "otlp")]
+    _exporter: Option<mdev::otlp::Exporter>,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Runtime {
+    CurrentThread,
+    MultiThread,
+}
+
+/// What the daemon reports on its control socket and to the service manager
+struct DaemonState {
+    /// SEQNUM up to which all the events are handled
+    handled: watch::Sender<u64>,
+    /// Events received and not yet handled nor dropped, moving `handled` once the earlier
+    /// ones are done too
+    in_flight: Mutex<seq::InFlight>,
+    /// Number of events handled
+    events: AtomicU64,
+    /// Events forwarded by the hotplug helpers, handled along the ones from netlink
+    forwarded: mpsc::UnboundedSender<UEvent>,
+    notifier: Option<Notifier>,
+    /// Events passed to the rebroadcaster
+    rebroadcast: filter::Rebroadcast,
+    /// Whether what the rules created is added to the rebroadcast events
+    rebroadcast_resolved: bool,
+    /// Where the events are signaled on the system bus
+    #[cfg(feature = "dbus")]
+    signaler: Option<mdev::dbus::Signaler>,
+}
+
+impl DaemonState {
+    fn notify(&self, state: &str) {
+        if let Some(notifier) = &self.notifier {
+            if let Err(e) = notifier.notify(state) {
+                warn!("Cannot notify the service manager: {e}");
+            }
+        }
+    }
+
+    /// Tracks the event `seq` from its reception, until it is handled or dropped
+    fn start(&self, seq: u64) {
+        if seq != 0 {
+            self.in_flight.lock().unwrap().start(seq);
+        }
+    }
+
+    /// Marks the event `seq` as handled or dropped, moving `handled` once the earlier ones
+    /// are done too
+    fn finish(&self, seq: u64) {
+        if seq == 0 {
+            return;
+        }
+        // the events of other devices may complete later, or have already
+        let done = self.in_flight.lock().unwrap().finish(seq);
+        self.handled.send_if_modified(|handled| {
+            let modified = done > *handled;
+            *handled = (*handled).max(done);
+            modified
+        });
+    }
+
+    /// Drops `ev` if its subsystem is not handled
+    fn skips(&self, manager: &DeviceManager, ev: &UEvent) -> bool {
+        if manager.handles(&ev.subsystem) {
+            return false;
+        }
+        debug!("Skipping the event of the {} subsystem", ev.subsystem);
+        self.finish(ev.seq);
+        true
+    }
+
+    /// Waits for the events up to `target` to be handled or dropped
+    async fn settle(&self, target: u64) {
+        let mut handled = self.handled.subscribe();
+        // the sender lives as long as the daemon
+        let _ = handled.wait_for(|&seq| seq >= target).await;
+    }
+}
+
+/// Number of SEQNUMs remembered to skip the events received both from netlink and a hotplug
+/// helper
+const HANDLED_SEQS: usize = 1024;
+
+/// How often the daemon writes its metrics with `--metrics-file`
+const METRICS_FILE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long the rebroadcaster has to send the queued events once the daemon stops
+const REBROADCAST_DRAIN: Duration = Duration::from_secs(5);
+
+/// Whether an event was handled since `last`, updated to `handled`, or none is waiting
+///
+/// A stuck event loop stops pinging the watchdogs, an idle one keeps them alive.
+fn progressed(last: &mut u64, handled: u64, queued: usize) -> bool {
+    let progressed = handled != *last || queued == 0;
+    *last = handled;
+    progressed
+}
+
+/// Reads the environment of a hotplug helper, the variables that are not UTF-8 are left out
+///
+/// `subsystem` is the argument of the helper, used if `SUBSYSTEM` is not set.
+fn hotplug_env(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+    subsystem: Option<&str>,
+) -> HashMap<String, String> {
+    let mut env: HashMap<_, _> = vars
+        .into_iter()
+        .filter_map(
+            |(name, value)| match (name.into_string(), value.into_string()) {
+                (Ok(name), Ok(value)) => Some((name, value)),
+                (name, _) => {
+                    debug!("Skipping the variable {:?}, not UTF-8", name);
                     None
                 }
-            })
-        } else {
-            None
-        }
-        // I don't like those unwraps
-        .unwrap_or_else(|| path.file_name().unwrap().to_str().unwrap())
+            },
+        )
+        .collect();
+    if let Some(subsystem) = subsystem {
+        env.entry(String::from("SUBSYSTEM"))
+            .or_insert_with(|| subsystem.to_string());
+    }
+    env
+}
+
+/// Reads the event described by the environment of a hotplug helper
+fn uevent_from_env(env: HashMap<String, String>) -> anyhow::Result<UEvent> {
+    let var = |name| {
+        env.get(name)
+            .with_context(|| format!("{} is not set", name))
     };
+    Ok(UEvent {
+        action: var("ACTION")?.parse()?,
+        devpath: PathBuf::from(var("DEVPATH")?),
+        subsystem: var("SUBSYSTEM")?.clone(),
+        seq: match env.get("SEQNUM") {
+            Some(seqnum) => seqnum
+                .parse()
+                .with_context(|| format!("Invalid SEQNUM {:?}", seqnum))?,
+            None => 0,
+        },
+        env,
+    })
+}
 
-    let device_number = if let Some(ref dev) = dev {
-        if let Some((maj, min)) = dev.trim().split_once(':') {
-            Some((maj.parse::<u32>()?, min.parse::<u32>()?))
-        } else {
+/// Reads the rules and the directives, none if there is no configuration
+fn load_conf() -> (Vec<Rule>, Subsystems) {
+    match std::fs::read_to_string(rule::CONF) {
+        Ok(input) => (rule::parse(&input), Subsystems::from_directives(&input)),
+        Err(_) => Default::default(),
+    }
+}
+
+/// Returns the SEQNUM of the last event emitted by the kernel
+fn kernel_seqnum(sysfs: &Path) -> anyhow::Result<u64> {
+    // an attribute of the sysfs, read without blocking
+    let path = sysfs.join("kernel/uevent_seqnum");
+    let seqnum =
+        std::fs::read_to_string(&path).with_context(|| format!("Cannot read {:?}", path))?;
+    Ok(seqnum.trim().parse()?)
+}
+
+/// Span of the handling of `ev`, the log lines and the child spans of the rules and commands
+/// are attributed to
+fn event_span(ev: &UEvent) -> Span {
+    info_span!(
+        "event",
+        seqnum = ev.seq,
+        action = %action_name(ev.action),
+        subsystem = %ev.subsystem,
+        devpath = %ev.devpath.display(),
+        otel.status_code = field::Empty,
+    )
+}
+
+/// Handles an event of the daemon, then reports it as handled
+async fn handle_event(
+    manager: &DeviceManager,
+    state: Arc<DaemonState>,
+    rebroadcast_sender: Option<mpsc::Sender<RebroadcastMessage>>,
+    mut ev: UEvent,
+) {
+    let record = match manager.handle_event(&ev).await {
+        Ok(record) => Some(record),
+        Err(e) => {
+            // the span of the event is reported as failed by the exporter
+            Span::current().record("otel.status_code", "ERROR");
+            warn!("{}", e.report());
             None
         }
-    } else {
-        None
     };
-
-    for rule in conf {
-        let devname = if let Some(s) =
-            rule::apply(rule, env, device_number, action, devpath, devname).await?
+    #[cfg(feature = "dbus")]
+    if let (Some(signaler), Some(record)) = (&state.signaler, &record) {
+        if let Err(e) = signaler.event(&ev, &record.nodes).await {
+            warn!("Cannot signal the event on the system bus: {e}");
+        }
+    }
+    if let (true, Some(record)) = (state.rebroadcast_resolved, &record) {
+        record.add_properties(&mut ev.env);
+    }
+    manager.metrics().handled(ev.action, &ev.subsystem);
+    state.finish(ev.seq);
+    state.events.fetch_add(1, Ordering::Relaxed);
+    if let Some(rebroadcast_sender) = rebroadcast_sender.filter(|_| state.rebroadcast.allows(&ev)) {
+        if rebroadcast_sender
+            .send(RebroadcastMessage::Event(ev))
+            .await
+            .is_err()
         {
-            s
-        } else {
-            continue;
-        };
-
-        let dev_full_path = devpath.join(devname.as_ref());
-        let dev_full_dir = dev_full_path.parent().unwrap();
-
-        match action {
-            ActionType::Add => {
-                if let Some((maj, min)) = device_number {
-                    let uid = nix::unistd::User::from_name(&rule.user)?
-                        .ok_or_else(|| anyhow!("User {} does not exist", rule.user))?
-                        .uid;
-                    let gid = nix::unistd::Group::from_name(&rule.group)?
-                        .ok_or_else(|| anyhow!("Group {} does not exist", rule.group))?
-                        .gid;
-
-                    fs::create_dir_all(dev_full_dir).await?;
-                    let kind = if path.iter().any(|v| v == OsStr::new("block")) {
-                        SFlag::S_IFBLK
-                    } else {
-                        SFlag::S_IFCHR
-                    };
-                    let mode = Mode::from_bits(rule.mode)
-                        .ok_or_else(|| anyhow::anyhow!("Invalid mode"))?;
-                    let dev = makedev(maj.into(), min.into());
-
-                    info!(
-                        "Creating {:?} {:?} {:?} {:?}",
-                        dev_full_path, kind, mode, dev
-                    );
-                    mknod(&dev_full_path, kind, mode, dev)?;
-                    chown(&dev_full_path, Some(uid), Some(gid))?;
-                }
-            }
-            ActionType::Remove => {
-                info!("Removing {:?}", dev_full_path);
-                unlink(&dev_full_path)?;
-            }
-            _ => info!("Action {:?}", action),
+            warn!("rebroadcaster channel is closed");
         }
+    }
+}
 
-        // TODO: actual actions
-
-        if rule.stop {
-            break;
+/// Writes the SEQNUM up to which all the events are handled to the `mdev.seq` file, for the
+/// scripts and the hotplug helpers waiting on it, never returns
+async fn publish_handled(manager: &DeviceManager, state: &DaemonState) {
+    let seq_file = manager.devpath().join(seq::FILE);
+    let mut handled = state.handled.subscribe();
+    // the sender lives as long as the daemon
+    while handled.changed().await.is_ok() {
+        let done = *handled.borrow_and_update();
+        if let Err(e) = seq::advance(&seq_file, done).await {
+            warn!("Cannot update {:?}: {}", seq_file, e);
         }
     }
-
-    Ok(())
+    std::future::pending().await
 }
 
 impl Opt {
-    #[tokio::main]
-    async fn run_daemon(&self, conf: &[Conf]) -> anyhow::Result<()> {
+    /// Runs the daemon with its pid file, in the process that will handle the events
+    fn daemon_main(
+        &self,
+        manager: &'static DeviceManager,
+        pidfile: PidFile,
+        listener: Listener,
+        seqnum: u64,
+        notifier: Option<Notifier>,
+        ready_fd: Option<ReadyFd>,
+    ) -> anyhow::Result<()> {
+        pidfile
+            .write_pid()
+            .with_context(|| format!("Cannot write the pid file {:?}", self.pidfile))?;
+        let mut runtime = match self.runtime {
+            Runtime::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+            Runtime::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+        };
+        if let Some(threads) = self.worker_threads {
+            runtime.worker_threads(threads);
+        }
+        let res = runtime
+            .enable_all()
+            .build()
+            .context("Cannot start the runtime")
+            .and_then(|runtime| {
+                runtime.block_on(self.run_daemon(manager, listener, seqnum, notifier, ready_fd))
+            });
+        drop(pidfile);
+        res
+    }
+
+    /// Handles the events queued by `listener` since the kernel emitted `seqnum`, then the
+    /// next ones
+    async fn run_daemon(
+        &self,
+        manager: &'static DeviceManager,
+        listener: Listener,
+        seqnum: u64,
+        notifier: Option<Notifier>,
+        ready_fd: Option<ReadyFd>,
+    ) -> anyhow::Result<()> {
         info!("mdev daemon starts");
 
+        // installed right away, the default action would kill the daemon mid-event
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let events = listener.into_stream()?;
+        // starts the countdown of the rebroadcaster, the events being handled may wait for it
+        let stopping = Notify::new();
+        let closer = events.closer();
+        let shutdown = async {
+            let name = select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            };
+            info!("{} received, stopping", name);
+            stopping.notify_one();
+            closer.close();
+        };
+
         // Waiting for `Option::unzip` or try_blocks
-        let (rebroadcaster, rebroadcast_sender) = match self
-            .rebroadcast
-            .then(|| Rebroadcaster::new(16))
-            .transpose()?
-        {
+        let (rebroadcaster, rebroadcast_sender) = match self.rebroadcaster(manager).await? {
             Some((rebroadcaster, sender)) => (Some(rebroadcaster), Some(sender)),
             None => (None, None),
         };
 
+        // SEQNUM of the last event, to notice the lost ones
+        let last_seq = Cell::new(None);
+        let handled_seqs = RefCell::new(BTreeSet::new());
+        let (forwarded, mut forwarded_events) = mpsc::unbounded_channel();
+        // the events emitted before have been handled by the scan, if at all
+        let state = Arc::new(DaemonState {
+            handled: watch::Sender::new(seqnum),
+            in_flight: Mutex::new(seq::InFlight::new(seqnum)),
+            events: AtomicU64::new(0),
+            forwarded,
+            notifier,
+            rebroadcast: filter::Rebroadcast {
+                subsystems: self.rebroadcast_subsystem.clone(),
+                actions: self.rebroadcast_action.clone(),
+                matches: self.rebroadcast_match.clone(),
+            },
+            rebroadcast_resolved: self.rebroadcast_resolved,
+            #[cfg(feature = "dbus")]
+            signaler: match self.dbus {
+                true => Some(
+                    mdev::dbus::Signaler::system()
+                        .await
+                        .context("Cannot connect to the system bus")?,
+                ),
+                false => None,
+            },
+        });
+        let control = self.bind_control().await?;
+        let metrics_listener = match self.metrics_listen {
+            Some(addr) => Some(
+                TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Cannot listen on {} for the metrics", addr))?,
+            ),
+            None => None,
+        };
+        let mut watchdog = match &self.watchdog {
+            Some(path) => Some(
+                Watchdog::open(path)
+                    .with_context(|| format!("Cannot open the watchdog {:?}", path))?,
+            ),
+            None => None,
+        };
+        // the scan is done and the events are queued by the listener meanwhile
+        state.notify(&format!(
+            "READY=1\nMAINPID={}\nSTATUS=Handling the events, {} rules",
+            process::id(),
+            manager.rules().len()
+        ));
+        if let Some(ready_fd) = ready_fd {
+            if let Err(e) = ready_fd.ready() {
+                warn!("Cannot notify the readiness on --notify-fd: {e}");
+            }
+        }
+
         let reactor_fut = async {
-            mdev::stream::uevents()?
-                .for_each(|ev| async {
-                    info!("event {:?}", ev);
-
-                    match ev {
-                        Ok(ev) => {
-                            if let Err(e) =
-                                react_to_event(&ev.devpath, &ev.env, ev.action, conf, &self.devpath)
-                                    .await
-                            {
-                                warn!("{e}");
+            // the stream finishes once both are closed, the events held by the adapters are
+            // handled meanwhile
+            let forwarded_events = stream::poll_fn(|cx| forwarded_events.poll_recv(cx))
+                .map(Ok)
+                .take_until(Box::pin(shutdown));
+            let events = stream::select(events, forwarded_events);
+            let events = match self.reorder_window {
+                Some(window) => {
+                    mdev::stream::reorder(events, Duration::from_millis(window)).boxed_local()
+                }
+                None => events.boxed_local(),
+            };
+            let events = events.filter_map(|ev| async {
+                let ev = match ev {
+                    Ok(ev) => ev,
+                    Err(e @ mdev::stream::Error::Overflow { .. }) => {
+                        warn!("{}", e);
+                        if self.rescan_on_gap {
+                            match self.reconcile(manager).await {
+                                // the next event is not a gap anymore
+                                Ok(()) => last_seq.set(None),
+                                Err(e) => warn!("Cannot rescan: {e}"),
                             }
-                            if let Some(rebroadcast_sender) = &rebroadcast_sender {
-                                if rebroadcast_sender
-                                    .send(RebroadcastMessage::Event(ev))
-                                    .await
-                                    .is_err()
-                                {
-                                    warn!("rebroadcaster channel is closed");
-                                }
+                        }
+                        return None;
+                    }
+                    Err(e) => {
+                        warn!("{}", e);
+                        return None;
+                    }
+                };
+                info!(
+                    seqnum = ev.seq,
+                    devpath = %ev.devpath.display(),
+                    action = %action_name(ev.action),
+                    subsystem = %ev.subsystem,
+                    "event"
+                );
+                debug!(seqnum = ev.seq, "environment {:?}", ev.env);
+                manager.metrics().received.fetch_add(1, Ordering::Relaxed);
+
+                // a forwarded event is received from netlink as well, unless the
+                // helper is run by a later SEQNUM
+                if ev.seq != 0 {
+                    let mut handled_seqs = handled_seqs.borrow_mut();
+                    if !handled_seqs.insert(ev.seq) {
+                        debug!("event {} already handled", ev.seq);
+                        return None;
+                    }
+                    if handled_seqs.len() > HANDLED_SEQS {
+                        handled_seqs.pop_first();
+                    }
+                }
+                // settle waits for it from now on, whether it is handled or dropped
+                state.start(ev.seq);
+                // late events, delivered after the reordering window, are not gaps
+                let last = last_seq.get();
+                last_seq.set(Some(last.map_or(ev.seq, |last: u64| last.max(ev.seq))));
+                if let Some(last) = last {
+                    if ev.seq > last + 1 {
+                        warn!(
+                            "{} events lost between SEQNUM {} and {}",
+                            ev.seq - last - 1,
+                            last,
+                            ev.seq
+                        );
+                        if self.rescan_on_gap {
+                            if let Err(e) = self.reconcile(manager).await {
+                                warn!("Cannot rescan: {e}");
                             }
                         }
-                        Err(e) => warn!("{}", e),
                     }
-                })
-                .await;
+                }
+                // after the gap detection, the events left out are not lost
+                if state.skips(manager, &ev) {
+                    return None;
+                }
+                Some(ev)
+            });
+            let events = match self.debounce {
+                Some(window) => {
+                    let state = Arc::clone(&state);
+                    let finish_dropped = move |ev: UEvent| state.finish(ev.seq);
+                    let window = Duration::from_millis(window);
+                    mdev::stream::debounce(Box::pin(events), window, finish_dropped).boxed_local()
+                }
+                None => events.boxed_local(),
+            };
+            let mut finish_dropped = |ev: UEvent| state.finish(ev.seq);
+            let handle_events = mdev::stream::for_each_device(
+                events,
+                self.workers,
+                self.coalesce
+                    .then_some(&mut finish_dropped as &mut dyn FnMut(UEvent)),
+                &manager.metrics().queue_depth,
+                |ev| {
+                    let state = Arc::clone(&state);
+                    let rebroadcast_sender = rebroadcast_sender.clone();
+                    async move {
+                        // on the threads of the runtime, along the events of the other devices
+                        let span = event_span(&ev);
+                        let handling = tokio::spawn(
+                            handle_event(manager, state, rebroadcast_sender, ev).instrument(span),
+                        );
+                        if let Err(e) = handling.await {
+                            warn!("Event handling failed: {e}");
+                        }
+                    }
+                },
+            );
+            select! {
+                () = handle_events => {}
+                () = self.serve_control(manager, &control, &state) => {}
+                () = self.keepalive(manager, &state, watchdog.as_mut()) => {}
+                () = self.export_metrics(manager, metrics_listener.as_ref()) => {}
+                () = publish_handled(manager, &state) => {}
+            }
+            state.notify("STOPPING=1");
+            if let Some(watchdog) = watchdog.take() {
+                if let Err(e) = watchdog.disarm() {
+                    warn!("Cannot disarm the watchdog: {e}");
+                }
+            }
 
             if let Some(rebroadcast_sender) = &rebroadcast_sender {
-                if rebroadcast_sender
-                    .send(RebroadcastMessage::Stop)
-                    .await
-                    .is_err()
-                {
-                    panic!("rebroadcaster channel is closed");
-                }
+                stopping.notify_one();
+                // closed if the rebroadcaster failed or gave up on all the sinks
+                let _ = rebroadcast_sender.send(RebroadcastMessage::Stop).await;
             }
             Ok(())
         };
 
-        match rebroadcaster {
-            Some(rebroadcaster) => join!(reactor_fut, rebroadcaster).0,
+        // the rebroadcaster sends the queued events before stopping, unless a sink is stuck
+        let res = match rebroadcaster {
+            Some(rebroadcaster) => {
+                // logged as soon as it gives up, the daemon goes on without it
+                let rebroadcaster = async {
+                    let res = select! {
+                        res = rebroadcaster => res,
+                        () = async {
+                            stopping.notified().await;
+                            sleep(REBROADCAST_DRAIN).await;
+                        } => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "the queued events could not be sent in time",
+                        )),
+                    };
+                    if let Err(e) = res {
+                        warn!("Cannot rebroadcast: {e}");
+                    }
+                };
+                let (res, ()) = join!(reactor_fut, rebroadcaster);
+                res
+            }
             None => reactor_fut.await,
+        };
+        let _ = fs::remove_file(&self.control).await;
+        info!("mdev daemon stops");
+        res
+    }
+
+    /// Pings the watchdogs while the event loop makes progress, never returns
+    async fn keepalive(
+        &self,
+        manager: &DeviceManager,
+        state: &DaemonState,
+        mut watchdog: Option<&mut Watchdog>,
+    ) {
+        // pinged twice per timeout, as sd_watchdog_enabled recommends
+        let systemd = state
+            .notifier
+            .as_ref()
+            .and_then(Notifier::watchdog)
+            .map(|timeout| timeout / 2);
+        let device = watchdog
+            .is_some()
+            .then(|| Duration::from_secs(self.watchdog_interval));
+        let Some(period) = systemd.into_iter().chain(device).min() else {
+            return std::future::pending().await;
+        };
+
+        let mut interval = tokio::time::interval(period);
+        let mut handled = state.events.load(Ordering::Relaxed);
+        loop {
+            interval.tick().await;
+            let queued = manager.metrics().queue_depth.load(Ordering::Relaxed);
+            if !progressed(&mut handled, state.events.load(Ordering::Relaxed), queued) {
+                warn!("No event handled for {period:?}, the watchdogs are not pinged");
+                continue;
+            }
+            if systemd.is_some() {
+                state.notify("WATCHDOG=1");
+            }
+            if let Some(watchdog) = watchdog.as_mut() {
+                if let Err(e) = watchdog.keepalive() {
+                    warn!("Cannot keep the watchdog alive: {e}");
+                }
+            }
+        }
+    }
+
+    /// Serves the metrics and writes them to their file while the event loop runs, never
+    /// returns
+    async fn export_metrics(&self, manager: &DeviceManager, listener: Option<&TcpListener>) {
+        let serve = async {
+            match listener {
+                Some(listener) => metrics::serve(listener, manager.metrics()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let write = async {
+            let Some(path) = &self.metrics_file else {
+                return std::future::pending().await;
+            };
+            let mut interval = tokio::time::interval(METRICS_FILE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = metrics::write_textfile(manager.metrics(), path).await {
+                    warn!("Cannot write the metrics to {:?}: {}", path, e);
+                }
+            }
+        };
+        join!(serve, write);
+    }
+
+    /// Builds the rebroadcaster with the sinks given, if any
+    async fn rebroadcaster(
+        &self,
+        manager: &DeviceManager,
+    ) -> anyhow::Result<Option<(Rebroadcaster, mpsc::Sender<RebroadcastMessage>)>> {
+        let mut builder = Rebroadcaster::builder(16)
+            .backpressure(self.rebroadcast_backpressure)
+            .dropped(manager.metrics().rebroadcast_dropped.clone())
+            .max_failures(self.rebroadcast_max_failures)
+            .errors(manager.metrics().rebroadcast_errors.clone());
+        if self.rebroadcast {
+            let group = self
+                .rebroadcast_group
+                .unwrap_or_else(|| self.rebroadcast_format.default_group());
+            let addr = netlink_sys::SocketAddr::new(self.rebroadcast_pid, group);
+            builder = builder.netlink(addr, self.rebroadcast_format)?;
+        }
+        if let Some(path) = &self.rebroadcast_socket {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            let publisher = mdev::seqpacket::Publisher::bind(path)
+                .with_context(|| format!("Cannot bind the rebroadcast socket {:?}", path))?;
+            builder = builder.seqpacket(publisher, self.rebroadcast_format);
+        }
+        if let Some(path) = &self.rebroadcast_file {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .with_context(|| format!("Cannot open the rebroadcast file {:?}", path))?;
+            builder = builder.file(file.compat_write());
+        }
+        Ok((!builder.is_empty()).then(|| builder.build()))
+    }
+
+    async fn bind_control(&self) -> anyhow::Result<UnixListener> {
+        if let Some(dir) = self.control.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        // left behind by a previous daemon, this one holds the pid file lock
+        match fs::remove_file(&self.control).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&self.control)
+            .with_context(|| format!("Cannot bind the control socket {:?}", self.control))?;
+        fs::set_permissions(&self.control, std::fs::Permissions::from_mode(0o600)).await?;
+        Ok(listener)
+    }
+
+    /// Answers the clients of the control socket, until the daemon stops
+    async fn serve_control(
+        &self,
+        manager: &DeviceManager,
+        listener: &UnixListener,
+        state: &DaemonState,
+    ) {
+        // settle can take a while, the other clients are answered meanwhile
+        let mut clients = FuturesUnordered::new();
+        loop {
+            select! {
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => clients.push(self.handle_client(manager, stream, state)),
+                    Err(e) => warn!("Cannot accept a control connection: {e}"),
+                },
+                Some(()) = clients.next() => {}
+            }
+        }
+    }
+
+    async fn handle_client(
+        &self,
+        manager: &DeviceManager,
+        mut stream: UnixStream,
+        state: &DaemonState,
+    ) {
+        let response = match control::receive(&mut stream).await {
+            Ok(Ok(request)) => {
+                debug!("control request {}", request);
+                self.handle_request(manager, request, state).await
+            }
+            Ok(Err(e)) => Response::Error(e),
+            Err(e) => {
+                debug!("Cannot read the control request: {e}");
+                return;
+            }
+        };
+        if let Err(e) = control::reply(&mut stream, &response).await {
+            debug!("Cannot answer the control request: {e}");
+        }
+    }
+
+    async fn handle_request(
+        &self,
+        manager: &DeviceManager,
+        request: Request,
+        state: &DaemonState,
+    ) -> Response {
+        match request {
+            Request::Reload => {
+                let (rules, subsystems) = load_conf();
+                let count = rules.len();
+                manager.set_rules(rules);
+                manager.set_subsystems(self.subsystems(subsystems));
+                info!("Reloaded {} rules", count);
+                state.notify(&format!("STATUS=Handling the events, {count} rules"));
+                Response::Ok(format!("rules={count}"))
+            }
+            Request::Settle => {
+                let target = match kernel_seqnum(manager.sysfs()) {
+                    Ok(target) => target,
+                    Err(e) => return Response::Error(format!("{e:#}")),
+                };
+                state.settle(target).await;
+                Response::Ok(format!("seqnum={target}"))
+            }
+            Request::Trigger { devpath, action } => {
+                if devpath.components().any(|c| c == Component::ParentDir) {
+                    return Response::Error(format!("invalid devpath {:?}", devpath));
+                }
+                let devpath = devpath.strip_prefix("/").unwrap_or(&devpath);
+                match sysfs::trigger(&manager.sysfs().join(devpath), &action).await {
+                    Ok(()) => Response::Ok(String::new()),
+                    Err(e) => Response::Error(e.report().to_string()),
+                }
+            }
+            Request::Event(env) => match uevent_from_env(env) {
+                Ok(ev) => {
+                    let seq = ev.seq;
+                    // the sender lives as long as the daemon
+                    let _ = state.forwarded.send(ev);
+                    Response::Ok(format!("seqnum={seq}"))
+                }
+                Err(e) => Response::Error(format!("{e:#}")),
+            },
+            Request::Status => Response::Ok(format!(
+                "pid={} events={} seqnum={} rules={}",
+                process::id(),
+                state.events.load(Ordering::Relaxed),
+                *state.handled.borrow(),
+                manager.rules().len()
+            )),
         }
     }
     #[tokio::main(flavor = "current_thread")]
-    async fn run_scan(&self, conf: &[Conf]) -> anyhow::Result<()> {
-        let mount_point = Path::new("/sys");
-        // WalkDir uses sync fs apis
-        let walk = WalkDir::new(mount_point.join("dev"))
-            .follow_links(true)
-            .max_depth(3)
-            .into_iter();
-
-        for e in walk.filter_map(|p| {
-            if let Ok(p) = p {
-                if p.file_name() == "dev" && p.depth() != 0 {
-                    Some(p)
-                } else {
-                    None
+    async fn run_scan(&self, manager: &DeviceManager) -> anyhow::Result<()> {
+        self.scan(manager, false).await
+    }
+
+    /// Adds the devices found in the sysfs, only the ones without a record if `missing_only`
+    async fn scan(&self, manager: &DeviceManager, missing_only: bool) -> anyhow::Result<()> {
+        // the sysfs is walked with the sync fs apis
+        for device in Enumerator::new(&self.sysfs).nodes_only(true).scan() {
+            debug!("{:?}", device.syspath());
+
+            let ev = device.to_uevent(ActionType::Add);
+            if !manager.handles(&ev.subsystem) {
+                continue;
+            }
+            if missing_only && manager.db().get(&ev.devpath).await?.is_some() {
+                continue;
+            }
+
+            manager
+                .handle_event(&ev)
+                .instrument(event_span(&ev))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Brings the dev directory up to date after lost events
+    async fn reconcile(&self, manager: &DeviceManager) -> anyhow::Result<()> {
+        info!("Rescanning {:?}", self.sysfs);
+        for devpath in manager.db().devpaths().await? {
+            if manager.sysfs().join(devpath.strip_prefix("/")?).exists() {
+                continue;
+            }
+            let ev = UEvent {
+                action: ActionType::Remove,
+                env: HashMap::from([
+                    (String::from("ACTION"), String::from("remove")),
+                    (
+                        String::from("DEVPATH"),
+                        devpath.to_string_lossy().into_owned(),
+                    ),
+                ]),
+                devpath,
+                subsystem: String::new(),
+                seq: 0,
+            };
+            if let Err(e) = manager.handle_event(&ev).instrument(event_span(&ev)).await {
+                warn!("{}", e.report());
+            }
+        }
+        self.scan(manager, true).await
+    }
+
+    fn create_static_nodes(&self) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.devpath)
+            .with_context(|| format!("Cannot create {:?}", self.devpath))?;
+        for node in bootstrap::NODES {
+            let path = self.devpath.join(node.name);
+            let mode = Mode::from_bits_truncate(node.mode);
+            info!("Creating {:?}", path);
+            make_node(
+                &path,
+                SFlag::S_IFCHR,
+                mode,
+                makedev(node.major.into(), node.minor.into()),
+            )
+            .with_context(|| format!("Cannot create the static node {}", node.name))?;
+        }
+
+        for (name, target) in bootstrap::LINKS {
+            let path = self.devpath.join(name);
+            match std::fs::read_link(&path) {
+                Ok(existing) if existing == Path::new(target) => continue,
+                Ok(_) => std::fs::remove_file(&path)
+                    .with_context(|| format!("Cannot replace {:?}", path))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(_) => {
+                    warn!("{:?} is not a symlink, not replacing it", path);
+                    continue;
                 }
-            } else {
-                None
             }
-        }) {
-            let path = e
-                .path()
-                .parent()
-                .ok_or_else(|| anyhow::anyhow!("Scanning an impossible path {:?}", e.path()))?;
-            debug!("{:?}", path);
+            info!("Linking {:?} to {:?}", path, target);
+            std::os::unix::fs::symlink(target, &path)
+                .with_context(|| format!("Cannot create {:?}", path))?;
+        }
 
-            let ev = UEvent::from_sysfs_path(path, mount_point)?;
+        for &(name, mode) in bootstrap::DIRS {
+            let path = self.devpath.join(name);
+            match std::fs::create_dir(&path) {
+                Ok(()) => info!("Creating {:?}", path),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e).with_context(|| format!("Cannot create {:?}", path)),
+            }
+            // the mode is subject to the umask, and the directory may be a mount point already
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Cannot change the mode of {:?}", path))?;
+        }
+        Ok(())
+    }
 
-            react_to_event(&ev.devpath, &ev.env, ev.action, conf, &self.devpath).await?;
+    /// Adds what `create_static_nodes` would create to `table`
+    fn describe_static_nodes(&self, table: &mut Table) {
+        for node in bootstrap::NODES {
+            table.push_node(table::Node {
+                path: self.devpath.join(node.name),
+                kind: table::Kind::Char,
+                mode: node.mode,
+                uid: 0,
+                gid: 0,
+                major: node.major,
+                minor: node.minor,
+            });
+        }
+        for (name, target) in bootstrap::LINKS {
+            table.push_link(&self.devpath.join(name), Path::new(target));
         }
+        for &(name, mode) in bootstrap::DIRS {
+            table.push_dir(&self.devpath.join(name), mode);
+        }
+    }
+
+    fn kill_daemon(&self) -> anyhow::Result<()> {
+        const TIMEOUT: Duration = Duration::from_secs(10);
+
+        let Some(pid) = PidFile::running(&self.pidfile)
+            .with_context(|| format!("Cannot read the pid file {:?}", self.pidfile))?
+        else {
+            anyhow::bail!("No mdev daemon is running");
+        };
+        info!("Stopping the mdev daemon {}", pid);
+        kill(Pid::from_raw(pid), Signal::SIGTERM)?;
 
+        // the lock is released when the daemon exits
+        let start = Instant::now();
+        while PidFile::running(&self.pidfile)?.is_some() {
+            if start.elapsed() > TIMEOUT {
+                anyhow::bail!("The mdev daemon {} did not stop", pid);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
         Ok(())
     }
 
-    fn setup_log(&self) -> anyhow::Result<()> {
-        if self.daemon && !self.foreground && !self.syslog {
-            return Ok(());
+    /// Merges the subsystems given on the command line with the ones of the configuration
+    fn subsystems(&self, mut conf: Subsystems) -> Subsystems {
+        conf.extend(Subsystems {
+            only: self.subsystem_only.clone(),
+            skip: self.subsystem_skip.clone(),
+        });
+        conf
+    }
+
+    fn manager(&self, conf: Vec<Rule>, subsystems: Subsystems) -> anyhow::Result<DeviceManager> {
+        let mut blacklist = Blacklist::default();
+        for module in &self.blacklist {
+            blacklist.insert(module);
         }
 
-        if self.syslog {
-            todo!("Wire in syslog somehow");
+        let modules: Option<Arc<dyn ModuleLoader>> = if self.modalias {
+            let mut blacklist = blacklist.clone();
+            blacklist.extend(
+                Blacklist::load(modalias::MODPROBE_DIRS)
+                    .context("Cannot read the modprobe.d blacklist")?,
+            );
+            let dir = match &self.modules_dir {
+                Some(dir) => dir.clone(),
+                None => ModuleIndex::default_dir()?,
+            };
+            let mut index = ModuleIndex::load(&dir)
+                .with_context(|| format!("Cannot load the module index in {:?}", dir))?;
+            index.set_blacklist(blacklist);
+            Some(Arc::new(index))
+        } else {
+            None
+        };
+        #[cfg(feature = "kmod")]
+        let modules: Option<Arc<dyn ModuleLoader>> = if self.kmod {
+            let kmod = mdev::kmod::Kmod::new(blacklist).context("Cannot initialize libkmod")?;
+            Some(Arc::new(kmod))
+        } else {
+            modules
+        };
+
+        let mut builder = DeviceManager::builder()
+            .rules(conf)
+            .subsystems(self.subsystems(subsystems))
+            .devpath(&self.devpath)
+            .sysfs(&self.sysfs)
+            .default_node(!self.no_default_node)
+            .id_cache_ttl(self.id_cache_ttl.map(Duration::from_secs))
+            .db(&self.db)
+            .firmware_dirs(self.firmware_dirs.clone())
+            .probe(self.probe)
+            .input_links(self.input_links)
+            .ignore(self.ignore.clone())
+            .table(self.emit.is_some());
+        if let Some(modules) = modules {
+            builder = builder.modules(modules);
+        }
+        if let Some(template) = &self.net_name {
+            builder = builder.net_name(template);
+        }
+        Ok(builder.build())
+    }
+
+    fn setup_log(&self) -> anyhow::Result<LogGuard> {
+        #[cfg(feature = "otlp")]
+        if self.otlp_endpoint.is_some() && self.daemon && !self.foreground {
+            anyhow::bail!(
+                "--otlp-endpoint needs --foreground, the exporter does not survive the fork"
+            );
+        }
+
+        // stderr is closed once the daemon forks
+        let target = match (self.daemon && !self.foreground, self.syslog) {
+            (true, false) => return Ok(LogGuard::default()),
+            (true, true) => LogTarget::Syslog,
+            (false, true) => LogTarget::Both,
+            (false, false) => LogTarget::Stderr,
+        };
+
+        let filter = log_filter(self.verbose, self.log_filter.as_deref())?;
+
+        #[cfg(feature = "otlp")]
+        if let Some(endpoint) = &self.otlp_endpoint {
+            let exporter = mdev::otlp::Exporter::new(endpoint)?;
+            setup_log_with(filter, self.log_format, target, exporter.layer())?;
+            return Ok(LogGuard {
+                _exporter: Some(exporter),
+            });
         }
 
-        setup_log(self.verbose)
+        let no_layer = tracing_subscriber::layer::Identity::new();
+        setup_log_with(filter, self.log_format, target, no_layer)?;
+        Ok(LogGuard::default())
     }
-}
 
-fn run_hotplug(_conf: &[Conf]) -> anyhow::Result<()> {
-    unimplemented!()
+    /// Whether the kernel runs mdev as its hotplug helper, given the number of `args` and
+    /// whether `ACTION` is set
+    ///
+    /// The kernel passes the subsystem as the only argument, e.g. `mdev block`.
+    fn run_by_kernel(&self, args: usize, action: bool) -> bool {
+        action && args == 1 + usize::from(self.subsystem.is_some())
+    }
+
+    /// Handles the event the kernel describes in the environment of the hotplug helper
+    #[tokio::main(flavor = "current_thread")]
+    async fn run_hotplug(&self, manager: &DeviceManager) -> anyhow::Result<()> {
+        let env = hotplug_env(std::env::vars_os(), self.subsystem.as_deref());
+
+        // the daemon handles the event, in order with the others
+        match control::send(&self.control, &Request::Event(env.clone())).await {
+            Ok(Response::Ok(_)) => return Ok(()),
+            Ok(Response::Error(e)) => anyhow::bail!("The mdev daemon rejected the event: {}", e),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                debug!("No mdev daemon, handling the event: {}", e);
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Cannot forward the event to {:?}", self.control))
+            }
+        }
+
+        // without SEQNUM, mdev.seq is left alone
+        let serialized = env.contains_key("SEQNUM");
+        let ev = uevent_from_env(env)?;
+        if !manager.handles(&ev.subsystem) {
+            debug!("Skipping the event of the {} subsystem", ev.subsystem);
+            return Ok(());
+        }
+
+        // the kernel starts a helper per event, without waiting for the previous ones
+        let seq_file = self.devpath.join(seq::FILE);
+        if serialized {
+            match seq::wait(&seq_file, ev.seq, seq::TIMEOUT).await {
+                Ok(seq::Wait::TimedOut(current)) => warn!(
+                    "Handling event {} while {:?} is still at {}",
+                    ev.seq, seq_file, current
+                ),
+                Ok(_) => {}
+                Err(e) => warn!("Cannot read {:?}: {}", seq_file, e),
+            }
+        }
+
+        let res = manager
+            .handle_event(&ev)
+            .instrument(event_span(&ev))
+            .await
+            .map(drop);
+
+        if serialized {
+            if let Err(e) = seq::advance(&seq_file, ev.seq).await {
+                warn!("Cannot write {:?}: {}", seq_file, e);
+            }
+        }
+
+        Ok(res?)
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let conf = if let Ok(input) = std::fs::read_to_string("/etc/mdev.conf") {
-        mdev_parser::parse(&input)
-    } else {
-        vec![Conf::default()]
-    };
+    let (conf, subsystems) = load_conf();
+
+    // borrowed by the tasks of the daemon, until the process exits
+    let opt: &'static Opt = Box::leak(Box::new(Opt::parse()));
+    let hotplug = opt.run_by_kernel(
+        std::env::args_os().len(),
+        std::env::var_os("ACTION").is_some(),
+    );
+
+    // the spans are exported until main returns
+    let _log_guard = opt.setup_log()?;
 
-    if std::env::args().count() == 0 {
-        return run_hotplug(&conf);
+    if opt.kill {
+        return opt.kill_daemon();
     }
 
-    let opt = Opt::parse();
+    let manager: &'static DeviceManager = Box::leak(Box::new(opt.manager(conf, subsystems)?));
 
-    opt.setup_log()?;
+    if hotplug || opt.hotplug {
+        return opt.run_hotplug(manager);
+    }
+
+    // taken before the scan, a second daemon would handle the same devices
+    let pidfile = match opt.daemon {
+        true => Some(PidFile::lock(&opt.pidfile).context("Another mdev daemon is running")?),
+        false => None,
+    };
+    // bound before the scan too, the daemon then handles the events emitted meanwhile, so no
+    // device is missed, at worst one is added twice
+    let listener = match opt.daemon {
+        true => {
+            let listener = Listener::bind_with_buffer(opt.receive_buffer)?;
+            match listener.receive_buffer() {
+                // doubled by the kernel for its bookkeeping
+                Ok(applied) if applied < opt.receive_buffer => warn!(
+                    "Receive buffer capped to {} bytes, the events may overflow it",
+                    applied / 2
+                ),
+                Ok(applied) => debug!("Receive buffer of {} bytes", applied / 2),
+                Err(e) => warn!("Cannot read the size of the receive buffer: {}", e),
+            }
+            let seqnum = kernel_seqnum(&opt.sysfs).unwrap_or(0);
+            Some((listener, seqnum))
+        }
+        false => None,
+    };
+    // read before the scan starts any thread
+    let notifier = match opt.daemon {
+        true => Notifier::from_env().unwrap_or_else(|e| {
+            warn!("Cannot use $NOTIFY_SOCKET: {e}");
+            None
+        }),
+        false => None,
+    };
+    // taken before the scan runs any command
+    let ready_fd = opt
+        .notify_fd
+        .map(|fd| ReadyFd::new(fd).with_context(|| format!("Invalid --notify-fd {fd}")))
+        .transpose()?;
+
+    if opt.static_nodes {
+        match manager.table() {
+            Some(table) => opt.describe_static_nodes(&mut table.lock().unwrap()),
+            None => opt.create_static_nodes()?,
+        }
+    }
 
     if opt.scan {
-        opt.run_scan(&conf)?;
+        opt.run_scan(manager)?;
     }
 
-    if opt.daemon {
+    if let (Some(pidfile), Some((listener, seqnum))) = (pidfile, listener) {
         if !opt.foreground {
-            if let Fork::Child = daemon(false, false).map_err(|_| anyhow::anyhow!("Cannot fork"))? {
-                opt.run_daemon(&conf)?;
+            if let Fork::Parent(_) =
+                daemon(false, false).map_err(|_| anyhow::anyhow!("Cannot fork"))?
+            {
+                // the pid file belongs to the daemon now
+                std::process::exit(0);
             }
-        } else {
-            opt.run_daemon(&conf)?;
         }
+        opt.daemon_main(manager, pidfile, listener, seqnum, notifier, ready_fd)?;
+    }
+
+    if let (Some(format), Some(table)) = (opt.emit, manager.table()) {
+        print!("{}", table.lock().unwrap().render(format));
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::*;
+
+    #[test]
+    fn run_by_kernel() {
+        // as in /proc/sys/kernel/hotplug, the subsystem being the only argument
+        let opt = Opt::try_parse_from(["mdev", "block"]).unwrap();
+        assert_eq!(opt.subsystem.as_deref(), Some("block"));
+        assert!(opt.run_by_kernel(2, true));
+        assert!(!opt.run_by_kernel(2, false));
+        assert!(Opt::try_parse_from(["mdev"])
+            .unwrap()
+            .run_by_kernel(1, true));
+        // run by a script of an event
+        assert!(!Opt::try_parse_from(["mdev", "-s"])
+            .unwrap()
+            .run_by_kernel(2, true));
+
+        let env = hotplug_env(
+            [
+                (OsString::from("ACTION"), OsString::from("add")),
+                (
+                    OsString::from("DEVPATH"),
+                    OsString::from("/devices/virtual/block/loop0"),
+                ),
+                (OsString::from("SEQNUM"), OsString::from("42")),
+                (
+                    OsString::from("BROKEN"),
+                    OsString::from_vec(b"\xff".to_vec()),
+                ),
+            ],
+            opt.subsystem.as_deref(),
+        );
+        assert!(!env.contains_key("BROKEN"));
+        let ev = uevent_from_env(env).unwrap();
+        assert_eq!(ev.subsystem, "block");
+        assert_eq!(ev.seq, 42);
+    }
+
+    #[test]
+    fn rebroadcast_format() {
+        let parse = |args: &[&str]| Opt::try_parse_from([&["mdev", "-d"], args].concat());
+        assert!(parse(&["--rebroadcast-format", "libudev", "--rebroadcast"]).is_ok());
+        // the file gets the properties only
+        assert!(parse(&["--rebroadcast-format", "libudev", "--rebroadcast-file", "f"]).is_err());
+        assert!(parse(&["--rebroadcast-file", "f"]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn settle_skipped() {
+        let manager = DeviceManager::builder()
+            .subsystems(mdev::filter::Subsystems {
+                only: Vec::new(),
+                skip: vec![String::from("bdi")],
+            })
+            .build();
+        let state = DaemonState {
+            handled: watch::Sender::new(4),
+            in_flight: Mutex::new(seq::InFlight::new(4)),
+            events: AtomicU64::new(0),
+            forwarded: mpsc::unbounded_channel().0,
+            notifier: None,
+            rebroadcast: filter::Rebroadcast::default(),
+            rebroadcast_resolved: false,
+            #[cfg(feature = "dbus")]
+            signaler: None,
+        };
+        let event = |seq, subsystem: &str| UEvent {
+            action: ActionType::Add,
+            devpath: PathBuf::from("/devices/virtual/bdi/7:0"),
+            subsystem: subsystem.to_string(),
+            env: HashMap::new(),
+            seq,
+        };
+
+        // the last event of the kernel is skipped while the previous one is handled
+        for seq in [5, 6] {
+            state.start(seq);
+        }
+        assert!(!state.skips(&manager, &event(5, "block")));
+        assert!(state.skips(&manager, &event(6, "bdi")));
+        assert_eq!(*state.handled.borrow(), 4);
+        state.finish(5);
+        tokio::time::timeout(Duration::from_secs(1), state.settle(6))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn progressed() {
+        let mut last = 3;
+        // idle
+        assert!(super::progressed(&mut last, 3, 0));
+        assert!(super::progressed(&mut last, 5, 2));
+        assert_eq!(last, 5);
+        // stuck on the events queued
+        assert!(!super::progressed(&mut last, 5, 2));
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use futures_util::{stream, StreamExt};
+use kobject_uevent::{ActionType, UEvent};
+use mdev::{
+    control::{self, Request, Response},
+    db::{self, Database},
+    device::Device,
+    enumerate::Enumerator,
+    rule::{self, Outcome},
+    setup_log, sysfs, usb, LogFormat,
+};
+use nix::{
+    sys::stat::{major, minor},
+    time::{clock_gettime, ClockId},
+};
+use tracing::{debug, warn};
+
+/// Inspects the devices and the events handled by mdev
+#[derive(Parser)]
+struct Opt {
+    /// Verbose mode, -v logs the debug lines as well, -vv the trace ones
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the events as they are received, until interrupted
+    Monitor(MonitorOpt),
+    /// Print what is known about a device
+    ///
+    /// The lines are prefixed by P: for the sysfs path, N: and S: for the nodes and links mdev
+    /// created, E: for the environment of its events, R: for the rules matching it and A: for
+    /// its sysfs attributes.
+    Info(InfoOpt),
+    /// Make the kernel emit events for the devices again, e.g. to apply changed rules
+    Trigger(TriggerOpt),
+    /// Wait for the daemon to handle the events emitted so far, e.g. before mounting by label
+    Settle(SettleOpt),
+}
+
+#[derive(Args)]
+struct MonitorOpt {
+    /// Print the events sent by the kernel
+    #[arg(short, long)]
+    kernel: bool,
+    /// Print the events rebroadcast by the mdev daemon once handled, in either format
+    #[arg(short, long)]
+    mdev: bool,
+    /// Print the environment of the events too
+    #[arg(short, long)]
+    property: bool,
+}
+
+#[derive(Args)]
+struct InfoOpt {
+    /// Device, as a node such as /dev/sda or a sysfs path such as /devices/virtual/mem/null
+    device: PathBuf,
+    /// Path where the sysfs is mounted
+    #[arg(long, default_value = "/sys")]
+    sysfs: PathBuf,
+    /// Directory where mdev records the nodes and links created for each device
+    #[arg(long, default_value = db::DEFAULT_DIR)]
+    db: PathBuf,
+}
+
+#[derive(Args)]
+struct TriggerOpt {
+    /// Sysfs paths of the devices to trigger, shell patterns such as /devices/pci*/*/block/sd*
+    /// [default: all the devices]
+    #[arg(value_name = "DEVPATH")]
+    devpaths: Vec<String>,
+    /// Trigger only the devices of this subsystem, can be repeated
+    #[arg(short, long = "subsystem", value_name = "SUBSYSTEM")]
+    subsystems: Vec<String>,
+    /// Action of the events
+    #[arg(short, long, default_value = "change", value_parser = [
+        "add", "remove", "change", "move", "online", "offline", "bind", "unbind",
+    ])]
+    action: String,
+    /// Print the devices that would be triggered instead
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Path where the sysfs is mounted
+    #[arg(long, default_value = "/sys")]
+    sysfs: PathBuf,
+}
+
+#[derive(Args)]
+struct SettleOpt {
+    /// Fail after waiting this long
+    #[arg(short, long, value_name = "SECONDS", default_value_t = 120)]
+    timeout: u64,
+    /// Control socket of the daemon
+    #[arg(long, value_name = "PATH", default_value = control::DEFAULT_PATH)]
+    control: PathBuf,
+}
+
+/// Where an event comes from
+#[derive(Clone, Copy)]
+enum Source {
+    Kernel,
+    Mdev,
+}
+
+impl MonitorOpt {
+    #[tokio::main(flavor = "current_thread")]
+    async fn run(&self) -> anyhow::Result<()> {
+        // both by default
+        let (kernel, mdev) = match (self.kernel, self.mdev) {
+            (false, false) => (true, true),
+            sources => sources,
+        };
+
+        let mut sources = Vec::new();
+        if kernel {
+            println!("KERNEL - the events sent by the kernel");
+            let events = mdev::stream::uevents()?;
+            sources.push(events.map(|ev| (Source::Kernel, ev)).boxed_local());
+        }
+        if mdev {
+            println!("MDEV   - the events handled by the mdev daemon");
+            let groups = mdev::stream::REBROADCAST_GROUP | mdev::libudev::GROUP;
+            let events = mdev::stream::uevents_from(groups)?;
+            sources.push(events.map(|ev| (Source::Mdev, ev)).boxed_local());
+        }
+        println!();
+
+        let mut events = stream::select_all(sources);
+        while let Some((source, ev)) = events.next().await {
+            match ev {
+                Ok(ev) => self.print(source, &ev)?,
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        Ok(())
+    }
+
+    fn print(&self, source: Source, ev: &UEvent) -> io::Result<()> {
+        let source = match source {
+            Source::Kernel => "KERNEL",
+            Source::Mdev => "MDEV  ",
+        };
+        // seconds since boot, as the kernel log
+        let now = clock_gettime(ClockId::CLOCK_MONOTONIC)?;
+
+        let mut stdout = io::stdout().lock();
+        writeln!(
+            stdout,
+            "{}[{}.{:06}] {:<8} {} ({})",
+            source,
+            now.tv_sec(),
+            now.tv_nsec() / 1000,
+            mdev::action_name(ev.action),
+            ev.devpath.display(),
+            ev.subsystem
+        )?;
+        if self.property {
+            let mut env: Vec<_> = ev.env.iter().collect();
+            env.sort();
+            for (name, value) in env {
+                writeln!(stdout, "{name}={value}")?;
+            }
+            writeln!(stdout)?;
+        }
+        // the output is usually piped to a file or a pager
+        stdout.flush()
+    }
+}
+
+impl InfoOpt {
+    /// Returns the sysfs path of the device, e.g. `/devices/virtual/mem/null`
+    fn devpath(&self, sysfs: &Path) -> anyhow::Result<PathBuf> {
+        let dir = match fs::metadata(&self.device) {
+            Ok(meta) if meta.file_type().is_char_device() || meta.file_type().is_block_device() => {
+                let kind = match meta.file_type().is_block_device() {
+                    true => "block",
+                    false => "char",
+                };
+                let rdev = meta.rdev();
+                sysfs.join(format!("dev/{}/{}:{}", kind, major(rdev), minor(rdev)))
+            }
+            _ => {
+                let path = self.device.strip_prefix(sysfs).unwrap_or(&self.device);
+                sysfs.join(path.strip_prefix("/").unwrap_or(path))
+            }
+        };
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("No device at {:?}", dir))?;
+        Ok(Path::new("/").join(dir.strip_prefix(sysfs)?))
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn run(&self) -> anyhow::Result<()> {
+        let sysfs = self.sysfs.canonicalize()?;
+        let devpath = self.devpath(&sysfs)?;
+        let dir = sysfs.join(devpath.strip_prefix("/")?);
+        let device = Device::from_syspath(&dir, &sysfs)?;
+        let env = device.properties();
+
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "P: {}", devpath.display())?;
+        if let Some(record) = Database::new(&self.db).get(&devpath).await? {
+            for node in &record.nodes {
+                writeln!(stdout, "N: {}", node.display())?;
+            }
+            for link in &record.links {
+                writeln!(stdout, "S: {}", link.display())?;
+            }
+        }
+        for (name, value) in env.iter().collect::<BTreeMap<_, _>>() {
+            writeln!(stdout, "E: {name}={value}")?;
+        }
+
+        // as mdev matches the device when it is added
+        let device_number = device.devnum();
+        let usb_name = usb::device_name(env);
+        let devname = match env.get("DEVNAME").or(usb_name.as_ref()) {
+            Some(devname) => devname.clone(),
+            None => devpath
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let conf = match fs::read_to_string(rule::CONF) {
+            Ok(input) => rule::parse(&input),
+            Err(_) => Vec::new(),
+        };
+        for rule in &conf {
+            let node =
+                match rule::apply(rule, env, device_number, ActionType::Add, &devname).await? {
+                    Outcome::Matched(node) => Some(node),
+                    Outcome::Prevented => None,
+                    Outcome::Skipped(_) => continue,
+                };
+            writeln!(stdout, "R: {rule}")?;
+            match node {
+                Some(node) if node.links.is_empty() => writeln!(stdout, "   node {}", node.name)?,
+                Some(node) => writeln!(
+                    stdout,
+                    "   node {}, links {}",
+                    node.name,
+                    node.links.join(", ")
+                )?,
+                None => writeln!(stdout, "   no node")?,
+            }
+            if rule.stop {
+                break;
+            }
+        }
+
+        for (name, value) in attributes(&dir) {
+            writeln!(stdout, "A: {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+impl TriggerOpt {
+    #[tokio::main(flavor = "current_thread")]
+    async fn run(&self) -> anyhow::Result<()> {
+        let mut enumerator = Enumerator::new(&self.sysfs);
+        for subsystem in &self.subsystems {
+            enumerator = enumerator.match_subsystem(subsystem);
+        }
+        for pattern in &self.devpaths {
+            enumerator = enumerator.match_devpath(pattern);
+        }
+        for device in enumerator.scan() {
+            let devpath = device.devpath().display();
+            if self.dry_run {
+                println!("{devpath}");
+                continue;
+            }
+            debug!("Triggering {} for {}", self.action, devpath);
+            if let Err(e) = sysfs::trigger(device.syspath(), &self.action).await {
+                warn!("{}", e.report());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SettleOpt {
+    #[tokio::main(flavor = "current_thread")]
+    async fn run(&self) -> anyhow::Result<()> {
+        let timeout = Duration::from_secs(self.timeout);
+        let response =
+            tokio::time::timeout(timeout, control::send(&self.control, &Request::Settle))
+                .await
+                .map_err(|_| anyhow::anyhow!("Events still pending after {:?}", timeout))?
+                .with_context(|| format!("Cannot reach the mdev daemon on {:?}", self.control))?;
+        match response {
+            Response::Ok(message) => {
+                debug!("settled: {}", message);
+                Ok(())
+            }
+            Response::Error(e) => anyhow::bail!("Cannot settle: {e}"),
+        }
+    }
+}
+
+/// Returns the readable attributes of the sysfs directory `dir`
+fn attributes(dir: &Path) -> BTreeMap<String, String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return BTreeMap::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if !entry.file_type().ok()?.is_file() || entry.file_name() == "uevent" {
+                return None;
+            }
+            // write-only or binary attributes are skipped
+            let value = fs::read_to_string(entry.path()).ok()?;
+            let value = value.trim_end().replace('\n', "\\n");
+            Some((entry.file_name().to_string_lossy().into_owned(), value))
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::parse();
+
+    setup_log(opt.verbose, LogFormat::Text)?;
+
+    match &opt.command {
+        Command::Monitor(monitor) => monitor.run(),
+        Command::Info(info) => info.run(),
+        Command::Trigger(trigger) => trigger.run(),
+        Command::Settle(settle) => settle.run(),
+    }
+}
@@ -0,0 +1,67 @@
+//! Baseline content of a dev directory not populated by devtmpfs
+
+/// Character device node every system expects to find
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    /// Name relative to the dev directory
+    pub name: &'static str,
+    pub major: u32,
+    pub minor: u32,
+    pub mode: u32,
+}
+
+const fn node(name: &'static str, major: u32, minor: u32, mode: u32) -> Node {
+    Node {
+        name,
+        major,
+        minor,
+        mode,
+    }
+}
+
+/// The nodes created by the kernel in devtmpfs, as listed in `Documentation/admin-guide/devices.txt`
+pub const NODES: &[Node] = &[
+    node("null", 1, 3, 0o666),
+    node("zero", 1, 5, 0o666),
+    node("full", 1, 7, 0o666),
+    node("random", 1, 8, 0o666),
+    node("urandom", 1, 9, 0o666),
+    node("kmsg", 1, 11, 0o644),
+    node("tty", 5, 0, 0o666),
+    node("console", 5, 1, 0o600),
+    node("ptmx", 5, 2, 0o666),
+];
+
+/// `(name, target)` of the symlinks to the process file descriptors
+pub const LINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+/// `(name, mode)` of the mount points of devpts and of the POSIX shared memory
+pub const DIRS: &[(&str, u32)] = &[("pts", 0o755), ("shm", 0o1777)];
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::rule::is_safe_name;
+
+    use super::*;
+
+    #[test]
+    fn nodes() {
+        let mut devices = HashSet::new();
+        for node in NODES {
+            assert!(is_safe_name(node.name));
+            assert!(devices.insert((node.major, node.minor)), "{node:?}");
+            assert_eq!(node.mode & !0o777, 0);
+        }
+        let links = LINKS.iter().map(|(name, _)| name);
+        for name in links.chain(DIRS.iter().map(|(name, _)| name)) {
+            assert!(is_safe_name(name));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, process::Stdio};
+
+use kobject_uevent::ActionType;
+use mdev_parser::{Command, Conf, WhenToRun};
+use tokio::process;
+use tracing::{debug, info, instrument};
+
+use crate::{Error, Result};
+
+/// Shell used to run the rule commands
+const SHELL: &str = "/bin/sh";
+
+/// Whether the command of `conf` has to be run for an event with the given `action`
+///
+/// Bind, unbind, online and offline events only run the commands of the rules matching
+/// `ACTION` explicitly, e.g. `ACTION=bind;DRIVER=foo;.* root:root 660 @setup-foo`, so the
+/// generic rules are not run again every time a driver attaches or a CPU goes offline.
+pub fn runs_on(conf: &Conf, action: ActionType) -> bool {
+    let Some(command) = &conf.command else {
+        return false;
+    };
+    match action {
+        ActionType::Add => matches!(command.when, WhenToRun::After | WhenToRun::Both),
+        ActionType::Remove => matches!(command.when, WhenToRun::Before | WhenToRun::Both),
+        ActionType::Change => matches!(command.when, WhenToRun::Both),
+        ActionType::Bind | ActionType::Unbind | ActionType::Online | ActionType::Offline => {
+            matches_action(conf)
+        }
+        _ => false,
+    }
+}
+
+fn matches_action(conf: &Conf) -> bool {
+    conf.envmatches
+        .iter()
+        .any(|env_match| env_match.envvar == "ACTION")
+}
+
+/// Returns the command line of `command`, as passed to the shell
+pub fn command_line(command: &Command) -> String {
+    let mut line = command.path.clone();
+    for arg in &command.args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    line
+}
+
+/// Runs the command `line` through the shell, with the event `env` and `MDEV` set to the node
+/// name
+#[instrument(name = "command", skip_all, fields(line = %line, mdev = %mdev))]
+pub async fn run(line: &str, env: &HashMap<String, String>, mdev: &str) -> Result<()> {
+    info!("Running {:?}", line);
+
+    let status = process::Command::new(SHELL)
+        .arg("-c")
+        .arg(line)
+        .envs(env)
+        .env("MDEV", mdev)
+        .stdin(Stdio::null())
+        .status()
+        .await
+        .map_err(|source| Error::CommandSpawn {
+            line: line.to_string(),
+            source,
+        })?;
+    debug!("{:?} exited with {}", line, status);
+
+    if !status.success() {
+        return Err(Error::CommandFailed {
+            line: line.to_string(),
+            status,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use kobject_uevent::ActionType;
+    use mdev_parser::{Command, Conf, EnvMatch, WhenToRun};
+    use regex::Regex;
+
+    use super::*;
+
+    fn command(path: &str, args: &[&str]) -> Command {
+        Command {
+            when: WhenToRun::Both,
+            path: path.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    fn conf(when: WhenToRun) -> Conf {
+        Conf {
+            command: Some(Command {
+                when,
+                ..command("true", &[])
+            }),
+            ..Conf::default()
+        }
+    }
+
+    #[test]
+    fn when() {
+        assert!(runs_on(&conf(WhenToRun::After), ActionType::Add));
+        assert!(!runs_on(&conf(WhenToRun::After), ActionType::Remove));
+        assert!(runs_on(&conf(WhenToRun::Before), ActionType::Remove));
+        assert!(runs_on(&conf(WhenToRun::Both), ActionType::Change));
+        assert!(!runs_on(&conf(WhenToRun::After), ActionType::Change));
+        assert!(!runs_on(&Conf::default(), ActionType::Add));
+    }
+
+    #[test]
+    fn bind() {
+        assert!(!runs_on(&conf(WhenToRun::Both), ActionType::Bind));
+
+        let conf = Conf {
+            envmatches: vec![EnvMatch {
+                envvar: String::from("ACTION"),
+                regex: Regex::new("^(un)?bind$").unwrap(),
+            }],
+            ..conf(WhenToRun::After)
+        };
+        assert!(runs_on(&conf, ActionType::Bind));
+        assert!(runs_on(&conf, ActionType::Unbind));
+    }
+
+    #[test]
+    fn hotplug() {
+        assert!(!runs_on(&conf(WhenToRun::Both), ActionType::Online));
+        assert!(!runs_on(&conf(WhenToRun::Both), ActionType::Offline));
+
+        let conf = Conf {
+            envmatches: vec![EnvMatch {
+                envvar: String::from("ACTION"),
+                regex: Regex::new("^offline$").unwrap(),
+            }],
+            ..conf(WhenToRun::After)
+        };
+        assert!(runs_on(&conf, ActionType::Offline));
+        assert!(!runs_on(&conf, ActionType::Move));
+    }
+
+    #[tokio::test]
+    async fn env() {
+        let env = HashMap::from([(String::from("ACTION"), String::from("add"))]);
+        let line = command_line(&command("test", &["\"$ACTION:$MDEV\"", "=", "add:sda"]));
+        run(&line, &env, "sda").await.unwrap();
+        assert!(matches!(
+            run("false", &env, "sda").await,
+            Err(Error::CommandFailed { status, .. }) if status.code() == Some(1)
+        ));
+    }
+}
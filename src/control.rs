@@ -0,0 +1,268 @@
+//! Control socket of the daemon
+//!
+//! Each connection carries a single request line, answered by a single response line:
+//! `ok` followed by the result, or `error` followed by the reason.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+
+/// Default location of the socket
+pub const DEFAULT_PATH: &str = "/run/mdev/control";
+
+/// Actions a device can be triggered with
+const ACTIONS: [&str; 8] = [
+    "add", "remove", "change", "move", "online", "offline", "bind", "unbind",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Reads the rules again
+    Reload,
+    /// Waits for the events emitted so far by the kernel to be handled
+    Settle,
+    /// Makes the kernel emit `action` for the device at `devpath`, relative to the sysfs
+    Trigger { devpath: PathBuf, action: String },
+    /// Reports the state of the daemon
+    Status,
+    /// Handles the event described by the environment of a hotplug helper
+    Event(HashMap<String, String>),
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let request = match words.next() {
+            Some("reload") => Self::Reload,
+            Some("settle") => Self::Settle,
+            Some("status") => Self::Status,
+            Some("trigger") => {
+                let devpath = words.next().ok_or("trigger needs a devpath")?;
+                let action = words.next().unwrap_or("change");
+                if !ACTIONS.contains(&action) {
+                    return Err(format!("unknown action {action:?}"));
+                }
+                Self::Trigger {
+                    devpath: PathBuf::from(devpath),
+                    action: action.to_string(),
+                }
+            }
+            Some("event") => {
+                let env = words
+                    .map(|word| {
+                        let (key, value) = word
+                            .split_once('=')
+                            .ok_or_else(|| format!("expected KEY=VALUE, got {word:?}"))?;
+                        Ok((key.to_string(), unescape(value)?))
+                    })
+                    .collect::<Result<HashMap<_, _>, String>>()?;
+                return Ok(Self::Event(env));
+            }
+            Some(command) => return Err(format!("unknown command {command:?}")),
+            None => return Err(String::from("empty request")),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected {extra:?}")),
+            None => Ok(request),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reload => write!(f, "reload"),
+            Self::Settle => write!(f, "settle"),
+            Self::Trigger { devpath, action } => {
+                write!(f, "trigger {} {}", devpath.display(), action)
+            }
+            Self::Status => write!(f, "status"),
+            Self::Event(env) => {
+                write!(f, "event")?;
+                let mut vars: Vec<_> = env.iter().collect();
+                vars.sort();
+                for (key, value) in vars {
+                    write!(f, " {}={}", key, escape(value))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Escapes the whitespace and the backslashes of `value` as `\u{HEX}`, so it stays a word
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || c.is_whitespace() {
+            escaped.push_str(&format!("\\u{{{:x}}}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('\\') {
+        unescaped.push_str(&rest[..start]);
+        let invalid = || format!("invalid escape in {value:?}");
+        let (hex, tail) = rest[start..]
+            .strip_prefix("\\u{")
+            .and_then(|escape| escape.split_once('}'))
+            .ok_or_else(invalid)?;
+        let c = u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(invalid)?;
+        unescaped.push(c);
+        rest = tail;
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok(String),
+    Error(String),
+}
+
+impl FromStr for Response {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (status, message) = s.split_once(' ').unwrap_or((s, ""));
+        match status {
+            "ok" => Ok(Self::Ok(message.to_string())),
+            "error" => Ok(Self::Error(message.to_string())),
+            _ => Err(format!("invalid response {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // a response is a single line
+        let (status, message) = match self {
+            Self::Ok(message) => ("ok", message),
+            Self::Error(message) => ("error", message),
+        };
+        match message.is_empty() {
+            true => write!(f, "{status}"),
+            false => write!(f, "{} {}", status, message.replace('\n', " ")),
+        }
+    }
+}
+
+/// Sends `request` to the daemon listening on `path` and returns its response
+pub async fn send(path: &Path, request: &Request) -> io::Result<Response> {
+    let stream = UnixStream::connect(path).await?;
+    let (read, mut write) = stream.into_split();
+    write.write_all(format!("{request}\n").as_bytes()).await?;
+    write.shutdown().await?;
+
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    line.trim_end()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the request of a client connected to the daemon
+pub async fn receive(stream: &mut UnixStream) -> io::Result<Result<Request, String>> {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    Ok(line.trim_end().parse())
+}
+
+/// Answers a client with `response`
+pub async fn reply(stream: &mut UnixStream, response: &Response) -> io::Result<()> {
+    stream.write_all(format!("{response}\n").as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        for line in [
+            "reload",
+            "settle",
+            "status",
+            "trigger /devices/virtual/block/loop0 add",
+        ] {
+            assert_eq!(line.parse::<Request>().unwrap().to_string(), line);
+        }
+        assert_eq!(
+            "trigger /devices/virtual/block/loop0".parse(),
+            Ok(Request::Trigger {
+                devpath: PathBuf::from("/devices/virtual/block/loop0"),
+                action: String::from("change"),
+            })
+        );
+        assert!("trigger".parse::<Request>().is_err());
+        assert!("trigger /devices/x explode".parse::<Request>().is_err());
+        assert!("status now".parse::<Request>().is_err());
+        assert!("".parse::<Request>().is_err());
+
+        let env = HashMap::from([
+            (String::from("ACTION"), String::from("add")),
+            (String::from("ID_MODEL"), String::from("Flash Disk\\1\t")),
+        ]);
+        let line = Request::Event(env.clone()).to_string();
+        assert_eq!(
+            line,
+            "event ACTION=add ID_MODEL=Flash\\u{20}Disk\\u{5c}1\\u{9}"
+        );
+        assert_eq!(line.parse(), Ok(Request::Event(env)));
+        assert_eq!("event".parse(), Ok(Request::Event(HashMap::new())));
+        assert!("event ACTION".parse::<Request>().is_err());
+        assert!("event A=\\u{zz}".parse::<Request>().is_err());
+        assert!("event A=\\x".parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn responses() {
+        assert_eq!("ok".parse(), Ok(Response::Ok(String::new())));
+        assert_eq!(
+            "error unknown command".parse(),
+            Ok(Response::Error(String::from("unknown command")))
+        );
+        assert!("maybe".parse::<Response>().is_err());
+        assert_eq!(
+            Response::Error(String::from("a\nb")).to_string(),
+            "error a b"
+        );
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(b"status\n").await.unwrap();
+        assert_eq!(receive(&mut server).await.unwrap(), Ok(Request::Status));
+        reply(&mut server, &Response::Ok(String::from("pid=1")))
+            .await
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(&mut client)
+            .read_line(&mut line)
+            .await
+            .unwrap();
+        assert_eq!(line, "ok pid=1\n");
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs;
+
+/// What has been created in the dev directory for a device
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Record {
+    /// Device nodes
+    pub nodes: Vec<PathBuf>,
+    /// Symlinks to the nodes
+    pub links: Vec<PathBuf>,
+    /// Rules that produced the nodes
+    pub rules: Vec<String>,
+    /// User and group owning the first node, as named by its rule
+    pub owner: Option<(String, String)>,
+    /// Permissions of the first node
+    pub mode: Option<u32>,
+}
+
+impl Record {
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.links.is_empty()
+    }
+
+    fn parse(s: &str) -> Self {
+        let mut record = Self::default();
+        for line in s.lines() {
+            match line.split_once(':') {
+                Some(("N", node)) => record.nodes.push(PathBuf::from(node)),
+                Some(("S", link)) => record.links.push(PathBuf::from(link)),
+                Some(("R", rule)) => record.rules.push(rule.to_string()),
+                Some(("O", owner)) => {
+                    record.owner = owner
+                        .split_once(':')
+                        .map(|(user, group)| (user.to_string(), group.to_string()))
+                }
+                Some(("M", mode)) => record.mode = u32::from_str_radix(mode, 8).ok(),
+                _ => {}
+            }
+        }
+        record
+    }
+
+    fn serialize(&self) -> String {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| format!("N:{}\n", node.display()));
+        let links = self
+            .links
+            .iter()
+            .map(|link| format!("S:{}\n", link.display()));
+        let rules = self.rules.iter().map(|rule| format!("R:{}\n", rule));
+        let owner = self
+            .owner
+            .iter()
+            .map(|(user, group)| format!("O:{}:{}\n", user, group));
+        let mode = self.mode.iter().map(|mode| format!("M:{:04o}\n", mode));
+        nodes
+            .chain(links)
+            .chain(rules)
+            .chain(owner)
+            .chain(mode)
+            .collect()
+    }
+
+    /// Adds what was created to the properties of an event, as udev names them: `DEVNAME` is
+    /// the path of the first node, `DEVLINKS` its links separated by spaces, then
+    /// `MDEV_OWNER`, `MDEV_GROUP` and `MDEV_MODE`
+    pub fn add_properties(&self, env: &mut HashMap<String, String>) {
+        if let Some(node) = self.nodes.first() {
+            env.insert(String::from("DEVNAME"), node.display().to_string());
+        }
+        if !self.links.is_empty() {
+            let links: Vec<_> = self
+                .links
+                .iter()
+                .map(|link| link.display().to_string())
+                .collect();
+            env.insert(String::from("DEVLINKS"), links.join(" "));
+        }
+        if let Some((user, group)) = &self.owner {
+            env.insert(String::from("MDEV_OWNER"), user.clone());
+            env.insert(String::from("MDEV_GROUP"), group.clone());
+        }
+        if let Some(mode) = self.mode {
+            env.insert(String::from("MDEV_MODE"), format!("{:04o}", mode));
+        }
+    }
+}
+
+/// Where the daemon keeps its database unless told otherwise
+pub const DEFAULT_DIR: &str = "/run/mdev/db";
+
+/// Persistent map from the sysfs path of a device to its [`Record`]
+///
+/// Every device is stored in its own file, named after the sysfs path with `/` replaced by `!`.
+/// The `!` and `\` found in sysfs names, as in `cciss!c0d0`, are written `\x21` and `\x5c`.
+#[derive(Debug, Clone)]
+pub struct Database {
+    dir: PathBuf,
+}
+
+impl Database {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Stores the record of the device at `devpath`, replacing the previous one
+    pub async fn insert(&self, devpath: &Path, record: &Record) -> io::Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let path = self.path(devpath);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, record.serialize()).await?;
+        fs::rename(&tmp, &path).await
+    }
+
+    /// Returns the record of the device at `devpath`, if any
+    pub async fn get(&self, devpath: &Path) -> io::Result<Option<Record>> {
+        match fs::read_to_string(self.path(devpath)).await {
+            Ok(s) => Ok(Some(Record::parse(&s))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes and returns the record of the device at `devpath`, if any
+    pub async fn remove(&self, devpath: &Path) -> io::Result<Option<Record>> {
+        let record = self.get(devpath).await?;
+        if record.is_some() {
+            fs::remove_file(self.path(devpath)).await?;
+        }
+        Ok(record)
+    }
+
+    /// Returns the sysfs paths of the stored devices
+    pub async fn devpaths(&self) -> io::Result<Vec<PathBuf>> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut devpaths = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            // left behind by an interrupted insert
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            devpaths.push(Path::new("/").join(unescape(&name)));
+        }
+        devpaths.sort();
+        Ok(devpaths)
+    }
+
+    fn path(&self, devpath: &Path) -> PathBuf {
+        self.dir.join(escape(&devpath.to_string_lossy()))
+    }
+}
+
+fn escape(devpath: &str) -> String {
+    let mut name = String::with_capacity(devpath.len());
+    for c in devpath.trim_start_matches('/').chars() {
+        match c {
+            '/' => name.push('!'),
+            '!' => name.push_str("\\x21"),
+            '\\' => name.push_str("\\x5c"),
+            c => name.push(c),
+        }
+    }
+    name
+}
+
+fn unescape(name: &str) -> String {
+    // a `\` is always escaped, so `\x21` is never the tail of another sequence
+    name.replace('!', "/")
+        .replace("\\x21", "!")
+        .replace("\\x5c", "\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::Path, process};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let dir = env::temp_dir().join(format!("mdev-db-{}", process::id()));
+        let db = Database::new(&dir);
+        let devpath = Path::new("/devices/virtual/block/loop0");
+        let record = Record {
+            nodes: vec![PathBuf::from("/dev/loop0")],
+            links: vec![PathBuf::from("/dev/disk/loop")],
+            rules: vec![String::from("loop[0-9]+ root:disk 660 >disk/loop")],
+            owner: Some((String::from("root"), String::from("disk"))),
+            mode: Some(0o660),
+        };
+
+        assert_eq!(db.get(devpath).await.unwrap(), None);
+        db.insert(devpath, &record).await.unwrap();
+        assert!(dir.join("devices!virtual!block!loop0").exists());
+        assert_eq!(db.get(devpath).await.unwrap().as_ref(), Some(&record));
+        assert_eq!(db.devpaths().await.unwrap(), [devpath]);
+        assert_eq!(db.remove(devpath).await.unwrap(), Some(record));
+        assert_eq!(db.remove(devpath).await.unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn escaped() {
+        let dir = env::temp_dir().join(format!("mdev-db-escaped-{}", process::id()));
+        let db = Database::new(&dir);
+        let devpaths = [
+            Path::new("/devices/pci0000:00/0000:00:03.0/host0/block/cciss!c0d0"),
+            Path::new("/devices/virtual/block/cciss/c0d0"),
+            Path::new("/devices/virtual/misc/a\\x21"),
+        ];
+        for (i, devpath) in devpaths.iter().enumerate() {
+            let record = Record {
+                nodes: vec![PathBuf::from(format!("/dev/node{i}"))],
+                ..Record::default()
+            };
+            db.insert(devpath, &record).await.unwrap();
+        }
+        assert!(dir
+            .join("devices!pci0000:00!0000:00:03.0!host0!block!cciss\\x21c0d0")
+            .exists());
+        let stored = db.devpaths().await.unwrap();
+        let mut expected = devpaths.map(Path::to_path_buf);
+        expected.sort();
+        assert_eq!(stored, expected);
+        // not mixed up
+        for (i, devpath) in devpaths.iter().enumerate() {
+            let record = db.get(devpath).await.unwrap().unwrap();
+            assert_eq!(record.nodes, [PathBuf::from(format!("/dev/node{i}"))]);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn properties() {
+        let record = Record {
+            nodes: vec![PathBuf::from("/dev/sda1")],
+            links: vec![
+                PathBuf::from("/dev/disk/by-uuid/1234"),
+                PathBuf::from("/dev/disk/by-label/root"),
+            ],
+            rules: Vec::new(),
+            owner: Some((String::from("root"), String::from("disk"))),
+            mode: Some(0o660),
+        };
+        let mut env = HashMap::from([(String::from("DEVNAME"), String::from("sda1"))]);
+        record.add_properties(&mut env);
+        assert_eq!(env["DEVNAME"], "/dev/sda1");
+        assert_eq!(
+            env["DEVLINKS"],
+            "/dev/disk/by-uuid/1234 /dev/disk/by-label/root"
+        );
+        assert_eq!(env["MDEV_OWNER"], "root");
+        assert_eq!(env["MDEV_GROUP"], "disk");
+        assert_eq!(env["MDEV_MODE"], "0660");
+
+        // nothing was created for a network interface
+        let mut env = HashMap::from([(String::from("INTERFACE"), String::from("eth0"))]);
+        Record::default().add_properties(&mut env);
+        assert_eq!(env.len(), 1);
+    }
+}
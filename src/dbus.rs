@@ -0,0 +1,109 @@
+//! Signals of the handled events on the system bus
+//!
+//! Each event is broadcast as the `Event` signal of the [`INTERFACE`] interface, from the
+//! [`PATH`] object, with the action, the DEVPATH, the subsystem, the SEQNUM, the nodes of the
+//! device and its properties, e.g. for `dbus-monitor --system "interface='org.mdev.Events'"`.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use kobject_uevent::UEvent;
+use zbus::Connection;
+
+use crate::action_name;
+
+pub const PATH: &str = "/org/mdev/Events";
+pub const INTERFACE: &str = "org.mdev.Events";
+
+/// Body of the `Event` signal, `(ssstasa{ss})`
+pub type Event = (
+    String,
+    String,
+    String,
+    u64,
+    Vec<String>,
+    HashMap<String, String>,
+);
+
+#[derive(Debug)]
+pub struct Signaler(Connection);
+
+impl Signaler {
+    /// Connects to the system bus, the signals need no name to be owned
+    pub async fn system() -> zbus::Result<Self> {
+        Connection::system().await.map(Self)
+    }
+
+    pub fn new(connection: Connection) -> Self {
+        Self(connection)
+    }
+
+    /// Broadcasts `ev`, handled with `nodes` created or removed
+    pub async fn event(&self, ev: &UEvent, nodes: &[PathBuf]) -> zbus::Result<()> {
+        let nodes: Vec<_> = nodes
+            .iter()
+            .map(|node| node.to_string_lossy().into_owned())
+            .collect();
+        let body = (
+            action_name(ev.action),
+            ev.devpath.to_string_lossy(),
+            &ev.subsystem,
+            ev.seq,
+            nodes,
+            &ev.env,
+        );
+        self.0
+            .emit_signal(None::<()>, PATH, INTERFACE, "Event", &body)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use kobject_uevent::ActionType;
+    use tokio::net::UnixStream;
+    use zbus::{connection::Builder, Guid, MessageStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn event() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let (server, client) = futures_util::try_join!(
+            Builder::unix_stream(server)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .build(),
+            Builder::unix_stream(client).p2p().build(),
+        )
+        .unwrap();
+        let mut messages = MessageStream::from(&client);
+
+        let ev = UEvent {
+            action: ActionType::Add,
+            devpath: PathBuf::from("/devices/virtual/block/loop0"),
+            subsystem: String::from("block"),
+            env: HashMap::from([(String::from("DEVNAME"), String::from("loop0"))]),
+            seq: 42,
+        };
+        Signaler::new(server)
+            .event(&ev, &[PathBuf::from("/dev/loop0")])
+            .await
+            .unwrap();
+
+        let message = messages.next().await.unwrap().unwrap();
+        let header = message.header();
+        assert_eq!(header.path().unwrap().as_str(), PATH);
+        assert_eq!(header.interface().unwrap().as_str(), INTERFACE);
+        assert_eq!(header.member().unwrap().as_str(), "Event");
+        let (action, devpath, subsystem, seq, nodes, env): Event =
+            message.body().deserialize().unwrap();
+        assert_eq!(action, "add");
+        assert_eq!(devpath, "/devices/virtual/block/loop0");
+        assert_eq!(subsystem, "block");
+        assert_eq!(seq, 42);
+        assert_eq!(nodes, ["/dev/loop0"]);
+        assert_eq!(env, ev.env);
+    }
+}
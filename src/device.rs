@@ -0,0 +1,318 @@
+//! Devices as found in the sysfs
+//!
+//! The attributes, the driver and the parents are read when asked for, so a [`Device`] is cheap
+//! to build, e.g. from each event.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use kobject_uevent::{ActionType, UEvent};
+
+use crate::action_name;
+
+/// A device of the sysfs, its attributes are read when asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    syspath: PathBuf,
+    devpath: PathBuf,
+    subsystem: String,
+    /// As in the events of the device, without `ACTION` and `SEQNUM`
+    properties: HashMap<String, String>,
+}
+
+impl Device {
+    /// Reads the device in the directory `dir` of the sysfs mounted on `sysfs`
+    pub fn from_syspath(dir: &Path, sysfs: &Path) -> io::Result<Self> {
+        let syspath = dir.canonicalize()?;
+        let devpath = match syspath.strip_prefix(sysfs.canonicalize()?) {
+            Ok(relative) => Path::new("/").join(relative),
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{dir:?} is not in {sysfs:?}"),
+                ))
+            }
+        };
+        Self::read(syspath, devpath)
+    }
+
+    /// Reads the device at `devpath`, as in the events, in the sysfs mounted on `sysfs`
+    pub fn from_devpath(devpath: &Path, sysfs: &Path) -> io::Result<Self> {
+        let relative = devpath.strip_prefix("/").unwrap_or(devpath);
+        Self::from_syspath(&sysfs.join(relative), sysfs)
+    }
+
+    /// Returns the device of `ev`, its properties being the ones of the event
+    ///
+    /// The sysfs is not read, so that the device of a remove event is known once gone.
+    pub fn from_uevent(ev: &UEvent, sysfs: &Path) -> Self {
+        let relative = ev.devpath.strip_prefix("/").unwrap_or(&ev.devpath);
+        let mut properties = ev.env.clone();
+        properties.remove("ACTION");
+        properties.remove("SEQNUM");
+        Self {
+            syspath: sysfs.join(relative),
+            devpath: ev.devpath.clone(),
+            subsystem: ev.subsystem.clone(),
+            properties,
+        }
+    }
+
+    /// Reads the device at `syspath`, already canonical
+    pub(crate) fn read(syspath: PathBuf, devpath: PathBuf) -> io::Result<Self> {
+        let subsystem = subsystem(&syspath)?;
+        let uevent = fs::read_to_string(syspath.join("uevent"))?;
+        let mut properties: HashMap<_, _> = uevent
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        properties.insert(
+            String::from("DEVPATH"),
+            devpath.to_string_lossy().into_owned(),
+        );
+        properties.insert(String::from("SUBSYSTEM"), subsystem.clone());
+        Ok(Self {
+            syspath,
+            devpath,
+            subsystem,
+            properties,
+        })
+    }
+
+    /// Directory of the device in the sysfs
+    pub fn syspath(&self) -> &Path {
+        &self.syspath
+    }
+
+    /// Path of the device relative to the sysfs, as in the `DEVPATH` of its events
+    pub fn devpath(&self) -> &Path {
+        &self.devpath
+    }
+
+    /// Kernel name of the device, e.g. sda1
+    pub fn sysname(&self) -> &str {
+        self.devpath
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+    }
+
+    pub fn subsystem(&self) -> &str {
+        &self.subsystem
+    }
+
+    /// e.g. disk or partition for the block devices
+    pub fn devtype(&self) -> Option<&str> {
+        self.property("DEVTYPE")
+    }
+
+    /// Name of the node, relative to the dev directory
+    pub fn devname(&self) -> Option<&str> {
+        self.property("DEVNAME")
+    }
+
+    /// Major and minor numbers of the node, if any
+    pub fn devnum(&self) -> Option<(u32, u32)> {
+        if let (Some(major), Some(minor)) = (self.property("MAJOR"), self.property("MINOR")) {
+            return Some((major.parse().ok()?, minor.parse().ok()?));
+        }
+        let dev = self.attribute("dev")?;
+        let (major, minor) = dev.split_once(':')?;
+        Some((major.parse().ok()?, minor.parse().ok()?))
+    }
+
+    /// Name of the driver bound to the device, if any
+    pub fn driver(&self) -> Option<String> {
+        match fs::read_link(self.syspath.join("driver")) {
+            Ok(link) => Some(link.file_name()?.to_string_lossy().into_owned()),
+            Err(_) => self.property("DRIVER").map(str::to_string),
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&str> {
+        self.properties.get(name).map(String::as_str)
+    }
+
+    pub fn properties(&self) -> &HashMap<String, String> {
+        &self.properties
+    }
+
+    /// Reads the sysfs attribute `name`, without the trailing newline
+    pub fn attribute(&self, name: &str) -> Option<String> {
+        let value = fs::read_to_string(self.syspath.join(name)).ok()?;
+        Some(value.trim_end_matches('\n').to_string())
+    }
+
+    /// Reads the attribute `name` of the device or of its closest parent having it, as the
+    /// `ATTRS{}` keys of udev rules
+    pub fn attribute_up(&self, name: &str) -> Option<String> {
+        self.attribute(name)
+            .or_else(|| self.parents().find_map(|parent| parent.attribute(name)))
+    }
+
+    /// Returns the closest device above this one, the directories in between that are not
+    /// devices being skipped
+    pub fn parent(&self) -> Option<Device> {
+        self.syspath
+            .ancestors()
+            .zip(self.devpath.ancestors())
+            .skip(1)
+            // the root of the sysfs is not a device
+            .take_while(|(_, devpath)| devpath.parent().is_some())
+            .find_map(|(syspath, devpath)| {
+                if !syspath.join("uevent").exists() {
+                    return None;
+                }
+                Self::read(syspath.to_path_buf(), devpath.to_path_buf()).ok()
+            })
+    }
+
+    /// Returns the parents of the device, the closest first
+    pub fn parents(&self) -> impl Iterator<Item = Device> {
+        std::iter::successors(self.parent(), Device::parent)
+    }
+
+    /// Returns the closest parent of `subsystem`, e.g. the usb device of an input device
+    pub fn parent_with_subsystem(&self, subsystem: &str) -> Option<Device> {
+        self.parents().find(|parent| parent.subsystem == subsystem)
+    }
+
+    /// Whether the device has a node, i.e. a device number
+    pub fn has_node(&self) -> bool {
+        self.syspath.join("dev").exists()
+    }
+
+    /// Returns the event the kernel would send for `action` on the device, e.g. add to
+    /// coldplug it
+    pub fn to_uevent(&self, action: ActionType) -> UEvent {
+        let mut env = self.properties.clone();
+        env.insert(String::from("ACTION"), action_name(action).to_string());
+        UEvent {
+            action,
+            devpath: self.devpath.clone(),
+            subsystem: self.subsystem.clone(),
+            env,
+            seq: 0,
+        }
+    }
+}
+
+/// Reads the subsystem of the device at `syspath`, the name of the directory it links to
+pub(crate) fn subsystem(syspath: &Path) -> io::Result<String> {
+    let link = fs::read_link(syspath.join("subsystem"))?;
+    Ok(link
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::unix::fs::symlink, process};
+
+    use super::*;
+
+    #[test]
+    fn from_syspath() {
+        let sysfs = env::temp_dir().join(format!("mdev-device-{}", process::id()));
+        let dir = sysfs.join("devices/virtual/mem/null");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("uevent"), "MAJOR=1\nMINOR=3\nDEVNAME=null\n").unwrap();
+        fs::write(dir.join("dev"), "1:3\n").unwrap();
+        symlink("../../../../class/mem", dir.join("subsystem")).unwrap();
+
+        let device = Device::from_syspath(&dir, &sysfs).unwrap();
+        assert_eq!(device.devpath(), Path::new("/devices/virtual/mem/null"));
+        assert_eq!(device.subsystem(), "mem");
+        assert_eq!(device.property("DEVNAME"), Some("null"));
+        assert_eq!(
+            device.property("DEVPATH"),
+            Some("/devices/virtual/mem/null")
+        );
+        assert_eq!(device.attribute("dev").as_deref(), Some("1:3"));
+        assert_eq!(device.attribute("power"), None);
+        assert!(device.has_node());
+
+        let ev = device.to_uevent(ActionType::Add);
+        assert_eq!(ev.devpath, Path::new("/devices/virtual/mem/null"));
+        assert_eq!(ev.env["ACTION"], "add");
+        assert_eq!(ev.env["SUBSYSTEM"], "mem");
+
+        assert!(Device::from_syspath(&env::temp_dir(), &sysfs).is_err());
+        fs::remove_dir_all(sysfs).unwrap();
+    }
+
+    #[test]
+    fn parents() {
+        let sysfs = env::temp_dir().join(format!("mdev-parents-{}", process::id()));
+        let pci = sysfs.join("devices/pci0000:00/0000:00:1f.2");
+        let disk = pci.join("ata1/host0/target0:0:0/0:0:0:0/block/sda");
+        let partition = disk.join("sda1");
+        fs::create_dir_all(&partition).unwrap();
+        // not a device, it has no subsystem
+        fs::write(sysfs.join("devices/pci0000:00/uevent"), "").unwrap();
+        for (dir, subsystem, uevent) in [
+            (&pci, "pci", "DRIVER=ahci\n"),
+            (
+                &disk,
+                "block",
+                "MAJOR=8\nMINOR=0\nDEVNAME=sda\nDEVTYPE=disk\n",
+            ),
+            (&partition, "block", "DEVNAME=sda1\nDEVTYPE=partition\n"),
+        ] {
+            fs::write(dir.join("uevent"), uevent).unwrap();
+            symlink(format!("/sys/class/{subsystem}"), dir.join("subsystem")).unwrap();
+        }
+        fs::write(pci.join("vendor"), "0x8086\n").unwrap();
+        fs::write(partition.join("dev"), "8:1\n").unwrap();
+        symlink("/sys/bus/pci/drivers/ahci", pci.join("driver")).unwrap();
+
+        let devpath = Path::new(
+            "/devices/pci0000:00/0000:00:1f.2/ata1/host0/target0:0:0/0:0:0:0/block/sda/sda1",
+        );
+        let device = Device::from_devpath(devpath, &sysfs).unwrap();
+        assert_eq!(device.sysname(), "sda1");
+        assert_eq!(device.devtype(), Some("partition"));
+        assert_eq!(device.devnum(), Some((8, 1)));
+        assert_eq!(device.driver(), None);
+
+        let parents: Vec<_> = device.parents().collect();
+        assert_eq!(parents.len(), 2);
+        assert_eq!(parents[0].devname(), Some("sda"));
+        assert_eq!(parents[0].devnum(), Some((8, 0)));
+        assert_eq!(parents[1].syspath(), pci.canonicalize().unwrap());
+        assert_eq!(parents[1].driver().as_deref(), Some("ahci"));
+        assert_eq!(
+            device.parent_with_subsystem("pci").unwrap().devpath(),
+            Path::new("/devices/pci0000:00/0000:00:1f.2")
+        );
+        assert!(device.parent_with_subsystem("usb").is_none());
+        assert_eq!(device.attribute("vendor"), None);
+        assert_eq!(device.attribute_up("vendor").as_deref(), Some("0x8086"));
+
+        // the device is gone, as on remove
+        fs::remove_dir_all(&sysfs).unwrap();
+        let ev = UEvent {
+            action: ActionType::Remove,
+            devpath: devpath.to_path_buf(),
+            subsystem: String::from("block"),
+            env: HashMap::from([
+                (String::from("ACTION"), String::from("remove")),
+                (String::from("SEQNUM"), String::from("42")),
+                (String::from("MAJOR"), String::from("8")),
+                (String::from("MINOR"), String::from("1")),
+            ]),
+            seq: 42,
+        };
+        let device = Device::from_uevent(&ev, &sysfs);
+        assert_eq!(device.devnum(), Some((8, 1)));
+        assert_eq!(device.property("ACTION"), None);
+        assert!(device.parent().is_none());
+        assert!(Device::from_devpath(devpath, &sysfs).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+//! Persistent names of the disks from their sysfs attributes
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs;
+
+use crate::sysfs::read_attr;
+
+/// How a disk is attached, deciding the prefix of its by-id names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Ata,
+    Scsi,
+    Nvme,
+    Virtio,
+}
+
+/// Identity of a disk as reported by the kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub bus: Bus,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// World wide identifier, e.g. `naa.5000c500a1b2c3d4` or `eui.0025388b71b1a8c5`
+    pub wwid: Option<String>,
+}
+
+impl Identity {
+    /// Reads the identity of the disk with sysfs directory `disk`, e.g. `/sys/block/sda`
+    pub async fn read(disk: &Path) -> Option<Self> {
+        let name = disk.file_name()?.to_str()?;
+        let device = disk.join("device");
+        if name.starts_with("nvme") {
+            return Some(Self {
+                bus: Bus::Nvme,
+                vendor: None,
+                model: read_attr(&device, "model").await,
+                serial: read_attr(&device, "serial").await,
+                wwid: read_attr(disk, "wwid").await,
+            });
+        }
+        if name.starts_with("vd") {
+            return Some(Self {
+                bus: Bus::Virtio,
+                vendor: None,
+                model: None,
+                serial: read_attr(disk, "serial").await,
+                wwid: None,
+            });
+        }
+        if name.starts_with("sd") || name.starts_with("sr") {
+            let real = fs::canonicalize(&device).await.ok()?;
+            let is_ata = real
+                .iter()
+                .any(|c| c.to_str().is_some_and(|c| c.starts_with("ata")));
+            let serial = fs::read(device.join("vpd_pg80"))
+                .await
+                .ok()
+                .and_then(|page| unit_serial(&page));
+            return Some(Self {
+                bus: if is_ata { Bus::Ata } else { Bus::Scsi },
+                vendor: read_attr(&device, "vendor").await,
+                model: read_attr(&device, "model").await,
+                serial,
+                wwid: read_attr(&device, "wwid").await,
+            });
+        }
+        None
+    }
+
+    /// Returns the names of the disk in `/dev/disk/by-id`
+    pub fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let serial = self.serial.as_deref().map(sanitize);
+        let model = self.model.as_deref().map(sanitize);
+        match self.bus {
+            Bus::Ata => {
+                if let (Some(model), Some(serial)) = (&model, &serial) {
+                    names.push(format!("ata-{model}_{serial}"));
+                }
+            }
+            Bus::Scsi => {
+                let vendor = self.vendor.as_deref().map(sanitize);
+                if let (Some(vendor), Some(model), Some(serial)) = (vendor, &model, &serial) {
+                    names.push(format!("scsi-{vendor}_{model}_{serial}"));
+                }
+            }
+            Bus::Nvme => {
+                if let (Some(model), Some(serial)) = (&model, &serial) {
+                    names.push(format!("nvme-{model}_{serial}"));
+                }
+                if let Some(wwid) = &self.wwid {
+                    names.push(format!("nvme-{}", sanitize(wwid)));
+                }
+            }
+            Bus::Virtio => {
+                if let Some(serial) = &serial {
+                    names.push(format!("virtio-{serial}"));
+                }
+            }
+        }
+        if let Some(naa) = self
+            .wwid
+            .as_deref()
+            .and_then(|wwid| wwid.strip_prefix("naa."))
+        {
+            if self.bus != Bus::Nvme {
+                names.push(format!("wwn-0x{}", sanitize(naa)));
+            }
+        }
+        names
+    }
+}
+
+/// Returns the sysfs directories of the partitions of the disk with sysfs directory `disk`
+pub async fn partitions(disk: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut partitions = Vec::new();
+    let mut entries = fs::read_dir(disk).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if fs::metadata(path.join("partition")).await.is_ok() {
+            partitions.push(path);
+        }
+    }
+    partitions.sort();
+    Ok(partitions)
+}
+
+/// Returns the number of the partition with sysfs directory `dir`, if it is one
+pub async fn partition_number(dir: &Path) -> Option<u32> {
+    read_attr(dir, "partition").await?.parse().ok()
+}
+
+/// Extracts the serial number from the unit serial number VPD page
+fn unit_serial(page: &[u8]) -> Option<String> {
+    if *page.get(1)? != 0x80 {
+        return None;
+    }
+    let len = u16::from_be_bytes([*page.get(2)?, *page.get(3)?]) as usize;
+    let serial = String::from_utf8_lossy(page.get(4..4 + len)?);
+    let serial = serial.trim();
+    (!serial.is_empty()).then(|| serial.to_string())
+}
+
+/// Replaces the whitespace and the characters that cannot be part of a name with `_`
+pub(crate) fn sanitize(s: &str) -> String {
+    let mut sanitized = String::with_capacity(s.len());
+    for word in s.split_whitespace() {
+        if !sanitized.is_empty() {
+            sanitized.push('_');
+        }
+        sanitized.extend(word.chars().map(|c| {
+            if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        }));
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let ata = Identity {
+            bus: Bus::Ata,
+            vendor: Some(String::from("ATA")),
+            model: Some(String::from("Samsung SSD 860 EVO 500GB")),
+            serial: Some(String::from("S3Z2NB0K123456A")),
+            wwid: Some(String::from("naa.5002538e40a1b2c3")),
+        };
+        assert_eq!(
+            ata.names(),
+            [
+                "ata-Samsung_SSD_860_EVO_500GB_S3Z2NB0K123456A",
+                "wwn-0x5002538e40a1b2c3"
+            ]
+        );
+
+        let nvme = Identity {
+            bus: Bus::Nvme,
+            vendor: None,
+            model: Some(String::from("WDC PC SN730/512GB")),
+            serial: Some(String::from("  20162V800123  ")),
+            wwid: Some(String::from("eui.e8238fa6bf530001001b448b4a1b2c3d")),
+        };
+        assert_eq!(
+            nvme.names(),
+            [
+                "nvme-WDC_PC_SN730_512GB_20162V800123",
+                "nvme-eui.e8238fa6bf530001001b448b4a1b2c3d"
+            ]
+        );
+
+        let virtio = Identity {
+            bus: Bus::Virtio,
+            vendor: None,
+            model: None,
+            serial: None,
+            wwid: None,
+        };
+        assert!(virtio.names().is_empty());
+    }
+
+    #[tokio::test]
+    async fn partitions() {
+        let disk = std::env::temp_dir().join(format!("mdev-disk-{}", std::process::id()));
+        for dir in ["sda2", "sda1", "queue", "holders"] {
+            std::fs::create_dir_all(disk.join(dir)).unwrap();
+        }
+        std::fs::write(disk.join("sda1/partition"), "1\n").unwrap();
+        std::fs::write(disk.join("sda2/partition"), "2\n").unwrap();
+
+        assert_eq!(
+            super::partitions(&disk).await.unwrap(),
+            [disk.join("sda1"), disk.join("sda2")]
+        );
+        assert_eq!(partition_number(&disk.join("sda2")).await, Some(2));
+        assert_eq!(partition_number(&disk.join("queue")).await, None);
+
+        std::fs::remove_dir_all(&disk).unwrap();
+    }
+
+    #[test]
+    fn vpd() {
+        assert_eq!(
+            unit_serial(b"\x00\x80\x00\x0a  ZA1B2C3D").as_deref(),
+            Some("ZA1B2C3D")
+        );
+        assert_eq!(unit_serial(b"\x00\x83\x00\x02ab"), None);
+        assert_eq!(unit_serial(b"\x00\x80\x00\x10short"), None);
+    }
+}
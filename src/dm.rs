@@ -0,0 +1,79 @@
+//! Names of the device-mapper devices, as used by LVM and cryptsetup
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{rule::is_file_name, sysfs::read_attr};
+
+/// Name and UUID of a mapped device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub name: String,
+    pub uuid: Option<String>,
+}
+
+impl Mapping {
+    /// Reads the mapping of the `dm-N` device with sysfs directory `dir`
+    ///
+    /// The `DM_NAME` and `DM_UUID` variables of the event are preferred, as the sysfs
+    /// attributes may already be gone on remove.
+    pub async fn read(dir: &Path, env: &HashMap<String, String>) -> Option<Self> {
+        let name = match env.get("DM_NAME") {
+            Some(name) => Some(name.clone()),
+            None => read_attr(&dir.join("dm"), "name").await,
+        };
+        let uuid = match env.get("DM_UUID") {
+            Some(uuid) => Some(uuid.clone()),
+            None => read_attr(&dir.join("dm"), "uuid").await,
+        };
+        Some(Self {
+            name: name.filter(|name| is_file_name(name))?,
+            uuid: uuid.filter(|uuid| is_file_name(uuid)),
+        })
+    }
+
+    /// Returns the links of the device, relative to the dev directory
+    pub fn links(&self) -> Vec<String> {
+        let mut links = vec![
+            format!("mapper/{}", self.name),
+            format!("disk/by-id/dm-name-{}", self.name),
+        ];
+        if let Some(uuid) = &self.uuid {
+            links.push(format!("disk/by-id/dm-uuid-{uuid}"));
+        }
+        links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn links() {
+        let env = HashMap::from([
+            (String::from("DM_NAME"), String::from("vg0-root")),
+            (
+                String::from("DM_UUID"),
+                String::from("LVM-Q2r3Ff7fJ9kWhQ3xWZ3AxhNf0rOKtFwB"),
+            ),
+        ]);
+        let mapping = Mapping::read(Path::new("/nonexistent"), &env)
+            .await
+            .unwrap();
+        assert_eq!(
+            mapping.links(),
+            [
+                "mapper/vg0-root",
+                "disk/by-id/dm-name-vg0-root",
+                "disk/by-id/dm-uuid-LVM-Q2r3Ff7fJ9kWhQ3xWZ3AxhNf0rOKtFwB"
+            ]
+        );
+
+        let env = HashMap::from([(String::from("DM_NAME"), String::from(".."))]);
+        assert_eq!(Mapping::read(Path::new("/nonexistent"), &env).await, None);
+        assert_eq!(
+            Mapping::read(Path::new("/nonexistent"), &HashMap::new()).await,
+            None
+        );
+    }
+}
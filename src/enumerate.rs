@@ -0,0 +1,242 @@
+//! Listing the devices of the sysfs, as `udev_enumerate` does
+//!
+//! The devices are found once, under their canonical path in /sys/devices, each one before its
+//! children, e.g. a disk before its partitions.
+
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+use walkdir::WalkDir;
+
+use crate::{
+    device::{self, Device},
+    modalias::glob_match,
+};
+
+/// Finds the devices matching all the filters given, all of them by default
+///
+/// The patterns are shell patterns, e.g. `sd[a-z]*`.
+#[derive(Debug, Clone)]
+#[must_use = "the devices are only listed by scan"]
+pub struct Enumerator {
+    sysfs: PathBuf,
+    subsystems: Vec<String>,
+    nosubsystems: Vec<String>,
+    devpaths: Vec<String>,
+    attributes: Vec<(String, String)>,
+    properties: Vec<(String, String)>,
+    nodes_only: bool,
+}
+
+impl Enumerator {
+    /// Lists the devices of the sysfs mounted on `sysfs`
+    pub fn new(sysfs: impl Into<PathBuf>) -> Self {
+        Self {
+            sysfs: sysfs.into(),
+            subsystems: Vec::new(),
+            nosubsystems: Vec::new(),
+            devpaths: Vec::new(),
+            attributes: Vec::new(),
+            properties: Vec::new(),
+            nodes_only: false,
+        }
+    }
+
+    /// Only lists the devices of `subsystem`, can be called several times to list the ones of
+    /// any of them
+    pub fn match_subsystem(mut self, subsystem: impl Into<String>) -> Self {
+        self.subsystems.push(subsystem.into());
+        self
+    }
+
+    /// Leaves out the devices of `subsystem`, can be called several times
+    pub fn nomatch_subsystem(mut self, subsystem: impl Into<String>) -> Self {
+        self.nosubsystems.push(subsystem.into());
+        self
+    }
+
+    /// Only lists the devices whose DEVPATH matches `pattern`, can be called several times to
+    /// list the ones matching any of them
+    pub fn match_devpath(mut self, pattern: impl Into<String>) -> Self {
+        self.devpaths.push(pattern.into());
+        self
+    }
+
+    /// Only lists the devices whose sysfs attribute `name` matches `pattern`, without the
+    /// trailing newline
+    pub fn match_attribute(mut self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.attributes.push((name.into(), pattern.into()));
+        self
+    }
+
+    /// Only lists the devices whose property `name` matches `pattern`
+    pub fn match_property(mut self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.properties.push((name.into(), pattern.into()));
+        self
+    }
+
+    /// Only lists the devices with a node
+    pub fn nodes_only(mut self, nodes_only: bool) -> Self {
+        self.nodes_only = nodes_only;
+        self
+    }
+
+    /// Walks the sysfs, the devices gone meanwhile are skipped
+    pub fn scan(&self) -> impl Iterator<Item = Device> + '_ {
+        WalkDir::new(self.sysfs.join("devices"))
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_dir())
+            .filter_map(|entry| self.device(entry.path()))
+    }
+
+    /// Mount point of the sysfs
+    pub(crate) fn sysfs(&self) -> &Path {
+        &self.sysfs
+    }
+
+    /// Reads the device at `syspath`, if it matches
+    pub(crate) fn device(&self, syspath: &Path) -> Option<Device> {
+        // not every directory is a device, e.g. power or queue
+        if !syspath.join("uevent").exists() {
+            return None;
+        }
+        let subsystem = device::subsystem(syspath).ok()?;
+        if !self.subsystems.is_empty() && !self.subsystems.contains(&subsystem)
+            || self.nosubsystems.contains(&subsystem)
+        {
+            return None;
+        }
+        let devpath = Path::new("/").join(syspath.strip_prefix(&self.sysfs).ok()?);
+        if !self.devpaths.is_empty()
+            && !self.devpaths.iter().any(|pattern| {
+                glob_match(pattern.as_bytes(), devpath.as_os_str().as_encoded_bytes())
+            })
+        {
+            return None;
+        }
+        if self.nodes_only && !syspath.join("dev").exists() {
+            return None;
+        }
+
+        let device = match Device::read(syspath.to_path_buf(), devpath) {
+            Ok(device) => device,
+            Err(e) => {
+                debug!("Cannot read the device at {:?}: {}", syspath, e);
+                return None;
+            }
+        };
+        let properties = self.properties.iter().all(|(name, pattern)| {
+            device
+                .property(name)
+                .is_some_and(|value| glob_match(pattern.as_bytes(), value.as_bytes()))
+        });
+        let attributes = || {
+            self.attributes.iter().all(|(name, pattern)| {
+                device
+                    .attribute(name)
+                    .is_some_and(|value| glob_match(pattern.as_bytes(), value.as_bytes()))
+            })
+        };
+        (properties && attributes()).then_some(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix::fs::symlink, process};
+
+    use super::*;
+
+    /// Creates the device `devpath` of `subsystem`, with the `uevent` and the attributes given
+    fn device(sysfs: &Path, devpath: &str, subsystem: &str, uevent: &str, attrs: &[(&str, &str)]) {
+        let dir = sysfs.join(devpath);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("uevent"), uevent).unwrap();
+        symlink(sysfs.join("class").join(subsystem), dir.join("subsystem")).unwrap();
+        for (name, value) in attrs {
+            fs::write(dir.join(name), format!("{value}\n")).unwrap();
+        }
+    }
+
+    fn devpaths(enumerator: &Enumerator) -> Vec<String> {
+        enumerator
+            .scan()
+            .map(|device| device.devpath().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn scan() {
+        let sysfs = env::temp_dir().join(format!("mdev-enumerate-{}", process::id()));
+        let pci = "devices/pci0000:00/0000:00:1f.2";
+        let disk = format!("{pci}/ata1/host0/target0:0:0/0:0:0:0/block/sda");
+        device(&sysfs, pci, "pci", "DRIVER=ahci\n", &[("vendor", "0x8086")]);
+        device(
+            &sysfs,
+            &format!("{disk}/sda1"),
+            "block",
+            "DEVNAME=sda1\nDEVTYPE=partition\n",
+            &[("dev", "8:1"), ("partition", "1")],
+        );
+        device(
+            &sysfs,
+            &disk,
+            "block",
+            "DEVNAME=sda\nDEVTYPE=disk\n",
+            &[("dev", "8:0")],
+        );
+        device(
+            &sysfs,
+            "devices/virtual/net/lo",
+            "net",
+            "INTERFACE=lo\n",
+            &[],
+        );
+        // not a device
+        fs::create_dir_all(sysfs.join(&disk).join("queue")).unwrap();
+
+        let all = Enumerator::new(&sysfs);
+        let (pci, disk) = (format!("/{pci}"), format!("/{disk}"));
+        let partition = format!("{disk}/sda1");
+        let (pci, disk, partition) = (pci.as_str(), disk.as_str(), partition.as_str());
+        // the disk comes before its partitions
+        assert_eq!(
+            devpaths(&all),
+            [pci, disk, partition, "/devices/virtual/net/lo"]
+        );
+        assert_eq!(
+            devpaths(&all.clone().match_subsystem("block")),
+            [disk, partition]
+        );
+        assert_eq!(
+            devpaths(
+                &all.clone()
+                    .nomatch_subsystem("block")
+                    .nomatch_subsystem("net")
+            ),
+            [pci]
+        );
+        assert_eq!(
+            devpaths(&all.clone().match_devpath("/devices/virtual/*")),
+            ["/devices/virtual/net/lo"]
+        );
+        assert_eq!(
+            devpaths(&all.clone().match_attribute("vendor", "0x8086")),
+            [pci]
+        );
+        assert_eq!(
+            devpaths(&all.clone().match_property("DEVTYPE", "part*")),
+            [partition]
+        );
+        assert_eq!(devpaths(&all.clone().nodes_only(true)), [disk, partition]);
+
+        let device = all.clone().match_property("DEVNAME", "sda").scan().next();
+        let ev = device.unwrap().to_uevent(kobject_uevent::ActionType::Add);
+        assert_eq!(ev.subsystem, "block");
+        assert_eq!(ev.env["DEVTYPE"], "disk");
+        fs::remove_dir_all(sysfs).unwrap();
+    }
+}
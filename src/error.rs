@@ -0,0 +1,178 @@
+//! Errors of the library, to be matched on by its users
+//!
+//! The underlying errors are kept as the [`source`](std::error::Error::source), and
+//! [`Error::report`] displays them along.
+
+use std::{error::Error as _, fmt, io, path::PathBuf, process::ExitStatus, time::Duration};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Invalid DEVPATH {0:?}")]
+    InvalidDevpath(PathBuf),
+    #[error("Invalid DEVNAME {devname:?} for {devpath:?}")]
+    InvalidDevname { devname: String, devpath: PathBuf },
+    #[error("Invalid device number {0:?}")]
+    InvalidDeviceNumber(String),
+    /// A node or a link named by a rule
+    #[error("{0:?} would be outside of the dev directory")]
+    UnsafeName(String),
+    #[error("User {0} does not exist")]
+    MissingUser(String),
+    #[error("Group {0} does not exist")]
+    MissingGroup(String),
+    #[error("Cannot resolve {name}")]
+    Lookup {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid mode {0:o}")]
+    InvalidMode(u32),
+    #[error("Cannot create {path:?}")]
+    MknodFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot change the owner of {path:?}")]
+    ChownFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot set {name} on {path:?}")]
+    SetXattrFailed {
+        path: PathBuf,
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot link {link:?} to {target:?}")]
+    SymlinkFailed {
+        link: PathBuf,
+        target: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot remove {path:?}")]
+    UnlinkFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot run {line:?}")]
+    CommandSpawn {
+        line: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{line:?} failed with {status}")]
+    CommandFailed { line: String, status: ExitStatus },
+    #[error("Invalid attribute {0:?}")]
+    InvalidAttribute(String),
+    #[error("Cannot write {value:?} to {path:?}")]
+    WriteAttrFailed {
+        path: PathBuf,
+        value: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid sysctl key {0:?}")]
+    InvalidSysctl(String),
+    #[error("Cannot set {key} to {value:?}")]
+    SysctlFailed {
+        key: String,
+        value: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot rename {from} to {to}")]
+    RenameFailed {
+        from: String,
+        to: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid firmware name {0:?}")]
+    InvalidFirmware(String),
+    #[error("Firmware {0} not found")]
+    FirmwareNotFound(String),
+    #[error("Cannot load the firmware {name}")]
+    FirmwareFailed {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot access the record of {devpath:?}")]
+    Database {
+        devpath: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot listen to the events")]
+    Events(#[source] crate::stream::Error),
+    #[error("No device appeared within {0:?}")]
+    Timeout(Duration),
+    #[error("Invalid log filter {directives:?}")]
+    LogFilter {
+        directives: String,
+        #[source]
+        source: tracing_subscriber::filter::ParseError,
+    },
+    #[cfg(feature = "otlp")]
+    #[error("Cannot export the spans to {endpoint}")]
+    Exporter {
+        endpoint: String,
+        #[source]
+        source: opentelemetry_otlp::ExporterBuildError,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Displays the error followed by its causes, e.g. `Cannot create "/dev/sda": Operation not
+    /// permitted (os error 1)`
+    pub fn report(&self) -> impl fmt::Display + '_ {
+        Report(self)
+    }
+}
+
+struct Report<'a>(&'a Error);
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(e) = source {
+            write!(f, ": {}", e)?;
+            source = e.source();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let e = Error::MknodFailed {
+            path: PathBuf::from("/dev/sda"),
+            source: io::Error::from(nix::errno::Errno::EPERM),
+        };
+        assert_eq!(e.to_string(), r#"Cannot create "/dev/sda""#);
+        assert_eq!(
+            e.report().to_string(),
+            r#"Cannot create "/dev/sda": Operation not permitted (os error 1)"#
+        );
+        assert_eq!(
+            Error::MissingUser(String::from("nobody"))
+                .report()
+                .to_string(),
+            "User nobody does not exist"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Events as data, to be logged, recorded, replayed or sent to other processes
+//!
+//! [`UEvent`] comes from another crate, [`Event`] wraps it to be (de)serialized, the action
+//! being written as in the events, e.g. `"add"`.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use kobject_uevent::{ActionType, UEvent};
+use serde::{Deserialize, Serialize};
+
+/// A [`UEvent`] that can be serialized
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Event(#[serde(with = "UEventDef")] pub UEvent);
+
+impl From<UEvent> for Event {
+    fn from(ev: UEvent) -> Self {
+        Self(ev)
+    }
+}
+
+impl From<Event> for UEvent {
+    fn from(ev: Event) -> Self {
+        ev.0
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "UEvent")]
+struct UEventDef {
+    #[serde(with = "action")]
+    action: ActionType,
+    devpath: PathBuf,
+    subsystem: String,
+    env: HashMap<String, String>,
+    seq: u64,
+}
+
+/// (De)serializes an [`ActionType`] as its name, e.g. with `#[serde(with = "mdev::event::action")]`
+pub mod action {
+    use std::borrow::Cow;
+
+    use kobject_uevent::ActionType;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::action_name;
+
+    pub fn serialize<S: Serializer>(action: &ActionType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(action_name(*action))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ActionType, D::Error> {
+        // owned when read from a stream or unescaped
+        let name = Cow::<str>::deserialize(deserializer)?;
+        name.parse()
+            .map_err(|_| D::Error::custom(format!("unknown action {name:?}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let ev = Event(UEvent {
+            action: ActionType::Remove,
+            devpath: PathBuf::from("/devices/virtual/mem/null"),
+            subsystem: String::from("mem"),
+            env: HashMap::from([(String::from("MAJOR"), String::from("1"))]),
+            seq: 7,
+        });
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(
+            json,
+            r#"{"action":"remove","devpath":"/devices/virtual/mem/null","subsystem":"mem","env":{"MAJOR":"1"},"seq":7}"#
+        );
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), ev);
+
+        // not borrowed from the input
+        let escaped = json.replace("remove", r"\u0072emove");
+        assert_eq!(serde_json::from_str::<Event>(&escaped).unwrap(), ev);
+        assert_eq!(
+            serde_json::from_reader::<_, Event>(json.as_bytes()).unwrap(),
+            ev
+        );
+
+        let json = json.replace("remove", "unplug");
+        let e = serde_json::from_str::<Event>(&json).unwrap_err();
+        assert!(e.to_string().contains("unknown action \"unplug\""), "{e}");
+    }
+}
@@ -0,0 +1,223 @@
+//! Events left out before the rules are evaluated, or when rebroadcasting them
+
+use std::{path::Path, str::FromStr};
+
+use kobject_uevent::{ActionType, UEvent};
+use regex::Regex;
+use tracing::warn;
+
+use crate::modalias::glob_match;
+
+/// A DEVPATH prefix, or a shell-style pattern when it contains `*`, `?` or `[`
+///
+/// `*` matches `/` as well, so `/devices/virtual/bdi/*` matches all the devices below.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DevpathPattern(String);
+
+impl DevpathPattern {
+    pub fn matches(&self, devpath: &Path) -> bool {
+        if self.0.contains(['*', '?', '[']) {
+            glob_match(self.0.as_bytes(), devpath.as_os_str().as_encoded_bytes())
+        } else {
+            // whole components, /devices/virtual/net does not match /devices/virtual/netdev
+            devpath.starts_with(&self.0)
+        }
+    }
+}
+
+impl FromStr for DevpathPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.starts_with('/') {
+            true => Ok(Self(s.to_string())),
+            false => Err(format!(
+                "{s:?} is not an absolute DEVPATH, e.g. /devices/virtual"
+            )),
+        }
+    }
+}
+
+/// Subsystems whose events are handled
+///
+/// In the configuration, `%subsystem-only net,block` and `%subsystem-skip bdi` lines add to
+/// the lists.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subsystems {
+    /// Handled exclusively, unless empty
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+}
+
+impl Subsystems {
+    /// Reads the directives of the configuration contained in `input`
+    pub fn from_directives(input: &str) -> Self {
+        let mut subsystems = Self::default();
+        for line in input.lines() {
+            let Some(directive) = line.trim().strip_prefix('%') else {
+                continue;
+            };
+            let (name, value) = directive
+                .split_once(char::is_whitespace)
+                .unwrap_or((directive, ""));
+            let list = match name {
+                "subsystem-only" => &mut subsystems.only,
+                "subsystem-skip" => &mut subsystems.skip,
+                _ => {
+                    warn!("{}: unknown directive", line);
+                    continue;
+                }
+            };
+            list.extend(
+                value
+                    .split([',', ' ', '\t'])
+                    .filter(|s| !s.is_empty())
+                    .map(String::from),
+            );
+        }
+        subsystems
+    }
+
+    /// Adds the subsystems of `other` to the lists
+    pub fn extend(&mut self, other: Self) {
+        self.only.extend(other.only);
+        self.skip.extend(other.skip);
+    }
+
+    pub fn allows(&self, subsystem: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|s| s == subsystem))
+            && !self.skip.iter().any(|s| s == subsystem)
+    }
+}
+
+/// A property of the events matching a regex, as the `$VAR=regex` fields of the rules
+#[derive(Debug, Clone)]
+pub struct PropertyMatch {
+    pub name: String,
+    pub regex: Regex,
+}
+
+impl PropertyMatch {
+    pub fn matches(&self, ev: &UEvent) -> bool {
+        ev.env
+            .get(&self.name)
+            .is_some_and(|value| self.regex.is_match(value))
+    }
+}
+
+impl FromStr for PropertyMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, regex) = s
+            .strip_prefix('$')
+            .unwrap_or(s)
+            .split_once('=')
+            .ok_or_else(|| format!("invalid match {s:?}, expected VAR=REGEX"))?;
+        let regex = Regex::new(regex).map_err(|e| format!("invalid regex {regex:?}: {e}"))?;
+        Ok(Self {
+            name: name.to_string(),
+            regex,
+        })
+    }
+}
+
+/// Events rebroadcast by the daemon, all of them unless restricted
+#[derive(Debug, Clone, Default)]
+pub struct Rebroadcast {
+    /// Rebroadcast exclusively, unless empty
+    pub subsystems: Vec<String>,
+    /// Rebroadcast exclusively, unless empty
+    pub actions: Vec<ActionType>,
+    /// All matching the events rebroadcast
+    pub matches: Vec<PropertyMatch>,
+}
+
+impl Rebroadcast {
+    pub fn allows(&self, ev: &UEvent) -> bool {
+        (self.subsystems.is_empty() || self.subsystems.contains(&ev.subsystem))
+            && (self.actions.is_empty() || self.actions.contains(&ev.action))
+            && self.matches.iter().all(|m| m.matches(ev))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devpath_patterns() {
+        let bdi: DevpathPattern = "/devices/virtual/bdi/*".parse().unwrap();
+        assert!(bdi.matches(Path::new("/devices/virtual/bdi/7:0")));
+        assert!(bdi.matches(Path::new("/devices/virtual/bdi/7:0/power")));
+        assert!(!bdi.matches(Path::new("/devices/virtual/block/loop0")));
+
+        let net: DevpathPattern = "/devices/virtual/net".parse().unwrap();
+        assert!(net.matches(Path::new("/devices/virtual/net")));
+        assert!(net.matches(Path::new("/devices/virtual/net/lo")));
+        assert!(!net.matches(Path::new("/devices/virtual/netdev/lo")));
+
+        let tty: DevpathPattern = "/devices/virtual/tty/tty[0-9]".parse().unwrap();
+        assert!(tty.matches(Path::new("/devices/virtual/tty/tty1")));
+        assert!(!tty.matches(Path::new("/devices/virtual/tty/ttyS1")));
+
+        assert!("devices/virtual".parse::<DevpathPattern>().is_err());
+    }
+
+    #[test]
+    fn subsystems() {
+        let subsystems = Subsystems::from_directives(
+            "%subsystem-only block, usb\n\
+             sd[a-z].* root:disk 660\n\
+             %subsystem-skip usb\n\
+             %subsystem-sometimes net\n",
+        );
+        assert_eq!(subsystems.only, ["block", "usb"]);
+        assert_eq!(subsystems.skip, ["usb"]);
+        assert!(subsystems.allows("block"));
+        assert!(!subsystems.allows("usb"));
+        assert!(!subsystems.allows("net"));
+
+        assert!(Subsystems::default().allows("net"));
+        let mut skip = Subsystems::default();
+        skip.extend(Subsystems::from_directives("%subsystem-skip bdi"));
+        assert!(!skip.allows("bdi"));
+        assert!(skip.allows("net"));
+    }
+
+    #[test]
+    fn rebroadcast() {
+        let ev = UEvent {
+            action: ActionType::Add,
+            devpath: "/devices/virtual/block/loop0".into(),
+            subsystem: String::from("block"),
+            env: [("DEVTYPE", "disk"), ("DEVNAME", "loop0")]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            seq: 1,
+        };
+        assert!(Rebroadcast::default().allows(&ev));
+
+        let mut rebroadcast = Rebroadcast {
+            subsystems: vec![String::from("usb"), String::from("block")],
+            actions: vec![ActionType::Add, ActionType::Remove],
+            matches: vec![
+                "$DEVTYPE=disk".parse().unwrap(),
+                "DEVNAME=^loop".parse().unwrap(),
+            ],
+        };
+        assert!(rebroadcast.allows(&ev));
+        rebroadcast.matches.push("ID_FS_TYPE=.*".parse().unwrap());
+        assert!(!rebroadcast.allows(&ev));
+        rebroadcast.matches.pop();
+        rebroadcast.actions = vec![ActionType::Change];
+        assert!(!rebroadcast.allows(&ev));
+        rebroadcast.actions.clear();
+        rebroadcast.subsystems = vec![String::from("net")];
+        assert!(!rebroadcast.allows(&ev));
+
+        assert!("DEVTYPE".parse::<PropertyMatch>().is_err());
+        assert!("DEVTYPE=(".parse::<PropertyMatch>().is_err());
+    }
+}
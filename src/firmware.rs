@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+
+use tokio::{fs, io};
+use tracing::{info, warn};
+
+use crate::{rule::is_safe_name, Error, Result};
+
+/// Default directories where the firmware blobs are looked up
+pub const DEFAULT_DIRS: &[&str] = &["/lib/firmware/updates", "/lib/firmware"];
+
+/// Serves a firmware request using the legacy sysfs loading protocol
+///
+/// `device` is the sysfs directory of the request, the blob `name` is looked up in `dirs`
+/// and streamed to its `data` attribute between writing `1` and `0` to `loading`.
+/// If the blob cannot be found or copied the request is aborted writing `-1`.
+pub async fn load(device: &Path, name: &str, dirs: &[PathBuf]) -> Result<()> {
+    let loading = device.join("loading");
+
+    let res = async {
+        if !is_safe_name(name) {
+            return Err(Error::InvalidFirmware(name.to_string()));
+        }
+        let path = find(name, dirs)
+            .await
+            .ok_or_else(|| Error::FirmwareNotFound(name.to_string()))?;
+
+        info!("Loading firmware {:?} for {:?}", path, device);
+        let copy = async {
+            fs::write(&loading, "1").await?;
+            let mut blob = fs::File::open(&path).await?;
+            let mut data = fs::OpenOptions::new()
+                .write(true)
+                .open(device.join("data"))
+                .await?;
+            io::copy(&mut blob, &mut data).await
+        };
+        copy.await.map_err(|source| Error::FirmwareFailed {
+            name: name.to_string(),
+            source,
+        })?;
+        Ok(())
+    }
+    .await;
+
+    let status = match res {
+        Ok(()) => "0",
+        Err(ref e) => {
+            warn!("{}", e.report());
+            "-1"
+        }
+    };
+    fs::write(&loading, status)
+        .await
+        .map_err(|source| Error::FirmwareFailed {
+            name: name.to_string(),
+            source,
+        })?;
+    res
+}
+
+async fn find(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    for dir in dirs {
+        let path = dir.join(name);
+        if fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+            return Some(path);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    #[tokio::test]
+    async fn load() {
+        let root = env::temp_dir().join(format!("mdev-firmware-{}", process::id()));
+        let device = root.join("sys/devices/platform/foo/firmware/foo");
+        let dirs = [root.join("updates"), root.join("firmware")];
+        fs::create_dir_all(&device).unwrap();
+        fs::create_dir_all(dirs[1].join("vendor")).unwrap();
+        fs::write(dirs[1].join("vendor/foo.bin"), b"\x01\x02\x03").unwrap();
+        fs::write(device.join("loading"), "").unwrap();
+        fs::write(device.join("data"), "").unwrap();
+
+        super::load(&device, "vendor/foo.bin", &dirs).await.unwrap();
+        assert_eq!(fs::read(device.join("data")).unwrap(), b"\x01\x02\x03");
+        assert_eq!(fs::read_to_string(device.join("loading")).unwrap(), "0");
+
+        assert!(super::load(&device, "missing.bin", &dirs).await.is_err());
+        assert_eq!(fs::read_to_string(device.join("loading")).unwrap(), "-1");
+
+        assert!(super::load(&device, "../../etc/passwd", &dirs)
+            .await
+            .is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use nix::unistd::{Gid, Group, Uid, User};
+use tokio::task::spawn_blocking;
+use tracing::debug;
+
+use crate::{Error, Result};
+
+/// Cache of the user and group ids resolved by name
+///
+/// Resolving a name goes through NSS, that can mean parsing `/etc/passwd` or
+/// querying a remote service, so the results are kept around, optionally for
+/// a limited amount of time. The lookups run on the blocking thread pool, so a slow
+/// NSS backend does not stall the async runtime.
+#[derive(Debug, Default)]
+pub struct IdCache {
+    ttl: Option<Duration>,
+    users: Mutex<HashMap<String, Entry<Uid>>>,
+    groups: Mutex<HashMap<String, Entry<Gid>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry<T> {
+    id: Option<T>,
+    resolved_at: Instant,
+}
+
+impl IdCache {
+    /// Creates a new cache, entries older than `ttl` are resolved again
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the uid of the user named `name`
+    pub async fn uid(&self, name: &str) -> Result<Uid> {
+        self.lookup(&self.users, name, |name| {
+            Ok(User::from_name(name)?.map(|user| user.uid))
+        })
+        .await?
+        .ok_or_else(|| Error::MissingUser(name.to_string()))
+    }
+
+    /// Returns the gid of the group named `name`
+    pub async fn gid(&self, name: &str) -> Result<Gid> {
+        self.lookup(&self.groups, name, |name| {
+            Ok(Group::from_name(name)?.map(|group| group.gid))
+        })
+        .await?
+        .ok_or_else(|| Error::MissingGroup(name.to_string()))
+    }
+
+    /// Forgets every resolved name, e.g. after the user database changed
+    pub fn clear(&self) {
+        self.users.lock().unwrap().clear();
+        self.groups.lock().unwrap().clear();
+    }
+
+    async fn lookup<T: Copy + Send + 'static>(
+        &self,
+        cache: &Mutex<HashMap<String, Entry<T>>>,
+        name: &str,
+        resolve: fn(&str) -> nix::Result<Option<T>>,
+    ) -> Result<Option<T>> {
+        if let Some(entry) = cache.lock().unwrap().get(name) {
+            if self.is_fresh(entry) {
+                return Ok(entry.id);
+            }
+        }
+
+        debug!("Resolving {}", name);
+        let owned_name = name.to_string();
+        let id = match spawn_blocking(move || resolve(&owned_name)).await {
+            Ok(id) => id.map_err(io::Error::from),
+            Err(e) => Err(io::Error::other(e)),
+        }
+        .map_err(|source| Error::Lookup {
+            name: name.to_string(),
+            source,
+        })?;
+        cache.lock().unwrap().insert(
+            name.to_string(),
+            Entry {
+                id,
+                resolved_at: Instant::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    fn is_fresh<T>(&self, entry: &Entry<T>) -> bool {
+        self.ttl
+            .map(|ttl| entry.resolved_at.elapsed() < ttl)
+            .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nix::unistd::{Gid, Uid};
+
+    use super::IdCache;
+
+    #[tokio::test]
+    async fn root() {
+        let cache = IdCache::new(None);
+        assert_eq!(cache.uid("root").await.unwrap(), Uid::from_raw(0));
+        assert_eq!(cache.gid("root").await.unwrap(), Gid::from_raw(0));
+        assert!(cache.users.lock().unwrap().contains_key("root"));
+        assert!(cache.groups.lock().unwrap().contains_key("root"));
+    }
+
+    #[tokio::test]
+    async fn missing() {
+        let cache = IdCache::new(Some(Duration::from_secs(60)));
+        assert!(cache.uid("mdev-missing-user").await.is_err());
+        assert!(cache.users.lock().unwrap()["mdev-missing-user"]
+            .id
+            .is_none());
+
+        cache.clear();
+        assert!(cache.users.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+//! Persistent names of the input devices, as the udev input rules create them
+
+use std::path::Path;
+
+use crate::{disk::sanitize, sysfs::read_attr};
+
+/// Kind of input device, as suffixed to the link names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Kbd,
+    Mouse,
+    Joystick,
+}
+
+impl Class {
+    /// Classifies a device from the bitmaps in its `capabilities` sysfs directory
+    pub fn from_capabilities(key: &str, rel: &str, abs: &str) -> Option<Self> {
+        let (key, rel, abs) = (Bitmap::parse(key), Bitmap::parse(rel), Bitmap::parse(abs));
+        if abs.has(ABS_X) && abs.has(ABS_Y) && (key.has(BTN_JOYSTICK) || key.has(BTN_GAMEPAD)) {
+            Some(Self::Joystick)
+        } else if rel.has(REL_X) && rel.has(REL_Y) && key.has(BTN_MOUSE)
+            || abs.has(ABS_X) && abs.has(ABS_Y) && key.has(BTN_TOUCH)
+        {
+            Some(Self::Mouse)
+        } else if (1..BTN_MISC).any(|code| key.has(code)) {
+            Some(Self::Kbd)
+        } else {
+            None
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Kbd => "kbd",
+            Self::Mouse => "mouse",
+            Self::Joystick => "joystick",
+        }
+    }
+}
+
+const REL_X: usize = 0x00;
+const REL_Y: usize = 0x01;
+const ABS_X: usize = 0x00;
+const ABS_Y: usize = 0x01;
+const BTN_MISC: usize = 0x100;
+const BTN_MOUSE: usize = 0x110;
+const BTN_JOYSTICK: usize = 0x120;
+const BTN_GAMEPAD: usize = 0x130;
+const BTN_TOUCH: usize = 0x14a;
+
+/// Capabilities bitmap, printed by the kernel as hex longs, the most significant first
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn parse(s: &str) -> Self {
+        let words = s
+            .split_whitespace()
+            .rev()
+            .map(|word| u64::from_str_radix(word, 16).unwrap_or(0));
+        Self(words.collect())
+    }
+
+    fn has(&self, bit: usize) -> bool {
+        let bits = libc::c_long::BITS as usize;
+        self.0
+            .get(bit / bits)
+            .is_some_and(|word| word >> (bit % bits) & 1 == 1)
+    }
+}
+
+/// USB identity of an input device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbId {
+    /// `vendor_product_serial`, as udev `ID_SERIAL`
+    pub serial: String,
+    /// The `bInterfaceNumber` of the interface the device belongs to
+    pub interface: String,
+}
+
+impl UsbId {
+    /// Reads the identity of the USB device owning the sysfs directory `dir`, if any
+    pub async fn read(dir: &Path) -> Option<Self> {
+        // the interface is the closest ancestor with an interface number
+        let mut interface = None;
+        for dir in dir.ancestors() {
+            if let Some(number) = read_attr(dir, "bInterfaceNumber").await {
+                interface = Some((dir, number));
+                break;
+            }
+        }
+        let (interface, number) = interface?;
+        let device = interface.parent()?;
+
+        let vendor = match read_attr(device, "manufacturer").await {
+            Some(vendor) => vendor,
+            None => read_attr(device, "idVendor").await?,
+        };
+        let product = match read_attr(device, "product").await {
+            Some(product) => product,
+            None => read_attr(device, "idProduct").await?,
+        };
+        let mut serial = format!("{}_{}", sanitize(&vendor), sanitize(&product));
+        if let Some(number) = read_attr(device, "serial").await {
+            serial.push('_');
+            serial.push_str(&sanitize(&number));
+        }
+        Some(Self {
+            serial,
+            interface: number,
+        })
+    }
+}
+
+/// Returns the names of the input device `kernel` (e.g. `event5`) in `/dev/input/by-id`
+pub fn id_names(kernel: &str, class: Option<Class>, usb: &UsbId) -> Vec<String> {
+    let serial = &usb.serial;
+    let interface = match usb.interface.as_str() {
+        "00" => String::new(),
+        number => format!("-if{number}"),
+    };
+    match (kernel.starts_with("event"), class) {
+        (true, Some(class)) => vec![format!("usb-{serial}{interface}-event-{}", class.as_str())],
+        (true, None) => vec![format!("usb-{serial}-event-if{}", usb.interface)],
+        (false, Some(class)) if is_legacy(kernel) => {
+            vec![format!("usb-{serial}{interface}-{}", class.as_str())]
+        }
+        (false, _) => Vec::new(),
+    }
+}
+
+/// Returns the names of the input device `kernel` in `/dev/input/by-path`, from its `path_id`
+pub fn path_names(kernel: &str, class: Option<Class>, path_id: &str) -> Vec<String> {
+    match (kernel.starts_with("event"), class) {
+        (true, Some(class)) => vec![format!("{path_id}-event-{}", class.as_str())],
+        (true, None) => vec![format!("{path_id}-event")],
+        (false, Some(class)) if is_legacy(kernel) => {
+            vec![format!("{path_id}-{}", class.as_str())]
+        }
+        (false, _) => Vec::new(),
+    }
+}
+
+/// The `mouseN` and `jsN` devices of the legacy interfaces
+fn is_legacy(kernel: &str) -> bool {
+    kernel.starts_with("mouse") || kernel.starts_with("js")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let keyboard = "1000000000007 ff9f207ac14057ff febeffdfffefffff fffffffffffffffe";
+        assert_eq!(
+            Class::from_capabilities(keyboard, "0", "0"),
+            Some(Class::Kbd)
+        );
+        let mouse = "1f0000 0 0 0 0";
+        assert_eq!(
+            Class::from_capabilities(mouse, "903", "0"),
+            Some(Class::Mouse)
+        );
+        let gamepad = "7fdb000000000000 0 0 0 0";
+        assert_eq!(
+            Class::from_capabilities(gamepad, "0", "3003f"),
+            Some(Class::Joystick)
+        );
+        assert_eq!(Class::from_capabilities("0", "0", "0"), None);
+    }
+
+    #[test]
+    fn names() {
+        let usb = UsbId {
+            serial: String::from("Logitech_USB_Receiver"),
+            interface: String::from("01"),
+        };
+        assert_eq!(
+            id_names("event5", Some(Class::Mouse), &usb),
+            ["usb-Logitech_USB_Receiver-if01-event-mouse"]
+        );
+        assert_eq!(
+            id_names("event6", None, &usb),
+            ["usb-Logitech_USB_Receiver-event-if01"]
+        );
+        assert_eq!(
+            id_names("mouse0", Some(Class::Mouse), &usb),
+            ["usb-Logitech_USB_Receiver-if01-mouse"]
+        );
+        assert!(id_names("input5", Some(Class::Mouse), &usb).is_empty());
+
+        assert_eq!(
+            path_names("event3", Some(Class::Kbd), "pci-0000:00:14.0-usb-0:2:1.0"),
+            ["pci-0000:00:14.0-usb-0:2:1.0-event-kbd"]
+        );
+        assert_eq!(
+            path_names("event4", None, "platform-gpio-keys"),
+            ["platform-gpio-keys-event"]
+        );
+    }
+}
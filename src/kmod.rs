@@ -0,0 +1,152 @@
+//! Module loading through libkmod, enabled by the `kmod` feature
+
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    io, ptr,
+    sync::Mutex,
+};
+
+use tracing::{debug, info};
+
+use crate::modalias::{Blacklist, ModuleLoader};
+
+#[repr(C)]
+struct KmodCtx {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct KmodModule {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct KmodList {
+    _private: [u8; 0],
+}
+
+const KMOD_PROBE_APPLY_BLACKLIST: c_uint = 0x10000;
+
+#[link(name = "kmod")]
+extern "C" {
+    fn kmod_new(dirname: *const c_char, config_paths: *const *const c_char) -> *mut KmodCtx;
+    fn kmod_unref(ctx: *mut KmodCtx) -> *mut KmodCtx;
+    fn kmod_load_resources(ctx: *mut KmodCtx) -> c_int;
+    fn kmod_module_new_from_lookup(
+        ctx: *mut KmodCtx,
+        given_alias: *const c_char,
+        list: *mut *mut KmodList,
+    ) -> c_int;
+    fn kmod_list_next(list: *const KmodList, curr: *const KmodList) -> *mut KmodList;
+    fn kmod_module_get_module(entry: *const KmodList) -> *mut KmodModule;
+    fn kmod_module_get_name(module: *const KmodModule) -> *const c_char;
+    fn kmod_module_probe_insert_module(
+        module: *mut KmodModule,
+        flags: c_uint,
+        extra_options: *const c_char,
+        run_install: *const c_void,
+        data: *const c_void,
+        print_action: *const c_void,
+    ) -> c_int;
+    fn kmod_module_unref(module: *mut KmodModule) -> *mut KmodModule;
+    fn kmod_module_unref_list(list: *mut KmodList) -> c_int;
+}
+
+/// libkmod context, resolving aliases and inserting modules with their dependencies
+/// like modprobe does, honoring the modprobe.d configuration
+pub struct Kmod {
+    ctx: Mutex<*mut KmodCtx>,
+    /// Modules skipped on top of the ones blacklisted in modprobe.d
+    blacklist: Blacklist,
+}
+
+// SAFETY: the context is only ever used behind the mutex
+unsafe impl Send for Kmod {}
+unsafe impl Sync for Kmod {}
+
+impl Kmod {
+    /// Creates a context for the modules of the running kernel
+    pub fn new(blacklist: Blacklist) -> io::Result<Self> {
+        // SAFETY: NULL selects the default modules directory and configuration
+        let ctx = unsafe { kmod_new(ptr::null(), ptr::null()) };
+        if ctx.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: ctx is a valid context
+        let ret = unsafe { kmod_load_resources(ctx) };
+        if ret < 0 {
+            // SAFETY: ctx is a valid context, not used anymore
+            unsafe { kmod_unref(ctx) };
+            return Err(io::Error::from_raw_os_error(-ret));
+        }
+        Ok(Self {
+            ctx: Mutex::new(ctx),
+            blacklist,
+        })
+    }
+}
+
+impl ModuleLoader for Kmod {
+    fn load_modalias(&self, modalias: &str) -> io::Result<Vec<String>> {
+        let alias = CString::new(modalias)?;
+        let ctx = self.ctx.lock().unwrap();
+
+        let mut list = ptr::null_mut();
+        // SAFETY: ctx is a valid context and alias a NUL terminated string
+        let ret = unsafe { kmod_module_new_from_lookup(*ctx, alias.as_ptr(), &mut list) };
+        if ret < 0 {
+            return Err(io::Error::from_raw_os_error(-ret));
+        }
+
+        let mut modules = Vec::new();
+        let mut res = Ok(());
+        let mut entry = list;
+        while !entry.is_null() {
+            // SAFETY: entry is an element of list, the returned module is owned by us
+            let module = unsafe { kmod_module_get_module(entry) };
+            // SAFETY: module is valid, the name is owned by the module
+            let name = unsafe { CStr::from_ptr(kmod_module_get_name(module)) }
+                .to_string_lossy()
+                .into_owned();
+
+            let ret = if self.blacklist.contains(&name) {
+                1
+            } else {
+                info!("Loading module {}", name);
+                // SAFETY: module is valid, the optional arguments can be NULL
+                unsafe {
+                    kmod_module_probe_insert_module(
+                        module,
+                        KMOD_PROBE_APPLY_BLACKLIST,
+                        ptr::null(),
+                        ptr::null(),
+                        ptr::null(),
+                        ptr::null(),
+                    )
+                }
+            };
+            match ret {
+                0 => modules.push(name),
+                ret if ret > 0 => debug!("module {} is blacklisted", name),
+                ret => res = res.and(Err(io::Error::from_raw_os_error(-ret))),
+            }
+
+            // SAFETY: the module is not used anymore, entry is still an element of list
+            unsafe {
+                kmod_module_unref(module);
+                entry = kmod_list_next(list, entry);
+            }
+        }
+        // SAFETY: list is not used anymore
+        unsafe { kmod_module_unref_list(list) };
+
+        res.map(|()| modules)
+    }
+}
+
+impl Drop for Kmod {
+    fn drop(&mut self) {
+        // SAFETY: the context is not used anymore
+        unsafe { kmod_unref(*self.ctx.get_mut().unwrap()) };
+    }
+}
@@ -1,101 +1,542 @@
 use std::{
-    fmt,
+    collections::VecDeque,
     future::Future,
-    ops::Not,
     pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 
-use futures_util::ready;
-use kobject_uevent::UEvent;
-use netlink_sys::{AsyncSocket, SocketAddr, TokioSocket};
-use tokio::sync::mpsc;
+use futures_util::{io::AsyncWrite, ready};
+use kobject_uevent::{ActionType, UEvent};
+use netlink_sys::{Socket, SocketAddr};
+use tokio::{io::unix::AsyncFd, sync::mpsc};
+use tracing::{debug, warn, Subscriber};
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::Identity, registry::LookupSpan, EnvFilter, Layer, Registry,
+};
+
+use crate::stream::Reactor;
 
+pub mod acl;
+pub mod bootstrap;
+pub mod command;
+pub mod control;
+pub mod db;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod device;
+pub mod disk;
+pub mod dm;
+pub mod enumerate;
+pub mod error;
+#[cfg(feature = "serde")]
+pub mod event;
+pub mod filter;
+pub mod firmware;
+pub mod ids;
+pub mod input;
+#[cfg(feature = "kmod")]
+pub mod kmod;
+pub mod libudev;
+pub mod manager;
+pub mod md;
+pub mod metrics;
+pub mod modalias;
+pub mod net;
+pub mod notify;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod packet;
+pub mod path_id;
+pub mod pidfile;
+pub mod plan;
+pub mod probe;
 pub mod rule;
+pub mod seq;
+pub mod seqpacket;
 pub mod stream;
+pub mod sysctl;
+pub mod sysfs;
+pub mod syslog;
+pub mod table;
+pub mod usb;
+pub mod wait;
+pub mod watchdog;
+pub mod xattr;
 
+pub use error::{Error, Result};
+pub use wait::wait_for_device;
+
+/// Sends the events to its sinks, built with [`Rebroadcaster::builder`]
+///
+/// Each sink has its own queue and [`Backpressure`]: one falling behind holds the others up only
+/// once its queue is full, and only if it blocks. The netlink socket and the retry timers come
+/// from the [`Reactor`], tokio's by default.
 #[must_use = "Rebroadcaster must be awaited in order to work"]
-pub struct Rebroadcaster {
+pub struct Rebroadcaster<R: Reactor = AsyncFd<Socket>> {
     receiver: mpsc::Receiver<RebroadcastMessage>,
-    socket: TokioSocket,
-    socket_addr: SocketAddr,
-    buffer: Vec<u8>,
+    sinks: Vec<Sink<R>>,
+    /// The queued events are sent before returning
+    stopping: bool,
+    dropped: Arc<AtomicU64>,
+    /// Consecutive failed sends to a sink before giving up
+    max_failures: u32,
+    errors: Arc<AtomicU64>,
+}
+
+/// The sinks of a [`Rebroadcaster`]
+#[must_use = "the sinks are unused until the rebroadcaster is built"]
+pub struct RebroadcasterBuilder<R: Reactor = AsyncFd<Socket>> {
+    buffer: usize,
+    sinks: Vec<Sink<R>>,
+    backpressure: Backpressure,
+    dropped: Arc<AtomicU64>,
+    max_failures: u32,
+    errors: Arc<AtomicU64>,
+}
+
+/// Consecutive failed sends to a sink before the rebroadcaster gives up on it, by default
+pub const DEFAULT_MAX_FAILURES: u32 = 10;
+/// Wait after the first failed send, doubled after each of the next ones
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// What happens to an event when the queue of a sink is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// The events are not received until the sink catches up, the senders wait
+    #[default]
+    Block,
+    /// The event is not sent to the sink
+    DropNewest,
+    /// The oldest event queued for the sink is dropped to make room
+    DropOldest,
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-newest" => Ok(Self::DropNewest),
+            "drop-oldest" => Ok(Self::DropOldest),
+            _ => Err(format!(
+                "unknown backpressure policy {s:?}, expected block, drop-newest or drop-oldest"
+            )),
+        }
+    }
+}
+
+/// Where the events are rebroadcast, with the ones not sent yet
+struct Sink<R: Reactor> {
+    target: Target<R>,
+    format: RebroadcastFormat,
+    backpressure: Backpressure,
+    /// Encoded events, the first one sent up to `offset`
+    queue: VecDeque<Vec<u8>>,
+    capacity: usize,
     offset: usize,
+    /// Buffers of the events sent, reused for the next ones
+    spare: Vec<Vec<u8>>,
+    /// Consecutive failed sends
+    failures: u32,
+    /// Waited for before sending again after a failure
+    backoff: Option<R::Timer>,
+}
+
+/// Size of the buffers of the kernel, most events fit without growing them
+const PACKET_SIZE: usize = 2048;
+
+enum Target<R> {
+    Netlink { socket: R, socket_addr: SocketAddr },
+    Seqpacket(seqpacket::Publisher),
+    File(Box<dyn AsyncWrite + Send + Unpin>),
+}
+
+impl<R> Target<R> {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Netlink { .. } => "netlink",
+            Self::Seqpacket(_) => "seqpacket",
+            Self::File(_) => "file",
+        }
+    }
+}
+
+/// Wire format of the rebroadcast events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RebroadcastFormat {
+    /// As the kernel sends them, see [`packet`]
+    #[default]
+    Kernel,
+    /// The udev events of libudev, see [`libudev`]
+    Libudev,
+}
+
+impl RebroadcastFormat {
+    /// Group the events are rebroadcast to unless told otherwise
+    pub fn default_group(self) -> u32 {
+        match self {
+            Self::Kernel => stream::REBROADCAST_GROUP,
+            Self::Libudev => libudev::GROUP,
+        }
+    }
+}
+
+impl FromStr for RebroadcastFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kernel" => Ok(Self::Kernel),
+            "libudev" => Ok(Self::Libudev),
+            _ => Err(format!(
+                "unknown rebroadcast format {s:?}, expected kernel or libudev"
+            )),
+        }
+    }
 }
 
 #[inline]
-fn get_rebroadcast_socket_and_socket_addr() -> std::io::Result<(TokioSocket, SocketAddr)> {
+fn get_rebroadcast_socket<R: Reactor>() -> std::io::Result<R> {
     use netlink_sys::constants;
 
-    Ok(if cfg!(test) {
-        let socket = TokioSocket::new(constants::NETLINK_USERSOCK)?;
-        let socket_addr = SocketAddr::new(std::process::id(), 0);
-        (socket, socket_addr)
+    let socket = Socket::new(if cfg!(test) {
+        constants::NETLINK_USERSOCK
     } else {
-        let socket = TokioSocket::new(constants::NETLINK_KOBJECT_UEVENT)?;
-        let socket_addr = SocketAddr::new(0, 0x4);
-        (socket, socket_addr)
-    })
+        constants::NETLINK_KOBJECT_UEVENT
+    })?;
+    socket.set_non_blocking(true)?;
+    R::register(socket)
 }
 
 impl Rebroadcaster {
-    pub fn new(buffer: usize) -> std::io::Result<(Self, mpsc::Sender<RebroadcastMessage>)> {
-        let (socket, socket_addr) = get_rebroadcast_socket_and_socket_addr()?;
+    /// Rebroadcasts to the sinks added to the builder, queuing up to `buffer` events for each
+    pub fn builder(buffer: usize) -> RebroadcasterBuilder {
+        Self::builder_with(buffer)
+    }
 
-        let (sender, receiver) = mpsc::channel(buffer);
-        Ok((
-            Self {
-                receiver,
+    /// Sends the events to `socket_addr` in `format`, e.g. the [`stream::REBROADCAST_GROUP`]
+    /// with no pid, queuing up to `buffer` of them before applying `backpressure`
+    pub fn new(
+        buffer: usize,
+        socket_addr: SocketAddr,
+        format: RebroadcastFormat,
+        backpressure: Backpressure,
+    ) -> std::io::Result<(Self, mpsc::Sender<RebroadcastMessage>)> {
+        Ok(Self::builder(buffer)
+            .backpressure(backpressure)
+            .netlink(socket_addr, format)?
+            .build())
+    }
+}
+
+impl<R: Reactor> Rebroadcaster<R> {
+    /// Like [`Rebroadcaster::builder`], in the runtime of `R`, e.g.
+    /// `Rebroadcaster::<async_io::Async<Socket>>::builder_with(16)`
+    pub fn builder_with(buffer: usize) -> RebroadcasterBuilder<R> {
+        RebroadcasterBuilder {
+            buffer: buffer.max(1),
+            sinks: Vec::new(),
+            backpressure: Backpressure::default(),
+            dropped: Arc::default(),
+            max_failures: DEFAULT_MAX_FAILURES,
+            errors: Arc::default(),
+        }
+    }
+
+    /// Events dropped so far by the [`Backpressure`] policy
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Sends failed so far, retried or not
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+impl<R: Reactor> RebroadcasterBuilder<R> {
+    /// Sends the events to `socket_addr` in `format`, e.g. the [`stream::REBROADCAST_GROUP`]
+    /// with no pid
+    pub fn netlink(
+        self,
+        socket_addr: SocketAddr,
+        format: RebroadcastFormat,
+    ) -> std::io::Result<Self> {
+        let socket = get_rebroadcast_socket()?;
+        Ok(self.sink(
+            Target::Netlink {
                 socket,
                 socket_addr,
-                buffer: Vec::new(),
-                offset: 0,
             },
-            sender,
+            format,
         ))
     }
+
+    /// Sends the events to the subscribers of `publisher` in `format`
+    pub fn seqpacket(self, publisher: seqpacket::Publisher, format: RebroadcastFormat) -> Self {
+        self.sink(Target::Seqpacket(publisher), format)
+    }
+
+    /// Writes the properties of the events to `file`, a `KEY=VALUE` line each as in the
+    /// uevent files of the sysfs, followed by an empty line
+    pub fn file(self, file: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        self.sink(Target::File(Box::new(file)), RebroadcastFormat::Kernel)
+    }
+
+    /// Applies `backpressure` once the queue of one of the sinks added next is full, blocking
+    /// by default
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Counts the events dropped by the backpressure policy in `dropped`, e.g. one of the
+    /// [metrics](metrics::Metrics)
+    pub fn dropped(mut self, dropped: Arc<AtomicU64>) -> Self {
+        self.dropped = dropped;
+        self
+    }
+
+    /// Retries the failed sends after a backoff, giving up on a sink once `max_failures` sends to
+    /// it failed in a row, [`DEFAULT_MAX_FAILURES`] by default
+    ///
+    /// The other sinks are still served, the rebroadcaster fails once it gave up on all of them.
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /// Counts the failed sends in `errors`, e.g. one of the [metrics](metrics::Metrics)
+    pub fn errors(mut self, errors: Arc<AtomicU64>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// No sink was added
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    fn sink(mut self, target: Target<R>, format: RebroadcastFormat) -> Self {
+        self.sinks.push(Sink {
+            target,
+            format,
+            backpressure: self.backpressure,
+            queue: VecDeque::new(),
+            capacity: self.buffer,
+            offset: 0,
+            spare: Vec::new(),
+            failures: 0,
+            backoff: None,
+        });
+        self
+    }
+
+    pub fn build(self) -> (Rebroadcaster<R>, mpsc::Sender<RebroadcastMessage>) {
+        let (sender, receiver) = mpsc::channel(self.buffer);
+        (
+            Rebroadcaster {
+                receiver,
+                sinks: self.sinks,
+                stopping: false,
+                dropped: self.dropped,
+                max_failures: self.max_failures,
+                errors: self.errors,
+            },
+            sender,
+        )
+    }
 }
 
-impl Future for Rebroadcaster {
+impl<R: Reactor> Future for Rebroadcaster<R> {
     type Output = std::io::Result<()>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        use std::io::Write;
-
         let this = self.get_mut();
-        if this.buffer.is_empty().not() {
-            ready!(this.send_message(cx))?;
-        }
-        debug_assert!(this.buffer.is_empty());
-        debug_assert_eq!(this.offset, 0);
-
         loop {
+            // a sink still sending does not keep the others from being flushed
+            let mut flushed = true;
+            let mut failed = None;
+            let (max_failures, errors) = (this.max_failures, &this.errors);
+            this.sinks
+                .retain_mut(|sink| match sink.poll_retry(cx, max_failures, errors) {
+                    Poll::Ready(Ok(())) => true,
+                    Poll::Ready(Err(e)) => {
+                        // the other sinks are still served
+                        warn!(
+                            "Giving up on the {} sink after {max_failures} failed sends: {e}",
+                            sink.target.name()
+                        );
+                        failed = Some(e);
+                        false
+                    }
+                    Poll::Pending => {
+                        flushed = false;
+                        true
+                    }
+                });
+            if let (true, Some(e)) = (this.sinks.is_empty(), failed) {
+                return Poll::Ready(Err(e));
+            }
+            if this.stopping {
+                return match flushed {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Pending,
+                };
+            }
+            // woken up once the full queues are flushed, the sinks dropping events do not wait
+            if this.sinks.iter().any(Sink::blocks) {
+                return Poll::Pending;
+            }
+
             match ready!(this.receiver.poll_recv(cx)) {
                 Some(RebroadcastMessage::Event(event)) => {
-                    write!(this.buffer, "{}", DisplayEvent(&event))?;
-                    ready!(this.send_message(cx))?;
+                    for sink in &mut this.sinks {
+                        if sink.is_full() {
+                            // either the oldest event of the sink or this one
+                            this.dropped.fetch_add(1, Ordering::Relaxed);
+                            debug!(seqnum = event.seq, "Sink full, an event is dropped");
+                            if !sink.make_room(sink.backpressure) {
+                                continue;
+                            }
+                        }
+                        sink.push(&event);
+                    }
                 }
-                Some(RebroadcastMessage::Stop) | None => break Poll::Ready(Ok(())),
+                Some(RebroadcastMessage::Stop) | None => this.stopping = true,
             }
         }
     }
 }
 
-impl Rebroadcaster {
-    fn send_message(&mut self, cx: &mut Context) -> Poll<<Self as Future>::Output> {
-        let buffer_len = self.buffer.len();
-        while self.offset < buffer_len {
-            let bytes_sent = ready!(self.socket.poll_send_to(
-                cx,
-                &self.buffer[self.offset..],
-                &self.socket_addr
-            ))?;
-            self.offset += bytes_sent;
+impl<R: Reactor> Sink<R> {
+    fn is_full(&self) -> bool {
+        self.queue.len() >= self.capacity
+    }
+
+    /// No event is received until this sink catches up
+    fn blocks(&self) -> bool {
+        self.backpressure == Backpressure::Block && self.is_full()
+    }
+
+    /// Drops the oldest event not being sent if `backpressure` allows it, returns whether
+    /// the new one can be queued
+    fn make_room(&mut self, backpressure: Backpressure) -> bool {
+        match backpressure {
+            Backpressure::Block => true,
+            Backpressure::DropNewest => false,
+            Backpressure::DropOldest => {
+                // a partly written event would be left truncated
+                let oldest = usize::from(self.offset > 0);
+                match self.queue.remove(oldest) {
+                    Some(packet) => {
+                        self.recycle(packet);
+                        true
+                    }
+                    None => false,
+                }
+            }
         }
+    }
+
+    fn push(&mut self, event: &UEvent) {
+        let mut packet = self
+            .spare
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(PACKET_SIZE));
+        match (&self.target, self.format) {
+            (Target::File(_), _) => {
+                for (name, value) in &event.env {
+                    packet.extend_from_slice(name.as_bytes());
+                    packet.push(b'=');
+                    packet.extend_from_slice(value.as_bytes());
+                    packet.push(b'\n');
+                }
+                packet.push(b'\n');
+            }
+            (_, RebroadcastFormat::Kernel) => packet::encode(event, &mut packet),
+            (_, RebroadcastFormat::Libudev) => libudev::encode(event, &mut packet),
+        }
+        self.queue.push_back(packet);
+    }
 
-        self.buffer.clear();
-        self.offset = 0;
+    fn recycle(&mut self, mut packet: Vec<u8>) {
+        packet.clear();
+        self.spare.push(packet);
+    }
+
+    /// Sends the queued events, retrying after a backoff until `max_failures` sends failed in
+    /// a row
+    fn poll_retry(
+        &mut self,
+        cx: &mut Context,
+        max_failures: u32,
+        errors: &AtomicU64,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if let Some(backoff) = &mut self.backoff {
+                ready!(Pin::new(backoff).poll(cx));
+                self.backoff = None;
+            }
+            let Err(e) = ready!(self.poll_flush(cx)) else {
+                return Poll::Ready(Ok(()));
+            };
+            errors.fetch_add(1, Ordering::Relaxed);
+            self.failures += 1;
+            if self.failures >= max_failures {
+                return Poll::Ready(Err(e));
+            }
+            let delay = (RETRY_BACKOFF * 2u32.pow((self.failures - 1).min(10))).min(MAX_BACKOFF);
+            warn!("Cannot rebroadcast, retrying in {delay:?}: {e}");
+            self.backoff = Some(R::timer(delay));
+        }
+    }
 
+    /// Sends the queued events
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        while let Some(packet) = self.queue.front() {
+            match &mut self.target {
+                Target::Netlink {
+                    socket,
+                    socket_addr,
+                } => {
+                    while self.offset < packet.len() {
+                        let bytes_sent = ready!(socket.poll_write_with(cx, |socket| {
+                            socket.send_to(&packet[self.offset..], socket_addr, 0)
+                        }))?;
+                        self.offset += bytes_sent;
+                    }
+                }
+                Target::Seqpacket(publisher) => publisher.send(packet),
+                Target::File(file) => {
+                    while self.offset < packet.len() {
+                        let bytes_written =
+                            ready!(Pin::new(&mut *file).poll_write(cx, &packet[self.offset..]))?;
+                        if bytes_written == 0 {
+                            return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                        }
+                        self.offset += bytes_written;
+                    }
+                }
+            }
+            if let Some(packet) = self.queue.pop_front() {
+                self.recycle(packet);
+            }
+            self.offset = 0;
+            self.failures = 0;
+        }
+        // the writes of a file complete in the background
+        if let Target::File(file) = &mut self.target {
+            ready!(Pin::new(file).poll_flush(cx))?;
+        }
         Poll::Ready(Ok(()))
     }
 }
@@ -106,30 +547,143 @@ pub enum RebroadcastMessage {
     Stop,
 }
 
-#[derive(Debug)]
-struct DisplayEvent<'a>(&'a UEvent);
-
-impl fmt::Display for DisplayEvent<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut iter = self.0.env.iter();
-        if let Some((name, value)) = iter.next() {
-            write!(f, "{}={}", name, value)?;
-            for (name, value) in iter {
-                write!(f, "\0{}={}", name, value)?;
-            }
+/// Name of `action` in the events and the rules, e.g. `add`
+pub fn action_name(action: ActionType) -> &'static str {
+    match action {
+        ActionType::Add => "add",
+        ActionType::Remove => "remove",
+        ActionType::Change => "change",
+        ActionType::Move => "move",
+        ActionType::Online => "online",
+        ActionType::Offline => "offline",
+        ActionType::Bind => "bind",
+        ActionType::Unbind => "unbind",
+    }
+}
+
+/// Format of the log lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// For humans
+    #[default]
+    Text,
+    /// A JSON object per line, the fields of the events at the top level, e.g. `seqnum`,
+    /// `devpath`, `action` and `rule`
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format {s:?}, expected text or json")),
         }
-        Ok(())
+    }
+}
+
+/// Where the log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTarget {
+    Stderr,
+    Syslog,
+    Both,
+}
+
+/// Lines logged: the ones selected by `directives` if given, e.g. `mdev::rule=trace,info`,
+/// else by `$RUST_LOG`, else the ones up to info, debug or trace as `verbose` grows
+pub fn log_filter(verbose: u8, directives: Option<&str>) -> Result<EnvFilter> {
+    if let Some(directives) = directives {
+        return EnvFilter::try_new(directives).map_err(|source| Error::LogFilter {
+            directives: directives.to_string(),
+            source,
+        });
+    }
+    Ok(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| match verbose {
+            0 => EnvFilter::new("info"),
+            1 => EnvFilter::new("debug"),
+            _ => EnvFilter::new("trace"),
+        }),
+    )
+}
+
+pub fn setup_log(verbose: u8, format: LogFormat) -> Result<()> {
+    setup_log_with(
+        log_filter(verbose, None)?,
+        format,
+        LogTarget::Stderr,
+        Identity::new(),
+    )
+}
+
+/// Like [`setup_log`], with the lines selected by `filter`, writing to `target` and passing the
+/// spans and events to `layer` as well, e.g. an exporter
+pub fn setup_log_with<L>(
+    filter: EnvFilter,
+    format: LogFormat,
+    target: LogTarget,
+    layer: L,
+) -> Result<()>
+where
+    L: Layer<Registry> + Send + Sync,
+{
+    use tracing_subscriber::prelude::*;
+
+    let stderr = matches!(target, LogTarget::Stderr | LogTarget::Both)
+        .then(|| fmt_layer(format, std::io::stderr));
+    // syslog records the time and the priority on its own
+    let syslog = matches!(target, LogTarget::Syslog | LogTarget::Both).then(|| {
+        let layer = tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .with_ansi(false)
+            .without_time()
+            .with_writer(syslog::Syslog::open());
+        match format {
+            LogFormat::Text => layer.with_level(false).boxed(),
+            LogFormat::Json => layer.json().flatten_event(true).boxed(),
+        }
+    });
+
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(filter)
+        .with(stderr)
+        .with(syslog)
+        .init();
+
+    Ok(())
+}
+
+fn fmt_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{path::PathBuf, process};
+    use std::{
+        io,
+        path::PathBuf,
+        process,
+        sync::{Arc, Mutex},
+    };
 
     use futures_util::{pin_mut, FutureExt};
-    use kobject_uevent::ActionType;
-    use netlink_sys::{constants::NETLINK_USERSOCK, AsyncSocketExt};
+    use netlink_sys::{constants::NETLINK_USERSOCK, AsyncSocket, AsyncSocketExt, TokioSocket};
     use tokio::select;
+    use tokio_util::compat::TokioAsyncWriteCompatExt;
 
     use super::*;
 
@@ -152,9 +706,15 @@ mod tests {
 
     #[tokio::test]
     async fn rebroadcaster() {
-        let (rebroadcaster, sender) = Rebroadcaster::new(2).unwrap();
-        let mut socket = TokioSocket::new(NETLINK_USERSOCK).unwrap();
         let socket_addr = SocketAddr::new(process::id(), 0);
+        let (rebroadcaster, sender) = Rebroadcaster::new(
+            2,
+            socket_addr,
+            RebroadcastFormat::Kernel,
+            Backpressure::Block,
+        )
+        .unwrap();
+        let mut socket = TokioSocket::new(NETLINK_USERSOCK).unwrap();
         socket.socket_mut().bind(&socket_addr).unwrap();
 
         sender
@@ -194,28 +754,268 @@ mod tests {
             create_event()
         );
     }
-}
 
-pub fn setup_log(verbose: u8) -> anyhow::Result<()> {
-    use tracing_subscriber::prelude::*;
-    use tracing_subscriber::{fmt, EnvFilter};
+    /// Rebroadcasts with no tokio runtime
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn async_io() {
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut socket_addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut socket_addr).unwrap();
+
+        let (rebroadcaster, sender) = Rebroadcaster::<async_io::Async<Socket>>::builder_with(2)
+            .netlink(socket_addr, RebroadcastFormat::Kernel)
+            .unwrap()
+            .build();
+        async_io::block_on(async {
+            sender
+                .send(RebroadcastMessage::Event(create_event()))
+                .await
+                .unwrap();
+            sender.send(RebroadcastMessage::Stop).await.unwrap();
+            rebroadcaster.await.unwrap();
+        });
+
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        socket.recv(&mut packet, 0).unwrap();
+        assert_eq!(
+            UEvent::from_netlink_packet(&packet).unwrap(),
+            create_event()
+        );
+    }
+
+    #[tokio::test]
+    async fn fan_out() {
+        let mut socket = TokioSocket::new(NETLINK_USERSOCK).unwrap();
+        socket.socket_mut().bind_auto().unwrap();
+        let mut socket_addr = SocketAddr::new(0, 0);
+        socket.socket_ref().get_address(&mut socket_addr).unwrap();
+        let path = std::env::temp_dir().join(format!("mdev-fan-out-{}", process::id()));
+        let file = tokio::fs::File::create(&path).await.unwrap();
 
-    let fmt_layer = fmt::layer().with_target(false);
+        let (rebroadcaster, sender) = Rebroadcaster::builder(1)
+            .netlink(socket_addr, RebroadcastFormat::Libudev)
+            .unwrap()
+            .file(file.compat_write())
+            .build();
+        let events = async {
+            sender
+                .send(RebroadcastMessage::Event(create_event()))
+                .await
+                .unwrap();
+            sender
+                .send(RebroadcastMessage::Event(create_event()))
+                .await
+                .unwrap();
+            sender.send(RebroadcastMessage::Stop).await.unwrap();
+        };
+        let (result, ()) = tokio::join!(rebroadcaster, events);
+        result.unwrap();
 
-    let filter_layer = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-        if verbose < 1 {
-            EnvFilter::new("info")
-        } else if verbose < 2 {
-            EnvFilter::new("warn")
-        } else {
-            EnvFilter::new("debug")
+        for _ in 0..2 {
+            let (packet, _) = socket.recv_from_full().await.unwrap();
+            assert!(packet.starts_with(b"libudev\0"));
         }
-    });
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<_> = content.split_terminator("\n\n").collect();
+        assert_eq!(events.len(), 2);
+        let mut lines: Vec<_> = events[0].lines().collect();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            [
+                "ACTION=add",
+                "DEVPATH=/dev/path",
+                "SEQNUM=1234",
+                "SUBSYSTEM=subsystem"
+            ]
+        );
+    }
 
-    tracing_subscriber::registry()
-        .with(filter_layer)
-        .with(fmt_layer)
-        .init();
+    #[tokio::test]
+    async fn retry() {
+        // nobody is bound to the port yet
+        let socket_addr = SocketAddr::new(process::id() | 0x8000_0000, 0);
+        let rebroadcaster = |max_failures, errors| {
+            let (rebroadcaster, sender) = Rebroadcaster::builder(2)
+                .max_failures(max_failures)
+                .errors(errors)
+                .netlink(socket_addr, RebroadcastFormat::Kernel)
+                .unwrap()
+                .build();
+            sender
+                .try_send(RebroadcastMessage::Event(create_event()))
+                .unwrap();
+            sender.try_send(RebroadcastMessage::Stop).unwrap();
+            rebroadcaster
+        };
 
-    Ok(())
+        let errors = Arc::new(AtomicU64::new(0));
+        assert!(rebroadcaster(2, Arc::clone(&errors)).await.is_err());
+        assert_eq!(errors.load(Ordering::Relaxed), 2);
+
+        let errors = Arc::new(AtomicU64::new(0));
+        let listen = async {
+            while errors.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            let mut socket = TokioSocket::new(NETLINK_USERSOCK).unwrap();
+            socket.socket_mut().bind(&socket_addr).unwrap();
+            socket.recv_from_full().await.unwrap().0
+        };
+        let (result, packet) = tokio::join!(rebroadcaster(5, Arc::clone(&errors)), listen);
+        result.unwrap();
+        assert_eq!(
+            UEvent::from_netlink_packet(&packet).unwrap(),
+            create_event()
+        );
+    }
+
+    #[tokio::test]
+    async fn dead_sink() {
+        // nobody is bound to the port
+        let dead = SocketAddr::new(process::id() | 0x4000_0000, 0);
+        let mut socket = TokioSocket::new(NETLINK_USERSOCK).unwrap();
+        socket.socket_mut().bind_auto().unwrap();
+        let mut socket_addr = SocketAddr::new(0, 0);
+        socket.socket_ref().get_address(&mut socket_addr).unwrap();
+
+        let (rebroadcaster, sender) = Rebroadcaster::builder(2)
+            .max_failures(1)
+            .netlink(dead, RebroadcastFormat::Kernel)
+            .unwrap()
+            .netlink(socket_addr, RebroadcastFormat::Kernel)
+            .unwrap()
+            .build();
+        let rebroadcaster = tokio::spawn(rebroadcaster);
+        for seq in [1, 2] {
+            let mut event = create_event();
+            event.seq = seq;
+            event.env.insert(String::from("SEQNUM"), seq.to_string());
+            sender.send(RebroadcastMessage::Event(event)).await.unwrap();
+        }
+        for seq in [1, 2] {
+            let received = tokio::time::timeout(Duration::from_secs(5), socket.recv_from_full());
+            let packet = received.await.unwrap().unwrap().0;
+            assert_eq!(UEvent::from_netlink_packet(&packet).unwrap().seq, seq);
+        }
+        sender.send(RebroadcastMessage::Stop).await.unwrap();
+        rebroadcaster.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn backpressure() {
+        let path = std::env::temp_dir().join(format!("mdev-backpressure-{}", process::id()));
+        let mut sink = Sink {
+            target: Target::Seqpacket(seqpacket::Publisher::bind(&path).unwrap()),
+            format: RebroadcastFormat::Kernel,
+            backpressure: Backpressure::DropNewest,
+            queue: VecDeque::new(),
+            capacity: 2,
+            offset: 0,
+            spare: Vec::new(),
+            failures: 0,
+            backoff: None,
+        };
+        std::fs::remove_file(&path).unwrap();
+        for seq in [1, 2] {
+            let mut event = create_event();
+            event.seq = seq;
+            event.env.insert(String::from("SEQNUM"), seq.to_string());
+            sink.push(&event);
+        }
+        assert!(sink.is_full());
+        // only the full sinks blocking hold the events back
+        assert!(!sink.blocks());
+        sink.backpressure = Backpressure::Block;
+        assert!(sink.blocks());
+        let seqs = |sink: &Sink<AsyncFd<Socket>>| -> Vec<_> {
+            sink.queue
+                .iter()
+                .map(|packet| {
+                    let packet = String::from_utf8_lossy(packet);
+                    if packet.contains("SEQNUM=1") {
+                        1
+                    } else {
+                        2
+                    }
+                })
+                .collect()
+        };
+
+        assert!(!sink.make_room(Backpressure::DropNewest));
+        assert_eq!(seqs(&sink), [1, 2]);
+        // the first event is being sent, the next one is dropped instead
+        sink.offset = 1;
+        assert!(sink.make_room(Backpressure::DropOldest));
+        assert_eq!(seqs(&sink), [1]);
+        sink.offset = 0;
+        assert!(sink.make_room(Backpressure::DropOldest));
+        assert!(sink.queue.is_empty());
+        // the buffers of the dropped events are reused
+        assert_eq!(sink.spare.len(), 2);
+        sink.push(&create_event());
+        assert_eq!(sink.spare.len(), 1);
+        assert!(sink.queue[0].capacity() >= PACKET_SIZE);
+
+        assert_eq!("drop-oldest".parse(), Ok(Backpressure::DropOldest));
+        assert!("drop".parse::<Backpressure>().is_err());
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_filter_directives() {
+        assert!(log_filter(0, Some("mdev::rule=trace,info")).is_ok());
+        assert!(matches!(
+            log_filter(0, Some("mdev::rule=loud")),
+            Err(Error::LogFilter { .. })
+        ));
+    }
+
+    #[test]
+    fn json_log() {
+        use tracing_subscriber::prelude::*;
+
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(fmt_layer(LogFormat::Json, move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let ev = create_event();
+            tracing::info!(
+                seqnum = ev.seq,
+                devpath = %ev.devpath.display(),
+                action = action_name(ev.action),
+                "event"
+            );
+        });
+        let line = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(line.ends_with("}\n"));
+        for field in [
+            r#""level":"INFO""#,
+            r#""message":"event""#,
+            r#""seqnum":1234"#,
+            r#""devpath":"/dev/path""#,
+            r#""action":"add""#,
+        ] {
+            assert!(line.contains(field), "{field} not in {line}");
+        }
+    }
 }
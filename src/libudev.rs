@@ -0,0 +1,249 @@
+//! Events in the wire format of the udev monitors of libudev
+//!
+//! Sent to the [`GROUP`] netlink group, they are received as `udev` events by the applications
+//! linked against libudev, e.g. through `udev_monitor_new_from_netlink(udev, "udev")`. The
+//! header carries the hashes of the subsystem and the devtype and a bloom filter of the tags,
+//! for the monitors to filter the events in the kernel.
+//!
+//! The events republished by udevd or eudev are read back with [`properties`].
+
+use kobject_uevent::UEvent;
+
+use crate::{action_name, packet::FIELDS};
+
+/// Netlink group of the udev events
+pub const GROUP: u32 = 2;
+
+/// Distinguishes the udev events from the kernel ones
+const PREFIX: &[u8; 8] = b"libudev\0";
+const MAGIC: u32 = 0xfeed_cafe;
+/// Size of the header, the properties follow it
+const HEADER_SIZE: u32 = 40;
+
+/// Writes `ev` to `buf`, the header followed by the NUL terminated properties
+pub fn encode(ev: &UEvent, buf: &mut Vec<u8>) {
+    use std::io::Write;
+
+    let start = buf.len();
+    buf.resize(start + HEADER_SIZE as usize, 0);
+
+    let devpath = ev.devpath.to_string_lossy();
+    let fields = [
+        ("ACTION", action_name(ev.action)),
+        ("DEVPATH", &devpath),
+        ("SUBSYSTEM", &ev.subsystem),
+    ];
+    let env = ev
+        .env
+        .iter()
+        .filter(|(name, _)| !FIELDS.contains(&name.as_str()))
+        .map(|(name, value)| (name.as_str(), value.as_str()));
+    for (name, value) in fields {
+        push_property(buf, name, value);
+    }
+    // formatted in place, the buffer is reused across the events
+    write!(buf, "SEQNUM={}\0", ev.seq).expect("writing to a Vec cannot fail");
+    for (name, value) in env {
+        push_property(buf, name, value);
+    }
+    let properties_len = (buf.len() - start) as u32 - HEADER_SIZE;
+
+    let bloom = ev
+        .env
+        .get("TAGS")
+        .map(|tags| {
+            tags.split(':')
+                .filter(|tag| !tag.is_empty())
+                .fold(0, |bloom, tag| bloom | bloom64(tag))
+        })
+        .unwrap_or(0);
+
+    // the offsets are in the byte order of the host, the filters in the network one
+    let header = &mut buf[start..start + HEADER_SIZE as usize];
+    header[..8].copy_from_slice(PREFIX);
+    header[8..12].copy_from_slice(&MAGIC.to_be_bytes());
+    header[12..16].copy_from_slice(&HEADER_SIZE.to_ne_bytes());
+    header[16..20].copy_from_slice(&HEADER_SIZE.to_ne_bytes());
+    header[20..24].copy_from_slice(&properties_len.to_ne_bytes());
+    header[24..28].copy_from_slice(&hash(&ev.subsystem).to_be_bytes());
+    let devtype = ev.env.get("DEVTYPE").map_or(0, |devtype| hash(devtype));
+    header[28..32].copy_from_slice(&devtype.to_be_bytes());
+    header[32..36].copy_from_slice(&((bloom >> 32) as u32).to_be_bytes());
+    header[36..40].copy_from_slice(&(bloom as u32).to_be_bytes());
+}
+
+fn push_property(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(b'=');
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(0);
+}
+
+/// Whether `packet` is in this format rather than the one of the kernel
+pub fn is_libudev(packet: &[u8]) -> bool {
+    packet.starts_with(PREFIX)
+}
+
+/// Properties of `packet`, NUL separated as in the packets of the kernel, or what is wrong
+/// with its header
+pub fn properties(packet: &[u8]) -> Result<&[u8], &'static str> {
+    let header = packet
+        .get(..HEADER_SIZE as usize)
+        .ok_or("shorter than its header")?;
+    let u32_at = |offset: usize| -> [u8; 4] { header[offset..offset + 4].try_into().unwrap() };
+    if !is_libudev(header) || u32::from_be_bytes(u32_at(8)) != MAGIC {
+        return Err("bad magic");
+    }
+    let offset = u32::from_ne_bytes(u32_at(16)) as usize;
+    let len = u32::from_ne_bytes(u32_at(20)) as usize;
+    let properties = offset
+        .checked_add(len)
+        .filter(|_| offset >= HEADER_SIZE as usize)
+        .and_then(|end| packet.get(offset..end))
+        .ok_or("properties out of the packet")?;
+    Ok(properties.strip_suffix(b"\0").unwrap_or(properties))
+}
+
+/// Hash of the filters, none for an empty string
+fn hash(s: &str) -> u32 {
+    if s.is_empty() {
+        0
+    } else {
+        murmur_hash2(s.as_bytes())
+    }
+}
+
+/// MurmurHash2 with a zero seed, as libudev computes it
+fn murmur_hash2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+
+    let mut h = data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_ne_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= u32::from(*byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+/// Bits of `tag` in the bloom filter of the tags
+fn bloom64(tag: &str) -> u64 {
+    let hash = murmur_hash2(tag.as_bytes());
+    [0, 6, 12, 18]
+        .into_iter()
+        .fold(0, |bits, shift| bits | 1 << ((hash >> shift) & 63))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use kobject_uevent::ActionType;
+
+    use super::*;
+
+    #[test]
+    fn hashes() {
+        assert_eq!(murmur_hash2(b""), 0);
+        assert_eq!(murmur_hash2(b"block"), 0xf003_1db7);
+        assert_eq!(murmur_hash2(b"usb"), 0x0577_c5e5);
+        assert_eq!(murmur_hash2(b"disk"), 0x7bcb_c5ee);
+        assert_eq!(bloom64("seat"), 0x0208_0000_0040_0001);
+        assert_eq!(bloom64("uaccess"), 0x0000_2008_0000_1008);
+    }
+
+    #[test]
+    fn encode() {
+        let ev = UEvent {
+            action: ActionType::Add,
+            devpath: PathBuf::from("/devices/virtual/block/loop0"),
+            subsystem: String::from("block"),
+            env: HashMap::from([
+                (String::from("ACTION"), String::from("add")),
+                (String::from("DEVTYPE"), String::from("disk")),
+                (String::from("TAGS"), String::from(":seat:uaccess:")),
+            ]),
+            seq: 42,
+        };
+        let mut buf = vec![0xff];
+        super::encode(&ev, &mut buf);
+        let buf = &buf[1..];
+
+        let u32_at = |offset: usize| buf[offset..offset + 4].try_into().unwrap();
+        assert_eq!(&buf[..8], b"libudev\0");
+        assert_eq!(&buf[8..12], [0xfe, 0xed, 0xca, 0xfe]);
+        assert_eq!(u32::from_ne_bytes(u32_at(12)), 40);
+        assert_eq!(u32::from_ne_bytes(u32_at(16)), 40);
+        assert_eq!(u32::from_ne_bytes(u32_at(20)) as usize, buf.len() - 40);
+        assert_eq!(u32::from_be_bytes(u32_at(24)), 0xf003_1db7);
+        assert_eq!(u32::from_be_bytes(u32_at(28)), 0x7bcb_c5ee);
+        assert_eq!(u32::from_be_bytes(u32_at(32)), 0x0208_2008);
+        assert_eq!(u32::from_be_bytes(u32_at(36)), 0x0040_1009);
+
+        let mut properties: Vec<_> = buf[40..]
+            .strip_suffix(b"\0")
+            .unwrap()
+            .split(|b| *b == 0)
+            .map(|property| std::str::from_utf8(property).unwrap())
+            .collect();
+        assert_eq!(
+            properties[..4],
+            [
+                "ACTION=add",
+                "DEVPATH=/devices/virtual/block/loop0",
+                "SUBSYSTEM=block",
+                "SEQNUM=42"
+            ]
+        );
+        properties[4..].sort_unstable();
+        assert_eq!(properties[4..], ["DEVTYPE=disk", "TAGS=:seat:uaccess:"]);
+    }
+
+    #[test]
+    fn decode() {
+        let ev = UEvent {
+            action: ActionType::Remove,
+            devpath: PathBuf::from("/devices/virtual/block/loop0"),
+            subsystem: String::from("block"),
+            env: HashMap::from([(String::from("DEVNAME"), String::from("loop0"))]),
+            seq: 42,
+        };
+        let mut packet = Vec::new();
+        super::encode(&ev, &mut packet);
+        assert!(is_libudev(&packet));
+        let decoded = UEvent::from_netlink_packet(properties(&packet).unwrap()).unwrap();
+        assert_eq!(
+            (
+                decoded.action,
+                decoded.devpath,
+                decoded.subsystem,
+                decoded.seq
+            ),
+            (ev.action, ev.devpath, ev.subsystem, ev.seq)
+        );
+        assert_eq!(decoded.env["DEVNAME"], "loop0");
+
+        assert!(!is_libudev(
+            b"remove@/devices/virtual/block/loop0\0ACTION=remove"
+        ));
+        assert!(properties(&packet[..20]).is_err());
+        let mut bad = packet.clone();
+        bad[8] = 0;
+        assert_eq!(properties(&bad), Err("bad magic"));
+        let mut bad = packet.clone();
+        bad[20..24].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert_eq!(properties(&bad), Err("properties out of the packet"));
+    }
+}
@@ -0,0 +1,1375 @@
+//! Nodes and links of the devices, created and removed after the rules as their events come
+//!
+//! A [`DeviceManager`] does what the mdev binary does for each event, so that other programs
+//! can embed it.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::OsStr,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Component, Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use kobject_uevent::{ActionType, UEvent};
+use mdev_parser::OnCreation;
+use nix::{
+    errno::Errno,
+    libc::dev_t,
+    sys::stat::{fchmodat, lstat, makedev, mknod, FchmodatFlags, Mode, SFlag},
+    unistd::unlink,
+};
+use tokio::{fs, task::spawn_blocking};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    acl, command,
+    db::{self, Database, Record},
+    disk::{self, Identity},
+    dm::Mapping,
+    filter::{DevpathPattern, Subsystems},
+    firmware,
+    ids::IdCache,
+    input::{self, UsbId},
+    md::Array,
+    metrics::Metrics,
+    modalias::ModuleLoader,
+    net, path_id,
+    plan::{DryRun, Executor, Expected, Operation, Plan, System},
+    probe,
+    rule::{self, Node, Outcome, Rule},
+    sysfs,
+    table::{self, Table},
+    usb, Error, Result,
+};
+
+/// Handles the events of the devices, built with [`DeviceManager::builder`]
+pub struct DeviceManager {
+    /// Replaced as a whole on reload, the events being handled keep the previous rules
+    conf: RwLock<Arc<[Rule]>>,
+    devpath: PathBuf,
+    sysfs: PathBuf,
+    default_node: bool,
+    ids: IdCache,
+    db: Database,
+    firmware_dirs: Vec<PathBuf>,
+    modules: Option<Arc<dyn ModuleLoader>>,
+    net_name: Option<String>,
+    probe: bool,
+    input_links: bool,
+    /// DEVPATHs whose events are left out
+    ignore: Vec<DevpathPattern>,
+    /// Replaced on reload along the rules
+    subsystems: RwLock<Subsystems>,
+    /// Where the nodes and links are described when they are not created
+    table: Option<Mutex<Table>>,
+    dry_run: bool,
+    /// Applies the plans, unless described in the table or dry run
+    system: System,
+    metrics: Metrics,
+    handlers: Vec<Arc<dyn EventHandler>>,
+}
+
+/// The configuration of a [`DeviceManager`]
+#[must_use = "the devices are not managed until the manager is built"]
+pub struct DeviceManagerBuilder {
+    rules: Vec<Rule>,
+    subsystems: Subsystems,
+    devpath: PathBuf,
+    sysfs: PathBuf,
+    default_node: bool,
+    id_cache_ttl: Option<Duration>,
+    db: PathBuf,
+    firmware_dirs: Vec<PathBuf>,
+    modules: Option<Arc<dyn ModuleLoader>>,
+    net_name: Option<String>,
+    probe: bool,
+    input_links: bool,
+    ignore: Vec<DevpathPattern>,
+    table: bool,
+    dry_run: bool,
+    handlers: Vec<Arc<dyn EventHandler>>,
+}
+
+/// Told by a [`DeviceManager`] what it does for the devices, e.g. to export it elsewhere
+///
+/// The hooks are called as the events are handled, they are expected to return quickly. The
+/// ones not implemented do nothing.
+pub trait EventHandler: Send + Sync {
+    /// `rule` matched the event of the device at `devpath`
+    fn on_rule_matched(&self, devpath: &Path, rule: &Rule) {
+        let _ = (devpath, rule);
+    }
+
+    /// `node` was created for the device at `devpath`, or updated if it existed
+    fn on_node_created(&self, devpath: &Path, node: &Path) {
+        let _ = (devpath, node);
+    }
+
+    /// `node` of the device at `devpath` was removed
+    fn on_node_removed(&self, devpath: &Path, node: &Path) {
+        let _ = (devpath, node);
+    }
+}
+
+impl DeviceManagerBuilder {
+    /// Rules applied to the events, in order
+    pub fn rules(mut self, rules: Vec<Rule>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Subsystems whose events are handled, all of them by default
+    pub fn subsystems(mut self, subsystems: Subsystems) -> Self {
+        self.subsystems = subsystems;
+        self
+    }
+
+    /// Dev directory to populate, /dev by default
+    pub fn devpath(mut self, devpath: impl Into<PathBuf>) -> Self {
+        self.devpath = devpath.into();
+        self
+    }
+
+    /// Where the sysfs is mounted, /sys by default
+    pub fn sysfs(mut self, sysfs: impl Into<PathBuf>) -> Self {
+        self.sysfs = sysfs.into();
+        self
+    }
+
+    /// Creates the node as root:root 660 when no rule matches, as by default
+    pub fn default_node(mut self, default_node: bool) -> Self {
+        self.default_node = default_node;
+        self
+    }
+
+    /// Resolves the cached user and group names again after `ttl`, never by default
+    pub fn id_cache_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.id_cache_ttl = ttl;
+        self
+    }
+
+    /// Directory where the nodes and links created for each device are recorded
+    pub fn db(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.db = dir.into();
+        self
+    }
+
+    /// Directories where the firmware requested by the kernel is looked up
+    pub fn firmware_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.firmware_dirs = dirs;
+        self
+    }
+
+    /// Loads the modules matching $MODALIAS on add, the rules only do by default
+    pub fn modules(mut self, modules: Arc<dyn ModuleLoader>) -> Self {
+        self.modules = Some(modules);
+        self
+    }
+
+    /// Names the network interfaces not renamed by the rules after `template`, see
+    /// [`net::persistent_name`]
+    pub fn net_name(mut self, template: impl Into<String>) -> Self {
+        self.net_name = Some(template.into());
+        self
+    }
+
+    /// Links the block devices in /dev/disk, probing them for filesystems
+    pub fn probe(mut self, probe: bool) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Links the input devices in /dev/input/by-id and by-path
+    pub fn input_links(mut self, input_links: bool) -> Self {
+        self.input_links = input_links;
+        self
+    }
+
+    /// Leaves out the events of the devices matching `patterns`
+    pub fn ignore(mut self, patterns: Vec<DevpathPattern>) -> Self {
+        self.ignore = patterns;
+        self
+    }
+
+    /// Describes the nodes and links in a [`Table`] instead of creating them, nothing else is
+    /// done
+    pub fn table(mut self, table: bool) -> Self {
+        self.table = table;
+        self
+    }
+
+    /// Only logs what would be done for the events, nothing is done
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Calls the hooks of `handler`, can be called several times
+    pub fn handler(mut self, handler: Arc<dyn EventHandler>) -> Self {
+        self.handlers.push(handler);
+        self
+    }
+
+    pub fn build(self) -> DeviceManager {
+        DeviceManager {
+            conf: RwLock::new(self.rules.into()),
+            system: System::new(&self.devpath),
+            devpath: self.devpath,
+            sysfs: self.sysfs,
+            default_node: self.default_node,
+            ids: IdCache::new(self.id_cache_ttl),
+            db: Database::new(self.db),
+            firmware_dirs: self.firmware_dirs,
+            modules: self.modules,
+            net_name: self.net_name,
+            probe: self.probe,
+            input_links: self.input_links,
+            ignore: self.ignore,
+            subsystems: RwLock::new(self.subsystems),
+            table: self.table.then(Mutex::default),
+            dry_run: self.dry_run,
+            metrics: Metrics::default(),
+            handlers: self.handlers,
+        }
+    }
+}
+
+impl DeviceManager {
+    /// Manages /dev after the sysfs mounted on /sys, with no rules
+    pub fn builder() -> DeviceManagerBuilder {
+        DeviceManagerBuilder {
+            rules: Vec::new(),
+            subsystems: Subsystems::default(),
+            devpath: PathBuf::from("/dev"),
+            sysfs: PathBuf::from("/sys"),
+            default_node: true,
+            id_cache_ttl: None,
+            db: PathBuf::from(db::DEFAULT_DIR),
+            firmware_dirs: firmware::DEFAULT_DIRS.iter().map(PathBuf::from).collect(),
+            modules: None,
+            net_name: None,
+            probe: false,
+            input_links: false,
+            ignore: Vec::new(),
+            table: false,
+            dry_run: false,
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Handles `ev`, returns what was created for the device, or removed on remove
+    ///
+    /// The events of a device are expected in order, the ones of different devices can be
+    /// handled at once.
+    pub async fn handle_event(&self, ev: &UEvent) -> Result<Record> {
+        let plan = self.plan(ev).await?;
+        match (&self.table, self.dry_run) {
+            (Some(table), _) => self.apply(&plan, table).await?,
+            (None, true) => self.apply(&plan, &DryRun).await?,
+            (None, false) => {
+                self.apply(&plan, &self.system).await?;
+                return self.finish(&ev.env, plan).await;
+            }
+        }
+        Ok(Record::default())
+    }
+
+    pub fn rules(&self) -> Arc<[Rule]> {
+        Arc::clone(&self.conf.read().unwrap())
+    }
+
+    /// Replaces the rules, the events being handled keep the previous ones
+    pub fn set_rules(&self, rules: Vec<Rule>) {
+        *self.conf.write().unwrap() = rules.into();
+    }
+
+    pub fn set_subsystems(&self, subsystems: Subsystems) {
+        *self.subsystems.write().unwrap() = subsystems;
+    }
+
+    /// Whether the events of `subsystem` are handled
+    pub fn handles(&self, subsystem: &str) -> bool {
+        self.subsystems.read().unwrap().allows(subsystem)
+    }
+
+    pub fn devpath(&self) -> &Path {
+        &self.devpath
+    }
+
+    pub fn sysfs(&self) -> &Path {
+        &self.sysfs
+    }
+
+    pub fn db(&self) -> &Database {
+        &self.db
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Where the nodes and links are described, if they are not created
+    pub fn table(&self) -> Option<&Mutex<Table>> {
+        self.table.as_ref()
+    }
+
+    /// Directory of the device at `path` in the sysfs
+    fn in_sys(&self, path: &Path) -> Result<PathBuf> {
+        let relative = path
+            .strip_prefix("/")
+            .map_err(|_| Error::InvalidDevpath(path.to_path_buf()))?;
+        Ok(self.sysfs.join(relative))
+    }
+
+    /// Plans what handling `ev` does, the system is left untouched
+    ///
+    /// The links derived from probing the devices, e.g. the ones in /dev/disk, are not part of
+    /// the plan, as the nodes are needed to probe them.
+    pub async fn plan(&self, ev: &UEvent) -> Result<Plan> {
+        let (path, env, action) = (ev.devpath.as_path(), &ev.env, ev.action);
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(Error::InvalidDevpath(path.to_path_buf()));
+        }
+        let mut plan = Plan {
+            devpath: path.to_path_buf(),
+            action,
+            devname: String::new(),
+            rules: self.rules(),
+            matched: Vec::new(),
+            operations: Vec::new(),
+            previous: None,
+            record: Record::default(),
+        };
+        if self.ignore.iter().any(|pattern| pattern.matches(path)) {
+            debug!("Ignoring the event of {:?}", path);
+            return Ok(plan);
+        }
+        let in_sys = self.in_sys(path)?;
+        let dev = fs::read_to_string(&in_sys.join("dev")).await.ok();
+        let uevent = fs::read_to_string(&in_sys.join("uevent")).await.ok();
+
+        let usb_name = usb::device_name(env);
+        let devname = if let Some(devname) = env.get("DEVNAME") {
+            devname.as_str()
+        } else {
+            if let Some(ref uevent) = uevent {
+                uevent.lines().find_map(|line| {
+                    if let Some((k, v)) = line.split_once('=') {
+                        if k == "DEVNAME" {
+                            Some(v)
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    }
+                })
+            } else {
+                None
+            }
+            .or(usb_name.as_deref())
+            .or_else(|| path.file_name().and_then(|name| name.to_str()))
+            .ok_or_else(|| Error::InvalidDevpath(path.to_path_buf()))?
+        };
+
+        if !rule::is_safe_name(devname) {
+            return Err(Error::InvalidDevname {
+                devname: devname.to_string(),
+                devpath: path.to_path_buf(),
+            });
+        }
+
+        let device_number = if let Some(ref dev) = dev {
+            dev.trim()
+                .split_once(':')
+                .map(|(maj, min)| parse_device_number(maj, min))
+                .transpose()?
+        } else if let (Some(maj), Some(min)) = (env.get("MAJOR"), env.get("MINOR")) {
+            // the sysfs entry is already gone on remove
+            Some(parse_device_number(maj, min)?)
+        } else {
+            None
+        };
+
+        // what was created for the device, if known
+        let previous = match action {
+            ActionType::Remove | ActionType::Change => {
+                self.db.get(path).await.map_err(|source| Error::Database {
+                    devpath: path.to_path_buf(),
+                    source,
+                })?
+            }
+            _ => None,
+        };
+
+        // network interfaces have no node, the rules rename them instead
+        let is_net = env.get("SUBSYSTEM").is_some_and(|s| s == "net");
+        let mut renamed = false;
+
+        let conf = Arc::clone(&plan.rules);
+        for (i, rule) in conf.iter().enumerate() {
+            let node = match rule::apply(rule, env, device_number, action, devname).await? {
+                Outcome::Matched(node) => Some(node),
+                Outcome::Prevented => None,
+                Outcome::Skipped(reason) => {
+                    debug!(devpath = %path.display(), rule = %rule, "rule skipped: {}", reason);
+                    continue;
+                }
+            };
+            debug!(devpath = %path.display(), rule = %rule, "rule matched");
+            plan.matched.push(i);
+
+            let mdev = node.as_ref().map_or(devname, |node| node.name.as_ref());
+            if action == ActionType::Remove {
+                plan_command(rule, env, action, mdev, &mut plan.operations);
+            }
+            if let (true, Some(node)) = (is_net, &node) {
+                if action == ActionType::Add && !renamed && node.name != devname {
+                    plan.operations.push(Operation::Rename {
+                        from: devname.to_string(),
+                        to: node.name.to_string(),
+                    });
+                    renamed = true;
+                }
+            } else if let Some(node) = &node {
+                // removing the nodes in the record does not depend on the current rules
+                if !(action == ActionType::Remove && previous.is_some()) {
+                    self.plan_node(rule, path, action, node, device_number, &mut plan)
+                        .await?;
+                }
+            }
+            if matches!(action, ActionType::Add | ActionType::Change) {
+                for (name, value) in &rule.options.attrs {
+                    plan.operations.push(Operation::WriteAttr {
+                        dir: in_sys.clone(),
+                        name: name.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+            if action == ActionType::Add {
+                let kernel = path.file_name().unwrap_or_default().to_string_lossy();
+                for (key, value) in &rule.options.sysctls {
+                    plan.operations.push(Operation::Sysctl {
+                        key: rule::expand(key, env, &kernel),
+                        value: rule::expand(value, env, &kernel),
+                    });
+                }
+            }
+            if action != ActionType::Remove {
+                plan_command(rule, env, action, mdev, &mut plan.operations);
+            }
+
+            if rule.stop {
+                break;
+            }
+        }
+
+        if let (true, ActionType::Add, false, Some(template)) =
+            (is_net, action, renamed, self.net_name.as_deref())
+        {
+            if let Some(name) = self.persistent_name(&in_sys, path, devname, template).await {
+                plan.operations.push(Operation::Rename {
+                    from: devname.to_string(),
+                    to: name,
+                });
+            }
+        }
+
+        if plan.matched.is_empty() && self.default_node {
+            debug!("no rule matched {}, using the default rule", devname);
+            let node = Node {
+                name: Cow::Borrowed(devname),
+                links: Vec::new(),
+            };
+            if !(action == ActionType::Remove && previous.is_some()) {
+                self.plan_node(
+                    &Rule::default(),
+                    path,
+                    action,
+                    &node,
+                    device_number,
+                    &mut plan,
+                )
+                .await?;
+            }
+        }
+
+        if let (ActionType::Remove, Some(previous)) = (action, &previous) {
+            // what was created for the device, whatever the current rules
+            for node in &previous.nodes {
+                for link in &previous.links {
+                    plan.operations.push(Operation::Unlink {
+                        path: link.clone(),
+                        expected: Expected::Link(node.clone()),
+                    });
+                }
+            }
+            for node in &previous.nodes {
+                plan.operations.push(Operation::Unlink {
+                    path: node.clone(),
+                    expected: Expected::Node(device_number),
+                });
+            }
+        }
+
+        plan.devname = devname.to_string();
+        plan.previous = previous;
+        Ok(plan)
+    }
+
+    /// Applies `plan` with `executor`
+    ///
+    /// The failures of the commands, of the sysfs attributes, of the kernel parameters and of
+    /// the renames are only warned about, the other ones stop the plan.
+    pub async fn apply(&self, plan: &Plan, executor: &impl Executor) -> Result<()> {
+        for rule in plan.matched() {
+            self.metrics.rule_matches.fetch_add(1, Ordering::Relaxed);
+            for handler in &self.handlers {
+                handler.on_rule_matched(&plan.devpath, rule);
+            }
+        }
+        for operation in &plan.operations {
+            match executor.execute(operation).await {
+                Ok(true) => self.applied(&plan.devpath, operation),
+                Ok(false) => {}
+                Err(e) if operation.is_optional() => {
+                    if let Operation::RunCommand { .. } = operation {
+                        self.metrics
+                            .command_failures
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    warn!("{}", e.report());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts and tells the handlers what `operation` did to the device at `devpath`
+    fn applied(&self, devpath: &Path, operation: &Operation) {
+        match operation {
+            Operation::Mknod { path, .. } => {
+                self.metrics.nodes_created.fetch_add(1, Ordering::Relaxed);
+                for handler in &self.handlers {
+                    handler.on_node_created(devpath, path);
+                }
+            }
+            Operation::Unlink {
+                path,
+                expected: Expected::Node(_),
+            } => {
+                self.metrics.nodes_removed.fetch_add(1, Ordering::Relaxed);
+                for handler in &self.handlers {
+                    handler.on_node_removed(devpath, path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Does what comes once `plan` is applied to the system, returns the nodes of the device
+    async fn finish(&self, env: &HashMap<String, String>, plan: Plan) -> Result<Record> {
+        let Plan {
+            devpath,
+            action,
+            devname,
+            previous,
+            mut record,
+            ..
+        } = plan;
+        let path = devpath.as_path();
+        let in_sys = self.in_sys(path)?;
+
+        let is_block = env.get("SUBSYSTEM").is_some_and(|s| s == "block");
+        if self.probe && is_block && matches!(action, ActionType::Add | ActionType::Change) {
+            if let Some(node) = record.nodes.first().cloned() {
+                let partition = env.get("DEVTYPE").is_some_and(|t| t == "partition");
+                self.create_disk_links(path, &in_sys, partition, &node, &mut record)
+                    .await;
+            }
+        }
+
+        // the partition table may have changed, their links have to be updated too
+        if self.probe
+            && is_block
+            && action == ActionType::Change
+            && env.get("DEVTYPE").is_some_and(|t| t == "disk")
+        {
+            self.rescan_partitions(&in_sys).await;
+        }
+
+        // the device-mapper names are only known once the table is loaded, on change
+        if is_block
+            && devname.starts_with("dm-")
+            && matches!(action, ActionType::Add | ActionType::Change)
+        {
+            if let (Some(node), Some(mapping)) = (
+                record.nodes.first().cloned(),
+                Mapping::read(&in_sys, env).await,
+            ) {
+                if let Err(e) = self
+                    .create_links(&node, &mapping.links(), &mut record)
+                    .await
+                {
+                    warn!("Cannot link {:?}: {}", node, e);
+                }
+            }
+        }
+
+        if is_block
+            && devname.starts_with("md")
+            && matches!(action, ActionType::Add | ActionType::Change)
+        {
+            if let Some(node) = record.nodes.first().cloned() {
+                self.create_md_links(&in_sys, env, &node, &mut record).await;
+            }
+        }
+
+        let is_input = env.get("SUBSYSTEM").is_some_and(|s| s == "input");
+        if self.input_links && is_input && matches!(action, ActionType::Add | ActionType::Change) {
+            if let Some(node) = record.nodes.first().cloned() {
+                self.create_input_links(path, &in_sys, &node, &mut record)
+                    .await;
+            }
+        }
+
+        if let (ActionType::Add, Some(modalias)) = (action, env.get("MODALIAS")) {
+            self.load_modules(modalias).await;
+        }
+
+        if action == ActionType::Add && env.get("SUBSYSTEM").is_some_and(|s| s == "firmware") {
+            if let Some(name) = env.get("FIRMWARE") {
+                firmware::load(&in_sys, name, &self.firmware_dirs).await?;
+            }
+        }
+
+        let database = |source| Error::Database {
+            devpath: path.to_path_buf(),
+            source,
+        };
+        match (action, previous) {
+            (ActionType::Remove, previous) => {
+                self.db.remove(path).await.map_err(database)?;
+                // what was there before the event
+                return Ok(previous.unwrap_or(record));
+            }
+            (ActionType::Change, Some(previous)) => {
+                // links the rules do not create anymore
+                for node in &previous.nodes {
+                    for link in previous
+                        .links
+                        .iter()
+                        .filter(|link| !record.links.contains(link))
+                    {
+                        let operation = Operation::Unlink {
+                            path: link.clone(),
+                            expected: Expected::Link(node.clone()),
+                        };
+                        self.system.execute(&operation).await?;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if !record.is_empty() {
+            self.db.insert(path, &record).await.map_err(database)?;
+        }
+
+        Ok(record)
+    }
+
+    /// Returns the name of the interface after `template`, if a stable one other than `devname`
+    /// can be derived for it
+    async fn persistent_name(
+        &self,
+        in_sys: &Path,
+        path: &Path,
+        devname: &str,
+        template: &str,
+    ) -> Option<String> {
+        // 0 is NET_ADDR_PERM, the other types are random or assigned from other devices
+        let permanent = fs::read_to_string(in_sys.join("addr_assign_type"))
+            .await
+            .is_ok_and(|t| t.trim() == "0");
+        let address = match permanent {
+            true => fs::read_to_string(in_sys.join("address")).await.ok(),
+            false => None,
+        };
+        let Some(name) = net::persistent_name(template, path, address.as_deref()) else {
+            debug!("no persistent name for {}", devname);
+            return None;
+        };
+        (name != devname).then_some(name)
+    }
+
+    /// Links the block device `node` in /dev/disk after its identity and its filesystem
+    async fn create_disk_links(
+        &self,
+        path: &Path,
+        in_sys: &Path,
+        partition: bool,
+        node: &Path,
+        record: &mut Record,
+    ) {
+        let mut links = Vec::new();
+
+        // partitions are named after their disk
+        let number = match partition {
+            true => disk::partition_number(in_sys).await,
+            false => None,
+        };
+        let (disk, suffix) = match (partition, number) {
+            (true, Some(number)) => (in_sys.parent(), format!("-part{number}")),
+            (true, None) => (None, String::new()),
+            (false, _) => (Some(in_sys), String::new()),
+        };
+        if let Some(identity) = match disk {
+            Some(disk) => Identity::read(disk).await,
+            None => None,
+        } {
+            for name in identity.names() {
+                links.push(format!("disk/by-id/{name}{suffix}"));
+            }
+        }
+        // without the partition number it would be named as its disk
+        let disk_path = match (partition, disk) {
+            (_, None) => None,
+            (true, Some(_)) => path.parent(),
+            (false, Some(_)) => Some(path),
+        };
+        if let Some(name) = disk_path.and_then(path_id::path_id) {
+            links.push(format!("disk/by-path/{name}{suffix}"));
+        }
+        if let (Some(number), Some(disk_path), Some(disk)) = (number, path.parent(), disk) {
+            if let Some(entry) = self.read_partition(disk_path, disk, number).await {
+                links.push(format!("disk/by-partuuid/{}", entry.uuid));
+                if let Some(label) = &entry.label {
+                    links.push(format!("disk/by-partlabel/{}", probe::encode_label(label)));
+                }
+            }
+        }
+
+        let path = node.to_path_buf();
+        match spawn_blocking(move || probe::probe(&path)).await {
+            Ok(Ok(Some(superblock))) => {
+                debug!("{:?} contains {:?}", node, superblock);
+                if let Some(uuid) = &superblock.uuid {
+                    links.push(format!("disk/by-uuid/{uuid}"));
+                }
+                if let Some(label) = &superblock.label {
+                    links.push(format!("disk/by-label/{}", probe::encode_label(label)));
+                }
+            }
+            Ok(Ok(None)) => {}
+            // e.g. drives without media
+            Ok(Err(e)) => debug!("Cannot probe {:?}: {}", node, e),
+            Err(e) => warn!("{e}"),
+        }
+
+        if let Err(e) = self.create_links(node, &links, record).await {
+            warn!("Cannot link {:?}: {}", node, e);
+        }
+    }
+
+    /// Reads the partition table entry of the partition `number` of the disk at `path`
+    async fn read_partition(
+        &self,
+        path: &Path,
+        in_sys: &Path,
+        number: u32,
+    ) -> Option<probe::Partition> {
+        // the disk has been handled before its partitions
+        let disk_node = match self.db.get(path).await {
+            Ok(Some(record)) => record.nodes.into_iter().next()?,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("{e}");
+                return None;
+            }
+        };
+        let sector_size = sysfs::read_attr(&in_sys.join("queue"), "logical_block_size")
+            .await
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(512);
+
+        let res = spawn_blocking(move || probe::partition(&disk_node, sector_size, number)).await;
+        match res {
+            Ok(Ok(entry)) => entry,
+            Ok(Err(e)) => {
+                debug!("Cannot read the partition table of {:?}: {}", path, e);
+                None
+            }
+            Err(e) => {
+                warn!("{e}");
+                None
+            }
+        }
+    }
+
+    /// Makes the kernel emit change events for the partitions of the disk at `in_sys`
+    async fn rescan_partitions(&self, in_sys: &Path) {
+        let partitions = match disk::partitions(in_sys).await {
+            Ok(partitions) => partitions,
+            Err(e) => {
+                warn!("Cannot list the partitions of {:?}: {}", in_sys, e);
+                return;
+            }
+        };
+        for partition in partitions {
+            debug!("Triggering a change event for {:?}", partition);
+            if let Err(e) = sysfs::trigger(&partition, "change").await {
+                warn!("{}", e.report());
+            }
+        }
+    }
+
+    /// Links the md array, or its partition, `node` in /dev/md after the array name
+    async fn create_md_links(
+        &self,
+        in_sys: &Path,
+        env: &HashMap<String, String>,
+        node: &Path,
+        record: &mut Record,
+    ) {
+        let partition = match env.get("DEVTYPE").is_some_and(|t| t == "partition") {
+            true => match disk::partition_number(in_sys).await {
+                Some(number) => Some(number),
+                None => return,
+            },
+            false => None,
+        };
+        let array_dir = match partition {
+            Some(_) => in_sys.parent(),
+            None => Some(in_sys),
+        };
+        let Some(array) = (match array_dir {
+            Some(dir) => Array::read(dir, env).await,
+            None => None,
+        }) else {
+            return;
+        };
+        if let Err(e) = self
+            .create_links(node, &array.links(partition), record)
+            .await
+        {
+            warn!("Cannot link {:?}: {}", node, e);
+        }
+    }
+
+    /// Links the input device `node` in /dev/input after its USB identity and its hardware path
+    async fn create_input_links(
+        &self,
+        path: &Path,
+        in_sys: &Path,
+        node: &Path,
+        record: &mut Record,
+    ) {
+        let Some(kernel) = path.file_name().and_then(|name| name.to_str()) else {
+            return;
+        };
+        // the capabilities are in the parent inputN device
+        let Some(parent) = in_sys.parent() else {
+            return;
+        };
+        let capabilities = parent.join("capabilities");
+        let read = |name| {
+            let path = capabilities.join(name);
+            async move { fs::read_to_string(path).await.unwrap_or_default() }
+        };
+        let class = input::Class::from_capabilities(
+            &read("key").await,
+            &read("rel").await,
+            &read("abs").await,
+        );
+
+        let mut links = Vec::new();
+        if let Some(usb) = UsbId::read(in_sys).await {
+            for name in input::id_names(kernel, class, &usb) {
+                links.push(format!("input/by-id/{name}"));
+            }
+        }
+        if let Some(path_id) = path_id::path_id(path) {
+            for name in input::path_names(kernel, class, &path_id) {
+                links.push(format!("input/by-path/{name}"));
+            }
+        }
+
+        if let Err(e) = self.create_links(node, &links, record).await {
+            warn!("Cannot link {:?}: {}", node, e);
+        }
+    }
+
+    /// Loads the modules handling `modalias`, if the built-in module loading is enabled
+    async fn load_modules(&self, modalias: &str) {
+        let Some(modules) = &self.modules else {
+            return;
+        };
+
+        let modules = Arc::clone(modules);
+        let owned = modalias.to_string();
+        let res = spawn_blocking(move || modules.load_modalias(&owned)).await;
+        match res {
+            Ok(Ok(loaded)) => debug!("Modules for {}: {:?}", modalias, loaded),
+            Ok(Err(e)) => warn!("Cannot load the modules for {}: {}", modalias, e),
+            Err(e) => warn!("{e}"),
+        }
+    }
+
+    /// Plans the creation or the removal of the node according to the matched rule
+    async fn plan_node(
+        &self,
+        rule: &Rule,
+        path: &Path,
+        action: ActionType,
+        node: &Node<'_>,
+        device_number: Option<(u32, u32)>,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        let dev_full_path = self.devpath.join(node.name.as_ref());
+        let operations = &mut plan.operations;
+        let record = &mut plan.record;
+
+        match action {
+            // on change the node is updated to match the rule again
+            ActionType::Add | ActionType::Change => {
+                if let Some((maj, min)) = device_number {
+                    let uid = self.ids.uid(&rule.user).await?;
+                    let gid = self.ids.gid(&rule.group).await?;
+                    let block = path.iter().any(|v| v == OsStr::new("block"));
+
+                    operations.push(Operation::Mknod {
+                        path: dev_full_path.clone(),
+                        kind: match block {
+                            true => table::Kind::Block,
+                            false => table::Kind::Char,
+                        },
+                        mode: rule.mode,
+                        major: maj,
+                        minor: min,
+                    });
+                    operations.push(Operation::Chown {
+                        path: dev_full_path.clone(),
+                        uid: uid.as_raw(),
+                        gid: gid.as_raw(),
+                    });
+                    for (name, value) in &rule.options.xattrs {
+                        operations.push(Operation::SetXattr {
+                            path: dev_full_path.clone(),
+                            name: name.clone(),
+                            value: value.as_bytes().to_vec(),
+                        });
+                    }
+                    if !rule.options.acl.is_empty() {
+                        operations.push(Operation::SetXattr {
+                            path: dev_full_path.clone(),
+                            name: acl::XATTR.to_string(),
+                            value: self.encode_acl(rule.mode, &rule.options.acl).await?,
+                        });
+                    }
+                    record.nodes.push(dev_full_path.clone());
+                    record.rules.push(rule.to_string());
+                    if record.owner.is_none() {
+                        record.owner = Some((rule.user.clone(), rule.group.clone()));
+                        record.mode = Some(rule.mode);
+                    }
+                    for link in &node.links {
+                        let link = self.devpath.join(link);
+                        operations.push(Operation::Symlink {
+                            link: link.clone(),
+                            target: dev_full_path.clone(),
+                        });
+                        record.links.push(link);
+                    }
+                }
+            }
+            ActionType::Remove => {
+                for link in &node.links {
+                    operations.push(Operation::Unlink {
+                        path: self.devpath.join(link),
+                        expected: Expected::Link(dev_full_path.clone()),
+                    });
+                }
+                let mut node_path = dev_full_path;
+                if let Some(OnCreation::Move(to)) = &rule.on_creation {
+                    // without a record the renamed node is looked for by its device number
+                    if fs::symlink_metadata(&node_path).await.is_err() {
+                        let found = match device_number {
+                            Some(device_number) => {
+                                let dir = self.devpath.clone();
+                                let block = path.iter().any(|v| v == OsStr::new("block"));
+                                spawn_blocking(move || find_node(&dir, block, device_number))
+                                    .await
+                                    .ok()
+                                    .flatten()
+                            }
+                            None => None,
+                        };
+                        let Some(found) = found else {
+                            warn!(
+                                "{:?} not found, cannot resolve the rename {} of {:?}",
+                                node_path, to, path
+                            );
+                            return Ok(());
+                        };
+                        debug!("{:?} renamed to {:?}", node_path, found);
+                        node_path = found;
+                    }
+                }
+                operations.push(Operation::Unlink {
+                    path: node_path,
+                    expected: Expected::Node(device_number),
+                });
+            }
+            ActionType::Bind | ActionType::Unbind => {
+                debug!("Driver {:?} for {:?}, nodes are unchanged", action, path)
+            }
+            ActionType::Online | ActionType::Offline => {
+                debug!("{:?} {:?}, nodes are unchanged", path, action)
+            }
+            _ => info!("Action {:?}", action),
+        }
+
+        Ok(())
+    }
+
+    async fn create_links(
+        &self,
+        dev_full_path: &Path,
+        links: &[String],
+        record: &mut Record,
+    ) -> Result<()> {
+        for link in links {
+            let link = self.devpath.join(link);
+            let operation = Operation::Symlink {
+                link: link.clone(),
+                target: dev_full_path.to_path_buf(),
+            };
+            self.system.execute(&operation).await?;
+            record.links.push(link);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the ACL of a node with the given `mode`, as its extended attribute
+    async fn encode_acl(&self, mode: u32, entries: &[acl::Entry]) -> Result<Vec<u8>> {
+        let mut resolved = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let qualifier = match &entry.qualifier {
+                acl::Qualifier::User(user) => {
+                    acl::Qualifier::User(self.ids.uid(user).await?.as_raw())
+                }
+                acl::Qualifier::Group(group) => {
+                    acl::Qualifier::Group(self.ids.gid(group).await?.as_raw())
+                }
+            };
+            resolved.push(acl::Entry {
+                qualifier,
+                perms: entry.perms,
+            });
+        }
+        Ok(acl::encode(mode, &resolved))
+    }
+}
+
+/// Parses the major and minor numbers of a device, from its `dev` attribute or its event
+fn parse_device_number(major: &str, minor: &str) -> Result<(u32, u32)> {
+    match (major.parse(), minor.parse()) {
+        (Ok(major), Ok(minor)) => Ok((major, minor)),
+        _ => Err(Error::InvalidDeviceNumber(format!("{major}:{minor}"))),
+    }
+}
+
+/// Plans the command of the rule, if it has to be run for `action`
+fn plan_command(
+    rule: &Rule,
+    env: &HashMap<String, String>,
+    action: ActionType,
+    mdev: &str,
+    operations: &mut Vec<Operation>,
+) {
+    if let Some(command) = rule
+        .command
+        .as_ref()
+        .filter(|_| command::runs_on(rule, action))
+    {
+        operations.push(Operation::RunCommand {
+            line: command::command_line(command),
+            env: env.clone(),
+            mdev: mdev.to_string(),
+        });
+    }
+}
+
+/// Looks in `dir` for the block or character node of the device `(major, minor)`
+fn find_node(dir: &Path, block: bool, (major, minor): (u32, u32)) -> Option<PathBuf> {
+    let dev = makedev(major.into(), minor.into());
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .find(|entry| {
+            let file_type = entry.file_type();
+            let kind_matches = match block {
+                true => file_type.is_block_device(),
+                false => file_type.is_char_device(),
+            };
+            kind_matches
+                && entry
+                    .metadata()
+                    .is_ok_and(|metadata| metadata.rdev() == dev)
+        })
+        .map(walkdir::DirEntry::into_path)
+}
+
+/// Creates the device node, reusing the existing one if it refers to the same device
+pub fn make_node(path: &Path, kind: SFlag, mode: Mode, dev: dev_t) -> Result<()> {
+    let failed = |e: Errno| Error::MknodFailed {
+        path: path.to_path_buf(),
+        source: e.into(),
+    };
+    match mknod(path, kind, mode, dev) {
+        Err(Errno::EEXIST) => {}
+        res => return res.map_err(failed),
+    }
+
+    let stat = lstat(path).map_err(failed)?;
+    let existing_kind = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT;
+    if existing_kind == kind && stat.st_rdev == dev {
+        debug!("{:?} already exists", path);
+    } else {
+        info!("Replacing {:?}", path);
+        unlink(path).map_err(failed)?;
+        mknod(path, kind, mode, dev).map_err(failed)?;
+    }
+    // the mode of an existing node can be different and mknod is subject to the umask
+    fchmodat(None, path, mode, FchmodatFlags::FollowSymlink).map_err(failed)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn event(action: ActionType, devpath: &str, env: &[(&str, &str)]) -> UEvent {
+        UEvent {
+            action,
+            devpath: PathBuf::from(devpath),
+            subsystem: String::from("block"),
+            env: env
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            seq: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_event() {
+        let dir = env::temp_dir().join(format!("mdev-manager-{}", process::id()));
+        let manager = DeviceManager::builder()
+            .rules(rule::parse("loop[0-9]+ root:root 640 >loops/"))
+            .devpath("/dev")
+            .sysfs(dir.join("sys"))
+            .db(dir.join("db"))
+            .ignore(vec!["/devices/virtual/bdi/*".parse().unwrap()])
+            .table(true)
+            .build();
+
+        let env = [
+            ("SUBSYSTEM", "block"),
+            ("MAJOR", "7"),
+            ("MINOR", "0"),
+            ("DEVNAME", "loop0"),
+        ];
+        let ev = event(ActionType::Add, "/devices/virtual/block/loop0", &env);
+        let record = manager.handle_event(&ev).await.unwrap();
+        // nothing is created, so nothing is recorded
+        assert!(record.is_empty());
+        let rendered = manager
+            .table()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .render(table::Format::DeviceTable);
+        assert!(rendered.contains("/dev/loop0 b 640 0 0 7 0"), "{rendered}");
+        assert!(
+            rendered.contains("# /dev/loops/loop0 -> /dev/loop0"),
+            "{rendered}"
+        );
+
+        let ev = event(ActionType::Add, "/devices/virtual/bdi/7:0", &[]);
+        assert!(manager.handle_event(&ev).await.unwrap().is_empty());
+        let ev = event(ActionType::Add, "/devices/../../etc", &env);
+        assert!(matches!(
+            manager.handle_event(&ev).await,
+            Err(Error::InvalidDevpath(_))
+        ));
+        // no name to fall back on
+        let ev = event(ActionType::Add, "/", &[("MAJOR", "7"), ("MINOR", "0")]);
+        assert!(matches!(
+            manager.handle_event(&ev).await,
+            Err(Error::InvalidDevpath(_))
+        ));
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn plan() {
+        let dir = env::temp_dir().join(format!("mdev-plan-{}", process::id()));
+        let manager = DeviceManager::builder()
+            .rules(rule::parse("loop[0-9]+ root:root 640 >loops/ @losetup -a"))
+            .devpath("/dev")
+            .sysfs(dir.join("sys"))
+            .db(dir.join("db"))
+            .build();
+
+        let env = [
+            ("SUBSYSTEM", "block"),
+            ("MAJOR", "7"),
+            ("MINOR", "0"),
+            ("DEVNAME", "loop0"),
+        ];
+        let ev = event(ActionType::Add, "/devices/virtual/block/loop0", &env);
+        let plan = manager.plan(&ev).await.unwrap();
+        assert_eq!(plan.matched().count(), 1);
+        let operations: Vec<_> = plan.operations().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            operations,
+            [
+                "mknod /dev/loop0 b 7:0 640",
+                "chown 0:0 /dev/loop0",
+                "symlink /dev/loops/loop0 -> /dev/loop0",
+                "run \"losetup -a\" MDEV=loop0",
+            ]
+        );
+
+        // the command is only run after the creation
+        let ev = event(ActionType::Remove, "/devices/virtual/block/loop0", &env);
+        let plan = manager.plan(&ev).await.unwrap();
+        assert_eq!(
+            plan.operations(),
+            [
+                Operation::Unlink {
+                    path: PathBuf::from("/dev/loops/loop0"),
+                    expected: Expected::Link(PathBuf::from("/dev/loop0")),
+                },
+                Operation::Unlink {
+                    path: PathBuf::from("/dev/loop0"),
+                    expected: Expected::Node(Some((7, 0))),
+                },
+            ]
+        );
+        assert!(!dir.exists());
+    }
+
+    /// Records the hooks called
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl EventHandler for Recorder {
+        fn on_rule_matched(&self, devpath: &Path, rule: &Rule) {
+            let mut calls = self.0.lock().unwrap();
+            calls.push(format!("matched {} {}", devpath.display(), rule));
+        }
+
+        fn on_node_created(&self, _: &Path, node: &Path) {
+            let mut calls = self.0.lock().unwrap();
+            calls.push(format!(
+                "created {}",
+                node.file_name().unwrap().to_string_lossy()
+            ));
+        }
+
+        fn on_node_removed(&self, _: &Path, node: &Path) {
+            let mut calls = self.0.lock().unwrap();
+            calls.push(format!(
+                "removed {}",
+                node.file_name().unwrap().to_string_lossy()
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks() {
+        let dir = env::temp_dir().join(format!("mdev-hooks-{}", process::id()));
+        let recorder = Arc::new(Recorder::default());
+        let builder = DeviceManager::builder()
+            .rules(rule::parse("null root:root 666"))
+            .devpath(dir.join("dev"))
+            .sysfs(dir.join("sys"))
+            .db(dir.join("db"))
+            .handler(recorder.clone());
+        // the nodes can only be created by root
+        let root = nix::unistd::getuid().is_root();
+        let manager = builder.table(!root).build();
+
+        let env = [
+            ("SUBSYSTEM", "mem"),
+            ("MAJOR", "1"),
+            ("MINOR", "3"),
+            ("DEVNAME", "null"),
+        ];
+        for action in [ActionType::Add, ActionType::Remove] {
+            let ev = event(action, "/devices/virtual/mem/null", &env);
+            manager.handle_event(&ev).await.unwrap();
+        }
+        let matched = "matched /devices/virtual/mem/null null root:root 666";
+        let calls = recorder.0.lock().unwrap().clone();
+        match root {
+            true => assert_eq!(calls, [matched, "created null", matched, "removed null"]),
+            false => assert_eq!(calls, [matched, matched]),
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn renamed() {
+        // the nodes can only be created by root
+        if !nix::unistd::getuid().is_root() {
+            return;
+        }
+        let dir = env::temp_dir().join(format!("mdev-renamed-{}", process::id()));
+        let dev = dir.join("dev");
+        let manager = DeviceManager::builder()
+            .rules(rule::parse("null root:root 666 =misc/"))
+            .devpath(&dev)
+            .sysfs(dir.join("sys"))
+            .db(dir.join("db"))
+            .build();
+        std::fs::create_dir_all(dev.join("misc")).unwrap();
+        make_node(
+            &dev.join("misc/null"),
+            SFlag::S_IFCHR,
+            Mode::from_bits_truncate(0o666),
+            makedev(1, 3),
+        )
+        .unwrap();
+
+        let env = [
+            ("SUBSYSTEM", "mem"),
+            ("MAJOR", "1"),
+            ("MINOR", "3"),
+            ("DEVNAME", "null"),
+        ];
+        let ev = event(ActionType::Remove, "/devices/virtual/mem/null", &env);
+        let plan = manager.plan(&ev).await.unwrap();
+        assert_eq!(
+            plan.operations(),
+            [Operation::Unlink {
+                path: dev.join("misc/null"),
+                expected: Expected::Node(Some((1, 3))),
+            }]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Names of the md RAID arrays
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{rule::is_file_name, sysfs::read_attr};
+
+/// Name and UUID of an md array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Array {
+    pub name: Option<String>,
+    pub uuid: Option<String>,
+}
+
+impl Array {
+    /// Reads the array with sysfs directory `dir`, e.g. `/sys/block/md127`
+    ///
+    /// `MD_DEVNAME` and `MD_UUID` are set by `mdadm` when it triggers the events, otherwise
+    /// the name of named arrays is taken from the kernel name, e.g. `md_home` is `home`.
+    pub async fn read(dir: &Path, env: &HashMap<String, String>) -> Option<Self> {
+        let kernel = dir.file_name()?.to_str()?;
+        let name = match env.get("MD_DEVNAME") {
+            Some(name) => Some(name.clone()),
+            None => kernel.strip_prefix("md_").map(String::from),
+        };
+        let uuid = match env.get("MD_UUID") {
+            Some(uuid) => Some(uuid.clone()),
+            None => read_attr(&dir.join("md"), "uuid").await,
+        };
+        let array = Self {
+            name: name.filter(|name| is_file_name(name)),
+            uuid: uuid.filter(|uuid| is_file_name(uuid)),
+        };
+        (array.name.is_some() || array.uuid.is_some()).then_some(array)
+    }
+
+    /// Returns the links of the array, or of its `partition`, relative to the dev directory
+    ///
+    /// As with udev, the partition number follows a `p` only when the name ends in a digit, e.g.
+    /// `md/home1` but `md/data0p1`.
+    pub fn links(&self, partition: Option<u32>) -> Vec<String> {
+        let mut links = Vec::new();
+        if let Some(name) = &self.name {
+            match partition {
+                Some(number) if name.ends_with(|c: char| c.is_ascii_digit()) => {
+                    links.push(format!("md/{name}p{number}"))
+                }
+                Some(number) => links.push(format!("md/{name}{number}")),
+                None => links.push(format!("md/{name}")),
+            }
+        }
+        let suffix = partition.map_or(String::new(), |number| format!("-part{number}"));
+        if let Some(name) = &self.name {
+            links.push(format!("disk/by-id/md-name-{name}{suffix}"));
+        }
+        if let Some(uuid) = &self.uuid {
+            links.push(format!("disk/by-id/md-uuid-{uuid}{suffix}"));
+        }
+        links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn links() {
+        let env = HashMap::from([
+            (String::from("MD_DEVNAME"), String::from("data")),
+            (
+                String::from("MD_UUID"),
+                String::from("3f5c2b1a:9d8e7f60:1a2b3c4d:5e6f7a8b"),
+            ),
+        ]);
+        let array = Array::read(Path::new("/sys/block/md127"), &env)
+            .await
+            .unwrap();
+        assert_eq!(
+            array.links(None),
+            [
+                "md/data",
+                "disk/by-id/md-name-data",
+                "disk/by-id/md-uuid-3f5c2b1a:9d8e7f60:1a2b3c4d:5e6f7a8b"
+            ]
+        );
+        assert_eq!(
+            array.links(Some(2)),
+            [
+                "md/data2",
+                "disk/by-id/md-name-data-part2",
+                "disk/by-id/md-uuid-3f5c2b1a:9d8e7f60:1a2b3c4d:5e6f7a8b-part2"
+            ]
+        );
+
+        let array = Array::read(Path::new("/nonexistent/md_home"), &HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(array.links(None), ["md/home", "disk/by-id/md-name-home"]);
+
+        let env = HashMap::from([(String::from("MD_DEVNAME"), String::from("data0"))]);
+        let array = Array::read(Path::new("/nonexistent/md0"), &env)
+            .await
+            .unwrap();
+        assert_eq!(
+            array.links(Some(1)),
+            ["md/data0p1", "disk/by-id/md-name-data0-part1"]
+        );
+
+        let env = HashMap::from([(String::from("MD_DEVNAME"), String::from("../x"))]);
+        assert_eq!(Array::read(Path::new("/nonexistent/md0"), &env).await, None);
+    }
+}
@@ -0,0 +1,241 @@
+//! Counters of the daemon, exported in the Prometheus text format
+//!
+//! They are served over HTTP or written to a file for the textfile collector of the node
+//! exporter.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use kobject_uevent::ActionType;
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::action_name;
+
+/// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Events received from the kernel or forwarded by the hotplug helpers
+    pub received: AtomicU64,
+    /// Events handled, by action and subsystem
+    handled: Mutex<BTreeMap<(&'static str, String), u64>>,
+    pub rule_matches: AtomicU64,
+    pub nodes_created: AtomicU64,
+    pub nodes_removed: AtomicU64,
+    pub command_failures: AtomicU64,
+    /// Shared with the [`Rebroadcaster`](crate::Rebroadcaster)
+    pub rebroadcast_dropped: Arc<AtomicU64>,
+    /// Shared with the [`Rebroadcaster`](crate::Rebroadcaster)
+    pub rebroadcast_errors: Arc<AtomicU64>,
+    /// Events waiting or being handled
+    pub queue_depth: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn handled(&self, action: ActionType, subsystem: &str) {
+        let mut handled = self.handled.lock().unwrap();
+        *handled
+            .entry((action_name(action), subsystem.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Renders the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "mdev_events_received_total",
+                "Events received from the kernel or forwarded by the hotplug helpers",
+                &self.received,
+            ),
+            (
+                "mdev_rule_matches_total",
+                "Rules matching the events",
+                &self.rule_matches,
+            ),
+            (
+                "mdev_nodes_created_total",
+                "Device nodes created",
+                &self.nodes_created,
+            ),
+            (
+                "mdev_nodes_removed_total",
+                "Device nodes removed",
+                &self.nodes_removed,
+            ),
+            (
+                "mdev_command_failures_total",
+                "Commands of the rules that could not run or failed",
+                &self.command_failures,
+            ),
+            (
+                "mdev_rebroadcast_dropped_total",
+                "Events not rebroadcast to a sink whose queue was full",
+                &self.rebroadcast_dropped,
+            ),
+            (
+                "mdev_rebroadcast_errors_total",
+                "Rebroadcast sends failed, retried or not",
+                &self.rebroadcast_errors,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+
+        let name = "mdev_events_handled_total";
+        let _ = writeln!(out, "# HELP {name} Events handled\n# TYPE {name} counter");
+        for ((action, subsystem), count) in self.handled.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{name}{{action=\"{action}\",subsystem=\"{}\"}} {count}",
+                escape(subsystem)
+            );
+        }
+
+        let name = "mdev_queue_depth";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Events waiting or being handled\n# TYPE {name} gauge"
+        );
+        let _ = writeln!(out, "{name} {}", self.queue_depth.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes the metrics to `path`, replacing it at once for the collector reading it
+pub async fn write_textfile(metrics: &Metrics, path: &Path) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, metrics.render()).await?;
+    fs::rename(&tmp, path).await
+}
+
+/// Answers `GET /metrics` on `listener`, never returns
+pub async fn serve(listener: &TcpListener, metrics: &Metrics) {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                // a request is tiny, a client too slow to send it is dropped
+                match timeout(REQUEST_TIMEOUT, respond(&mut stream, metrics)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!("Cannot answer the metrics request: {e}"),
+                    Err(_) => debug!("Metrics request timed out"),
+                }
+            }
+            Err(e) => debug!("Cannot accept a metrics connection: {e}"),
+        }
+    }
+}
+
+async fn respond(stream: &mut TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let (read, mut write) = stream.split();
+    let mut lines = BufReader::new(read).lines();
+    let request = lines.next_line().await?.unwrap_or_default();
+    // the headers are not needed
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Method not allowed\n"),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.0 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    write.write_all(response.as_bytes()).await?;
+    write.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.received.fetch_add(3, Ordering::Relaxed);
+        metrics.handled(ActionType::Add, "block");
+        metrics.handled(ActionType::Add, "block");
+        metrics.handled(ActionType::Remove, "usb");
+        metrics.queue_depth.store(1, Ordering::Relaxed);
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE mdev_events_received_total counter\n"));
+        assert!(out.contains("\nmdev_events_received_total 3\n"));
+        assert!(out.contains("\nmdev_nodes_created_total 0\n"));
+        assert!(out.contains(
+            "\nmdev_events_handled_total{action=\"add\",subsystem=\"block\"} 2\n\
+             mdev_events_handled_total{action=\"remove\",subsystem=\"usb\"} 1\n"
+        ));
+        assert!(out.ends_with("# TYPE mdev_queue_depth gauge\nmdev_queue_depth 1\n"));
+
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+
+    #[tokio::test]
+    async fn http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::default();
+        metrics.rule_matches.fetch_add(2, Ordering::Relaxed);
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let client = async {
+            let response = get("/metrics").await;
+            assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+            assert!(response.contains("\nmdev_rule_matches_total 2\n"));
+            let response = get("/").await;
+            assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+        };
+        tokio::select! {
+            () = serve(&listener, &metrics) => unreachable!(),
+            () = client => {}
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CStr,
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, info, warn};
+
+/// Loads the kernel modules handling a `MODALIAS`
+pub trait ModuleLoader: Send + Sync {
+    /// Loads the modules matching `modalias`, returning their names
+    fn load_modalias(&self, modalias: &str) -> io::Result<Vec<String>>;
+}
+
+/// Directories with the modprobe configuration, looked up for `blacklist` lines
+pub const MODPROBE_DIRS: &[&str] = &["/etc/modprobe.d", "/run/modprobe.d", "/lib/modprobe.d"];
+
+/// Modules that must not be loaded to handle a `MODALIAS`
+///
+/// As with modprobe, the blacklist only applies to the modules found through their aliases.
+#[derive(Debug, Default, Clone)]
+pub struct Blacklist(HashSet<String>);
+
+impl Blacklist {
+    /// Collects the `blacklist` lines of the `*.conf` files in `dirs`, skipping missing directories
+    pub fn load(dirs: &[impl AsRef<Path>]) -> io::Result<Self> {
+        let mut blacklist = Self::default();
+        for dir in dirs {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "conf") {
+                    blacklist.parse(&std::fs::read_to_string(&path)?);
+                }
+            }
+        }
+        Ok(blacklist)
+    }
+
+    fn parse(&mut self, conf: &str) {
+        for line in conf.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some("blacklist"), Some(module)) = (fields.next(), fields.next()) {
+                self.insert(module);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, module: &str) {
+        self.0.insert(normalize(module));
+    }
+
+    pub fn extend(&mut self, other: Blacklist) {
+        self.0.extend(other.0);
+    }
+
+    pub fn contains(&self, module: &str) -> bool {
+        self.0.contains(&normalize(module))
+    }
+}
+
+/// Flag telling `finit_module` to decompress the module in the kernel
+const MODULE_INIT_COMPRESSED_FILE: libc::c_uint = 4;
+
+/// Kernel modules index, as generated by depmod
+///
+/// It is used to resolve a `MODALIAS` to the modules handling it and to load them,
+/// together with their dependencies, without running modprobe.
+#[derive(Debug, Default)]
+pub struct ModuleIndex {
+    dir: PathBuf,
+    /// `(pattern, module)` pairs from `modules.alias`
+    aliases: Vec<(String, String)>,
+    /// Path and dependencies of every module from `modules.dep`
+    deps: HashMap<String, (PathBuf, Vec<PathBuf>)>,
+    blacklist: Blacklist,
+}
+
+impl ModuleIndex {
+    /// Directory of the modules of the running kernel, `/lib/modules/$(uname -r)`
+    pub fn default_dir() -> io::Result<PathBuf> {
+        let release = std::fs::read_to_string("/proc/sys/kernel/osrelease")?;
+        Ok(Path::new("/lib/modules").join(release.trim()))
+    }
+
+    /// Loads `modules.alias` and `modules.dep` from the modules directory `dir`
+    pub fn load(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        let aliases = std::fs::read_to_string(dir.join("modules.alias"))?;
+        let deps = std::fs::read_to_string(dir.join("modules.dep"))?;
+        Ok(Self::parse(dir, &aliases, &deps))
+    }
+
+    fn parse(dir: PathBuf, aliases: &str, deps: &str) -> Self {
+        let aliases = aliases
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some("alias"), Some(pattern), Some(module)) => {
+                        Some((pattern.to_string(), normalize(module)))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        let deps = deps
+            .lines()
+            .filter_map(|line| {
+                let (path, deps) = line.split_once(':')?;
+                let path = PathBuf::from(path);
+                let deps = deps.split_whitespace().map(PathBuf::from).collect();
+                Some((module_name(&path)?, (path, deps)))
+            })
+            .collect();
+
+        Self {
+            dir,
+            aliases,
+            deps,
+            blacklist: Blacklist::default(),
+        }
+    }
+
+    /// Sets the modules skipped by [`ModuleLoader::load_modalias`]
+    pub fn set_blacklist(&mut self, blacklist: Blacklist) {
+        self.blacklist = blacklist;
+    }
+
+    /// Returns the modules whose aliases match `modalias`
+    pub fn resolve(&self, modalias: &str) -> Vec<&str> {
+        let mut modules: Vec<&str> = Vec::new();
+        for (pattern, module) in &self.aliases {
+            if glob_match(pattern.as_bytes(), modalias.as_bytes())
+                && !modules.contains(&module.as_str())
+            {
+                modules.push(module);
+            }
+        }
+        modules
+    }
+
+    /// Inserts `module` and its dependencies in the kernel, skipping the loaded ones
+    pub fn insert(&self, module: &str) -> io::Result<()> {
+        let module = normalize(module);
+        let (path, deps) = self.deps.get(&module).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("module {module} not found, it may be built-in"),
+            )
+        })?;
+
+        // the dependencies are listed so that the last one has to be loaded first
+        for path in deps.iter().rev().chain([path]) {
+            let Some(name) = module_name(path) else {
+                continue;
+            };
+            if Path::new("/sys/module").join(&name).exists() {
+                debug!("module {} already loaded", name);
+                continue;
+            }
+            info!("Loading module {}", name);
+            finit_module(&self.dir.join(path))?;
+        }
+        Ok(())
+    }
+}
+
+impl ModuleLoader for ModuleIndex {
+    fn load_modalias(&self, modalias: &str) -> io::Result<Vec<String>> {
+        let (blacklisted, modules): (Vec<_>, Vec<_>) = self
+            .resolve(modalias)
+            .into_iter()
+            .partition(|module| self.blacklist.contains(module));
+        if !blacklisted.is_empty() {
+            debug!("Skipping the blacklisted modules {:?}", blacklisted);
+        }
+        for module in &modules {
+            if let Err(e) = self.insert(module) {
+                warn!("Cannot load module {} for {}: {}", module, modalias, e);
+            }
+        }
+        Ok(modules.into_iter().map(String::from).collect())
+    }
+}
+
+/// Module names use `_` and `-` interchangeably, the kernel uses `_`
+fn normalize(module: &str) -> String {
+    module.replace('-', "_")
+}
+
+/// Returns the name of the module at `path`, e.g. `kernel/fs/fat/vfat.ko.xz` is `vfat`
+fn module_name(path: &Path) -> Option<String> {
+    let file_name = path.file_name()?.to_str()?;
+    let (name, _) = file_name.split_once(".ko")?;
+    Some(normalize(name))
+}
+
+fn finit_module(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    let flags = if path.extension().is_some_and(|ext| ext != "ko") {
+        MODULE_INIT_COMPRESSED_FILE
+    } else {
+        0
+    };
+    let params: &CStr = c"";
+
+    // SAFETY: the file descriptor is valid for the duration of the call and params is a valid
+    // NUL terminated string
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_finit_module,
+            file.as_raw_fd(),
+            params.as_ptr(),
+            flags,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// Shell-style pattern matching, as used by the module aliases
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // position of the last `*` and of the input it is matching up to
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p, i));
+            p += 1;
+            continue;
+        }
+        if let Some(len) = pattern
+            .get(p..)
+            .and_then(|pattern| match_one(pattern, s[i]))
+        {
+            p += len;
+            i += 1;
+            continue;
+        }
+        // let the last `*` match one more character
+        let Some((star_p, star_i)) = star else {
+            return false;
+        };
+        star = Some((star_p, star_i + 1));
+        p = star_p + 1;
+        i = star_i + 1;
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the start of `pattern`, returning the length of the matching part
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'[' => {
+            // a `]` right after the `[` is part of the class
+            let Some(end) = pattern
+                .iter()
+                .skip(2)
+                .position(|&c| c == b']')
+                .map(|i| i + 2)
+            else {
+                return (c == b'[').then_some(1);
+            };
+            let (negate, class) = match pattern[1..end].split_first() {
+                Some((b'!' | b'^', class)) => (true, class),
+                _ => (false, &pattern[1..end]),
+            };
+            (class_match(class, c) != negate).then_some(end + 1)
+        }
+        p => (p == c).then_some(1),
+    }
+}
+
+fn class_match(class: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            if (class[i]..=class[i + 2]).contains(&c) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"usb:v*p*d*", b"usb:v1D6Bp0002d0515"));
+        assert!(glob_match(
+            b"pci:v00008086d*sv*",
+            b"pci:v00008086d000015B8sv1"
+        ));
+        assert!(!glob_match(b"pci:v00008086d*", b"pci:v000010ECd00008168"));
+        assert!(glob_match(
+            b"of:N*T*Cfsl,imx[67]*",
+            b"of:NuartTCfsl,imx6q-uart"
+        ));
+        assert!(!glob_match(
+            b"of:N*T*Cfsl,imx[!67]*",
+            b"of:NuartTCfsl,imx6q-uart"
+        ));
+        assert!(glob_match(b"input:b????v*", b"input:b0003v046D"));
+        assert!(!glob_match(b"acpi*:PNP0C0A:*", b"acpi:PNP0C0B:"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_match(b"[]x]", b"]"));
+    }
+
+    #[test]
+    fn blacklist() {
+        let mut blacklist = Blacklist::default();
+        blacklist.parse(
+            "# Do not load the nouveau driver\n\
+             blacklist nouveau\n\
+             alias pci:v000010DEd* nouveau\n\
+             blacklist   pcspkr # beeps\n\
+             options snd-hda-intel power_save=1\n",
+        );
+        blacklist.insert("snd-pcsp");
+        assert!(blacklist.contains("nouveau"));
+        assert!(blacklist.contains("pcspkr"));
+        assert!(blacklist.contains("snd_pcsp"));
+        assert!(!blacklist.contains("snd_hda_intel"));
+    }
+
+    #[test]
+    fn resolve() {
+        let index = ModuleIndex::parse(
+            PathBuf::from("/lib/modules/6.1.0"),
+            "# Aliases extracted from modules themselves.\n\
+             alias usb:v*p*d*dc*dsc*dp*ic03isc*ip*in* usbhid\n\
+             alias usb:v046Dp*d*dc*dsc*dp*ic03isc*ip*in* hid-logitech-dj\n\
+             alias usb:v*p*d*dc*dsc*dp*ic03isc*ip*in* usbhid\n",
+            "kernel/drivers/hid/usbhid/usbhid.ko.zst: kernel/drivers/hid/hid.ko.zst\n\
+             kernel/drivers/hid/hid-logitech-dj.ko: kernel/drivers/hid/hid.ko.zst\n\
+             kernel/drivers/hid/hid.ko.zst:\n",
+        );
+        assert_eq!(
+            index.resolve("usb:v046DpC52Bd1211dc00dsc00dp00ic03isc01ip01in00"),
+            ["usbhid", "hid_logitech_dj"]
+        );
+        assert!(index.resolve("pci:v00008086d000015B8").is_empty());
+        assert_eq!(
+            index.deps["hid_logitech_dj"],
+            (
+                PathBuf::from("kernel/drivers/hid/hid-logitech-dj.ko"),
+                vec![PathBuf::from("kernel/drivers/hid/hid.ko.zst")]
+            )
+        );
+    }
+}
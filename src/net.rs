@@ -0,0 +1,141 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use tracing::info;
+
+/// Whether `name` can be used as a network interface name
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() < libc::IFNAMSIZ
+        && name != "."
+        && name != ".."
+        && !name
+            .chars()
+            .any(|c| c == '/' || c == ':' || c.is_whitespace() || c.is_control())
+}
+
+/// Builds a persistent name for the interface at `devpath` from `template`
+///
+/// `%mac%` is replaced with the hardware `address` without separators, `%path%` with the
+/// name of the parent device stripped of punctuation, e.g. `11210` for the USB interface
+/// `1-1.2:1.0`. `address` is `None` when it is not permanent, as no stable name can be derived
+/// from it. Virtual interfaces such as bridges and the loopback are not named.
+pub fn persistent_name(template: &str, devpath: &Path, address: Option<&str>) -> Option<String> {
+    if devpath.starts_with("/devices/virtual") {
+        return None;
+    }
+    let mut name = template.to_string();
+    if name.contains("%mac%") {
+        let mac: String = address?
+            .trim()
+            .chars()
+            .filter(|c| c.is_ascii_hexdigit())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        if mac.is_empty() || mac.chars().all(|c| c == '0') {
+            return None;
+        }
+        name = name.replace("%mac%", &mac);
+    }
+    if name.contains("%path%") {
+        // the parent of the `net` directory
+        let parent = devpath.parent()?.parent()?.file_name()?.to_str()?;
+        let path: String = parent.chars().filter(char::is_ascii_alphanumeric).collect();
+        name = name.replace("%path%", &path);
+    }
+    is_valid_name(&name).then_some(name)
+}
+
+/// Renames the network interface `old` to `new`, as `nameif` and `ip link set name` do
+///
+/// The kernel only allows renaming interfaces that are down, as they are when they appear.
+pub fn rename(old: &str, new: &str) -> io::Result<()> {
+    if let Some(name) = [old, new].into_iter().find(|name| !is_valid_name(name)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid interface name {name:?}"),
+        ));
+    }
+
+    // SAFETY: ifreq is plain old data, all zeros is a valid value
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    copy_name(&mut req.ifr_name, old);
+    // SAFETY: the union is all zeros, writing one of its arrays is always valid
+    copy_name(unsafe { &mut req.ifr_ifru.ifru_newname }, new);
+
+    // SAFETY: plain socket creation, the descriptor is checked before being owned
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a valid descriptor nobody else owns
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    info!("Renaming interface {} to {}", old, new);
+    // SAFETY: the socket is valid and req is a NUL terminated ifreq living across the call
+    let ret = unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFNAME as _, &req) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copies `name` to `buf`, leaving at least the last byte to NUL
+fn copy_name(buf: &mut [libc::c_char; libc::IFNAMSIZ], name: &str) {
+    for (dst, &src) in buf.iter_mut().zip(name.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("eth0"));
+        assert!(is_valid_name("wan.100"));
+        assert!(is_valid_name("lan-usb0"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name("net/eth0"));
+        assert!(!is_valid_name("eth0:1"));
+        assert!(!is_valid_name("a very long name"));
+        assert!(!is_valid_name("sixteen-chars-01"));
+        let e = rename("eth0", "net/eth0").unwrap_err();
+        assert_eq!(e.to_string(), "invalid interface name \"net/eth0\"");
+        let e = rename("eth0:1", "eth1").unwrap_err();
+        assert_eq!(e.to_string(), "invalid interface name \"eth0:1\"");
+    }
+
+    #[test]
+    fn persistent_names() {
+        let usb = Path::new("/devices/pci0000:00/0000:00:14.0/usb1/1-1/1-1.2/1-1.2:1.0/net/eth1");
+        assert_eq!(
+            persistent_name("enx%mac%", usb, Some("00:E0:4C:68:01:2A\n")).as_deref(),
+            Some("enx00e04c68012a")
+        );
+        assert_eq!(
+            persistent_name("usb%path%", usb, None).as_deref(),
+            Some("usb11210")
+        );
+        // random addresses are not stable across boots
+        assert_eq!(persistent_name("enx%mac%", usb, None), None);
+        // too long
+        assert_eq!(
+            persistent_name("net-%mac%", usb, Some("00:e0:4c:68:01:2a")),
+            None
+        );
+        assert_eq!(
+            persistent_name(
+                "enx%mac%",
+                Path::new("/devices/virtual/net/br0"),
+                Some("aa:bb:cc:dd:ee:ff")
+            ),
+            None
+        );
+    }
+}
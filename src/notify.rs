@@ -0,0 +1,158 @@
+//! Readiness and status notifications to the service manager
+//!
+//! Implements the sd_notify protocol: each datagram sent to `$NOTIFY_SOCKET` carries
+//! newline separated assignments, e.g. `READY=1` or `STATUS=...`. The s6 readiness protocol,
+//! also used by OpenRC, is a newline written to a descriptor given by the supervisor.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, Write},
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    path::Path,
+    process,
+    time::Duration,
+};
+
+/// The socket of the service manager
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Uses the socket named by `$NOTIFY_SOCKET`, if set, with the watchdog timeout in
+    /// `$WATCHDOG_USEC` if it is meant for this process
+    ///
+    /// The variables are removed so that the commands run by the rules do not inherit them,
+    /// this has to be called before any thread is started.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let name = env::var_os("NOTIFY_SOCKET");
+        let usec = env::var("WATCHDOG_USEC").ok();
+        let pid = env::var("WATCHDOG_PID").ok();
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(var);
+        }
+        let Some(name) = name else {
+            return Ok(None);
+        };
+        let mut notifier = Self::new(Path::new(&name))?;
+        if pid.is_none_or(|pid| pid == process::id().to_string()) {
+            notifier.watchdog = usec
+                .and_then(|usec| usec.parse().ok())
+                .filter(|&usec| usec > 0)
+                .map(Duration::from_micros);
+        }
+        Ok(Some(notifier))
+    }
+
+    /// Uses the socket at `path`, in the abstract namespace if it starts with `@`
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let addr = match path.as_os_str().as_encoded_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog: None,
+        })
+    }
+
+    /// Time after which the service manager considers the daemon hung without `WATCHDOG=1`
+    pub fn watchdog(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Sends `state`, e.g. `READY=1\nSTATUS=Waiting for events`
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+/// The descriptor where the supervisor waits for a newline
+#[derive(Debug)]
+pub struct ReadyFd(File);
+
+impl ReadyFd {
+    /// Takes `fd`, inherited from the supervisor, closing it on exec so that the commands run
+    /// by the rules do not keep it open
+    pub fn new(fd: RawFd) -> io::Result<Self> {
+        // SAFETY: fcntl checks that `fd` is open, it is owned by the File from then on
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(File::from_raw_fd(fd)))
+        }
+    }
+
+    /// Tells the supervisor the daemon is ready, closing the descriptor
+    pub fn ready(mut self) -> io::Result<()> {
+        self.0.write_all(b"\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Read,
+        os::fd::{AsRawFd, IntoRawFd, OwnedFd},
+        process,
+    };
+
+    use super::*;
+
+    #[test]
+    fn notify() {
+        let dir = env::temp_dir().join(format!("mdev-notify-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::new(&path).unwrap();
+        notifier.notify("READY=1\nSTATUS=Waiting").unwrap();
+        let mut buf = [0; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Waiting");
+
+        let name = format!("@mdev-notify-{}", process::id());
+        let manager = UnixDatagram::bind_addr(
+            &SocketAddr::from_abstract_name(&name.as_bytes()[1..]).unwrap(),
+        )
+        .unwrap();
+        Notifier::new(Path::new(&name))
+            .unwrap()
+            .notify("STOPPING=1")
+            .unwrap();
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ready_fd() {
+        let (mut supervisor, daemon) = io::pipe().unwrap();
+        let fd = OwnedFd::from(daemon).into_raw_fd();
+        let ready = ReadyFd::new(fd).unwrap();
+        // SAFETY: the descriptor is open, owned by `ready`
+        let flags = unsafe { libc::fcntl(ready.0.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+        ready.ready().unwrap();
+        let mut line = String::new();
+        supervisor.read_to_string(&mut line).unwrap();
+        assert_eq!(line, "\n");
+
+        assert!(ReadyFd::new(-1).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+//! Export of the spans to an OpenTelemetry collector, through OTLP over HTTP
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    trace::{SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use tracing::{warn, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::{Error, Result};
+
+/// Sends the spans in batches from its own thread, the ones still buffered when dropped
+///
+/// The thread does not survive a fork, the exporter has to be created by the process
+/// handling the events.
+#[derive(Debug)]
+pub struct Exporter(SdkTracerProvider);
+
+impl Exporter {
+    /// Sends the spans to `endpoint`, e.g. `http://localhost:4318/v1/traces`
+    pub fn new(endpoint: &str) -> Result<Self> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|source| Error::Exporter {
+                endpoint: endpoint.to_string(),
+                source,
+            })?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("mdev").build())
+            .build();
+        Ok(Self(provider))
+    }
+
+    /// Layer passing the spans to the exporter
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, SdkTracer>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.0.tracer("mdev"))
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            warn!("Cannot export the last spans: {e}");
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Events in the wire format of the kernel
+//!
+//! A header `ACTION@DEVPATH` followed by the properties, each one NUL terminated, as sent to the
+//! `NETLINK_KOBJECT_UEVENT` groups. [`UEvent::from_netlink_packet`] parses them back.
+
+use std::io::Write;
+
+use kobject_uevent::UEvent;
+
+use crate::action_name;
+
+/// Properties written from the fields of the event rather than its environment
+pub(crate) const FIELDS: [&str; 4] = ["ACTION", "DEVPATH", "SUBSYSTEM", "SEQNUM"];
+
+/// Writes `ev` to `buf`, the properties in the order of the kernel, `SEQNUM` last
+pub fn encode(ev: &UEvent, buf: &mut Vec<u8>) {
+    let action = action_name(ev.action);
+    let devpath = ev.devpath.to_string_lossy();
+    // formatted in place, the buffer is reused across the events
+    write!(
+        buf,
+        "{action}@{devpath}\0ACTION={action}\0DEVPATH={devpath}\0SUBSYSTEM={}\0",
+        ev.subsystem
+    )
+    .expect("writing to a Vec cannot fail");
+    for (name, value) in &ev.env {
+        if FIELDS.contains(&name.as_str()) {
+            continue;
+        }
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(b'=');
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(0);
+    }
+    write!(buf, "SEQNUM={}\0", ev.seq).expect("writing to a Vec cannot fail");
+}
+
+/// Returns `ev` as a packet
+pub fn to_packet(ev: &UEvent) -> Vec<u8> {
+    let mut packet = Vec::new();
+    encode(ev, &mut packet);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use kobject_uevent::ActionType;
+
+    use super::*;
+
+    fn event(env: &[(&str, &str)]) -> UEvent {
+        UEvent {
+            action: ActionType::Add,
+            devpath: PathBuf::from("/devices/virtual/mem/null"),
+            subsystem: String::from("mem"),
+            env: env
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            seq: 7,
+        }
+    }
+
+    #[test]
+    fn encode() {
+        // the fields win over a stale environment
+        let ev = event(&[("ACTION", "remove"), ("SEQNUM", "6"), ("MAJOR", "1")]);
+        assert_eq!(
+            to_packet(&ev),
+            b"add@/devices/virtual/mem/null\0ACTION=add\0DEVPATH=/devices/virtual/mem/null\0\
+              SUBSYSTEM=mem\0MAJOR=1\0SEQNUM=7\0"
+        );
+
+        // appended, as when reusing a buffer
+        let mut buf = b"previous".to_vec();
+        super::encode(&event(&[]), &mut buf);
+        assert_eq!(
+            &buf[8..],
+            b"add@/devices/virtual/mem/null\0ACTION=add\0DEVPATH=/devices/virtual/mem/null\0\
+              SUBSYSTEM=mem\0SEQNUM=7\0"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let ev = event(&[
+            ("ACTION", "add"),
+            ("DEVPATH", "/devices/virtual/mem/null"),
+            ("SUBSYSTEM", "mem"),
+            ("SEQNUM", "7"),
+            ("MAJOR", "1"),
+            ("MINOR", "3"),
+            ("DEVNAME", "null"),
+            ("DEVMODE", "0666"),
+        ]);
+        assert_eq!(UEvent::from_netlink_packet(&to_packet(&ev)).unwrap(), ev);
+
+        // the properties the kernel always sends are added
+        let decoded = UEvent::from_netlink_packet(&to_packet(&event(&[]))).unwrap();
+        let env: HashMap<_, _> = decoded
+            .env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            env,
+            HashMap::from([
+                ("ACTION", "add"),
+                ("DEVPATH", "/devices/virtual/mem/null"),
+                ("SUBSYSTEM", "mem"),
+                ("SEQNUM", "7"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Identifiers of devices after how they are attached, as udev `path_id` does
+
+use std::path::Path;
+
+/// Returns the hardware path of the device at `devpath`, as used for the by-path links
+///
+/// `devpath` is the sysfs path of the device, e.g.
+/// `/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host0/target0:0:0/0:0:0:0/block/sda`
+/// is named `pci-0000:00:14.0-usb-0:1:1.0-scsi-0:0:0:0`, as udev does.
+pub fn path_id(devpath: &Path) -> Option<String> {
+    let components: Vec<&str> = devpath.iter().filter_map(|c| c.to_str()).collect();
+    let on_platform = devpath.starts_with("/devices/platform");
+
+    let mut platform = None;
+    let mut pci = None;
+    let mut virtio = false;
+    let mut usb = None;
+    let mut ata = None;
+    let mut scsi = None;
+    let mut nvme = None;
+    for (i, &c) in components.iter().enumerate() {
+        if is_pci_address(c) {
+            // the PCI device closest to the device
+            pci = Some(c);
+            virtio = components
+                .get(i + 1)
+                .is_some_and(|c| c.starts_with("virtio"));
+        } else if is_usb_interface(c) {
+            usb = c.split_once('-').map(|(_, port)| port);
+        } else if let Some(port) = c.strip_prefix("ata").filter(|n| is_number(n)) {
+            ata = Some(port);
+        } else if c.split(':').count() == 4 && c.split(':').all(is_number) {
+            scsi = Some(c);
+        } else if let Some((_, ns)) = c
+            .strip_prefix("nvme")
+            .and_then(|c| c.split_once('n'))
+            .filter(|(ctrl, ns)| is_number(ctrl) && is_number(ns))
+        {
+            nvme = Some(ns);
+        }
+
+        // the platform device is the one the first bus is attached to
+        let is_bus = c == "mmc_host"
+            || c == "input"
+            || is_pci_address(c)
+            || ["usb", "ata", "host"]
+                .iter()
+                .any(|bus| c.strip_prefix(bus).is_some_and(is_number));
+        if on_platform && is_bus && platform.is_none() && pci.is_none() {
+            platform = components[..i].last().filter(|&&prev| prev != "platform");
+        }
+    }
+
+    let mut parts = Vec::new();
+    if let Some(platform) = platform {
+        parts.push(format!("platform-{platform}"));
+    }
+    match (pci, virtio) {
+        (Some(pci), true) => parts.push(format!("virtio-pci-{pci}")),
+        (Some(pci), false) => parts.push(format!("pci-{pci}")),
+        _ => {}
+    }
+    if let Some(usb) = usb {
+        parts.push(format!("usb-0:{usb}"));
+    }
+    match (ata, scsi) {
+        (Some(ata), _) => parts.push(format!("ata-{ata}")),
+        (None, Some(scsi)) => parts.push(format!("scsi-{scsi}")),
+        _ => {}
+    }
+    if let Some(nvme) = nvme {
+        parts.push(format!("nvme-{nvme}"));
+    }
+    // at least the bus the disk is attached to is needed
+    (platform.is_some() || pci.is_some()).then(|| parts.join("-"))
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Whether `s` is a PCI address, e.g. `0000:00:1f.2`
+fn is_pci_address(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() == 12
+        && b[4] == b':'
+        && b[7] == b':'
+        && b[10] == b'.'
+        && s.split([':', '.'])
+            .all(|c| c.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Whether `s` is a USB interface, e.g. `1-1.2:1.0`
+fn is_usb_interface(s: &str) -> bool {
+    let Some((device, interface)) = s.split_once(':') else {
+        return false;
+    };
+    let Some((bus, port)) = device.split_once('-') else {
+        return false;
+    };
+    is_number(bus)
+        && port.split('.').all(is_number)
+        && interface
+            .split_once('.')
+            .is_some_and(|(config, interface)| is_number(config) && is_number(interface))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let cases = [
+            (
+                "/devices/pci0000:00/0000:00:17.0/ata3/host2/target2:0:0/2:0:0:0/block/sda",
+                Some("pci-0000:00:17.0-ata-3"),
+            ),
+            (
+                "/devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0/nvme0n1",
+                Some("pci-0000:3d:00.0-nvme-1"),
+            ),
+            (
+                "/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host0/target0:0:0/0:0:0:0/block/sdb",
+                Some("pci-0000:00:14.0-usb-0:1:1.0-scsi-0:0:0:0"),
+            ),
+            (
+                "/devices/pci0000:00/0000:00:05.0/virtio2/block/vda",
+                Some("virtio-pci-0000:00:05.0"),
+            ),
+            (
+                "/devices/platform/soc/fe340000.mmc/mmc_host/mmc0/mmc0:aaaa/block/mmcblk0",
+                Some("platform-fe340000.mmc"),
+            ),
+            (
+                "/devices/platform/soc/3f980000.usb/usb1/1-1/1-1.3/1-1.3:1.0/host0/target0:0:0/0:0:0:0/block/sda",
+                Some("platform-3f980000.usb-usb-0:1.3:1.0-scsi-0:0:0:0"),
+            ),
+            (
+                "/devices/platform/gpio-keys/input/input0/event0",
+                Some("platform-gpio-keys"),
+            ),
+            (
+                "/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/0003:046D:C52B.0001/input/input5/event5",
+                Some("pci-0000:00:14.0-usb-0:2:1.0"),
+            ),
+            ("/devices/virtual/block/loop0", None),
+        ];
+        for (devpath, path) in cases {
+            assert_eq!(path_id(Path::new(devpath)).as_deref(), path, "{devpath}");
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! File holding the pid of the running daemon
+//!
+//! The file is also locked while the daemon runs, so that a single instance handles the events.
+
+use std::{
+    fs::{self, File},
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Default location of the file
+pub const DEFAULT_PATH: &str = "/run/mdev.pid";
+
+/// The locked pid file of this process, removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Locks the file at `path`, failing with [`io::ErrorKind::WouldBlock`] if another
+    /// process holds it
+    ///
+    /// The lock is inherited by the forked processes, the pid is written by the one that
+    /// keeps running with [`PidFile::write_pid`].
+    pub fn lock(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        // SAFETY: the descriptor is owned by `file`
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+            let msg = match Self::read(&path) {
+                Ok(Some(pid)) => format!("{:?} is locked by pid {}", path, pid),
+                _ => format!("{:?} is locked by another process", path),
+            };
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+        }
+        Ok(Self { path, file })
+    }
+
+    /// Writes the pid of this process
+    pub fn write_pid(&self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file
+            .write_all_at(format!("{}\n", process::id()).as_bytes(), 0)
+    }
+
+    /// Reads the pid stored at `path`, `None` if there is no such file
+    pub fn read(path: &Path) -> io::Result<Option<i32>> {
+        match fs::read_to_string(path) {
+            Ok(s) => s
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the pid of the process holding the lock on `path`, if any
+    pub fn running(path: &Path) -> io::Result<Option<i32>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // SAFETY: the descriptor is owned by `file`, closing it releases the lock if taken
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+            // left behind by a daemon that did not stop cleanly
+            return Ok(None);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(e);
+        }
+        Self::read(path)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        let dir = env::temp_dir().join(format!("mdev-pidfile-{}", process::id()));
+        let path = dir.join("mdev.pid");
+
+        assert_eq!(PidFile::read(&path).unwrap(), None);
+        assert_eq!(PidFile::running(&path).unwrap(), None);
+        let pidfile = PidFile::lock(&path).unwrap();
+        pidfile.write_pid().unwrap();
+        assert_eq!(PidFile::read(&path).unwrap(), Some(process::id() as i32));
+        // the lock is per open file, so it is held against this process too
+        let e = PidFile::lock(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(PidFile::running(&path).unwrap(), Some(process::id() as i32));
+        drop(pidfile);
+        assert!(!path.exists());
+
+        fs::write(&path, "mdev").unwrap();
+        assert!(PidFile::read(&path).is_err());
+        assert_eq!(PidFile::running(&path).unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,502 @@
+//! What handling an event does to the system, planned first and applied by an [`Executor`]
+//!
+//! The plan of an event only depends on the rules, the sysfs and the database, so it can be
+//! printed, audited or described in a [`Table`] instead of being applied.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use kobject_uevent::ActionType;
+use nix::{
+    errno::Errno,
+    sys::stat::{lstat, makedev, Mode, SFlag},
+    unistd::{chown, unlink, Gid, Uid},
+};
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+use crate::{
+    command,
+    db::Record,
+    manager::make_node,
+    net,
+    rule::Rule,
+    sysctl, sysfs,
+    table::{self, Kind, Table},
+    xattr, Error, Result,
+};
+
+/// A change to the system
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "op", rename_all = "snake_case")
+)]
+pub enum Operation {
+    /// Creates the device node, replacing a different one at `path`
+    Mknod {
+        path: PathBuf,
+        kind: Kind,
+        mode: u32,
+        major: u32,
+        minor: u32,
+    },
+    Chown {
+        path: PathBuf,
+        uid: u32,
+        gid: u32,
+    },
+    /// Sets an extended attribute of the node, e.g. its SELinux label or its ACL
+    SetXattr {
+        path: PathBuf,
+        name: String,
+        value: Vec<u8>,
+    },
+    /// Links `link` to `target`, replacing what is there
+    Symlink {
+        link: PathBuf,
+        target: PathBuf,
+    },
+    /// Removes `path`, only if it is what is expected there
+    Unlink {
+        path: PathBuf,
+        expected: Expected,
+    },
+    /// Runs the command line of a rule through the shell
+    RunCommand {
+        line: String,
+        env: HashMap<String, String>,
+        mdev: String,
+    },
+    /// Writes the sysfs attribute `name` of the device in `dir`
+    WriteAttr {
+        dir: PathBuf,
+        name: String,
+        value: String,
+    },
+    /// Sets a kernel parameter
+    Sysctl {
+        key: String,
+        value: String,
+    },
+    /// Renames the network interface `from`
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+/// What an [`Operation::Unlink`] removes
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Expected {
+    /// A device node, of this device if known
+    Node(Option<(u32, u32)>),
+    /// A symlink to this node
+    Link(PathBuf),
+}
+
+impl Operation {
+    /// Whether the event handling goes on when the operation fails
+    ///
+    /// The nodes and links are needed by what comes after them, the rest is only warned about.
+    pub fn is_optional(&self) -> bool {
+        matches!(
+            self,
+            Self::RunCommand { .. }
+                | Self::WriteAttr { .. }
+                | Self::Sysctl { .. }
+                | Self::Rename { .. }
+        )
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mknod {
+                path,
+                kind,
+                mode,
+                major,
+                minor,
+            } => write!(
+                f,
+                "mknod {} {} {major}:{minor} {mode:o}",
+                path.display(),
+                kind.as_char()
+            ),
+            Self::Chown { path, uid, gid } => write!(f, "chown {uid}:{gid} {}", path.display()),
+            Self::SetXattr { path, name, .. } => write!(f, "setxattr {name} {}", path.display()),
+            Self::Symlink { link, target } => {
+                write!(f, "symlink {} -> {}", link.display(), target.display())
+            }
+            Self::Unlink { path, .. } => write!(f, "unlink {}", path.display()),
+            Self::RunCommand { line, mdev, .. } => write!(f, "run {line:?} MDEV={mdev}"),
+            Self::WriteAttr { dir, name, value } => {
+                write!(f, "write {value:?} to {}", dir.join(name).display())
+            }
+            Self::Sysctl { key, value } => write!(f, "sysctl {key}={value}"),
+            Self::Rename { from, to } => write!(f, "rename {from} to {to}"),
+        }
+    }
+}
+
+/// The operations for an event, built by [`DeviceManager::plan`](crate::manager::DeviceManager::plan)
+#[derive(Debug)]
+pub struct Plan {
+    pub(crate) devpath: PathBuf,
+    pub(crate) action: ActionType,
+    pub(crate) devname: String,
+    /// The rules of the manager when planned, with the indices of the ones matched
+    pub(crate) rules: Arc<[Rule]>,
+    pub(crate) matched: Vec<usize>,
+    pub(crate) operations: Vec<Operation>,
+    /// What was created for the device before the event, from the database
+    pub(crate) previous: Option<Record>,
+    /// What the operations create for the device
+    pub(crate) record: Record,
+}
+
+impl Plan {
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// The rules matching the event, in order
+    pub fn matched(&self) -> impl Iterator<Item = &Rule> {
+        self.matched.iter().map(|&i| &self.rules[i])
+    }
+
+    /// Whether the event is left out, e.g. as its device is ignored
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty() && self.matched.is_empty()
+    }
+}
+
+/// Applies the operations
+pub trait Executor: Send + Sync {
+    /// Applies `operation`, returns whether the system was changed
+    fn execute(&self, operation: &Operation) -> impl Future<Output = Result<bool>> + Send;
+}
+
+/// Applies the operations to the system, the parent directories in the dev directory being
+/// created and removed along
+#[derive(Debug, Clone)]
+pub struct System {
+    devpath: PathBuf,
+}
+
+impl System {
+    pub fn new(devpath: impl Into<PathBuf>) -> Self {
+        Self {
+            devpath: devpath.into(),
+        }
+    }
+
+    /// Removes the parent directories of a removed `path` as long as they are empty,
+    /// stopping at the dev directory
+    async fn remove_empty_dirs(&self, path: &Path) {
+        for dir in path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.devpath) && *dir != self.devpath)
+        {
+            match fs::remove_dir(dir).await {
+                Ok(()) => debug!("Removed empty directory {:?}", dir),
+                // most likely not empty
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Executor for System {
+    async fn execute(&self, operation: &Operation) -> Result<bool> {
+        match operation {
+            Operation::Mknod {
+                path,
+                kind,
+                mode,
+                major,
+                minor,
+            } => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)
+                        .await
+                        .map_err(|source| Error::MknodFailed {
+                            path: path.clone(),
+                            source,
+                        })?;
+                }
+                let kind = match kind {
+                    Kind::Char => SFlag::S_IFCHR,
+                    Kind::Block => SFlag::S_IFBLK,
+                };
+                let mode = Mode::from_bits(*mode).ok_or(Error::InvalidMode(*mode))?;
+                let dev = makedev((*major).into(), (*minor).into());
+                info!("Creating {:?} {:?} {:?} {:?}", path, kind, mode, dev);
+                make_node(path, kind, mode, dev)?;
+            }
+            Operation::Chown { path, uid, gid } => {
+                chown(path, Some(Uid::from_raw(*uid)), Some(Gid::from_raw(*gid))).map_err(|e| {
+                    Error::ChownFailed {
+                        path: path.clone(),
+                        source: e.into(),
+                    }
+                })?;
+            }
+            Operation::SetXattr { path, name, value } => {
+                debug!("Setting {} on {:?}", name, path);
+                xattr::set(path, name, value).map_err(|source| Error::SetXattrFailed {
+                    path: path.clone(),
+                    name: name.clone(),
+                    source,
+                })?;
+            }
+            Operation::Symlink { link, target } => {
+                let failed = |source| Error::SymlinkFailed {
+                    link: link.clone(),
+                    target: target.clone(),
+                    source,
+                };
+                if let Some(dir) = link.parent() {
+                    fs::create_dir_all(dir).await.map_err(failed)?;
+                }
+                match fs::read_link(link).await {
+                    Ok(existing) if existing == *target => {
+                        debug!("{:?} already links to {:?}", link, target);
+                        return Ok(false);
+                    }
+                    Ok(_) => {
+                        info!("Replacing {:?} with a link to {:?}", link, target);
+                        fs::remove_file(link).await.map_err(failed)?;
+                    }
+                    Err(_) => info!("Linking {:?} to {:?}", link, target),
+                }
+                fs::symlink(target, link).await.map_err(failed)?;
+            }
+            Operation::Unlink { path, expected } => {
+                let removed = match expected {
+                    Expected::Node(device_number) => remove_node(path, *device_number)?,
+                    Expected::Link(target) => remove_link(path, target).await?,
+                };
+                if !removed {
+                    return Ok(false);
+                }
+                self.remove_empty_dirs(path).await;
+            }
+            Operation::RunCommand { line, env, mdev } => command::run(line, env, mdev).await?,
+            Operation::WriteAttr { dir, name, value } => {
+                sysfs::write_attr(dir, name, value).await?
+            }
+            Operation::Sysctl { key, value } => sysctl::set(key, value).await?,
+            Operation::Rename { from, to } => {
+                net::rename(from, to).map_err(|source| Error::RenameFailed {
+                    from: from.clone(),
+                    to: to.clone(),
+                    source,
+                })?
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Only logs the operations, the system is left untouched
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DryRun;
+
+impl Executor for DryRun {
+    async fn execute(&self, operation: &Operation) -> Result<bool> {
+        info!("Would {}", operation);
+        Ok(false)
+    }
+}
+
+/// Describes the nodes and links, the rest is left out
+impl Executor for Mutex<Table> {
+    async fn execute(&self, operation: &Operation) -> Result<bool> {
+        let mut table = self.lock().unwrap();
+        match operation {
+            Operation::Mknod {
+                path,
+                kind,
+                mode,
+                major,
+                minor,
+            } => table.push_node(table::Node {
+                path: path.clone(),
+                kind: *kind,
+                mode: *mode,
+                // set by the chown following
+                uid: 0,
+                gid: 0,
+                major: *major,
+                minor: *minor,
+            }),
+            Operation::Chown { path, uid, gid } => table.chown(path, *uid, *gid),
+            Operation::Symlink { link, target } => table.push_link(link, target),
+            _ => {}
+        }
+        Ok(false)
+    }
+}
+
+/// Removes the node at `path`, only if it is the device node of `device_number`
+///
+/// This avoids removing unrelated files, or a node already created again for another device.
+fn remove_node(path: &Path, device_number: Option<(u32, u32)>) -> Result<bool> {
+    let failed = |e: Errno| Error::UnlinkFailed {
+        path: path.to_path_buf(),
+        source: e.into(),
+    };
+    let stat = match lstat(path) {
+        Ok(stat) => stat,
+        Err(Errno::ENOENT) => {
+            debug!("{:?} does not exist", path);
+            return Ok(false);
+        }
+        Err(e) => return Err(failed(e)),
+    };
+
+    let kind = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT;
+    if kind != SFlag::S_IFCHR && kind != SFlag::S_IFBLK {
+        warn!("{:?} is not a device node, not removing it", path);
+        return Ok(false);
+    }
+    if let Some((maj, min)) = device_number {
+        if stat.st_rdev != makedev(maj.into(), min.into()) {
+            warn!(
+                "{:?} is not the node of device {}:{}, not removing it",
+                path, maj, min
+            );
+            return Ok(false);
+        }
+    }
+
+    info!("Removing {:?}", path);
+    unlink(path).map_err(failed)?;
+
+    Ok(true)
+}
+
+/// Removes `link`, only if it points to `target`
+async fn remove_link(link: &Path, target: &Path) -> Result<bool> {
+    match fs::read_link(link).await {
+        Ok(existing) if existing == target => {
+            info!("Removing {:?}", link);
+            fs::remove_file(link)
+                .await
+                .map_err(|source| Error::UnlinkFailed {
+                    path: link.to_path_buf(),
+                    source,
+                })?;
+            Ok(true)
+        }
+        Ok(existing) => {
+            debug!("{:?} points to {:?}, not removing it", link, existing);
+            Ok(false)
+        }
+        Err(e) => {
+            debug!("Cannot read link {:?}: {}", link, e);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn table() {
+        let table = Mutex::new(Table::default());
+        let operations = [
+            Operation::Mknod {
+                path: PathBuf::from("/dev/sda"),
+                kind: Kind::Block,
+                mode: 0o660,
+                major: 8,
+                minor: 0,
+            },
+            Operation::Chown {
+                path: PathBuf::from("/dev/sda"),
+                uid: 0,
+                gid: 6,
+            },
+            Operation::Symlink {
+                link: PathBuf::from("/dev/disk/by-label/root"),
+                target: PathBuf::from("/dev/sda"),
+            },
+            Operation::Sysctl {
+                key: String::from("vm/dirty_ratio"),
+                value: String::from("10"),
+            },
+        ];
+        for operation in &operations {
+            // nothing is changed
+            assert!(!table.execute(operation).await.unwrap());
+            assert!(!DryRun.execute(operation).await.unwrap());
+        }
+        let rendered = table.lock().unwrap().render(table::Format::CpioList);
+        assert!(
+            rendered.contains("nod /dev/sda 660 0 6 b 8 0\n"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("slink /dev/disk/by-label/root /dev/sda"),
+            "{rendered}"
+        );
+
+        let rendered: Vec<_> = operations.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            rendered,
+            [
+                "mknod /dev/sda b 8:0 660",
+                "chown 0:6 /dev/sda",
+                "symlink /dev/disk/by-label/root -> /dev/sda",
+                "sysctl vm/dirty_ratio=10",
+            ]
+        );
+        assert!(operations[3].is_optional() && !operations[0].is_optional());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let operations = vec![
+            Operation::Mknod {
+                path: PathBuf::from("/dev/loop0"),
+                kind: Kind::Block,
+                mode: 0o640,
+                major: 7,
+                minor: 0,
+            },
+            Operation::Unlink {
+                path: PathBuf::from("/dev/disk/loop0"),
+                expected: Expected::Link(PathBuf::from("/dev/loop0")),
+            },
+        ];
+        let json = serde_json::to_string(&operations).unwrap();
+        assert!(json.starts_with(r#"[{"op":"mknod","path":"/dev/loop0","kind":"block","#));
+        assert!(
+            json.contains(r#""expected":{"link":"/dev/loop0"}"#),
+            "{json}"
+        );
+        assert_eq!(
+            serde_json::from_str::<Vec<Operation>>(&json).unwrap(),
+            operations
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! Filesystem detection from the superblocks of block devices
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// How much of the device is read, enough to reach the btrfs superblock
+const PROBE_SIZE: u64 = 0x11000;
+
+/// What has been found on a block device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    /// Filesystem type, as named by `mount`
+    pub fs_type: &'static str,
+    /// Filesystem UUID, formatted as `blkid` does
+    pub uuid: Option<String>,
+    /// Filesystem label, as stored on the device
+    pub label: Option<String>,
+}
+
+/// Reads the beginning of the block device at `path` looking for a known filesystem
+pub fn probe(path: &Path) -> io::Result<Option<Superblock>> {
+    let mut buf = Vec::new();
+    File::open(path)?.take(PROBE_SIZE).read_to_end(&mut buf)?;
+    Ok(parse(&buf))
+}
+
+/// Detects the filesystem whose superblock is contained in `buf`, the start of a device
+pub fn parse(buf: &[u8]) -> Option<Superblock> {
+    ext(buf)
+        .or_else(|| xfs(buf))
+        .or_else(|| btrfs(buf))
+        .or_else(|| swap(buf))
+        .or_else(|| vfat(buf))
+}
+
+/// Entry of a partition table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Unique partition GUID, or `SIGNATURE-NN` on MBR disks, as `blkid` reports `PARTUUID`
+    pub uuid: String,
+    /// GPT partition name
+    pub label: Option<String>,
+}
+
+/// Reads the entry of the partition `number` from the table of the disk at `path`
+pub fn partition(path: &Path, sector_size: u64, number: u32) -> io::Result<Option<Partition>> {
+    let mut disk = File::open(path)?;
+    let mut head = vec![0; 2 * sector_size as usize];
+    disk.read_exact(&mut head)?;
+    let (mbr, header) = head.split_at(sector_size as usize);
+
+    let Some((lba, size)) = gpt_entry_location(header, number) else {
+        return Ok(parse_mbr(mbr, number));
+    };
+    // the header comes from the disk, a bogus one must not overflow
+    let Some(offset) = lba
+        .checked_mul(sector_size)
+        .and_then(|start| start.checked_add((u64::from(number) - 1).checked_mul(size as u64)?))
+    else {
+        return Ok(None);
+    };
+    // only the start of the entries is parsed, whatever their size
+    let mut entry = [0; GPT_ENTRY_SIZE];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut entry)?;
+    Ok(parse_gpt_entry(&entry))
+}
+
+/// Size of the GPT partition entries as parsed, larger ones have reserved space after it
+const GPT_ENTRY_SIZE: usize = 128;
+
+/// Largest GPT partition entry accepted, the specification only requires a power of two
+const GPT_ENTRY_MAX: usize = 4096;
+
+/// Returns the LBA of the GPT partition entries and their size, if `number` is one of them
+fn gpt_entry_location(header: &[u8], number: u32) -> Option<(u64, usize)> {
+    if header.get(..8)? != b"EFI PART" {
+        return None;
+    }
+    let lba = u64::from_le_bytes(header.get(72..80)?.try_into().ok()?);
+    let count = le32(header, 80)?;
+    let size = le32(header, 84)? as usize;
+    (number >= 1 && number <= count && (GPT_ENTRY_SIZE..=GPT_ENTRY_MAX).contains(&size))
+        .then_some((lba, size))
+}
+
+fn parse_gpt_entry(entry: &[u8]) -> Option<Partition> {
+    // an unused entry has no type
+    if entry.get(..16)?.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let uuid = guid(entry.get(16..32)?);
+    let name: Vec<u16> = entry
+        .get(56..128)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    let label = String::from_utf16_lossy(&name);
+    Some(Partition {
+        uuid,
+        label: (!label.is_empty()).then_some(label),
+    })
+}
+
+fn parse_mbr(mbr: &[u8], number: u32) -> Option<Partition> {
+    if mbr.get(510..512)? != [0x55, 0xaa] {
+        return None;
+    }
+    let signature = le32(mbr, 440)?;
+    (signature != 0).then(|| Partition {
+        uuid: format!("{signature:08x}-{number:02x}"),
+        label: None,
+    })
+}
+
+/// Formats a GUID stored with the first three fields little endian
+fn guid(bytes: &[u8]) -> String {
+    let mut swapped = bytes.to_vec();
+    swapped[0..4].reverse();
+    swapped[4..6].reverse();
+    swapped[6..8].reverse();
+    let hex: String = swapped.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn ext(buf: &[u8]) -> Option<Superblock> {
+    let sb = buf.get(1024..2048)?;
+    if le16(sb, 0x38)? != 0xef53 {
+        return None;
+    }
+    let compat = le32(sb, 0x5c)?;
+    let incompat = le32(sb, 0x60)?;
+    let fs_type = if incompat & !(INCOMPAT_FILETYPE | INCOMPAT_RECOVER) != 0 {
+        "ext4"
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+    Some(Superblock {
+        fs_type,
+        uuid: uuid(sb.get(0x68..0x78)?),
+        label: label(sb.get(0x78..0x88)?),
+    })
+}
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+
+fn xfs(buf: &[u8]) -> Option<Superblock> {
+    if buf.get(0..4)? != b"XFSB" {
+        return None;
+    }
+    Some(Superblock {
+        fs_type: "xfs",
+        uuid: uuid(buf.get(32..48)?),
+        label: label(buf.get(108..120)?),
+    })
+}
+
+fn btrfs(buf: &[u8]) -> Option<Superblock> {
+    let sb = buf.get(0x10000..0x11000)?;
+    if sb.get(0x40..0x48)? != b"_BHRfS_M" {
+        return None;
+    }
+    Some(Superblock {
+        fs_type: "btrfs",
+        uuid: uuid(sb.get(0x20..0x30)?),
+        label: label(sb.get(0x12b..0x22b)?),
+    })
+}
+
+fn swap(buf: &[u8]) -> Option<Superblock> {
+    // the signature is at the end of the first page, assuming 4k pages
+    if buf.get(4086..4096)? != b"SWAPSPACE2" {
+        return None;
+    }
+    Some(Superblock {
+        fs_type: "swap",
+        uuid: uuid(buf.get(0x40c..0x41c)?),
+        label: label(buf.get(0x41c..0x42c)?),
+    })
+}
+
+fn vfat(buf: &[u8]) -> Option<Superblock> {
+    if buf.get(510..512)? != [0x55, 0xaa] {
+        return None;
+    }
+    // the extended boot record is at a different offset on FAT32
+    let id = if buf.get(82..87)? == b"FAT32" {
+        67
+    } else if buf.get(54..57)? == b"FAT" {
+        39
+    } else {
+        return None;
+    };
+    let serial = le32(buf, id)?;
+    // the label of the boot sector is padded with spaces, NO NAME meaning none
+    let label = label(buf.get(id + 4..id + 15)?).filter(|label| label != "NO NAME");
+    Some(Superblock {
+        fs_type: "vfat",
+        uuid: (serial != 0).then(|| format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff)),
+        label,
+    })
+}
+
+/// Formats a binary UUID, `None` if it is not set
+fn uuid(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// Extracts a NUL terminated or padded label, `None` if it is empty
+fn label(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let label = String::from_utf8_lossy(&bytes[..end]);
+    let label = label.trim_end_matches(' ');
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// Escapes `label` to be used as a file name, as udev does for the by-label links
+///
+/// Characters other than alphanumerics, `#+-.:=@_` and non ASCII ones are written as `\xNN`,
+/// e.g. `My Disk` is `My\x20Disk`, so that slashes and spaces cannot alter the link path.
+pub fn encode_label(label: &str) -> String {
+    let mut encoded = String::with_capacity(label.len());
+    for c in label.chars() {
+        if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() && !c.is_control() {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("\\x{b:02x}"));
+            }
+        }
+    }
+    // `.` and `..` would not name a link
+    if encoded.chars().all(|c| c == '.') {
+        encoded = encoded.replace('.', "\\x2e");
+    }
+    encoded
+}
+
+fn le16(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn le32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: [u8; 16] = [
+        0x3e, 0x6b, 0xe9, 0xde, 0x81, 0x39, 0x4a, 0x62, 0x9a, 0x73, 0x0d, 0x08, 0xd2, 0x18, 0xfb,
+        0x61,
+    ];
+
+    fn image() -> Vec<u8> {
+        vec![0; PROBE_SIZE as usize]
+    }
+
+    #[test]
+    fn ext() {
+        let mut buf = image();
+        buf[1024 + 0x38..][..2].copy_from_slice(&0xef53u16.to_le_bytes());
+        buf[1024 + 0x68..][..16].copy_from_slice(&UUID);
+        buf[1024 + 0x5c] = 0x4;
+        assert_eq!(
+            parse(&buf),
+            Some(Superblock {
+                fs_type: "ext3",
+                uuid: Some(String::from("3e6be9de-8139-4a62-9a73-0d08d218fb61")),
+                label: None,
+            })
+        );
+        buf[1024 + 0x60] = 0x42;
+        buf[1024 + 0x78..][..6].copy_from_slice(b"rootfs");
+        let superblock = parse(&buf).unwrap();
+        assert_eq!(superblock.fs_type, "ext4");
+        assert_eq!(superblock.label.as_deref(), Some("rootfs"));
+    }
+
+    #[test]
+    fn others() {
+        let mut buf = image();
+        buf[..4].copy_from_slice(b"XFSB");
+        buf[32..48].copy_from_slice(&UUID);
+        assert_eq!(parse(&buf).unwrap().fs_type, "xfs");
+
+        let mut buf = image();
+        buf[0x10040..0x10048].copy_from_slice(b"_BHRfS_M");
+        buf[0x10020..0x10030].copy_from_slice(&UUID);
+        assert_eq!(parse(&buf).unwrap().fs_type, "btrfs");
+
+        let mut buf = image();
+        buf[4086..4096].copy_from_slice(b"SWAPSPACE2");
+        assert_eq!(
+            parse(&buf),
+            Some(Superblock {
+                fs_type: "swap",
+                uuid: None,
+                label: None,
+            })
+        );
+
+        let mut buf = image();
+        buf[510..512].copy_from_slice(&[0x55, 0xaa]);
+        buf[82..90].copy_from_slice(b"FAT32   ");
+        buf[67..71].copy_from_slice(&0x1234abcdu32.to_le_bytes());
+        buf[71..82].copy_from_slice(b"NO NAME    ");
+        let superblock = parse(&buf).unwrap();
+        assert_eq!(superblock.uuid.as_deref(), Some("1234-ABCD"));
+        assert_eq!(superblock.label, None);
+        buf[71..82].copy_from_slice(b"EFI BOOT   ");
+        assert_eq!(parse(&buf).unwrap().label.as_deref(), Some("EFI BOOT"));
+
+        // a partition table without a filesystem
+        let mut buf = image();
+        buf[510..512].copy_from_slice(&[0x55, 0xaa]);
+        assert_eq!(parse(&buf), None);
+        assert_eq!(parse(&[]), None);
+    }
+
+    #[test]
+    fn partitions() {
+        let mut header = vec![0; 512];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        assert_eq!(gpt_entry_location(&header, 3), Some((2, 128)));
+        assert_eq!(gpt_entry_location(&header, 129), None);
+        header[84..88].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(gpt_entry_location(&header, 3), None);
+
+        let mut entry = vec![0; 128];
+        // EFI system partition
+        entry[..16].copy_from_slice(&[
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ]);
+        entry[16..32].copy_from_slice(&UUID);
+        for (i, c) in "EFI".encode_utf16().enumerate() {
+            entry[56 + 2 * i..][..2].copy_from_slice(&c.to_le_bytes());
+        }
+        assert_eq!(
+            parse_gpt_entry(&entry),
+            Some(Partition {
+                uuid: String::from("dee96b3e-3981-624a-9a73-0d08d218fb61"),
+                label: Some(String::from("EFI")),
+            })
+        );
+        assert_eq!(parse_gpt_entry(&[0; 128]), None);
+
+        let mut mbr = vec![0; 512];
+        mbr[440..444].copy_from_slice(&0x8a0fe1b3u32.to_le_bytes());
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        assert_eq!(
+            parse_mbr(&mbr, 2),
+            Some(Partition {
+                uuid: String::from("8a0fe1b3-02"),
+                label: None,
+            })
+        );
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(encode_label("rootfs"), "rootfs");
+        assert_eq!(encode_label("EFI BOOT"), "EFI\\x20BOOT");
+        assert_eq!(encode_label("a/b"), "a\\x2fb");
+        assert_eq!(encode_label("..\\"), "..\\x5c");
+        assert_eq!(encode_label("données"), "données");
+        assert_eq!(encode_label("."), "\\x2e");
+    }
+}
@@ -1,28 +1,334 @@
 use std::{
     borrow::Cow,
     collections::{BTreeMap, HashMap},
-    path::{Path, MAIN_SEPARATOR},
+    fmt, iter,
+    ops::Deref,
+    path::{Component, Path, MAIN_SEPARATOR},
 };
 
 use kobject_uevent::ActionType;
 use mdev_parser::{Conf, Filter, OnCreation};
-use tokio::fs;
-use tracing::{debug, info};
+use tracing::{debug, error, info, instrument};
 
+use crate::{acl, sysctl, Error, Result};
+
+/// Default location of the rules
+pub const CONF: &str = "/etc/mdev.conf";
+
+/// A line of the configuration, together with the mdev specific options
+///
+/// Options are written as `KEY=value` fields between the mode and the command, e.g.
+/// `sd[a-z].* root:disk 660 selabel=system_u:object_r:fixed_disk_device_t:s0`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Rule {
+    pub conf: Conf,
+    pub options: Options,
+}
+
+impl Deref for Rule {
+    type Target = Conf;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conf
+    }
+}
+
+impl From<Conf> for Rule {
+    fn from(conf: Conf) -> Self {
+        Self {
+            conf,
+            options: Options::default(),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the options go before the command, which takes the rest of the line
+        let line = self.conf.to_string();
+        let command = self.conf.command.as_ref().map_or(0, |command| {
+            let args: usize = command.args.iter().map(|arg| arg.len() + 1).sum();
+            command.path.len() + args + 2
+        });
+        let (conf, command) = line.split_at(line.len() - command);
+        write!(f, "{}", conf)?;
+        for (name, value) in &self.options.xattrs {
+            write!(f, " xattr={}={}", name, value)?;
+        }
+        for (name, value) in &self.options.attrs {
+            write!(f, " attr={}={}", name, value)?;
+        }
+        for (key, value) in &self.options.sysctls {
+            write!(f, " sysctl={}={}", key, value)?;
+        }
+        let mut acl = self.options.acl.iter();
+        if let Some(entry) = acl.next() {
+            write!(f, " acl={}", entry)?;
+            for entry in acl {
+                write!(f, ",{}", entry)?;
+            }
+        }
+        write!(f, "{}", command)
+    }
+}
+
+/// Written as its line in the configuration
+#[cfg(feature = "serde")]
+impl serde::Serialize for Rule {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Rule {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let line = <Cow<'de, str>>::deserialize(deserializer)?;
+        parse_line(&line).ok_or_else(|| D::Error::custom(format!("invalid rule {line:?}")))
+    }
+}
+
+/// Additional per-rule settings not covered by the mdev.conf format
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Extended attributes to set on the node, e.g. the SELinux label
+    pub xattrs: Vec<(String, String)>,
+    /// Additional access ACL entries of the node
+    pub acl: Vec<acl::Entry>,
+    /// Sysfs attributes of the device to write on add and change, e.g. `power/control`
+    pub attrs: Vec<(String, String)>,
+    /// Kernel parameters to set on add, with the keys in the `/` separated form
+    ///
+    /// Both keys and values are [expanded](expand) before being written.
+    pub sysctls: Vec<(String, String)>,
+}
+
+impl Options {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key.to_ascii_lowercase().as_str() {
+            "xattr" => {
+                let (name, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid xattr {value:?}, expected NAME=VALUE"))?;
+                self.xattrs.push((name.to_string(), value.to_string()));
+            }
+            "selabel" => self
+                .xattrs
+                .push((SELINUX_XATTR.to_string(), value.to_string())),
+            "attr" => {
+                let (name, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid attr {value:?}, expected NAME=VALUE"))?;
+                if !is_safe_name(name) {
+                    return Err(format!("invalid attr name {name:?}"));
+                }
+                self.attrs.push((name.to_string(), value.to_string()));
+            }
+            "sysctl" => {
+                let (key, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid sysctl {value:?}, expected KEY=VALUE"))?;
+                self.sysctls
+                    .push((sysctl::normalize_key(key), value.to_string()));
+            }
+            "acl" => {
+                for entry in value.split(',') {
+                    self.acl.push(entry.parse().map_err(|e| format!("{e}"))?);
+                }
+            }
+            _ => return Err(format!("unknown option {key:?}")),
+        }
+        Ok(())
+    }
+}
+
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// Expands the variables in `template`
+///
+/// `$VAR` and `${VAR}` are replaced with the event environment, unset variables being empty,
+/// `%k` with the kernel name of the device, `%n` with its kernel number and `%%` with `%`.
+pub fn expand(template: &str, env: &HashMap<String, String>, kernel: &str) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('%', Some('k')) => {
+                chars.next();
+                expanded.push_str(kernel);
+            }
+            ('%', Some('n')) => {
+                chars.next();
+                let number = kernel.trim_end_matches(|c: char| c.is_ascii_digit());
+                expanded.push_str(&kernel[number.len()..]);
+            }
+            ('%', Some('%')) => {
+                chars.next();
+                expanded.push('%');
+            }
+            ('$', Some('{')) => {
+                chars.next();
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                expanded.push_str(env.get(&name).map_or("", String::as_str));
+            }
+            ('$', Some(&c)) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|&c| c.is_ascii_alphanumeric() || c == '_') {
+                    name.push(c);
+                }
+                expanded.push_str(env.get(&name).map_or("", String::as_str));
+            }
+            (c, _) => expanded.push(c),
+        }
+    }
+    expanded
+}
+
+/// Parses every line of the configuration contained in `input`, excluding invalid ones and
+/// the `%` directives of [`Subsystems`](crate::filter::Subsystems)
+pub fn parse(input: &str) -> Vec<Rule> {
+    input.lines().filter_map(parse_line).collect()
+}
+
+fn is_option(field: &str) -> bool {
+    field.split_once('=').is_some_and(|(key, _)| {
+        !key.is_empty() && key.chars().all(|c| c.is_ascii_alphabetic() || c == '_')
+    })
+}
+
+fn parse_line(line: &str) -> Option<Rule> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with(['#', '%']) {
+        return None;
+    }
+
+    let mut options = Options::default();
+    let mut fields: Vec<String> = Vec::new();
+    let mut in_command = false;
+    let mut in_links = false;
+    for (index, field) in trimmed.split_whitespace().enumerate() {
+        in_command |= field.starts_with(['@', '$', '*']);
+        // space separated links are joined in a single comma separated field
+        if in_links && !in_command && !is_option(field) {
+            if let Some(links) = fields.last_mut() {
+                links.push(',');
+                links.push_str(field);
+                continue;
+            }
+        }
+        in_links = index > 2 && !in_command && field.starts_with('>');
+        // the first three fields are the matcher, the owner and the mode
+        if index > 2 && !in_command && is_option(field) {
+            let (key, value) = field.split_once('=').unwrap();
+            if let Err(e) = options.set(key, value) {
+                error!("{}: {}", line, e);
+                return None;
+            }
+        } else {
+            fields.push(field.to_string());
+        }
+    }
+
+    // the parser appends a catch-all rule to what it parsed
+    let mut confs = mdev_parser::parse(&fields.join(" "));
+    (confs.len() > 1).then(|| Rule {
+        conf: confs.swap_remove(0),
+        options,
+    })
+}
+
+/// Result of evaluating a rule against an event
+#[derive(Debug, PartialEq)]
+pub enum Outcome<'a> {
+    /// The rule matched, the node has to be handled as described
+    Matched(Node<'a>),
+    /// The rule matched, but it prevents the creation of the node
+    Prevented,
+    /// The rule did not match
+    Skipped(Mismatch),
+}
+
+impl<'a> Outcome<'a> {
+    /// Returns the name of the node, if any
+    pub fn node(self) -> Option<Cow<'a, str>> {
+        match self {
+            Self::Matched(node) => Some(node.name),
+            Self::Prevented | Self::Skipped(_) => None,
+        }
+    }
+}
+
+/// Device node as resolved by a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node<'a> {
+    /// Path of the node, relative to the dev directory
+    pub name: Cow<'a, str>,
+    /// Symlinks pointing to the node, relative to the dev directory
+    pub links: Vec<String>,
+}
+
+/// Reason why a rule did not match an event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// An environment variable required by the rule is not set
+    MissingEnv(String),
+    /// An environment variable does not match the rule regex
+    Env {
+        envvar: String,
+        value: String,
+        regex: String,
+    },
+    /// The device major number is different
+    Major { expected: u32, found: u32 },
+    /// The device minor number is outside the rule range
+    Minor { min: u32, max: u32, found: u32 },
+    /// The device name (or the environment variable) does not match the rule regex
+    Regex { value: String, regex: String },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingEnv(envvar) => write!(f, "env {envvar} not set"),
+            Self::Env {
+                envvar,
+                value,
+                regex,
+            } => write!(f, "env {envvar}={value:?} does not match {regex:?}"),
+            Self::Major { expected, found } => {
+                write!(f, "major {found} is not {expected}")
+            }
+            Self::Minor { min, max, found } => {
+                write!(f, "minor {found} is not in {min}-{max}")
+            }
+            Self::Regex { value, regex } => write!(f, "{value:?} does not match {regex:?}"),
+        }
+    }
+}
+
+#[instrument(name = "rule", level = "debug", skip_all, fields(rule = %rule))]
 pub async fn apply<'a>(
     rule: &Conf,
     env: &HashMap<String, String>,
     device_number: Option<(u32, u32)>,
     action: ActionType,
-    devpath: &Path,
     devname: &'a str,
-) -> anyhow::Result<Option<Cow<'a, str>>> {
-    if !rule.envmatches.iter().all(|env_match| {
-        env.get(&env_match.envvar)
-            .map(|var| env_match.regex.is_match(var))
-            .unwrap_or(false)
-    }) {
-        return Ok(None);
+) -> Result<Outcome<'a>> {
+    for env_match in &rule.envmatches {
+        let Some(var) = env.get(&env_match.envvar) else {
+            return Ok(Outcome::Skipped(Mismatch::MissingEnv(
+                env_match.envvar.clone(),
+            )));
+        };
+        if !env_match.regex.is_match(var) {
+            return Ok(Outcome::Skipped(Mismatch::Env {
+                envvar: env_match.envvar.clone(),
+                value: var.clone(),
+                regex: env_match.regex.to_string(),
+            }));
+        }
     }
 
     // to avoid unneeded allocations
@@ -32,11 +338,18 @@ pub async fn apply<'a>(
         Filter::MajMin(ref device_number_match) => {
             if let Some((maj, min)) = device_number {
                 if maj != device_number_match.maj {
-                    return Ok(None);
+                    return Ok(Outcome::Skipped(Mismatch::Major {
+                        expected: device_number_match.maj,
+                        found: maj,
+                    }));
                 }
                 let min2 = device_number_match.min2.unwrap_or(device_number_match.min);
                 if min < device_number_match.min || min > min2 {
-                    return Ok(None);
+                    return Ok(Outcome::Skipped(Mismatch::Minor {
+                        min: device_number_match.min,
+                        max: min2,
+                        found: min,
+                    }));
                 }
             }
         }
@@ -45,11 +358,17 @@ pub async fn apply<'a>(
                 if let Some(var) = env.get(envvar) {
                     var
                 } else {
-                    return Ok(None);
+                    return Ok(Outcome::Skipped(Mismatch::MissingEnv(envvar.clone())));
                 }
             } else {
                 devname
             };
+            let mismatch = || {
+                Outcome::Skipped(Mismatch::Regex {
+                    value: var.to_string(),
+                    regex: device_regex.regex.to_string(),
+                })
+            };
             if let Some(old_on_creation) = on_creation {
                 // this creates a sorted collection of usize:(String:&str)
                 // because is lighter and quicker having matches already indexed
@@ -65,7 +384,7 @@ pub async fn apply<'a>(
                     })
                     .collect();
                 if matches.is_empty() {
-                    return Ok(None);
+                    return Ok(mismatch());
                 }
 
                 let mut new_on_creation = old_on_creation.into_owned();
@@ -80,60 +399,67 @@ pub async fn apply<'a>(
                 }
                 on_creation = Some(Cow::Owned(new_on_creation));
             } else if !device_regex.regex.is_match(var) {
-                return Ok(None);
+                return Ok(mismatch());
             }
         }
     }
 
     info!("rule matched {:?} action {:?}", rule, action);
 
-    // WARNING: WIP code
+    let mut node = Node {
+        name: Cow::Borrowed(devname),
+        links: Vec::new(),
+    };
     if let Some(creation) = on_creation.as_deref() {
         match creation {
-            OnCreation::Move(to) | OnCreation::SymLink(to) => {
-                debug!(
-                    "{} {} to {}",
-                    if let OnCreation::Move(_) = creation {
-                        "Rename"
-                    } else {
-                        "Link"
-                    },
-                    devname,
-                    to
-                );
-                let (dir, target) = if is_dir(to) {
-                    (to.clone(), format!("{}{}", to, devname))
-                } else {
-                    let nsep = to.chars().filter(|c| *c == MAIN_SEPARATOR).count();
-                    let mut n = 0;
-                    let parent = to
-                        .chars()
-                        .take_while(|c| {
-                            if *c == MAIN_SEPARATOR {
-                                n += 1;
-                            }
-                            n < nsep
-                        })
-                        .collect();
-                    (parent, to.clone())
-                };
-
-                if let OnCreation::Move(_) = creation {
-                    // fs::rename(devpath.join(devname), devpath.join(target)).await?;
-                    return Ok(Some(Cow::Owned(target)));
-                } else {
-                    fs::create_dir_all(devpath.join(dir)).await?;
-                    fs::symlink(devpath.join(devname), devpath.join(target)).await?;
+            OnCreation::Move(to) => {
+                debug!("Rename {} to {}", devname, to);
+                node.name = Cow::Owned(target_path(to, devname));
+            }
+            OnCreation::SymLink(to) => {
+                // several links can be given, separated by commas
+                for to in to.split(',').filter(|to| !to.is_empty()) {
+                    debug!("Link {} to {}", devname, to);
+                    node.links.push(target_path(to, devname));
                 }
             }
             OnCreation::Prevent => {
                 debug!("Do not create node");
-                return Ok(None);
+                return Ok(Outcome::Prevented);
             }
         }
     }
 
-    Ok(Some(Cow::Borrowed(devname)))
+    for name in iter::once(node.name.as_ref()).chain(node.links.iter().map(String::as_str)) {
+        if !is_safe_name(name) {
+            return Err(Error::UnsafeName(name.to_string()));
+        }
+    }
+
+    Ok(Outcome::Matched(node))
+}
+
+/// Checks that `name` is a relative path that cannot escape the directory it is joined to
+pub fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains('\0')
+        && Path::new(name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Checks that `name` is a single file name, e.g. of a link in `/dev/mapper`
+pub fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+/// Returns the path of the `to` target, relative to the dev directory
+fn target_path(to: &str, devname: &str) -> String {
+    if is_dir(to) {
+        format!("{}{}", to, devname)
+    } else {
+        to.to_string()
+    }
 }
 
 fn is_dir(path: &str) -> bool {
@@ -153,12 +479,14 @@ fn replace_in_path(pb: &mut String, matches: &BTreeMap<usize, (String, &str)>) {
 
 #[cfg(test)]
 mod tests {
-    use std::{borrow::Cow, collections::HashMap, path::Path};
+    use std::{borrow::Cow, collections::HashMap};
 
     use kobject_uevent::ActionType;
     use mdev_parser::{Conf, DeviceRegex, Filter, MajMin, OnCreation};
     use regex::Regex;
 
+    use super::{Mismatch, Node, Outcome};
+
     #[tokio::test]
     async fn basic() {
         let conf = Conf {
@@ -176,11 +504,11 @@ mod tests {
             command: None,
         };
         let env = HashMap::new();
-        let devpath = Path::new("/dev");
         assert_eq!(
-            super::apply(&conf, &env, None, ActionType::Add, devpath, "foo")
+            super::apply(&conf, &env, None, ActionType::Add, "foo")
                 .await
-                .unwrap(),
+                .unwrap()
+                .node(),
             Some(Cow::Borrowed("foo"))
         );
     }
@@ -202,11 +530,11 @@ mod tests {
             command: None,
         };
         let env = HashMap::new();
-        let devpath = Path::new("/dev");
         assert_eq!(
-            super::apply(&conf, &env, None, ActionType::Add, devpath, "foo")
+            super::apply(&conf, &env, None, ActionType::Add, "foo")
                 .await
-                .unwrap(),
+                .unwrap()
+                .node(),
             Some(Cow::Borrowed("bar"))
         );
     }
@@ -227,12 +555,224 @@ mod tests {
             command: None,
         };
         let env = HashMap::new();
-        let devpath = Path::new("/dev");
         assert_eq!(
-            super::apply(&conf, &env, None, ActionType::Add, devpath, "foo/bar")
+            super::apply(&conf, &env, None, ActionType::Add, "foo/bar")
                 .await
-                .unwrap(),
+                .unwrap()
+                .node(),
             Some(Cow::Borrowed("bar/foo"))
         );
     }
+
+    #[tokio::test]
+    async fn links() {
+        let conf = Conf {
+            stop: false,
+            envmatches: vec![],
+            filter: Filter::DeviceRegex(DeviceRegex {
+                regex: Regex::new("\\d+").unwrap(),
+                envvar: None,
+            }),
+            user: String::from("root"),
+            group: String::from("root"),
+            mode: 0o660,
+            on_creation: Some(OnCreation::SymLink(String::from("cdrom%1,optical/"))),
+            command: None,
+        };
+        let env = HashMap::new();
+        assert_eq!(
+            super::apply(&conf, &env, None, ActionType::Add, "sr0")
+                .await
+                .unwrap(),
+            Outcome::Matched(Node {
+                name: Cow::Borrowed("sr0"),
+                links: vec![String::from("cdrom0"), String::from("optical/sr0")],
+            })
+        );
+    }
+
+    #[test]
+    fn safe_names() {
+        assert!(super::is_safe_name("sda"));
+        assert!(super::is_safe_name("bus/usb/001/002"));
+        assert!(super::is_safe_name("./input/event0"));
+        assert!(!super::is_safe_name(""));
+        assert!(!super::is_safe_name("/etc/passwd"));
+        assert!(!super::is_safe_name("../etc/passwd"));
+        assert!(!super::is_safe_name("input/../../etc"));
+
+        assert!(super::is_file_name("vg0-root"));
+        assert!(!super::is_file_name("."));
+        assert!(!super::is_file_name(".."));
+        assert!(!super::is_file_name("md/home"));
+        assert!(!super::is_file_name(""));
+    }
+
+    #[tokio::test]
+    async fn unsafe_rename() {
+        let conf = Conf {
+            stop: false,
+            envmatches: vec![],
+            filter: Filter::DeviceRegex(DeviceRegex {
+                regex: Regex::new("\\w+").unwrap(),
+                envvar: None,
+            }),
+            user: String::from("root"),
+            group: String::from("root"),
+            mode: 0o660,
+            on_creation: Some(OnCreation::Move(String::from("../%1"))),
+            command: None,
+        };
+        let env = HashMap::new();
+        assert!(super::apply(&conf, &env, None, ActionType::Add, "shadow")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn mismatch() {
+        let conf = Conf {
+            stop: false,
+            envmatches: vec![],
+            filter: Filter::MajMin(MajMin {
+                maj: 4,
+                min: 1,
+                min2: Some(3),
+            }),
+            user: String::from("root"),
+            group: String::from("root"),
+            mode: 0o700,
+            on_creation: None,
+            command: None,
+        };
+        let env = HashMap::new();
+        assert_eq!(
+            super::apply(&conf, &env, Some((5, 1)), ActionType::Add, "foo")
+                .await
+                .unwrap(),
+            Outcome::Skipped(Mismatch::Major {
+                expected: 4,
+                found: 5
+            })
+        );
+        assert_eq!(
+            super::apply(&conf, &env, Some((4, 4)), ActionType::Add, "foo")
+                .await
+                .unwrap(),
+            Outcome::Skipped(Mismatch::Minor {
+                min: 1,
+                max: 3,
+                found: 4
+            })
+        );
+
+        let conf = Conf {
+            filter: Filter::DeviceRegex(DeviceRegex {
+                regex: Regex::new("^sd[a-z]$").unwrap(),
+                envvar: None,
+            }),
+            ..conf
+        };
+        assert_eq!(
+            super::apply(&conf, &env, None, ActionType::Add, "tty0")
+                .await
+                .unwrap(),
+            Outcome::Skipped(Mismatch::Regex {
+                value: String::from("tty0"),
+                regex: String::from("^sd[a-z]$"),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        let rules = super::parse(
+            "# comment\n\
+             %subsystem-skip bdi\n\
+             sd[a-z] root:disk 660 selabel=system_u:object_r:fixed_disk_device_t:s0 =disk/ @env FOO=bar\n\
+             video[0-9]+ root:video 660 ACL=u:video-user:rw,g:audio:r\n\
+             (sr[0-9]+) root:cdrom 660 >cdrom,dvd cdrw @echo %1\n\
+             null root:root 666 unknown=1\n\
+             SUBSYSTEM=usb;.* root:root 660 attr=power/control=auto attr=authorized=1\n\
+             -.* root:root 660 attr=../power/control=auto\n\
+             SUBSYSTEM=net;.* root:root 660 sysctl=net.ipv6.conf.$INTERFACE.disable_ipv6=1\n",
+        );
+        assert_eq!(rules.len(), 5);
+        let rule = &rules[0];
+        assert_eq!(
+            rule.options.xattrs,
+            [(
+                String::from("security.selinux"),
+                String::from("system_u:object_r:fixed_disk_device_t:s0")
+            )]
+        );
+        assert_eq!(
+            rule.on_creation,
+            Some(OnCreation::Move(String::from("disk/")))
+        );
+        assert_eq!(rule.command.as_ref().unwrap().args, ["FOO=bar"]);
+
+        let rule = &rules[1];
+        assert_eq!(rule.options.acl.len(), 2);
+        assert_eq!(
+            rule.to_string(),
+            "video[0-9]+ root:video 660 acl=u:video-user:rw-,g:audio:r--"
+        );
+
+        let rule = &rules[2];
+        assert_eq!(
+            rule.on_creation,
+            Some(OnCreation::SymLink(String::from("cdrom,dvd,cdrw")))
+        );
+        assert_eq!(rule.command.as_ref().unwrap().path, "echo");
+
+        let rule = &rules[3];
+        assert_eq!(
+            rule.options.attrs,
+            [
+                (String::from("power/control"), String::from("auto")),
+                (String::from("authorized"), String::from("1"))
+            ]
+        );
+        assert!(rule
+            .to_string()
+            .ends_with(" attr=power/control=auto attr=authorized=1"));
+
+        let rule = &rules[4];
+        assert_eq!(
+            rule.options.sysctls,
+            [(
+                String::from("net/ipv6/conf/$INTERFACE/disable_ipv6"),
+                String::from("1")
+            )]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let line = "-sd[a-z].* root:disk 660 =disk/ selabel=system_u:object_r:fixed_disk_device_t:s0 @echo $MDEV";
+        let rule = super::parse_line(line).unwrap();
+        let json = serde_json::to_string(&rule).unwrap();
+        assert_eq!(json, format!("{:?}", rule.to_string()));
+        assert_eq!(serde_json::from_str::<super::Rule>(&json).unwrap(), rule);
+        assert!(serde_json::from_str::<super::Rule>(r##""# comment""##).is_err());
+    }
+
+    #[test]
+    fn expand() {
+        let env = HashMap::from([
+            (String::from("INTERFACE"), String::from("eth0.100")),
+            (String::from("SUBSYSTEM"), String::from("net")),
+        ]);
+        assert_eq!(
+            super::expand("net/ipv4/conf/$INTERFACE/forwarding", &env, "eth0.100"),
+            "net/ipv4/conf/eth0.100/forwarding"
+        );
+        assert_eq!(
+            super::expand("${SUBSYSTEM}s:%k:%n:%%:$MISSING.", &env, "sda12"),
+            "nets:sda12:12:%:."
+        );
+        assert_eq!(super::expand("$1 100%", &env, "sda"), "$1 100%");
+    }
 }
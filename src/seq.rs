@@ -0,0 +1,202 @@
+//! Serialization of the events through the `mdev.seq` file, as busybox mdev does
+//!
+//! When the file exists in the dev directory, each handler waits for it to contain the
+//! `SEQNUM` of its event, then writes the next one once done.
+
+use std::{
+    collections::BTreeSet,
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use tokio::{fs, io::AsyncWriteExt, time::sleep};
+
+/// Name of the file in the dev directory
+pub const FILE: &str = "mdev.seq";
+
+/// How long an event waits for the previous ones before being handled anyway
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(32);
+
+/// Outcome of [`wait`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    /// The file does not exist, events are not serialized
+    Disabled,
+    /// The previous events have been handled
+    Ready,
+    /// The file still contained this sequence number after the timeout
+    TimedOut(u64),
+}
+
+/// Waits for the file at `path` to reach `seqnum`
+///
+/// An empty file, as created at boot, is seeded with `seqnum`. A file already past `seqnum`
+/// means the event is late and it is handled right away.
+pub async fn wait(path: &Path, seqnum: u64, timeout: Duration) -> io::Result<Wait> {
+    let start = Instant::now();
+    loop {
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Wait::Disabled),
+            Err(e) => return Err(e),
+        };
+        let current = match content.trim().parse::<u64>() {
+            Ok(current) => current,
+            Err(_) => {
+                store(path, seqnum).await?;
+                return Ok(Wait::Ready);
+            }
+        };
+        if current >= seqnum {
+            return Ok(Wait::Ready);
+        }
+        if start.elapsed() >= timeout {
+            return Ok(Wait::TimedOut(current));
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Lets the event following `seqnum` be handled, if the file at `path` exists
+pub async fn advance(path: &Path, seqnum: u64) -> io::Result<()> {
+    match store(path, seqnum + 1).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// SEQNUMs of the events being handled, to tell up to which one all of them are handled
+///
+/// The events of different devices complete out of order, the mark only moves past an event
+/// once it and the ones before it are done.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    seqs: BTreeSet<u64>,
+    /// Highest SEQNUM started
+    highest: u64,
+}
+
+impl InFlight {
+    /// Tracks the events following `seqnum`, the ones up to it being handled
+    pub fn new(seqnum: u64) -> Self {
+        Self {
+            seqs: BTreeSet::new(),
+            highest: seqnum,
+        }
+    }
+
+    /// Marks the event `seqnum` as being handled
+    pub fn start(&mut self, seqnum: u64) {
+        self.seqs.insert(seqnum);
+        self.highest = self.highest.max(seqnum);
+    }
+
+    /// Marks the event `seqnum` as handled, returns the SEQNUM up to which all are
+    pub fn finish(&mut self, seqnum: u64) -> u64 {
+        self.seqs.remove(&seqnum);
+        self.handled()
+    }
+
+    /// SEQNUM up to which all the events started are handled
+    pub fn handled(&self) -> u64 {
+        match self.seqs.first() {
+            Some(first) => first.saturating_sub(1),
+            None => self.highest,
+        }
+    }
+}
+
+/// Overwrites the existing file at `path` with `seqnum`
+async fn store(path: &Path, seqnum: u64) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).truncate(true);
+    let mut file = options.open(path).await?;
+    file.write_all(seqnum.to_string().as_bytes()).await?;
+    // tokio completes the write in the background otherwise
+    file.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        env, fs,
+        path::PathBuf,
+        process,
+        sync::{atomic::AtomicUsize, Mutex},
+    };
+
+    use futures_util::stream;
+    use kobject_uevent::{ActionType, UEvent};
+
+    use super::*;
+    use crate::stream::for_each_device;
+
+    #[tokio::test]
+    async fn wait_and_advance() {
+        let dir = env::temp_dir().join(format!("mdev-seq-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(FILE);
+
+        assert_eq!(wait(&path, 5, TIMEOUT).await.unwrap(), Wait::Disabled);
+        advance(&path, 5).await.unwrap();
+        assert!(!path.exists());
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(wait(&path, 5, TIMEOUT).await.unwrap(), Wait::Ready);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "5");
+
+        advance(&path, 5).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "6");
+        assert_eq!(wait(&path, 6, TIMEOUT).await.unwrap(), Wait::Ready);
+        assert_eq!(wait(&path, 4, TIMEOUT).await.unwrap(), Wait::Ready);
+        assert_eq!(
+            wait(&path, 8, Duration::from_millis(50)).await.unwrap(),
+            Wait::TimedOut(6)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_flight() {
+        let device = |seq, devpath: &str| UEvent {
+            action: ActionType::Change,
+            devpath: PathBuf::from(devpath),
+            subsystem: String::from("block"),
+            env: HashMap::new(),
+            seq,
+        };
+        let events = stream::iter([
+            device(7, "/devices/virtual/block/loop0"),
+            device(8, "/devices/virtual/block/loop1"),
+        ]);
+        let in_flight = Mutex::new(InFlight::new(6));
+        let marks = Mutex::new(Vec::new());
+        for_each_device(events, 2, None, &AtomicUsize::new(0), |ev| {
+            in_flight.lock().unwrap().start(ev.seq);
+            let (in_flight, marks) = (&in_flight, &marks);
+            async move {
+                // slow, the next one completes first
+                if ev.seq == 7 {
+                    sleep(Duration::from_millis(20)).await;
+                }
+                let handled = in_flight.lock().unwrap().finish(ev.seq);
+                marks.lock().unwrap().push((ev.seq, handled));
+            }
+        })
+        .await;
+        // 8 is done before 7, the mark waits for 7
+        assert_eq!(marks.into_inner().unwrap(), [(8, 6), (7, 8)]);
+
+        let mut in_flight = InFlight::new(0);
+        in_flight.start(3);
+        in_flight.start(2);
+        assert_eq!(in_flight.handled(), 1);
+        assert_eq!(in_flight.finish(2), 2);
+        assert_eq!(in_flight.finish(3), 3);
+    }
+}
@@ -0,0 +1,223 @@
+//! Events published on a Unix `SOCK_SEQPACKET` socket
+//!
+//! Unlike the netlink groups, the socket can be reached by unprivileged processes and from the
+//! containers it is bind mounted in. Each subscriber connecting to it receives the events sent
+//! afterwards, one per packet.
+
+use std::{
+    fs::{self, Permissions},
+    io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::PermissionsExt,
+    },
+    path::Path,
+    ptr,
+};
+
+use tracing::{debug, warn};
+
+/// Default location of the socket
+pub const DEFAULT_PATH: &str = "/run/mdev/events";
+
+/// Connections waiting to be accepted, until the next event is sent
+const BACKLOG: libc::c_int = 128;
+
+/// The listening socket and its subscribers
+#[derive(Debug)]
+pub struct Publisher {
+    listener: OwnedFd,
+    subscribers: Vec<OwnedFd>,
+}
+
+impl Publisher {
+    /// Listens on `path`, replacing the socket left behind by a previous daemon, any user can
+    /// subscribe
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let addr = sockaddr(path)?;
+        let listener = socket(libc::SOCK_NONBLOCK)?;
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // SAFETY: the socket is valid and addr is a sockaddr_un living across the call
+        let ret = unsafe {
+            libc::bind(
+                listener.as_raw_fd(),
+                ptr::from_ref(&addr).cast(),
+                mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the socket is valid and bound
+        if unsafe { libc::listen(listener.as_raw_fd(), BACKLOG) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        fs::set_permissions(path, Permissions::from_mode(0o666))?;
+        Ok(Self {
+            listener,
+            subscribers: Vec::new(),
+        })
+    }
+
+    /// Number of subscribers accepted so far and not gone yet
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Sends `packet` to the subscribers, dropping the ones gone
+    ///
+    /// A subscriber whose queue is full misses the packet, rather than holding up the others.
+    pub fn send(&mut self, packet: &[u8]) {
+        self.accept();
+        self.subscribers.retain(|subscriber| {
+            // SAFETY: the socket is valid and packet lives across the call
+            let ret = unsafe {
+                libc::send(
+                    subscriber.as_raw_fd(),
+                    packet.as_ptr().cast(),
+                    packet.len(),
+                    libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                )
+            };
+            if ret >= 0 {
+                return true;
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock => {
+                    debug!("Subscriber too slow, event dropped");
+                    true
+                }
+                _ => {
+                    debug!("Subscriber gone: {e}");
+                    false
+                }
+            }
+        });
+    }
+
+    /// Accepts the pending connections
+    fn accept(&mut self) {
+        loop {
+            // SAFETY: the socket is valid, the address of the peer is not needed
+            let fd = unsafe {
+                libc::accept4(
+                    self.listener.as_raw_fd(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    libc::SOCK_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    io::ErrorKind::WouldBlock => {}
+                    _ => warn!("Cannot accept a subscriber: {e}"),
+                }
+                break;
+            }
+            // SAFETY: fd is a valid descriptor nobody else owns
+            self.subscribers.push(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+    }
+}
+
+fn socket(flags: libc::c_int) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket creation, the descriptor is checked before being owned
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC | flags,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a valid descriptor nobody else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn sockaddr(path: &Path) -> io::Result<libc::sockaddr_un> {
+    // SAFETY: sockaddr_un is plain old data, all zeros is a valid value
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let bytes = path.as_os_str().as_encoded_bytes();
+    // the last byte is left to NUL
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("socket path {path:?} too long"),
+        ));
+    }
+    for (dst, &src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = src as libc::c_char;
+    }
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn connect(path: &Path) -> OwnedFd {
+        let socket = socket(0).unwrap();
+        let addr = sockaddr(path).unwrap();
+        // SAFETY: the socket is valid and addr is a sockaddr_un living across the call
+        let ret = unsafe {
+            libc::connect(
+                socket.as_raw_fd(),
+                ptr::from_ref(&addr).cast(),
+                mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        socket
+    }
+
+    fn recv(socket: &OwnedFd) -> Vec<u8> {
+        let mut buf = [0; 64];
+        // SAFETY: the socket is valid and buf lives across the call
+        let len = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        assert!(len >= 0, "{}", io::Error::last_os_error());
+        buf[..len as usize].to_vec()
+    }
+
+    #[test]
+    fn publish() {
+        let path = env::temp_dir().join(format!("mdev-seqpacket-{}", process::id()));
+        let mut publisher = Publisher::bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o666);
+
+        // nobody is listening yet
+        publisher.send(b"lost");
+        let subscriber = connect(&path);
+        publisher.send(b"ACTION=add\0SEQNUM=1");
+        publisher.send(b"ACTION=remove\0SEQNUM=2");
+        assert_eq!(publisher.subscribers(), 1);
+        // the packets are not merged
+        assert_eq!(recv(&subscriber), b"ACTION=add\0SEQNUM=1");
+        assert_eq!(recv(&subscriber), b"ACTION=remove\0SEQNUM=2");
+
+        drop(subscriber);
+        publisher.send(b"gone");
+        assert_eq!(publisher.subscribers(), 0);
+
+        assert!(sockaddr(&Path::new("/run").join("x".repeat(108))).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,19 +1,33 @@
 use std::{
-    future::Future,
-    io,
-    pin::Pin,
-    process,
+    collections::{BTreeMap, VecDeque},
+    future::{poll_fn, Future},
+    io, mem,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    pin::{pin, Pin},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     task::{ready, Context, Poll},
+    time::Duration,
 };
 
-use futures_util::{stream::FusedStream, FutureExt, Stream};
-use kobject_uevent::UEvent;
-use netlink_sys::{
-    protocols::NETLINK_KOBJECT_UEVENT, AsyncSocket, AsyncSocketExt, SocketAddr, TokioSocket,
+use futures_util::{
+    stream::{FusedStream, FuturesUnordered},
+    task::AtomicWaker,
+    FutureExt, Stream, StreamExt,
+};
+use kobject_uevent::{ActionType, UEvent};
+use netlink_sys::{protocols::NETLINK_KOBJECT_UEVENT, Socket, SocketAddr};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::broadcast::{self, error::RecvError},
+    time::{sleep_until, Instant, Sleep},
 };
 
-/// TODO: replace this with TAIT as soon it's stabilized
-type UEventsFuture = Pin<Box<dyn Future<Output = (TokioSocket, Result<Vec<u8>, io::Error>)>>>;
+use crate::libudev;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -23,84 +37,2145 @@ pub enum Error {
     Bind(#[source] io::Error),
     #[error("Socket receive error")]
     Receive(#[source] io::Error),
+    #[error("Socket filter error")]
+    Filter(#[source] io::Error),
+    #[error("Packet sent by port {port}, uid {}, rejected", uid.map_or(String::from("unknown"), |uid| uid.to_string()))]
+    Spoofed { port: u32, uid: Option<u32> },
+    /// Some events were lost, the stream goes on with the next ones
+    #[error("Socket receive buffer overflowed, events lost and {drained} queued ones dropped")]
+    Overflow { drained: usize },
+    #[error("Invalid libudev packet: {0}")]
+    Libudev(&'static str),
+    /// Received by a [`SharedUEvents`] subscriber too slow, it goes on with the next events
+    #[error("Subscriber too slow, {missed} events missed")]
+    Lagged { missed: u64 },
+    /// Error of the stream behind a [`SharedUEvents`], received by each subscriber
+    #[error(transparent)]
+    Shared(Arc<Error>),
     #[error(transparent)]
     NetlinkPacket(kobject_uevent::Error),
 }
 
+/// Senders the packets of a stream are accepted from, the others are reported as
+/// [`Error::Spoofed`]
+///
+/// Any process with the right capabilities can send to a netlink group, so a forged event could
+/// lead to a bogus node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The kernel, for the events of the [`KERNEL_GROUP`]
+    Kernel,
+    /// A process run by root, such as the daemon rebroadcasting the events
+    Root,
+    /// Anyone
+    Any,
+}
+
+impl Origin {
+    /// Expected for the packets sent to `groups`, the kernel alone sends to the
+    /// [`KERNEL_GROUP`]
+    fn of(groups: u32) -> Self {
+        match groups {
+            KERNEL_GROUP => Self::Kernel,
+            _ => Self::Root,
+        }
+    }
+
+    fn accepts(self, port: u32, uid: Option<u32>) -> bool {
+        match self {
+            Self::Kernel => port == 0 && uid == Some(0),
+            Self::Root => uid == Some(0),
+            Self::Any => true,
+        }
+    }
+}
+
+/// Netlink group of the events sent by the kernel
+///
+/// The groups are given as masks, to be or'ed to listen to several of them, e.g.
+/// `KERNEL_GROUP | REBROADCAST_GROUP`.
+pub const KERNEL_GROUP: u32 = 1;
+/// Netlink group where the daemon rebroadcasts the events it handled
+pub const REBROADCAST_GROUP: u32 = 4;
+
+/// Size of the receive buffer of the sockets unless told otherwise, as udev does
+pub const RECEIVE_BUFFER: usize = 128 * 1024 * 1024;
+/// Size of the buffer the packets are read in unless told otherwise, as libudev does
+///
+/// The kernel sends events of 2048 bytes at most.
+pub const READ_BUFFER: usize = 8192;
+
 /// creates a new stream of UEvents
 pub fn uevents() -> Result<impl Stream<Item = Result<UEvent, Error>>, Error> {
-    let mut socket = TokioSocket::new(NETLINK_KOBJECT_UEVENT).map_err(Error::Open)?;
-    let sa = SocketAddr::new(process::id(), 1);
-    socket.socket_mut().bind(&sa).map_err(Error::Bind)?;
+    UEventsBuilder::new().build()
+}
+
+/// A stream of UEvents, only the ones of some subsystems or actions
+///
+/// The events are filtered on the raw packets, the others are not parsed. With [`bpf`], the
+/// kernel drops the packets of the other actions before they reach the socket.
+///
+/// [`bpf`]: UEventsBuilder::bpf
+#[derive(Debug, Clone)]
+pub struct UEventsBuilder {
+    groups: u32,
+    filter: PacketFilter,
+    bpf: bool,
+    receive_buffer: usize,
+    read_buffer: usize,
+    origin: Option<Origin>,
+}
+
+impl Default for UEventsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UEventsBuilder {
+    /// All the events sent by the kernel
+    pub fn new() -> Self {
+        Self {
+            groups: KERNEL_GROUP,
+            filter: PacketFilter::default(),
+            bpf: false,
+            receive_buffer: RECEIVE_BUFFER,
+            read_buffer: READ_BUFFER,
+            origin: None,
+        }
+    }
+
+    /// Receives the events sent to the netlink `groups` instead, a mask such as
+    /// [`REBROADCAST_GROUP`] or `KERNEL_GROUP | REBROADCAST_GROUP` for both
+    pub fn groups(mut self, groups: u32) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Only the events of `subsystem`, can be called several times
+    pub fn subsystem(mut self, subsystem: impl Into<String>) -> Self {
+        self.filter.subsystems.push(subsystem.into());
+        self
+    }
+
+    /// Only the events of `action`, can be called several times
+    pub fn action(mut self, action: ActionType) -> Self {
+        self.filter.actions.push(action);
+        self
+    }
 
-    Ok(UEventsStream::new(socket))
+    /// Installs a classic BPF filter on the socket for the actions
+    ///
+    /// Only the packets of the kernel start with their action, the others are let through
+    /// and filtered as usual.
+    pub fn bpf(mut self, bpf: bool) -> Self {
+        self.bpf = bpf;
+        self
+    }
+
+    /// Queues up to `size` bytes of packets in the socket, [`RECEIVE_BUFFER`] by default
+    ///
+    /// The size is capped to `net.core.rmem_max` without `CAP_NET_ADMIN`, see
+    /// [`UEvents::receive_buffer`] for the one applied.
+    pub fn receive_buffer(mut self, size: usize) -> Self {
+        self.receive_buffer = size;
+        self
+    }
+
+    /// Reads the packets in a buffer of `size` bytes, [`READ_BUFFER`] by default
+    ///
+    /// A packet filling it is reported as truncated rather than parsed.
+    pub fn read_buffer(mut self, size: usize) -> Self {
+        self.read_buffer = size;
+        self
+    }
+
+    /// Accepts the packets of `origin` only, by default the ones of the kernel for the
+    /// [`KERNEL_GROUP`] and of root for the other groups, told apart for each packet
+    pub fn origin(mut self, origin: Origin) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// Binds the socket, the events are received once the stream is polled in a tokio runtime
+    pub fn build(self) -> Result<UEvents, Error> {
+        self.build_with()
+    }
+
+    /// Binds the socket, the events are received once the stream is polled in the runtime
+    /// of `R`
+    pub fn build_with<R: Reactor>(self) -> Result<UEvents<R>, Error> {
+        self.build_raw_with().map(UEvents::from)
+    }
+
+    /// Binds the socket, the packets are received unparsed once the stream is polled in a
+    /// tokio runtime
+    pub fn build_raw(self) -> Result<RawPackets, Error> {
+        self.build_raw_with()
+    }
+
+    /// Binds the socket, the packets are received unparsed once the stream is polled in the
+    /// runtime of `R`
+    pub fn build_raw_with<R: Reactor>(self) -> Result<RawPackets<R>, Error> {
+        let listener = self.bind()?;
+        Ok(RawPackets::new(
+            listener.register()?,
+            self.filter,
+            self.read_buffer,
+            self.origin,
+        ))
+    }
+
+    /// Binds the socket, the events are received as the iterator is advanced, blocking meanwhile
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking(self) -> Result<BlockingUEvents, Error> {
+        let listener = self.bind()?;
+        pass_credentials(&listener.0)?;
+        Ok(BlockingUEvents {
+            socket: listener.0,
+            buf: Vec::with_capacity(self.read_buffer.max(1)),
+            filter: self.filter,
+            origin: self.origin,
+            done: false,
+        })
+    }
+
+    fn bind(&self) -> Result<Listener, Error> {
+        let listener = Listener::bind_group(self.groups, self.receive_buffer)?;
+        if self.bpf && !self.filter.actions.is_empty() {
+            attach_filter(&listener.0, &self.filter.actions).map_err(Error::Filter)?;
+        }
+        Ok(listener)
+    }
+}
+
+/// Events let through by a [`UEventsBuilder`], read from the packets before parsing them
+#[derive(Debug, Clone, Default)]
+struct PacketFilter {
+    subsystems: Vec<String>,
+    actions: Vec<ActionType>,
 }
 
-enum UEventsStream {
-    Socket(TokioSocket),
-    Future(UEventsFuture),
-    None,
+impl PacketFilter {
+    /// A packet missing the properties is let through, for the parser to report it
+    fn allows(&self, packet: &[u8]) -> bool {
+        let property = |name: &[u8]| {
+            packet
+                .split(|b| *b == 0)
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(b"="))
+        };
+        let subsystem = self.subsystems.is_empty()
+            || property(b"SUBSYSTEM").is_none_or(|value| {
+                self.subsystems
+                    .iter()
+                    .any(|subsystem| subsystem.as_bytes() == value)
+            });
+        let action = self.actions.is_empty()
+            || property(b"ACTION").is_none_or(|value| {
+                self.actions
+                    .iter()
+                    .any(|action| crate::action_name(*action).as_bytes() == value)
+            });
+        subsystem && action
+    }
 }
 
-impl UEventsStream {
-    fn new(socket: TokioSocket) -> Self {
-        Self::Socket(socket)
+/// Drops the packets of the kernel whose action is not one of `actions`
+///
+/// The packets of the kernel start with `action@devpath`, the first 4 bytes of the actions
+/// tell them apart. The other packets start with an upper case property and are accepted.
+fn attach_filter(socket: &Socket, actions: &[ActionType]) -> io::Result<()> {
+    const ALL: [ActionType; 8] = [
+        ActionType::Add,
+        ActionType::Remove,
+        ActionType::Change,
+        ActionType::Move,
+        ActionType::Online,
+        ActionType::Offline,
+        ActionType::Bind,
+        ActionType::Unbind,
+    ];
+
+    let insn = |code: u32, jt: u8, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf: 0,
+        k,
+    };
+    let rejected: Vec<_> = ALL
+        .into_iter()
+        .filter(|action| !actions.contains(action))
+        .map(|action| {
+            // "add@" is the only header shorter than 4 bytes without the separator
+            let header = format!("{}@", crate::action_name(action));
+            u32::from_be_bytes(header.as_bytes()[..4].try_into().unwrap())
+        })
+        .collect();
+    // the first word, then a jump to the final drop for each rejected prefix
+    let mut program = vec![insn(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0, 0)];
+    for (i, prefix) in rejected.iter().enumerate() {
+        let to_drop = (rejected.len() - i) as u8;
+        program.push(insn(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            to_drop,
+            *prefix,
+        ));
+    }
+    program.push(insn(libc::BPF_RET | libc::BPF_K, 0, u32::MAX));
+    program.push(insn(libc::BPF_RET | libc::BPF_K, 0, 0));
+
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: the descriptor is owned by `socket`, the program outlives the call and the
+    // kernel copies it
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &fprog as *const libc::sock_fprog as *const libc::c_void,
+            mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A packet received by [`recv`]
+struct Received {
+    port: u32,
+    /// The packet was sent to
+    groups: u32,
+    /// Of the process sending it, unknown without `SO_PASSCRED`
+    uid: Option<u32>,
+    truncated: bool,
+}
+
+/// Receives a packet in the spare capacity of `buf`, along with its sender
+fn recv(socket: &Socket, buf: &mut Vec<u8>) -> io::Result<Received> {
+    // SAFETY: the C structures are plain old data, all zeros is a valid value
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    let spare = buf.spare_capacity_mut();
+    let mut iov = libc::iovec {
+        iov_base: spare.as_mut_ptr().cast(),
+        iov_len: spare.len(),
+    };
+    // room for the credentials, aligned as a cmsghdr
+    let mut control = [0u64; 8];
+    msg.msg_name = ptr::from_mut(&mut addr).cast();
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    // SAFETY: the socket is valid and msg points to buffers living across the call
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: recvmsg initialized len bytes of the spare capacity, at most all of it
+    unsafe { buf.set_len(len as usize) };
+
+    let mut uid = None;
+    // SAFETY: msg describes the control messages written by recvmsg
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        // SAFETY: cmsg points to a complete control message within the buffer
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if level == libc::SOL_SOCKET && kind == libc::SCM_CREDENTIALS {
+            // SAFETY: the data of SCM_CREDENTIALS is a ucred, not necessarily aligned
+            let cred: libc::ucred = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()) };
+            uid = Some(cred.uid);
+        }
+        // SAFETY: as above, the next one is null after the last one
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    Ok(Received {
+        port: addr.nl_pid,
+        groups: addr.nl_groups,
+        uid,
+        truncated: msg.msg_flags & libc::MSG_TRUNC != 0,
+    })
+}
+
+/// A socket receiving the UEvents of the kernel, queued until it becomes a stream
+///
+/// Bound before a scan of the sysfs, it keeps the events of the devices appearing meanwhile.
+/// It needs no runtime, so it can be bound before forking.
+pub struct Listener(Socket);
+
+impl Listener {
+    pub fn bind() -> Result<Self, Error> {
+        Self::bind_with_buffer(RECEIVE_BUFFER)
+    }
+
+    /// Queues up to `size` bytes of packets, capped to `net.core.rmem_max` without
+    /// `CAP_NET_ADMIN`
+    pub fn bind_with_buffer(size: usize) -> Result<Self, Error> {
+        Self::bind_group(KERNEL_GROUP, size)
+    }
+
+    fn bind_group(groups: u32, receive_buffer: usize) -> Result<Self, Error> {
+        let mut socket = Socket::new(NETLINK_KOBJECT_UEVENT).map_err(Error::Open)?;
+        let sa = SocketAddr::new(0, groups);
+        socket.bind(&sa).map_err(Error::Bind)?;
+        let size = libc::c_int::try_from(receive_buffer).unwrap_or(libc::c_int::MAX);
+        // SO_RCVBUFFORCE ignores rmem_max, but needs CAP_NET_ADMIN
+        // SAFETY: the descriptor is owned by `socket` and the value outlives the call
+        let forced = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUFFORCE,
+                &size as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        } == 0;
+        if !forced {
+            // capped to rmem_max, the default size is better than nothing
+            let _ = socket.set_rx_buf_sz(size);
+        }
+
+        Ok(Self(socket))
+    }
+
+    /// Size of the receive buffer applied by the kernel, twice the one asked for unless capped
+    pub fn receive_buffer(&self) -> io::Result<usize> {
+        self.0.get_rx_buf_sz()
+    }
+
+    /// Turns the socket into a stream of the queued events and the next ones, in a runtime
+    pub fn into_stream(self) -> Result<UEvents, Error> {
+        Ok(UEvents::new(
+            self.into_socket()?,
+            PacketFilter::default(),
+            READ_BUFFER,
+            Some(Origin::Kernel),
+        ))
+    }
+
+    fn into_socket(self) -> Result<AsyncFd<Socket>, Error> {
+        self.register()
+    }
+
+    fn register<R: Reactor>(self) -> Result<R, Error> {
+        self.0.set_non_blocking(true).map_err(Error::Open)?;
+        pass_credentials(&self.0)?;
+        R::register(self.0).map_err(Error::Open)
+    }
+}
+
+/// Socket registered in the reactor of an async runtime, tokio's [`AsyncFd`] by default
+///
+/// Implemented for the other runtimes, e.g. `async_io::Async` with the `async-io` feature, it
+/// lets a [`UEvents`] and a [`Rebroadcaster`](crate::Rebroadcaster) be polled by their
+/// executors.
+pub trait Reactor: Sized + Unpin {
+    /// Timer of the runtime, its output is ignored
+    type Timer: Future + Unpin;
+
+    /// Registers `socket`, already non-blocking
+    fn register(socket: Socket) -> io::Result<Self>;
+
+    fn get_ref(&self) -> &Socket;
+
+    /// Runs `f` once the socket is readable, until it does not fail with `WouldBlock`
+    fn poll_read_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: impl FnMut(&Socket) -> io::Result<T>,
+    ) -> Poll<io::Result<T>>;
+
+    /// Runs `f` once the socket is writable, until it does not fail with `WouldBlock`
+    fn poll_write_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: impl FnMut(&Socket) -> io::Result<T>,
+    ) -> Poll<io::Result<T>>;
+
+    /// Starts a timer expiring after `duration`
+    fn timer(duration: Duration) -> Self::Timer;
+}
+
+impl Reactor for AsyncFd<Socket> {
+    type Timer = Pin<Box<Sleep>>;
+
+    fn register(socket: Socket) -> io::Result<Self> {
+        // SAFETY: the socket owns its descriptor, closed once the AsyncFd drops it
+        unsafe { AsyncFd::register(socket) }.map_err(Into::into)
+    }
+
+    fn get_ref(&self) -> &Socket {
+        AsyncFd::get_ref(self)
+    }
+
+    fn poll_read_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut(&Socket) -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        loop {
+            let mut guard = ready!(self.poll_read_ready(cx))?;
+            if let Ok(res) = guard.try_io(|socket| f(socket.get_ref())) {
+                return Poll::Ready(res);
+            }
+        }
+    }
+
+    fn poll_write_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut(&Socket) -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        loop {
+            let mut guard = ready!(self.poll_write_ready(cx))?;
+            if let Ok(res) = guard.try_io(|socket| f(socket.get_ref())) {
+                return Poll::Ready(res);
+            }
+        }
+    }
+
+    fn timer(duration: Duration) -> Self::Timer {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(feature = "async-io")]
+impl Reactor for async_io::Async<Socket> {
+    type Timer = async_io::Timer;
+
+    fn register(socket: Socket) -> io::Result<Self> {
+        Self::new_nonblocking(socket)
+    }
+
+    fn get_ref(&self) -> &Socket {
+        async_io::Async::get_ref(self)
     }
 
-    fn take_socket(&mut self) -> Option<TokioSocket> {
-        if matches!(self, Self::Socket(_)) {
-            let Self::Socket(socket) = std::mem::replace(self, Self::None) else {
-                unreachable!();
+    fn poll_read_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut(&Socket) -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        loop {
+            match f(self.get_ref()) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => ready!(self.poll_readable(cx))?,
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    fn poll_write_with<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut(&Socket) -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        loop {
+            match f(self.get_ref()) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => ready!(self.poll_writable(cx))?,
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    fn timer(duration: Duration) -> Self::Timer {
+        async_io::Timer::after(duration)
+    }
+}
+
+/// Makes the credentials of the senders come along the packets
+fn pass_credentials(socket: &Socket) -> Result<(), Error> {
+    let on: libc::c_int = 1;
+    // SAFETY: the descriptor is owned by the socket and the value outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PASSCRED,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::Open(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// creates a new stream of the UEvents sent to the netlink `groups`, e.g. [`REBROADCAST_GROUP`]
+///
+/// The port is chosen by the kernel, so that several streams can be open in a process.
+pub fn uevents_from(groups: u32) -> Result<impl Stream<Item = Result<UEvent, Error>>, Error> {
+    UEventsBuilder::new().groups(groups).build()
+}
+
+/// A packet as received, before being parsed
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    /// Port of the sender and groups the packet was sent to
+    pub addr: SocketAddr,
+    /// Of the process sending it
+    pub uid: Option<u32>,
+}
+
+/// Finishes a [`UEvents`] or [`RawPackets`] stream from anywhere, e.g. on a signal
+///
+/// The stream ends the next time it is polled, between two packets, the ones still queued in
+/// the socket are dropped.
+#[derive(Debug, Clone, Default)]
+pub struct Closer(Arc<Closing>);
+
+#[derive(Debug, Default)]
+struct Closing {
+    closed: AtomicBool,
+    /// Of the task polling the stream
+    waker: AtomicWaker,
+}
+
+impl Closer {
+    pub fn close(&self) {
+        self.0.closed.store(true, Ordering::Release);
+        self.0.waker.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Stream of the packets received by a [`UEventsBuilder`] socket, as they are
+///
+/// The packets are checked and filtered as for a [`UEvents`], the ones republished by udevd
+/// or eudev keep their libudev header.
+pub struct RawPackets<R = AsyncFd<Socket>> {
+    socket: R,
+    /// Reused for each packet
+    buf: Vec<u8>,
+    filter: PacketFilter,
+    /// Told from the group of each packet unless given
+    origin: Option<Origin>,
+    closer: Closer,
+    done: bool,
+}
+
+impl<R: Reactor> RawPackets<R> {
+    fn new(socket: R, filter: PacketFilter, read_buffer: usize, origin: Option<Origin>) -> Self {
+        Self {
+            socket,
+            buf: Vec::with_capacity(read_buffer.max(1)),
+            filter,
+            origin,
+            closer: Closer::default(),
+            done: false,
+        }
+    }
+
+    /// Handle finishing the stream
+    pub fn closer(&self) -> Closer {
+        self.closer.clone()
+    }
+
+    /// Size of the receive buffer applied by the kernel, twice the one asked for unless capped
+    pub fn receive_buffer(&self) -> io::Result<usize> {
+        self.socket.get_ref().get_rx_buf_sz()
+    }
+
+    /// Size of the buffer the packets are read in
+    pub fn read_buffer(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Receives the next packet let through in `buf`
+    fn poll_packet(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Received, Error>>> {
+        self.closer.0.waker.register(cx.waker());
+        if self.closer.is_closed() {
+            self.done = true;
+        }
+        while !self.done {
+            self.buf.clear();
+            let received = ready!(self
+                .socket
+                .poll_read_with(cx, |socket| recv(socket, &mut self.buf)));
+            let received = match received {
+                Ok(received) => received,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    let drained = drain(self.socket.get_ref(), &mut self.buf);
+                    return Poll::Ready(Some(Err(Error::Overflow { drained })));
+                }
+                Err(e) => return Poll::Ready(Some(Err(Error::Receive(e)))),
             };
-            Some(socket)
-        } else {
-            None
+            let capacity = self.buf.capacity();
+            match check(&self.buf, &received, capacity, self.origin, &self.filter) {
+                Ok(Checked::Accepted) => return Poll::Ready(Some(Ok(received))),
+                Ok(Checked::Filtered) => {}
+                Ok(Checked::End) => self.done = true,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl<R: Reactor> Stream for RawPackets<R> {
+    type Item = Result<Packet, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let received = ready!(this.poll_packet(cx));
+        Poll::Ready(received.map(|received| {
+            received.map(|received| Packet {
+                data: this.buf.clone(),
+                addr: SocketAddr::new(received.port, received.groups),
+                uid: received.uid,
+            })
+        }))
+    }
+}
+
+impl<R: Reactor> FusedStream for RawPackets<R> {
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+/// Stream built by a [`UEventsBuilder`]
+pub struct UEvents<R = AsyncFd<Socket>>(RawPackets<R>);
+
+impl<R: Reactor> UEvents<R> {
+    fn new(socket: R, filter: PacketFilter, read_buffer: usize, origin: Option<Origin>) -> Self {
+        Self(RawPackets::new(socket, filter, read_buffer, origin))
+    }
+
+    /// Size of the receive buffer applied by the kernel, twice the one asked for unless capped
+    pub fn receive_buffer(&self) -> io::Result<usize> {
+        self.0.receive_buffer()
+    }
+
+    /// Size of the buffer the packets are read in
+    pub fn read_buffer(&self) -> usize {
+        self.0.read_buffer()
+    }
+
+    /// Handle finishing the stream
+    pub fn closer(&self) -> Closer {
+        self.0.closer()
+    }
+}
+
+impl<R: Reactor> From<RawPackets<R>> for UEvents<R> {
+    fn from(packets: RawPackets<R>) -> Self {
+        Self(packets)
+    }
+}
+
+/// Drops the packets queued in the non-blocking `socket` when its receive buffer overflowed,
+/// the gap they leave is better recovered from all at once, e.g. by a scan of the sysfs
+fn drain(socket: &Socket, buf: &mut Vec<u8>) -> usize {
+    let mut drained = 0;
+    loop {
+        buf.clear();
+        match recv(socket, buf) {
+            Ok(_) => drained += 1,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // WouldBlock once empty, the readiness is cleared by the next receive
+            Err(_) => return drained,
+        }
+    }
+}
+
+/// What [`check`] tells of a packet
+enum Checked {
+    Accepted,
+    Filtered,
+    /// The socket was shut down
+    End,
+}
+
+/// Checks the sender and the filter of the packet received in `buf`
+fn check(
+    buf: &[u8],
+    received: &Received,
+    capacity: usize,
+    origin: Option<Origin>,
+    filter: &PacketFilter,
+) -> Result<Checked, Error> {
+    let origin = origin.unwrap_or(Origin::of(received.groups));
+    if !origin.accepts(received.port, received.uid) {
+        return Err(Error::Spoofed {
+            port: received.port,
+            uid: received.uid,
+        });
+    } else if buf.is_empty() {
+        return Ok(Checked::End);
+    } else if received.truncated {
+        return Err(Error::Receive(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("packet truncated to the read buffer of {capacity} bytes"),
+        )));
+    }
+    // the invalid headers are reported when parsing
+    let properties = match libudev::is_libudev(buf) {
+        true => libudev::properties(buf).unwrap_or(buf),
+        false => buf,
+    };
+    match filter.allows(properties) {
+        true => Ok(Checked::Accepted),
+        false => Ok(Checked::Filtered),
+    }
+}
+
+/// Parses a packet let through by [`check`]
+fn parse(packet: &[u8]) -> Result<UEvent, Error> {
+    // republished by udevd or eudev
+    let properties = match libudev::is_libudev(packet) {
+        true => libudev::properties(packet).map_err(Error::Libudev)?,
+        false => packet,
+    };
+    UEvent::from_netlink_packet(properties).map_err(Error::NetlinkPacket)
+}
+
+impl<R: Reactor> Stream for UEvents<R> {
+    type Item = Result<UEvent, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let packets = &mut self.get_mut().0;
+        let received = ready!(packets.poll_packet(cx));
+        Poll::Ready(received.map(|received| received.and_then(|_| parse(&packets.buf))))
+    }
+}
+
+impl<R: Reactor> FusedStream for UEvents<R> {
+    fn is_terminated(&self) -> bool {
+        self.0.done
+    }
+}
+
+/// creates a new iterator over the UEvents, for the programs running no tokio runtime
+///
+/// tokio is still a dependency of the crate, the iterator only needs no runtime to be started.
+#[cfg(feature = "blocking")]
+pub fn uevents_blocking() -> Result<impl Iterator<Item = Result<UEvent, Error>>, Error> {
+    UEventsBuilder::new().build_blocking()
+}
+
+/// Iterator built by a [`UEventsBuilder`], blocking until the next event
+#[cfg(feature = "blocking")]
+pub struct BlockingUEvents {
+    socket: Socket,
+    /// Reused for each packet
+    buf: Vec<u8>,
+    filter: PacketFilter,
+    /// Told from the group of each packet unless given
+    origin: Option<Origin>,
+    done: bool,
+}
+
+#[cfg(feature = "blocking")]
+impl BlockingUEvents {
+    /// Size of the receive buffer applied by the kernel, twice the one asked for unless capped
+    pub fn receive_buffer(&self) -> io::Result<usize> {
+        self.socket.get_rx_buf_sz()
+    }
+
+    /// Size of the buffer the packets are read in
+    pub fn read_buffer(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    fn overflow(&mut self) -> Result<UEvent, Error> {
+        self.socket.set_non_blocking(true).map_err(Error::Receive)?;
+        let drained = drain(&self.socket, &mut self.buf);
+        self.socket
+            .set_non_blocking(false)
+            .map_err(Error::Receive)?;
+        Err(Error::Overflow { drained })
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl Iterator for BlockingUEvents {
+    type Item = Result<UEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the packets filtered out are skipped
+        while !self.done {
+            self.buf.clear();
+            let received = match recv(&self.socket, &mut self.buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => return Some(self.overflow()),
+                Err(e) => return Some(Err(Error::Receive(e))),
+            };
+            let capacity = self.buf.capacity();
+            match check(&self.buf, &received, capacity, self.origin, &self.filter) {
+                Ok(Checked::Accepted) => return Some(parse(&self.buf)),
+                Ok(Checked::Filtered) => {}
+                Ok(Checked::End) => self.done = true,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl std::iter::FusedIterator for BlockingUEvents {}
+
+/// Feeds the events of a stream to several subscribers, e.g. to read a single socket
+///
+/// Runs as a future until the stream ends, the subscribers then end once they have received
+/// the remaining events. Each subscriber gets up to `capacity` events behind the others, it
+/// then misses the oldest ones and receives [`Error::Lagged`] instead, so that a slow
+/// subscriber does not hold up the others.
+pub struct SharedUEvents<S> {
+    stream: S,
+    /// None once the stream has ended
+    sender: Option<broadcast::Sender<Result<UEvent, Arc<Error>>>>,
+}
+
+impl<S> SharedUEvents<S>
+where
+    S: Stream<Item = Result<UEvent, Error>> + Unpin,
+{
+    pub fn new(stream: S, capacity: usize) -> Self {
+        Self {
+            stream,
+            sender: Some(broadcast::channel(capacity).0),
+        }
+    }
+
+    /// Subscribes to the events received from now on
+    pub fn subscribe(&self) -> Subscriber {
+        let receiver = match &self.sender {
+            Some(sender) => sender.subscribe(),
+            // closed right away
+            None => broadcast::channel(1).1,
+        };
+        let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            let res = match receiver.recv().await {
+                Ok(res) => res.map_err(Error::Shared),
+                Err(RecvError::Lagged(missed)) => Err(Error::Lagged { missed }),
+                Err(RecvError::Closed) => return None,
+            };
+            Some((res, receiver))
+        });
+        Subscriber(Box::pin(stream))
+    }
+}
+
+impl<S> Future for SharedUEvents<S>
+where
+    S: Stream<Item = Result<UEvent, Error>> + Unpin,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let Some(sender) = &this.sender else {
+            return Poll::Ready(());
+        };
+        while let Some(res) = ready!(this.stream.poll_next_unpin(cx)) {
+            // nobody subscribed yet
+            let _ = sender.send(res.map_err(Arc::new));
         }
+        this.sender = None;
+        Poll::Ready(())
+    }
+}
+
+/// Stream of the events of a [`SharedUEvents`]
+pub struct Subscriber(Pin<Box<dyn Stream<Item = Result<UEvent, Error>> + Send>>);
+
+impl Stream for Subscriber {
+    type Item = Result<UEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+/// Delivers the events of `stream` in SEQNUM order
+///
+/// Each event is held for up to `window`, waiting for the events with a lower SEQNUM that
+/// may still arrive, unless it is the one following the last delivered event. Events older
+/// than the last delivered one and errors are delivered right away.
+pub fn reorder<S>(stream: S, window: Duration) -> Reorder<S>
+where
+    S: Stream<Item = Result<UEvent, Error>> + Unpin,
+{
+    Reorder {
+        stream,
+        window,
+        pending: BTreeMap::new(),
+        next: None,
+        sleep: None,
+        done: false,
+    }
+}
+
+/// Stream returned by [`reorder`]
+pub struct Reorder<S> {
+    stream: S,
+    window: Duration,
+    /// Events by SEQNUM, with the instant they have to be delivered anyway
+    pending: BTreeMap<u64, (Instant, UEvent)>,
+    /// SEQNUM following the last delivered event
+    next: Option<u64>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    done: bool,
+}
+
+impl<S> Reorder<S> {
+    fn pop_first(&mut self) -> Option<UEvent> {
+        let (seq, (_, ev)) = self.pending.pop_first()?;
+        self.next = Some(seq + 1);
+        Some(ev)
     }
 }
 
-impl Stream for UEventsStream {
+impl<S> Stream for Reorder<S>
+where
+    S: Stream<Item = Result<UEvent, Error>> + Unpin,
+{
     type Item = Result<UEvent, Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
 
-        if let Some(mut socket) = this.take_socket() {
-            *this = Self::Future(Box::pin(async move {
-                let res = socket.recv_from_full().await.map(|(buf, _)| buf);
-                (socket, res)
-            }));
-        }
-
-        if let Self::Future(fut) = this {
-            let (socket, res) = ready!(fut.poll_unpin(cx));
-            *this = Self::Socket(socket);
-            match res {
-                Ok(buf) => {
-                    if buf.is_empty() {
-                        *this = Self::None;
-                    } else {
-                        return Poll::Ready(Some(
-                            UEvent::from_netlink_packet(&buf).map_err(Error::NetlinkPacket),
-                        ));
+        loop {
+            if !this.done {
+                match this.stream.poll_next_unpin(cx) {
+                    Poll::Ready(Some(Ok(ev))) => {
+                        if this.next.is_some_and(|next| ev.seq < next) {
+                            return Poll::Ready(Some(Ok(ev)));
+                        }
+                        let deadline = Instant::now() + this.window;
+                        this.pending.insert(ev.seq, (deadline, ev));
+                        continue;
                     }
+                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                    Poll::Ready(None) => this.done = true,
+                    Poll::Pending => {}
+                }
+            }
+
+            let Some((&seq, _)) = this.pending.first_key_value() else {
+                return match this.done {
+                    true => Poll::Ready(None),
+                    false => Poll::Pending,
+                };
+            };
+            if this.done || this.next == Some(seq) {
+                return Poll::Ready(this.pop_first().map(Ok));
+            }
+
+            // the oldest event has waited long enough for the ones before it
+            let deadline = this
+                .pending
+                .values()
+                .map(|(deadline, _)| *deadline)
+                .min()
+                .unwrap();
+            if deadline <= Instant::now() {
+                return Poll::Ready(this.pop_first().map(Ok));
+            }
+            let sleep = match &mut this.sleep {
+                Some(sleep) => {
+                    sleep.as_mut().reset(deadline);
+                    sleep
                 }
-                Err(e) => {
-                    return Poll::Ready(Some(Err(Error::Receive(e))));
+                None => this.sleep.insert(Box::pin(sleep_until(deadline))),
+            };
+            ready!(sleep.as_mut().poll(cx));
+        }
+    }
+}
+
+/// Collapses the change events of a device received within `window` into the last one
+///
+/// The first change event of a device is held for `window`, the next ones with the same
+/// properties but SEQNUM replace it meanwhile, so that a storm of them is handled once per
+/// `window`. Any other event of the device delivers the held one first. The events replaced are
+/// passed to `dropped`.
+pub fn debounce<S, D>(stream: S, window: Duration, dropped: D) -> Debounce<S, D>
+where
+    S: Stream<Item = UEvent> + Unpin,
+    D: FnMut(UEvent),
+{
+    Debounce {
+        stream,
+        window,
+        dropped,
+        held: Vec::new(),
+        ready: VecDeque::new(),
+        sleep: None,
+        done: false,
+    }
+}
+
+/// Stream returned by [`debounce`]
+pub struct Debounce<S, D> {
+    stream: S,
+    window: Duration,
+    dropped: D,
+    /// Change events with the instant they are delivered, in order of arrival
+    held: Vec<(Instant, UEvent)>,
+    ready: VecDeque<UEvent>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    done: bool,
+}
+
+impl<S, D> Stream for Debounce<S, D>
+where
+    S: Stream<Item = UEvent> + Unpin,
+    D: FnMut(UEvent) + Unpin,
+{
+    type Item = UEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(ev) = this.ready.pop_front() {
+                return Poll::Ready(Some(ev));
+            }
+
+            if !this.done {
+                match this.stream.poll_next_unpin(cx) {
+                    Poll::Ready(Some(ev)) => {
+                        let held = this.held.iter().position(|(_, h)| h.devpath == ev.devpath);
+                        match (ev.action, held) {
+                            (ActionType::Change, Some(i))
+                                if same_properties(&this.held[i].1, &ev) =>
+                            {
+                                (this.dropped)(mem::replace(&mut this.held[i].1, ev))
+                            }
+                            (ActionType::Change, held) => {
+                                if let Some(i) = held {
+                                    this.ready.push_back(this.held.remove(i).1);
+                                }
+                                let deadline = Instant::now() + this.window;
+                                this.held.push((deadline, ev));
+                            }
+                            (_, held) => {
+                                if let Some(i) = held {
+                                    this.ready.push_back(this.held.remove(i).1);
+                                }
+                                this.ready.push_back(ev);
+                            }
+                        }
+                        continue;
+                    }
+                    Poll::Ready(None) => {
+                        this.done = true;
+                        this.ready.extend(this.held.drain(..).map(|(_, ev)| ev));
+                        continue;
+                    }
+                    Poll::Pending => {}
                 }
             }
+
+            // held in order of arrival, so of deadline too
+            let Some(&(deadline, _)) = this.held.first() else {
+                return match this.done {
+                    true => Poll::Ready(None),
+                    false => Poll::Pending,
+                };
+            };
+            if deadline <= Instant::now() {
+                return Poll::Ready(Some(this.held.remove(0).1));
+            }
+            let sleep = match &mut this.sleep {
+                Some(sleep) => {
+                    sleep.as_mut().reset(deadline);
+                    sleep
+                }
+                None => this.sleep.insert(Box::pin(sleep_until(deadline))),
+            };
+            ready!(sleep.as_mut().poll(cx));
         }
+    }
+}
 
-        if matches!(this, Self::None) {
-            Poll::Ready(None)
-        } else {
-            unreachable!();
+/// Drops the events already delivered within `window`, the same action of the same device with
+/// the same SEQNUM
+///
+/// An event sent to several of the groups listened to, e.g. `KERNEL_GROUP | REBROADCAST_GROUP`,
+/// is delivered once. Errors are delivered as they are.
+pub fn dedup<S>(stream: S, window: Duration) -> Dedup<S>
+where
+    S: Stream<Item = Result<UEvent, Error>> + Unpin,
+{
+    Dedup {
+        stream,
+        window,
+        delivered: VecDeque::new(),
+    }
+}
+
+/// Stream returned by [`dedup`]
+pub struct Dedup<S> {
+    stream: S,
+    window: Duration,
+    /// Events with the instant they were delivered, in order of delivery
+    delivered: VecDeque<(Instant, u64, ActionType, PathBuf)>,
+}
+
+impl<S> Stream for Dedup<S>
+where
+    S: Stream<Item = Result<UEvent, Error>> + Unpin,
+{
+    type Item = Result<UEvent, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let ev = match ready!(this.stream.poll_next_unpin(cx)) {
+                Some(Ok(ev)) => ev,
+                res => return Poll::Ready(res),
+            };
+            let now = Instant::now();
+            while let Some((at, ..)) = this.delivered.front() {
+                if now.duration_since(*at) < this.window {
+                    break;
+                }
+                this.delivered.pop_front();
+            }
+            let duplicate = this.delivered.iter().any(|(_, seq, action, devpath)| {
+                *seq == ev.seq && *action == ev.action && *devpath == ev.devpath
+            });
+            if !duplicate {
+                this.delivered
+                    .push_back((now, ev.seq, ev.action, ev.devpath.clone()));
+                return Poll::Ready(Some(Ok(ev)));
+            }
         }
     }
 }
 
-impl FusedStream for UEventsStream {
-    fn is_terminated(&self) -> bool {
-        matches!(self, Self::None)
+/// Events [`for_each_device`] keeps waiting per event it handles at once
+const QUEUED_PER_TASK: usize = 64;
+
+/// Runs `f` on the events of `stream`, up to `limit` at once
+///
+/// An event waits for the ones received before it for the same device, its parents or its
+/// children, so that each device sees its events in order. Returns once all the events of
+/// the stream have been handled. The stream is not polled while `64 * limit` events wait, so
+/// that a storm stays in the receive buffer rather than in memory.
+///
+/// With `coalesce`, the events still waiting are merged as described in [`coalesce`], the ones
+/// dropped meanwhile being passed to it. `depth` is kept at the number of events waiting or
+/// being handled.
+pub async fn for_each_device<S, F, Fut>(
+    stream: S,
+    limit: usize,
+    mut coalesce: Option<&mut dyn FnMut(UEvent)>,
+    depth: &AtomicUsize,
+    mut f: F,
+) where
+    S: Stream<Item = UEvent>,
+    F: FnMut(UEvent) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    enum Next {
+        Event(UEvent),
+        Done(PathBuf),
+        End,
+    }
+
+    let mut stream = pin!(stream);
+    let mut ended = false;
+    let mut queue: VecDeque<UEvent> = VecDeque::new();
+    // DEVPATHs of the events being handled
+    let mut busy: Vec<PathBuf> = Vec::new();
+    let mut running = FuturesUnordered::new();
+    let queued = limit.max(1) * QUEUED_PER_TASK;
+
+    loop {
+        // an event blocked by a running one blocks the later ones of the same devices too
+        let mut blocked: Vec<PathBuf> = Vec::new();
+        let mut i = 0;
+        while i < queue.len() && running.len() < limit.max(1) {
+            let devpath = &queue[i].devpath;
+            if busy
+                .iter()
+                .chain(&blocked)
+                .any(|other| related(devpath, other))
+            {
+                blocked.push(devpath.clone());
+                i += 1;
+                continue;
+            }
+            let ev = queue.remove(i).unwrap();
+            let devpath = ev.devpath.clone();
+            busy.push(devpath.clone());
+            running.push(f(ev).map(move |()| devpath));
+        }
+        depth.store(queue.len() + running.len(), Ordering::Relaxed);
+
+        if ended && running.is_empty() && queue.is_empty() {
+            return;
+        }
+
+        let next = poll_fn(|cx| {
+            if let Poll::Ready(Some(devpath)) = running.poll_next_unpin(cx) {
+                return Poll::Ready(Next::Done(devpath));
+            }
+            if !ended && queue.len() < queued {
+                match stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(ev)) => return Poll::Ready(Next::Event(ev)),
+                    Poll::Ready(None) => return Poll::Ready(Next::End),
+                    Poll::Pending => {}
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        match next {
+            Next::Event(ev) => match coalesce.as_mut() {
+                Some(dropped) => self::coalesce(&mut queue, ev, dropped),
+                None => queue.push_back(ev),
+            },
+            Next::Done(devpath) => {
+                if let Some(i) = busy.iter().position(|busy| *busy == devpath) {
+                    busy.swap_remove(i);
+                }
+            }
+            Next::End => ended = true,
+        }
+    }
+}
+
+/// Whether the events of the devices at `a` and `b` have to be handled in order
+fn related(a: &Path, b: &Path) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+/// Whether `a` and `b` carry the same properties, SEQNUM apart
+fn same_properties(a: &UEvent, b: &UEvent) -> bool {
+    let properties = |ev: &UEvent| ev.env.len() - usize::from(ev.env.contains_key("SEQNUM"));
+    properties(a) == properties(b)
+        && a.env
+            .iter()
+            .all(|(name, value)| name == "SEQNUM" || b.env.get(name) == Some(value))
+}
+
+/// Queues `ev`, merging it with the last queued event of the same device
+///
+/// The last event is replaced if it has the same action and properties but SEQNUM, and dropped
+/// along `ev` if it is an add cancelled by a remove. An event of a parent or child in between
+/// keeps both. The events replaced or cancelled are passed to `dropped`.
+pub fn coalesce(queue: &mut VecDeque<UEvent>, ev: UEvent, mut dropped: impl FnMut(UEvent)) {
+    let last = queue
+        .iter()
+        .rposition(|queued| related(&queued.devpath, &ev.devpath))
+        .filter(|&i| queue[i].devpath == ev.devpath);
+    if let Some(i) = last {
+        match (queue[i].action, ev.action) {
+            (last, action) if last == action && same_properties(&queue[i], &ev) => {
+                dropped(mem::replace(&mut queue[i], ev));
+                return;
+            }
+            (ActionType::Add, ActionType::Remove) => {
+                if let Some(add) = queue.remove(i) {
+                    dropped(add);
+                }
+                dropped(ev);
+                return;
+            }
+            _ => {}
+        }
+    }
+    queue.push_back(ev);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use futures_util::stream;
+
+    use super::*;
+
+    fn event(seq: u64, action: ActionType) -> Result<UEvent, Error> {
+        Ok(UEvent {
+            action,
+            ..device_event(seq, "/devices/virtual/block/loop0")
+        })
+    }
+
+    fn device_event(seq: u64, devpath: &str) -> UEvent {
+        UEvent {
+            action: ActionType::Add,
+            devpath: PathBuf::from(devpath),
+            subsystem: String::from("block"),
+            env: HashMap::new(),
+            seq,
+        }
+    }
+
+    #[tokio::test]
+    async fn filter() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut addr).unwrap();
+        attach_filter(&socket, &[ActionType::Add, ActionType::Remove]).unwrap();
+
+        let packets: [&[u8]; 4] = [
+            // dropped by the kernel
+            b"change@/devices/virtual/block/loop0\0ACTION=change\0\
+              DEVPATH=/devices/virtual/block/loop0\0SUBSYSTEM=block\0SEQNUM=1",
+            b"add@/devices/virtual/mem/null\0ACTION=add\0\
+              DEVPATH=/devices/virtual/mem/null\0SUBSYSTEM=mem\0SEQNUM=2",
+            b"add@/devices/virtual/block/loop0\0ACTION=add\0\
+              DEVPATH=/devices/virtual/block/loop0\0SUBSYSTEM=block\0SEQNUM=3",
+            // rebroadcast, with no header
+            b"ACTION=remove\0DEVPATH=/devices/virtual/block/loop0\0SUBSYSTEM=block\0SEQNUM=4",
+        ];
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        for packet in packets {
+            sender.send_to(packet, &addr, 0).unwrap();
+        }
+
+        let filter = PacketFilter {
+            subsystems: vec![String::from("block")],
+            actions: Vec::new(),
+        };
+        let events = UEvents::new(
+            Listener(socket).into_socket().unwrap(),
+            filter,
+            128,
+            Some(Origin::Any),
+        );
+        let seqs: Vec<_> = events.take(2).map(|ev| ev.unwrap().seq).collect().await;
+        assert_eq!(seqs, [3, 4]);
+
+        let filter = PacketFilter {
+            subsystems: Vec::new(),
+            actions: vec![ActionType::Remove],
+        };
+        assert!(!filter.allows(packets[2]));
+        assert!(filter.allows(packets[3]));
+        // reported by the parser
+        assert!(filter.allows(b"SEQNUM=5"));
+    }
+
+    #[tokio::test]
+    async fn filter_rejects_add() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut addr).unwrap();
+        attach_filter(&socket, &[ActionType::Remove]).unwrap();
+
+        let packets: [&[u8]; 2] = [
+            // dropped by the kernel
+            b"add@/devices/virtual/block/loop0\0ACTION=add\0\
+              DEVPATH=/devices/virtual/block/loop0\0SUBSYSTEM=block\0SEQNUM=1",
+            b"remove@/devices/virtual/block/loop0\0ACTION=remove\0\
+              DEVPATH=/devices/virtual/block/loop0\0SUBSYSTEM=block\0SEQNUM=2",
+        ];
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        for packet in packets {
+            sender.send_to(packet, &addr, 0).unwrap();
+        }
+
+        let events = UEvents::new(
+            Listener(socket).into_socket().unwrap(),
+            PacketFilter::default(),
+            128,
+            Some(Origin::Any),
+        );
+        let seqs: Vec<_> = events.take(1).map(|ev| ev.unwrap().seq).collect().await;
+        assert_eq!(seqs, [2]);
+    }
+
+    #[tokio::test]
+    async fn buffers() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let events = UEventsBuilder::new()
+            .receive_buffer(1 << 16)
+            .read_buffer(4096)
+            .build()
+            .unwrap();
+        // doubled by the kernel for its bookkeeping
+        assert_eq!(events.receive_buffer().unwrap(), 1 << 17);
+        assert_eq!(events.read_buffer(), 4096);
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut addr).unwrap();
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        sender
+            .send_to(b"ACTION=add\0DEVPATH=/devices/virtual/mem/null", &addr, 0)
+            .unwrap();
+        let mut events = UEvents::new(
+            Listener(socket).into_socket().unwrap(),
+            PacketFilter::default(),
+            16,
+            Some(Origin::Any),
+        );
+        let e = events.next().await.unwrap().unwrap_err();
+        assert!(matches!(e, Error::Receive(e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[tokio::test]
+    async fn origin() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let packet = b"ACTION=add\0DEVPATH=/devices/virtual/mem/null\0SUBSYSTEM=mem\0SEQNUM=1";
+        let uid = nix::unistd::getuid().as_raw();
+        for origin in [Origin::Kernel, Origin::Root, Origin::Any] {
+            let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+            socket.bind_auto().unwrap();
+            let mut addr = SocketAddr::new(0, 0);
+            socket.get_address(&mut addr).unwrap();
+            let mut sender = Socket::new(NETLINK_USERSOCK).unwrap();
+            sender.bind_auto().unwrap();
+            let mut sender_addr = SocketAddr::new(0, 0);
+            sender.get_address(&mut sender_addr).unwrap();
+            sender.send_to(packet, &addr, 0).unwrap();
+
+            let mut events = UEvents::new(
+                Listener(socket).into_socket().unwrap(),
+                PacketFilter::default(),
+                READ_BUFFER,
+                Some(origin),
+            );
+            let ev = events.next().await.unwrap();
+            match (origin, uid) {
+                (Origin::Kernel, _) | (Origin::Root, 1..) => {
+                    let Err(Error::Spoofed { port, uid: sent_by }) = ev else {
+                        panic!("{ev:?} accepted from {origin:?}");
+                    };
+                    assert_eq!(port, sender_addr.port_number());
+                    assert_eq!(sent_by, Some(uid));
+                }
+                _ => assert_eq!(ev.unwrap().seq, 1),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn groups() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let other = 1 << 18;
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket
+            .bind(&SocketAddr::new(0, KERNEL_GROUP | other))
+            .unwrap();
+        let mut events = UEvents::new(
+            Listener(socket).into_socket().unwrap(),
+            PacketFilter::default(),
+            READ_BUFFER,
+            None,
+        );
+
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        for (seq, group) in [(1, other), (2, KERNEL_GROUP)] {
+            let packet = format!(
+                "ACTION=add\0DEVPATH=/devices/virtual/mem/null\0SUBSYSTEM=mem\0SEQNUM={seq}"
+            );
+            // broadcast, then refused by the port 0 missing on this protocol
+            let _ = sender.send_to(packet.as_bytes(), &SocketAddr::new(0, group), 0);
+        }
+
+        let ev = events.next().await.unwrap();
+        if nix::unistd::getuid().is_root() {
+            assert_eq!(ev.unwrap().seq, 1);
+        } else {
+            assert!(matches!(ev, Err(Error::Spoofed { .. })));
+        }
+        // only the kernel sends to its group
+        let ev = events.next().await.unwrap();
+        assert!(matches!(ev, Err(Error::Spoofed { port, .. }) if port != 0));
+    }
+
+    #[tokio::test]
+    async fn overflow() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        // a multicast group, the kernel drops what overflows the receive buffer
+        let group = 1 << 17;
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind(&SocketAddr::new(0, group)).unwrap();
+        socket.set_rx_buf_sz(1).unwrap();
+        let mut events = UEvents::new(
+            Listener(socket).into_socket().unwrap(),
+            PacketFilter::default(),
+            READ_BUFFER,
+            Some(Origin::Any),
+        );
+
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        let send = |seq: u64| {
+            let packet = format!(
+                "ACTION=add\0DEVPATH=/devices/virtual/mem/null\0SUBSYSTEM=mem\0SEQNUM={seq}"
+            );
+            // broadcast, then refused by the port 0 missing on this protocol
+            let _ = sender.send_to(packet.as_bytes(), &SocketAddr::new(0, group), 0);
+        };
+        for seq in 1..=100 {
+            send(seq);
+        }
+        let ev = events.next().await.unwrap();
+        assert!(
+            matches!(ev, Err(Error::Overflow { drained }) if drained > 0),
+            "{ev:?}"
+        );
+
+        // the next events are received again
+        send(101);
+        assert_eq!(events.next().await.unwrap().unwrap().seq, 101);
+    }
+
+    /// Polls the socket again and again, with no runtime
+    struct Spin(Socket);
+
+    impl Reactor for Spin {
+        type Timer = std::future::Ready<()>;
+
+        fn register(socket: Socket) -> io::Result<Self> {
+            Ok(Self(socket))
+        }
+
+        fn get_ref(&self) -> &Socket {
+            &self.0
+        }
+
+        fn poll_read_with<T>(
+            &self,
+            cx: &mut Context<'_>,
+            mut f: impl FnMut(&Socket) -> io::Result<T>,
+        ) -> Poll<io::Result<T>> {
+            match f(&self.0) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                res => Poll::Ready(res),
+            }
+        }
+
+        fn poll_write_with<T>(
+            &self,
+            _: &mut Context<'_>,
+            mut f: impl FnMut(&Socket) -> io::Result<T>,
+        ) -> Poll<io::Result<T>> {
+            Poll::Ready(f(&self.0))
+        }
+
+        fn timer(_: Duration) -> Self::Timer {
+            std::future::ready(())
+        }
+    }
+
+    #[test]
+    fn reactor() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut addr).unwrap();
+        let mut events = UEvents::new(
+            Listener(socket).register::<Spin>().unwrap(),
+            PacketFilter::default(),
+            READ_BUFFER,
+            Some(Origin::Any),
+        );
+        assert!(events.next().now_or_never().is_none());
+
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        sender
+            .send_to(
+                b"ACTION=add\0DEVPATH=/devices/virtual/mem/null\0SUBSYSTEM=mem\0SEQNUM=1",
+                &addr,
+                0,
+            )
+            .unwrap();
+        let ev = events.next().now_or_never().unwrap().unwrap().unwrap();
+        assert_eq!(ev.seq, 1);
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn async_io() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut addr).unwrap();
+        let mut events = UEvents::new(
+            Listener(socket)
+                .register::<async_io::Async<Socket>>()
+                .unwrap(),
+            PacketFilter::default(),
+            READ_BUFFER,
+            Some(Origin::Any),
+        );
+
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        sender
+            .send_to(
+                b"ACTION=add\0DEVPATH=/devices/virtual/mem/null\0SUBSYSTEM=mem\0SEQNUM=1",
+                &addr,
+                0,
+            )
+            .unwrap();
+        let ev = async_io::block_on(events.next()).unwrap().unwrap();
+        assert_eq!(ev.seq, 1);
+    }
+
+    #[tokio::test]
+    async fn republished() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut addr).unwrap();
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        for (seq, devpath) in [
+            (1, "/devices/virtual/mem/null"),
+            (2, "/devices/virtual/block/loop0"),
+        ] {
+            let mut ev = device_event(seq, devpath);
+            if seq == 1 {
+                ev.subsystem = String::from("mem");
+            }
+            let mut packet = Vec::new();
+            libudev::encode(&ev, &mut packet);
+            sender.send_to(&packet, &addr, 0).unwrap();
+        }
+        sender.send_to(b"libudev\0garbage", &addr, 0).unwrap();
+
+        let filter = PacketFilter {
+            subsystems: vec![String::from("block")],
+            actions: Vec::new(),
+        };
+        let mut events = UEvents::new(
+            Listener(socket).into_socket().unwrap(),
+            filter,
+            READ_BUFFER,
+            Some(Origin::Any),
+        );
+        let ev = events.next().await.unwrap().unwrap();
+        assert_eq!(ev.seq, 2);
+        assert_eq!(ev.devpath, Path::new("/devices/virtual/block/loop0"));
+        let ev = events.next().await.unwrap();
+        assert!(matches!(ev, Err(Error::Libudev(_))), "{ev:?}");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn blocking() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let group = 1 << 16;
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind(&SocketAddr::new(0, group)).unwrap();
+        socket.set_rx_buf_sz(1).unwrap();
+        pass_credentials(&socket).unwrap();
+        let mut events = BlockingUEvents {
+            socket,
+            buf: Vec::with_capacity(READ_BUFFER),
+            filter: PacketFilter {
+                subsystems: vec![String::from("block")],
+                actions: Vec::new(),
+            },
+            origin: Some(Origin::Any),
+            done: false,
+        };
+
+        let sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        let send = |seq: u64, subsystem: &str| {
+            let packet = format!(
+                "ACTION=add\0DEVPATH=/devices/virtual/{subsystem}/foo\0\
+                 SUBSYSTEM={subsystem}\0SEQNUM={seq}"
+            );
+            // broadcast, then refused by the port 0 missing on this protocol
+            let _ = sender.send_to(packet.as_bytes(), &SocketAddr::new(0, group), 0);
+        };
+        for seq in 1..=100 {
+            send(seq, "block");
+        }
+        let ev = events.next().unwrap();
+        assert!(
+            matches!(ev, Err(Error::Overflow { drained }) if drained > 0),
+            "{ev:?}"
+        );
+
+        // blocking again once drained
+        send(101, "mem");
+        send(102, "block");
+        assert_eq!(events.next().unwrap().unwrap().seq, 102);
+    }
+
+    #[tokio::test]
+    async fn raw() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let mut addr = SocketAddr::new(0, 0);
+        socket.get_address(&mut addr).unwrap();
+        let mut sender = Socket::new(NETLINK_USERSOCK).unwrap();
+        sender.bind_auto().unwrap();
+        let mut sender_addr = SocketAddr::new(0, 0);
+        sender.get_address(&mut sender_addr).unwrap();
+
+        let mut republished = Vec::new();
+        libudev::encode(
+            &device_event(1, "/devices/virtual/block/loop0"),
+            &mut republished,
+        );
+        let packets: [&[u8]; 3] = [
+            b"ACTION=add\0DEVPATH=/devices/virtual/mem/null\0SUBSYSTEM=mem\0SEQNUM=2",
+            &republished,
+            // left to the parser of the consumer
+            b"ACTION=add\0SUBSYSTEM=block\0SEQNUM=3\0unparsable",
+        ];
+        for packet in packets {
+            sender.send_to(packet, &addr, 0).unwrap();
+        }
+
+        let filter = PacketFilter {
+            subsystems: vec![String::from("block")],
+            actions: Vec::new(),
+        };
+        let mut packets = RawPackets::new(
+            Listener(socket).into_socket().unwrap(),
+            filter,
+            READ_BUFFER,
+            Some(Origin::Any),
+        );
+        let packet = packets.next().await.unwrap().unwrap();
+        assert_eq!(packet.data, republished);
+        assert_eq!(packet.addr.port_number(), sender_addr.port_number());
+        assert_eq!(packet.uid, Some(nix::unistd::getuid().as_raw()));
+        let packet = packets.next().await.unwrap().unwrap();
+        assert!(packet.data.ends_with(b"unparsable"));
+    }
+
+    #[tokio::test]
+    async fn close() {
+        use netlink_sys::constants::NETLINK_USERSOCK;
+
+        let mut socket = Socket::new(NETLINK_USERSOCK).unwrap();
+        socket.bind_auto().unwrap();
+        let events = UEvents::new(
+            Listener(socket).into_socket().unwrap(),
+            PacketFilter::default(),
+            READ_BUFFER,
+            Some(Origin::Any),
+        );
+        let closer = events.closer();
+        let close = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            closer.close();
+        };
+        // waiting for a packet meanwhile
+        let (events, ()) = tokio::join!(events.collect::<Vec<_>>(), close);
+        assert!(events.is_empty());
+        assert!(closer.is_closed());
+    }
+
+    #[tokio::test]
+    async fn shared() {
+        let events = || {
+            stream::iter([
+                event(1, ActionType::Add),
+                Err(Error::Libudev("bad magic")),
+                event(2, ActionType::Add),
+                event(3, ActionType::Remove),
+            ])
+        };
+        let mut shared = SharedUEvents::new(events(), 4);
+        let subscribers = [shared.subscribe(), shared.subscribe()];
+        (&mut shared).await;
+        // the stream has ended, nothing is left
+        assert!(shared.subscribe().next().await.is_none());
+        for subscriber in subscribers {
+            let received: Vec<_> = subscriber.collect().await;
+            assert_eq!(received.len(), 4);
+            assert_eq!(received[0].as_ref().unwrap().seq, 1);
+            assert!(
+                matches!(&received[1], Err(Error::Shared(e)) if matches!(**e, Error::Libudev(_)))
+            );
+            assert_eq!(received[3].as_ref().unwrap().action, ActionType::Remove);
+        }
+
+        // the oldest events are missed
+        let mut shared = SharedUEvents::new(events(), 2);
+        let mut subscriber = shared.subscribe();
+        (&mut shared).await;
+        let ev = subscriber.next().await.unwrap();
+        assert!(matches!(ev, Err(Error::Lagged { missed: 2 })), "{ev:?}");
+        assert_eq!(subscriber.next().await.unwrap().unwrap().seq, 2);
+        assert_eq!(subscriber.next().await.unwrap().unwrap().seq, 3);
+        assert!(subscriber.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reorder() {
+        let events = stream::iter([
+            event(11, ActionType::Add),
+            event(10, ActionType::Remove),
+            event(12, ActionType::Change),
+        ]);
+        let seqs: Vec<_> = super::reorder(events, Duration::from_millis(10))
+            .map(|ev| ev.unwrap().seq)
+            .collect()
+            .await;
+        assert_eq!(seqs, [10, 11, 12]);
+
+        // the socket stays open, the window elapses instead
+        let events = stream::iter([event(21, ActionType::Add), event(20, ActionType::Add)])
+            .chain(stream::pending());
+        let seqs: Vec<_> = super::reorder(events, Duration::from_millis(10))
+            .take(2)
+            .map(|ev| ev.unwrap().seq)
+            .collect()
+            .await;
+        assert_eq!(seqs, [20, 21]);
+
+        // too late to be reordered
+        let events = stream::iter([(0, 31), (50, 30)])
+            .then(|(delay, seq)| async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                event(seq, ActionType::Add)
+            })
+            .chain(stream::pending());
+        let seqs: Vec<_> = super::reorder(Box::pin(events), Duration::from_millis(10))
+            .take(2)
+            .map(|ev| ev.unwrap().seq)
+            .collect()
+            .await;
+        assert_eq!(seqs, [31, 30]);
+    }
+
+    #[tokio::test]
+    async fn dedup() {
+        let events = stream::iter([
+            event(1, ActionType::Add),
+            event(2, ActionType::Add),
+            event(1, ActionType::Add),
+            Err(Error::Libudev("bad magic")),
+            event(2, ActionType::Add),
+            // not the same event
+            event(2, ActionType::Remove),
+            Ok(device_event(2, "/devices/virtual/block/loop1")),
+        ]);
+        let events: Vec<_> = super::dedup(events, Duration::from_secs(1)).collect().await;
+        assert_eq!(events.len(), 5);
+        assert!(matches!(events[2], Err(Error::Libudev(_))));
+        let seqs: Vec<_> = events.iter().flatten().map(|ev| ev.seq).collect();
+        assert_eq!(seqs, [1, 2, 2, 2]);
+
+        // delivered again once the window is over
+        let events = stream::iter([(0, 1), (5, 1), (30, 1)]).then(|(delay, seq)| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            event(seq, ActionType::Add)
+        });
+        let events: Vec<_> = super::dedup(Box::pin(events), Duration::from_millis(20))
+            .collect()
+            .await;
+        assert_eq!(events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn debounce() {
+        let change = |seq, devpath| UEvent {
+            action: ActionType::Change,
+            ..device_event(seq, devpath)
+        };
+        let events = stream::iter([
+            change(1, "/devices/virtual/thermal/thermal_zone0"),
+            change(2, "/devices/virtual/thermal/thermal_zone0"),
+            device_event(3, "/devices/virtual/block/loop0"),
+            change(4, "/devices/virtual/thermal/thermal_zone0"),
+            change(5, "/devices/virtual/block/loop0"),
+            UEvent {
+                action: ActionType::Remove,
+                ..device_event(6, "/devices/virtual/block/loop0")
+            },
+        ])
+        .chain(stream::pending());
+        let mut dropped = Vec::new();
+        let seqs: Vec<_> =
+            super::debounce(events, Duration::from_millis(10), |ev| dropped.push(ev.seq))
+                .take(4)
+                .map(|ev| ev.seq)
+                .collect()
+                .await;
+        // the remove delivers the held change of its device first
+        assert_eq!(seqs, [3, 5, 6, 4]);
+        assert_eq!(dropped, [1, 2]);
+
+        // held until the end of the window at most
+        let events = stream::iter([(0, 1), (5, 2), (30, 3)])
+            .then(|(delay, seq)| async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                change(seq, "/devices/virtual/thermal/thermal_zone0")
+            })
+            .chain(stream::pending());
+        let seqs: Vec<_> = super::debounce(Box::pin(events), Duration::from_millis(15), drop)
+            .take(2)
+            .map(|ev| ev.seq)
+            .collect()
+            .await;
+        assert_eq!(seqs, [2, 3]);
+
+        // not merged with other properties, e.g. a media change and an eject request
+        let with = |seq: u64, name: &str| UEvent {
+            env: HashMap::from([
+                (String::from(name), String::from("1")),
+                (String::from("SEQNUM"), seq.to_string()),
+            ]),
+            ..change(seq, "/devices/virtual/block/loop0")
+        };
+        let events = stream::iter([
+            with(1, "DISK_MEDIA_CHANGE"),
+            with(2, "DISK_MEDIA_CHANGE"),
+            with(3, "DISK_EJECT_REQUEST"),
+        ])
+        .chain(stream::pending());
+        let seqs: Vec<_> = super::debounce(events, Duration::from_millis(10), drop)
+            .take(2)
+            .map(|ev| ev.seq)
+            .collect()
+            .await;
+        assert_eq!(seqs, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn for_each_device() {
+        let events = stream::iter([
+            // slow, the events of sda and its partitions wait for it
+            device_event(1, "/devices/pci0000:00/ata1/host0/block/sda"),
+            device_event(2, "/devices/pci0000:00/ata1/host0/block/sda/sda1"),
+            device_event(3, "/devices/virtual/block/loop0"),
+            device_event(4, "/devices/pci0000:00/ata1/host0/block/sda"),
+            device_event(5, "/devices/virtual/block/loop1"),
+        ]);
+        let done = std::cell::RefCell::new(Vec::new());
+        let depth = AtomicUsize::new(0);
+        super::for_each_device(events, 4, None, &depth, |ev| {
+            let (done, depth) = (&done, &depth);
+            async move {
+                if ev.seq == 1 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    // sda1 and sda wait for it
+                    assert_eq!(depth.load(Ordering::Relaxed), 3);
+                }
+                done.borrow_mut().push(ev.seq);
+            }
+        })
+        .await;
+        assert_eq!(done.into_inner(), [3, 5, 1, 2, 4]);
+        assert_eq!(depth.load(Ordering::Relaxed), 0);
+
+        // one at a time
+        let events = stream::iter([
+            device_event(1, "/devices/virtual/block/loop0"),
+            device_event(2, "/devices/virtual/block/loop1"),
+        ]);
+        let done = std::cell::RefCell::new(Vec::new());
+        super::for_each_device(events, 1, None, &AtomicUsize::new(0), |ev| {
+            let done = &done;
+            async move {
+                if ev.seq == 1 {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                done.borrow_mut().push(ev.seq);
+            }
+        })
+        .await;
+        assert_eq!(done.into_inner(), [1, 2]);
+
+        // received no faster than handled
+        let received = std::cell::Cell::new(0);
+        let events = stream::iter(1..=1000)
+            .map(|seq| device_event(seq, "/devices/virtual/block/loop0"))
+            .inspect(|_| received.set(received.get() + 1));
+        let backlog = std::cell::Cell::new(0);
+        super::for_each_device(events, 2, None, &AtomicUsize::new(0), |ev| {
+            let (received, backlog) = (&received, &backlog);
+            async move {
+                backlog.set(backlog.get().max(received.get() - ev.seq));
+                tokio::task::yield_now().await;
+            }
+        })
+        .await;
+        assert_eq!(received.get(), 1000);
+        assert!(backlog.get() <= 2 * super::QUEUED_PER_TASK as u64);
+    }
+
+    #[tokio::test]
+    async fn coalesce() {
+        let with = |action, seq, devpath| UEvent {
+            action,
+            ..device_event(seq, devpath)
+        };
+        let events = stream::iter([
+            // slow, the next ones are queued meanwhile
+            with(ActionType::Add, 1, "/devices/virtual/block/loop0"),
+            with(ActionType::Change, 2, "/devices/virtual/block/loop0"),
+            with(ActionType::Change, 3, "/devices/virtual/block/loop0"),
+            with(ActionType::Add, 4, "/devices/virtual/block/loop1"),
+            with(ActionType::Remove, 5, "/devices/virtual/block/loop1"),
+            with(ActionType::Add, 6, "/devices/virtual/block/loop2"),
+            with(ActionType::Add, 7, "/devices/virtual/block/loop2/loop2p1"),
+            with(ActionType::Remove, 8, "/devices/virtual/block/loop2"),
+        ]);
+        let done = std::cell::RefCell::new(Vec::new());
+        let mut dropped = Vec::new();
+        let mut drop = |ev: UEvent| dropped.push(ev.seq);
+        super::for_each_device(events, 1, Some(&mut drop), &AtomicUsize::new(0), |ev| {
+            let done = &done;
+            async move {
+                if ev.seq == 1 {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                done.borrow_mut().push(ev.seq);
+            }
+        })
+        .await;
+        assert_eq!(done.into_inner(), [1, 3, 6, 7, 8]);
+        // reported as done too
+        dropped.sort();
+        assert_eq!(dropped, [2, 4, 5]);
+
+        let mut queue = VecDeque::new();
+        for (seq, name) in [(1, "DISK_MEDIA_CHANGE"), (2, "DISK_EJECT_REQUEST")] {
+            let ev = UEvent {
+                env: HashMap::from([
+                    (String::from(name), String::from("1")),
+                    (String::from("SEQNUM"), seq.to_string()),
+                ]),
+                ..with(ActionType::Change, seq, "/devices/virtual/block/loop0")
+            };
+            super::coalesce(&mut queue, ev, |_| panic!("not merged"));
+        }
+        assert_eq!(queue.len(), 2);
     }
 }
@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tracing::info;
+
+use crate::{rule::is_safe_name, Error, Result};
+
+/// Directory exposing the kernel parameters
+const PROC_SYS: &str = "/proc/sys";
+
+/// Converts a `net.ipv4.ip_forward` style key to the `/` separated form
+///
+/// Keys already containing a `/` are kept as they are, as `sysctl` does, so that components
+/// with dots such as VLAN interfaces can be written, e.g. `net/ipv4/conf/eth0.100/forwarding`.
+pub fn normalize_key(key: &str) -> String {
+    if key.contains('/') {
+        key.to_string()
+    } else {
+        key.replace('.', "/")
+    }
+}
+
+/// Returns the path of the `/` separated `key` under `/proc/sys`
+pub fn path(key: &str) -> Option<PathBuf> {
+    is_safe_name(key).then(|| Path::new(PROC_SYS).join(key))
+}
+
+/// Sets the kernel parameter `key`, given in the `/` separated form
+pub async fn set(key: &str, value: &str) -> Result<()> {
+    let Some(path) = path(key) else {
+        return Err(Error::InvalidSysctl(key.to_string()));
+    };
+    info!("Setting {} to {:?}", key, value);
+    fs::write(&path, value)
+        .await
+        .map_err(|source| Error::SysctlFailed {
+            key: key.to_string(),
+            value: value.to_string(),
+            source,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(normalize_key("net.ipv4.ip_forward"), "net/ipv4/ip_forward");
+        assert_eq!(
+            normalize_key("net/ipv4/conf/eth0.100/forwarding"),
+            "net/ipv4/conf/eth0.100/forwarding"
+        );
+        assert_eq!(
+            path("net/ipv6/conf/wlan0/disable_ipv6").as_deref(),
+            Some(Path::new("/proc/sys/net/ipv6/conf/wlan0/disable_ipv6"))
+        );
+        assert_eq!(path("net/../../etc/passwd"), None);
+        assert_eq!(path(""), None);
+    }
+}
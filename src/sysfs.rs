@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use tokio::fs;
+use tracing::info;
+
+use crate::{rule::is_safe_name, Error, Result};
+
+/// Writes `value` to the attribute `name` of the sysfs directory `device`
+///
+/// `name` is relative to the device, e.g. `power/control`, and cannot leave its directory.
+pub async fn write_attr(device: &Path, name: &str, value: &str) -> Result<()> {
+    if !is_safe_name(name) {
+        return Err(Error::InvalidAttribute(name.to_string()));
+    }
+    let path = device.join(name);
+    info!("Writing {:?} to {:?}", value, path);
+    fs::write(&path, value)
+        .await
+        .map_err(|source| Error::WriteAttrFailed {
+            path,
+            value: value.to_string(),
+            source,
+        })
+}
+
+/// Asks the kernel to emit again an `action` event for `device`, e.g. `change`
+pub async fn trigger(device: &Path, action: &str) -> Result<()> {
+    write_attr(device, "uevent", action).await
+}
+
+/// Reads the attribute `name` of the sysfs directory `dir`, `None` if it is missing or empty
+pub async fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    let value = fs::read_to_string(dir.join(name)).await.ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    #[tokio::test]
+    async fn write_attr() {
+        let device = env::temp_dir().join(format!("mdev-sysfs-{}", process::id()));
+        fs::create_dir_all(device.join("power")).unwrap();
+        fs::write(device.join("power/control"), "on").unwrap();
+
+        super::write_attr(&device, "power/control", "auto")
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(device.join("power/control")).unwrap(),
+            "auto"
+        );
+        assert!(super::write_attr(&device, "../authorized", "1")
+            .await
+            .is_err());
+        assert!(super::write_attr(&device, "/sys/kernel/uevent_helper", "")
+            .await
+            .is_err());
+
+        fs::remove_dir_all(&device).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Log lines sent to syslog, at the priority matching their level
+//!
+//! The lines are formatted by the fmt layer of tracing, without the time and the level that
+//! syslog records on its own.
+
+use std::{
+    ffi::{c_int, CString},
+    io, ptr,
+};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Writer of the log lines to syslog, with the `daemon` facility
+#[derive(Debug)]
+pub struct Syslog(());
+
+impl Syslog {
+    /// Tags the lines with the program name and the pid, the connection is opened by the first
+    /// line
+    pub fn open() -> Self {
+        // SAFETY: without an ident glibc uses the program name, no pointer is kept
+        unsafe { libc::openlog(ptr::null(), libc::LOG_PID, libc::LOG_DAEMON) };
+        Self(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Line::new(libc::LOG_INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Line::new(priority(*meta.level()))
+    }
+}
+
+/// Priority of the lines of `level`
+pub fn priority(level: Level) -> c_int {
+    match level {
+        Level::ERROR => libc::LOG_ERR,
+        Level::WARN => libc::LOG_WARNING,
+        Level::INFO => libc::LOG_INFO,
+        Level::DEBUG | Level::TRACE => libc::LOG_DEBUG,
+    }
+}
+
+/// A line being formatted, sent once complete
+#[derive(Debug)]
+pub struct Line {
+    priority: c_int,
+    buf: Vec<u8>,
+}
+
+impl Line {
+    fn new(priority: c_int) -> Self {
+        Self {
+            priority,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl io::Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        if buf.is_empty() {
+            return;
+        }
+        // a NUL would cut the line short
+        buf.iter_mut().filter(|b| **b == 0).for_each(|b| *b = b' ');
+        let line = CString::new(buf).unwrap();
+        // SAFETY: the format takes a single string, `line` is NUL terminated
+        unsafe { libc::syslog(self.priority, c"%s".as_ptr(), line.as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priorities() {
+        assert_eq!(priority(Level::ERROR), libc::LOG_ERR);
+        assert_eq!(priority(Level::WARN), libc::LOG_WARNING);
+        assert_eq!(priority(Level::INFO), libc::LOG_INFO);
+        assert_eq!(priority(Level::DEBUG), libc::LOG_DEBUG);
+        assert_eq!(priority(Level::TRACE), libc::LOG_DEBUG);
+
+        // empty, the lines are not sent when dropped
+        let line = Line::new(priority(Level::WARN));
+        assert_eq!(line.priority, libc::LOG_WARNING);
+        let line = Syslog(()).make_writer();
+        assert_eq!(line.priority, libc::LOG_INFO);
+    }
+}
@@ -0,0 +1,229 @@
+//! Descriptions of the dev directory for building images without creating the nodes
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Format of the description
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Device table, as read by `makedevs`, `genext2fs` and `mkfs.jffs2`
+    ///
+    /// Symlinks cannot be described, they are written as comments.
+    DeviceTable,
+    /// File list of the kernel `gen_init_cpio`, as used for initramfs images
+    CpioList,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "device-table" => Ok(Self::DeviceTable),
+            "cpio-list" => Ok(Self::CpioList),
+            _ => Err(format!(
+                "unknown format {s:?}, expected device-table or cpio-list"
+            )),
+        }
+    }
+}
+
+/// Kind of device node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Kind {
+    Char,
+    Block,
+}
+
+impl Kind {
+    pub(crate) fn as_char(self) -> char {
+        match self {
+            Self::Char => 'c',
+            Self::Block => 'b',
+        }
+    }
+}
+
+/// Device node, with its numeric owner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// What would be created in the dev directory
+#[derive(Debug, Default)]
+pub struct Table {
+    dirs: BTreeSet<PathBuf>,
+    nodes: Vec<Node>,
+    links: Vec<(PathBuf, PathBuf)>,
+    /// Directories with a mode other than the default `0755`
+    modes: Vec<(PathBuf, u32)>,
+}
+
+impl Table {
+    pub fn push_dir(&mut self, path: &Path, mode: u32) {
+        self.push_parents(path);
+        self.dirs.insert(path.to_path_buf());
+        if mode != 0o755 {
+            self.modes.push((path.to_path_buf(), mode));
+        }
+    }
+
+    pub fn push_node(&mut self, node: Node) {
+        self.push_parents(&node.path);
+        self.nodes.push(node);
+    }
+
+    /// Sets the owner of the node at `path`, if described
+    pub fn chown(&mut self, path: &Path, uid: u32, gid: u32) {
+        if let Some(node) = self.nodes.iter_mut().rev().find(|node| node.path == path) {
+            node.uid = uid;
+            node.gid = gid;
+        }
+    }
+
+    pub fn push_link(&mut self, path: &Path, target: &Path) {
+        self.push_parents(path);
+        self.links.push((path.to_path_buf(), target.to_path_buf()));
+    }
+
+    /// Adds the directories containing `path`
+    fn push_parents(&mut self, path: &Path) {
+        for dir in path.ancestors().skip(1) {
+            if dir.parent().is_none() || dir.as_os_str().is_empty() {
+                break;
+            }
+            self.dirs.insert(dir.to_path_buf());
+        }
+    }
+
+    fn dir_mode(&self, dir: &Path) -> u32 {
+        self.modes
+            .iter()
+            .find(|(path, _)| path == dir)
+            .map_or(0o755, |&(_, mode)| mode)
+    }
+
+    /// Writes the table in the given format, directories first so that they exist when needed
+    pub fn render(&self, format: Format) -> String {
+        let mut out = String::new();
+        match format {
+            Format::DeviceTable => {
+                let _ = writeln!(
+                    out,
+                    "# <name> <type> <mode> <uid> <gid> <major> <minor> <start> <inc> <count>"
+                );
+                for dir in &self.dirs {
+                    let mode = self.dir_mode(dir);
+                    let _ = writeln!(out, "{} d {:o} 0 0 - - - - -", dir.display(), mode);
+                }
+                for node in &self.nodes {
+                    let _ = writeln!(
+                        out,
+                        "{} {} {:o} {} {} {} {} - - -",
+                        node.path.display(),
+                        node.kind.as_char(),
+                        node.mode,
+                        node.uid,
+                        node.gid,
+                        node.major,
+                        node.minor
+                    );
+                }
+                for (link, target) in &self.links {
+                    let _ = writeln!(out, "# {} -> {}", link.display(), target.display());
+                }
+            }
+            Format::CpioList => {
+                for dir in &self.dirs {
+                    let mode = self.dir_mode(dir);
+                    let _ = writeln!(out, "dir {} {:o} 0 0", dir.display(), mode);
+                }
+                for node in &self.nodes {
+                    let _ = writeln!(
+                        out,
+                        "nod {} {:o} {} {} {} {} {}",
+                        node.path.display(),
+                        node.mode,
+                        node.uid,
+                        node.gid,
+                        node.kind.as_char(),
+                        node.major,
+                        node.minor
+                    );
+                }
+                for (link, target) in &self.links {
+                    let _ = writeln!(out, "slink {} {} 777 0 0", link.display(), target.display());
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let mut table = Table::default();
+        table.push_node(Node {
+            path: PathBuf::from("/dev/null"),
+            kind: Kind::Char,
+            mode: 0o666,
+            uid: 0,
+            gid: 0,
+            major: 1,
+            minor: 3,
+        });
+        table.push_node(Node {
+            path: PathBuf::from("/dev/disk/sda"),
+            kind: Kind::Block,
+            mode: 0o660,
+            uid: 0,
+            gid: 6,
+            major: 8,
+            minor: 0,
+        });
+        table.push_link(Path::new("/dev/cdrom"), Path::new("/dev/sr0"));
+        table.push_dir(Path::new("/dev/shm"), 0o1777);
+
+        assert_eq!(
+            table.render(Format::CpioList),
+            "dir /dev 755 0 0\n\
+             dir /dev/disk 755 0 0\n\
+             dir /dev/shm 1777 0 0\n\
+             nod /dev/null 666 0 0 c 1 3\n\
+             nod /dev/disk/sda 660 0 6 b 8 0\n\
+             slink /dev/cdrom /dev/sr0 777 0 0\n"
+        );
+        assert_eq!(
+            table.render(Format::DeviceTable),
+            "# <name> <type> <mode> <uid> <gid> <major> <minor> <start> <inc> <count>\n\
+             /dev d 755 0 0 - - - - -\n\
+             /dev/disk d 755 0 0 - - - - -\n\
+             /dev/shm d 1777 0 0 - - - - -\n\
+             /dev/null c 666 0 0 1 3 - - -\n\
+             /dev/disk/sda b 660 0 6 8 0 - - -\n\
+             # /dev/cdrom -> /dev/sr0\n"
+        );
+        assert_eq!("cpio-list".parse(), Ok(Format::CpioList));
+        assert!("tar".parse::<Format>().is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+/// Returns the node name of a USB device, `bus/usb/BBB/DDD`
+///
+/// Kernels before 2.6.26 do not set `DEVNAME` for USB devices, so it is derived from the
+/// bus and device numbers as busybox mdev does.
+pub fn device_name(env: &HashMap<String, String>) -> Option<String> {
+    if env.get("DEVTYPE").is_none_or(|t| t != "usb_device") {
+        return None;
+    }
+    let bus: u32 = env.get("BUSNUM")?.parse().ok()?;
+    let device: u32 = env.get("DEVNUM")?.parse().ok()?;
+    Some(format!("bus/usb/{bus:03}/{device:03}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    #[test]
+    fn device_name() {
+        let mut env = HashMap::from([
+            (String::from("SUBSYSTEM"), String::from("usb")),
+            (String::from("DEVTYPE"), String::from("usb_device")),
+            (String::from("BUSNUM"), String::from("001")),
+            (String::from("DEVNUM"), String::from("4")),
+        ]);
+        assert_eq!(super::device_name(&env).as_deref(), Some("bus/usb/001/004"));
+
+        env.insert(String::from("DEVTYPE"), String::from("usb_interface"));
+        assert_eq!(super::device_name(&env), None);
+    }
+}
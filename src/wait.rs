@@ -0,0 +1,198 @@
+//! Waiting for a device node, e.g. the root filesystem in an initramfs
+//!
+//! The events are listened to before looking at the dev directory, so that a node created in
+//! between is not missed. The dev directory is only looked at again on the events, and every
+//! [`RECHECK`] as a fallback.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use futures_util::{stream::FusedStream, StreamExt};
+use kobject_uevent::{ActionType, UEvent};
+use tokio::time::{interval, sleep_until, Instant, MissedTickBehavior};
+use tracing::{debug, warn};
+
+use crate::{
+    enumerate::Enumerator,
+    stream::{UEventsBuilder, KERNEL_GROUP, REBROADCAST_GROUP},
+    Error, Result,
+};
+
+/// Interval the nodes are looked for again with no event, in case none follows their creation,
+/// e.g. by a hotplug helper with no daemon rebroadcasting, or no event can be listened to
+pub const RECHECK: Duration = Duration::from_secs(2);
+
+/// What [`wait_for_device`] waits for
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// A node or a link to it, e.g. `/dev/disk/by-label/root`
+    Path(PathBuf),
+    /// The node in `devpath` of any device listed by `enumerator`
+    Device {
+        enumerator: Enumerator,
+        devpath: PathBuf,
+    },
+}
+
+impl From<PathBuf> for Target {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl From<&Path> for Target {
+    fn from(path: &Path) -> Self {
+        Self::Path(path.to_path_buf())
+    }
+}
+
+impl From<&str> for Target {
+    fn from(path: &str) -> Self {
+        Self::Path(PathBuf::from(path))
+    }
+}
+
+/// The node in /dev of a device listed by the enumerator
+impl From<Enumerator> for Target {
+    fn from(enumerator: Enumerator) -> Self {
+        Self::Device {
+            enumerator,
+            devpath: PathBuf::from("/dev"),
+        }
+    }
+}
+
+impl Target {
+    /// Returns the nodes of the devices already there
+    fn nodes(&self) -> Vec<PathBuf> {
+        match self {
+            Self::Path(path) => vec![path.clone()],
+            Self::Device {
+                enumerator,
+                devpath,
+            } => enumerator
+                .scan()
+                .filter_map(|device| Some(devpath.join(device.devname()?)))
+                .collect(),
+        }
+    }
+
+    /// Adds the node of the device of `ev` to `nodes`, if it is one of the target
+    fn add_node(&self, ev: &UEvent, nodes: &mut Vec<PathBuf>) {
+        let Self::Device {
+            enumerator,
+            devpath,
+        } = self
+        else {
+            return;
+        };
+        if !matches!(ev.action, ActionType::Add | ActionType::Change) {
+            return;
+        }
+        let relative = ev.devpath.strip_prefix("/").unwrap_or(&ev.devpath);
+        let Some(device) = enumerator.device(&enumerator.sysfs().join(relative)) else {
+            return;
+        };
+        if let Some(node) = device.devname().map(|devname| devpath.join(devname)) {
+            if !nodes.contains(&node) {
+                debug!("Waiting for {:?}", node);
+                nodes.push(node);
+            }
+        }
+    }
+}
+
+/// Waits up to `timeout` for the node of a device, given by its path or as an [`Enumerator`],
+/// returns its path
+///
+/// The nodes are looked for again on each event of the kernel and of the daemon, and every
+/// [`RECHECK`]. A path is still waited for if the events cannot be listened to, a device is
+/// not as its node is only known from them.
+pub async fn wait_for_device(target: impl Into<Target>, timeout: Duration) -> Result<PathBuf> {
+    let target = target.into();
+    let deadline = Instant::now() + timeout;
+    let events = UEventsBuilder::new()
+        .groups(KERNEL_GROUP | REBROADCAST_GROUP)
+        .build();
+    let mut events = match (events, &target) {
+        (Ok(events), _) => Some(events),
+        (Err(e), Target::Path(path)) => {
+            warn!("Cannot listen to the events, looking for {path:?} every {RECHECK:?}: {e}");
+            None
+        }
+        (Err(e), Target::Device { .. }) => return Err(Error::Events(e)),
+    };
+    let mut nodes = target.nodes();
+    let mut recheck = interval(RECHECK);
+    recheck.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        if let Some(node) = nodes.iter().find(|node| node.exists()) {
+            return Ok(node.clone());
+        }
+        let listening = events
+            .as_ref()
+            .is_some_and(|events| !events.is_terminated());
+        tokio::select! {
+            () = sleep_until(deadline) => return Err(Error::Timeout(timeout)),
+            ev = async { events.as_mut().unwrap().next().await }, if listening => match ev {
+                Some(Ok(ev)) => target.add_node(&ev, &mut nodes),
+                Some(Err(e)) => debug!("{}", e),
+                None => debug!("No more events"),
+            },
+            _ = recheck.tick() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix::fs::symlink, process};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn path() {
+        let dir = env::temp_dir().join(format!("mdev-wait-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let node = dir.join("sda1");
+
+        assert!(matches!(
+            wait_for_device(node.as_path(), Duration::from_millis(50)).await,
+            Err(Error::Timeout(_))
+        ));
+
+        let created = node.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            fs::write(created, "").unwrap();
+        });
+        let found = wait_for_device(node.as_path(), Duration::from_secs(5)).await;
+        assert_eq!(found.unwrap(), node);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn device() {
+        let root = env::temp_dir().join(format!("mdev-wait-device-{}", process::id()));
+        let (sysfs, dev) = (root.join("sys"), root.join("dev"));
+        let disk = sysfs.join("devices/virtual/block/loop0");
+        fs::create_dir_all(&disk).unwrap();
+        fs::create_dir_all(&dev).unwrap();
+        fs::write(disk.join("uevent"), "DEVNAME=loop0\nDEVTYPE=disk\n").unwrap();
+        fs::write(disk.join("dev"), "7:0\n").unwrap();
+        symlink("/sys/class/block", disk.join("subsystem")).unwrap();
+
+        let target = Target::Device {
+            enumerator: Enumerator::new(&sysfs).match_property("DEVNAME", "loop*"),
+            devpath: dev.clone(),
+        };
+        let wait = tokio::spawn(wait_for_device(target, Duration::from_secs(5)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        fs::write(dev.join("loop0"), "").unwrap();
+        assert_eq!(wait.await.unwrap().unwrap(), dev.join("loop0"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+//! Keepalive of a watchdog device, e.g. `/dev/watchdog`
+//!
+//! The device is armed when opened and resets the system unless it is written to before its
+//! timeout, so it is left armed if the daemon dies or hangs.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Default location of the device
+pub const DEFAULT_PATH: &str = "/dev/watchdog";
+
+#[derive(Debug)]
+pub struct Watchdog(File);
+
+impl Watchdog {
+    /// Opens and arms the device at `path`
+    pub fn open(path: &Path) -> io::Result<Self> {
+        File::options().write(true).open(path).map(Self)
+    }
+
+    pub fn keepalive(&mut self) -> io::Result<()> {
+        self.0.write_all(b"\0")
+    }
+
+    /// Stops the device, unless its driver has been built to never stop once armed
+    pub fn disarm(mut self) -> io::Result<()> {
+        // the magic close
+        self.0.write_all(b"V")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn keepalive_and_disarm() {
+        let path = env::temp_dir().join(format!("mdev-watchdog-{}", process::id()));
+        fs::write(&path, "").unwrap();
+
+        let mut watchdog = Watchdog::open(&path).unwrap();
+        watchdog.keepalive().unwrap();
+        watchdog.keepalive().unwrap();
+        watchdog.disarm().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"\0\0V");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
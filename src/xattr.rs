@@ -0,0 +1,43 @@
+use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+/// Sets the extended attribute `name` of `path`, without following symlinks
+pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+
+    // SAFETY: path and name are valid NUL terminated strings and value is a valid slice
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    #[test]
+    fn user_xattr() {
+        let path = env::temp_dir().join(format!("mdev-xattr-{}", process::id()));
+        fs::write(&path, "").unwrap();
+
+        let res = super::set(&path, "user.mdev", b"test");
+        fs::remove_file(&path).unwrap();
+        match res {
+            Ok(()) => {}
+            // the temporary directory may not support user xattrs
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {}
+            Err(e) => panic!("{e}"),
+        }
+    }
+}